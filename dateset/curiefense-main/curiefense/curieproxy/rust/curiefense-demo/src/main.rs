@@ -0,0 +1,270 @@
+/* Self-contained curiefense demo
+
+   A tiny HTTP server with a compiled-in sample configuration, so that the
+   engine can be experienced with a single command, without Envoy or NGINX:
+
+       cf-demo
+       curl localhost:8080/
+       curl -H 'x-demo-block: 1' localhost:8080/
+
+   The sample configuration tags requests carrying the x-demo-block header
+   and force-denies them through the ACL, and rate limits each client IP.
+   Requests that pass are forwarded to the upstream given with --upstream,
+   or answered with a canned page when there is none. Decisions and the
+   JSON access log are printed to stdout.
+
+   This is a demonstration tool: the HTTP handling is minimal (no keepalive,
+   no chunked bodies) and not meant to face real traffic.
+*/
+
+use std::collections::HashMap;
+
+use async_std::io::prelude::{ReadExt, WriteExt};
+use async_std::net::{TcpListener, TcpStream};
+use include_dir::{include_dir, Dir};
+use structopt::StructOpt;
+
+use curiefense::config::reload_config;
+use curiefense::grasshopper::DynGrasshopper;
+use curiefense::inspect_generic_request_map_async;
+use curiefense::interface::{jsonlog, Action};
+use curiefense::logs::Logs;
+use curiefense::utils::{RawRequest, RequestMeta};
+
+static SAMPLE_CONFIG: Dir = include_dir!("$CARGO_MANIFEST_DIR/sample-config");
+
+#[derive(Debug, StructOpt)]
+#[structopt(name = "cf-demo", about = "A self-contained curiefense demo server.")]
+struct Opt {
+    #[structopt(long, default_value = "127.0.0.1:8080")]
+    listen: String,
+    /// host:port to forward passing requests to, answers a canned page when absent
+    #[structopt(long)]
+    upstream: Option<String>,
+    /// use this configuration directory instead of the embedded sample
+    #[structopt(long)]
+    configpath: Option<String>,
+}
+
+struct DemoRequest {
+    method: String,
+    path: String,
+    headers: HashMap<String, String>,
+    body: Vec<u8>,
+}
+
+fn find_subsequence(haystack: &[u8], needle: &[u8]) -> Option<usize> {
+    haystack.windows(needle.len()).position(|w| w == needle)
+}
+
+async fn read_request(stream: &mut TcpStream) -> Result<DemoRequest, String> {
+    let mut buf = Vec::new();
+    let mut tmp = [0u8; 4096];
+    let header_end = loop {
+        if let Some(i) = find_subsequence(&buf, b"\r\n\r\n") {
+            break i;
+        }
+        if buf.len() > 65536 {
+            return Err("headers too large".to_string());
+        }
+        let n = stream.read(&mut tmp).await.map_err(|rr| rr.to_string())?;
+        if n == 0 {
+            return Err("connection closed".to_string());
+        }
+        buf.extend_from_slice(&tmp[..n]);
+    };
+    let head = std::str::from_utf8(&buf[..header_end]).map_err(|rr| rr.to_string())?;
+    let mut lines = head.split("\r\n");
+    let requestline = lines.next().ok_or("empty request")?;
+    let mut parts = requestline.split(' ');
+    let method = parts.next().ok_or("no method")?.to_string();
+    let path = parts.next().ok_or("no path")?.to_string();
+    let mut headers = HashMap::new();
+    for line in lines {
+        if let Some((name, value)) = line.split_once(':') {
+            headers.insert(name.trim().to_ascii_lowercase(), value.trim().to_string());
+        }
+    }
+    let content_length: usize = headers
+        .get("content-length")
+        .and_then(|s| s.parse().ok())
+        .unwrap_or(0);
+    let mut body = buf[header_end + 4..].to_vec();
+    while body.len() < content_length {
+        let n = stream.read(&mut tmp).await.map_err(|rr| rr.to_string())?;
+        if n == 0 {
+            break;
+        }
+        body.extend_from_slice(&tmp[..n]);
+    }
+    Ok(DemoRequest {
+        method,
+        path,
+        headers,
+        body,
+    })
+}
+
+/// forwards the request to the upstream, returning the raw response and the
+/// upstream status code
+async fn forward(upstream: &str, req: &DemoRequest) -> Result<(Vec<u8>, Option<u32>), String> {
+    let mut stream = TcpStream::connect(upstream).await.map_err(|rr| rr.to_string())?;
+    let mut head = format!("{} {} HTTP/1.1\r\n", req.method, req.path);
+    for (name, value) in &req.headers {
+        if name != "connection" {
+            head.push_str(&format!("{}: {}\r\n", name, value));
+        }
+    }
+    head.push_str("connection: close\r\n\r\n");
+    stream.write_all(head.as_bytes()).await.map_err(|rr| rr.to_string())?;
+    stream.write_all(&req.body).await.map_err(|rr| rr.to_string())?;
+    let mut answer = Vec::new();
+    stream.read_to_end(&mut answer).await.map_err(|rr| rr.to_string())?;
+    let status = std::str::from_utf8(&answer)
+        .ok()
+        .and_then(|s| s.split(' ').nth(1))
+        .and_then(|c| c.parse().ok());
+    Ok((answer, status))
+}
+
+fn simple_response(status: u32, content_type: &str, content: &str) -> Vec<u8> {
+    format!(
+        "HTTP/1.1 {} CF\r\ncontent-type: {}\r\ncontent-length: {}\r\nconnection: close\r\n\r\n{}",
+        status,
+        content_type,
+        content.len(),
+        content
+    )
+    .into_bytes()
+}
+
+fn action_response(action: &Action) -> Vec<u8> {
+    let mut out = format!("HTTP/1.1 {} CF\r\n", action.status);
+    if let Some(headers) = &action.headers {
+        for (name, value) in headers {
+            out.push_str(&format!("{}: {}\r\n", name, value));
+        }
+    }
+    out.push_str(&format!(
+        "content-length: {}\r\nconnection: close\r\n\r\n{}",
+        action.content.len(),
+        action.content
+    ));
+    out.into_bytes()
+}
+
+async fn handle(mut socket: TcpStream, upstream: Option<String>) {
+    let req = match read_request(&mut socket).await {
+        Ok(r) => r,
+        Err(rr) => {
+            eprintln!("Could not read the request: {}", rr);
+            return;
+        }
+    };
+    let ipstr = socket
+        .peer_addr()
+        .map(|a| a.ip().to_string())
+        .unwrap_or_else(|_| "127.0.0.1".to_string());
+    let meta = RequestMeta {
+        authority: req.headers.get("host").cloned(),
+        method: req.method.clone(),
+        path: req.path.clone(),
+        requestid: None,
+        protocol: Some("http".to_string()),
+        client_cert_subject: None,
+        client_cert_san: None,
+        client_cert_fingerprint: None,
+        extra: HashMap::new(),
+    };
+    let raw = RawRequest {
+        ipstr,
+        headers: req.headers.clone(),
+        meta,
+        mbody: if req.body.is_empty() { None } else { Some(&req.body) },
+    };
+    let mut logs = Logs::default();
+    let result =
+        inspect_generic_request_map_async(Some(&DynGrasshopper {}), raw, &mut logs, None, None, HashMap::new()).await;
+
+    let (answer, status) = if result.decision.is_blocking() {
+        match &result.decision.maction {
+            Some(action) => (action_response(action), Some(action.status)),
+            None => (simple_response(403, "text/plain", "blocked\n"), Some(403)),
+        }
+    } else {
+        match &upstream {
+            Some(up) => match forward(up, &req).await {
+                Ok((answer, status)) => (answer, status),
+                Err(rr) => (
+                    simple_response(502, "text/plain", &format!("upstream error: {}\n", rr)),
+                    Some(502),
+                ),
+            },
+            None => (
+                simple_response(200, "text/plain", "welcome to the curiefense demo\n"),
+                Some(200),
+            ),
+        }
+    };
+    let _ = socket.write_all(&answer).await;
+
+    println!(
+        "{} {} -> {}",
+        req.method,
+        req.path,
+        if result.decision.is_blocking() { "BLOCKED" } else { "PASS" }
+    );
+    let mut proxy = HashMap::new();
+    if let Some(code) = status {
+        proxy.insert("status".to_string(), code.to_string());
+    }
+    let (logged, _) = jsonlog(
+        &result.decision,
+        Some(&result.rinfo),
+        status,
+        &result.tags,
+        &result.stats,
+        &logs,
+        proxy,
+    )
+    .await;
+    if logged != b"null" {
+        println!("{}", String::from_utf8_lossy(&logged));
+    }
+}
+
+#[async_std::main]
+async fn main() -> Result<(), Box<dyn std::error::Error>> {
+    let opt = Opt::from_args();
+
+    // without a redis server, keep the rate limit counters in memory
+    if std::env::var("LIMIT_BACKEND").is_err() {
+        std::env::set_var("LIMIT_BACKEND", "memory");
+    }
+
+    let configpath = match &opt.configpath {
+        Some(p) => p.clone(),
+        None => {
+            let target = std::env::temp_dir().join("curiefense-demo-config");
+            std::fs::create_dir_all(&target)?;
+            SAMPLE_CONFIG.extract(&target)?;
+            target.join("config").to_string_lossy().to_string()
+        }
+    };
+    reload_config(&configpath, Vec::new());
+    let mut logs = Logs::default();
+    curiefense::config::with_config(&mut logs, |_, cfg| {
+        println!("Loaded configuration revision {}", cfg.revision);
+    });
+    for l in logs.to_stringvec() {
+        println!("{}", l);
+    }
+
+    let listener = TcpListener::bind(&opt.listen).await?;
+    println!("Demo server listening on http://{}", opt.listen);
+    loop {
+        let (socket, _) = listener.accept().await?;
+        let upstream = opt.upstream.clone();
+        async_std::task::spawn(handle(socket, upstream));
+    }
+}
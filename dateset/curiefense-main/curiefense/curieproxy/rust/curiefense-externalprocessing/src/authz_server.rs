@@ -0,0 +1,262 @@
+use curiefense::{
+    config::with_config,
+    grasshopper::DynGrasshopper,
+    incremental::extract_ip,
+    inspect_generic_request_map_async,
+    interface::{aggregator::aggregated_values, jsonlog, BlockReason},
+    logs::{LogLevel, Logs},
+    utils::{RawRequest, RequestMeta},
+};
+use log::{debug, error, info, warn, LevelFilter};
+use std::collections::HashMap;
+use structopt::StructOpt;
+use syslog::Facility;
+use tokio::signal::unix::{signal, SignalKind};
+use tonic::{transport::Server, Request, Response, Status};
+
+mod ext_authz;
+mod metadata;
+
+use metadata::decision_metadata;
+
+use ext_authz::{
+    authorization_server::{Authorization, AuthorizationServer},
+    check_response, CheckRequest, CheckResponse, DeniedHttpResponse, HeaderValue, HeaderValueOption, HttpStatus,
+    OkHttpResponse, RpcStatus,
+};
+
+// gRPC status codes from google.rpc.Code
+const RPC_OK: i32 = 0;
+const RPC_PERMISSION_DENIED: i32 = 7;
+
+#[derive(Clone)]
+struct MyAuthz {
+    loglevel: LogLevel,
+    trustedhops: usize,
+}
+
+fn header_options(headers: &HashMap<String, String>) -> Vec<HeaderValueOption> {
+    headers
+        .iter()
+        .map(|(key, value)| HeaderValueOption {
+            header: Some(HeaderValue {
+                key: key.clone(),
+                value: value.clone(),
+            }),
+            append: None,
+            append_action: 0,
+        })
+        .collect()
+}
+
+impl MyAuthz {
+    async fn run_check(&self, request: CheckRequest) -> Result<CheckResponse, String> {
+        let http = request
+            .attributes
+            .as_ref()
+            .and_then(|a| a.request.as_ref())
+            .and_then(|r| r.http.as_ref())
+            .ok_or_else(|| "Missing http request attributes".to_string())?;
+
+        let headers: HashMap<String, String> = http.headers.iter().map(|(k, v)| (k.to_lowercase(), v.clone())).collect();
+
+        let peer_ip = request
+            .attributes
+            .as_ref()
+            .and_then(|a| a.source.as_ref())
+            .and_then(|p| p.address.as_ref())
+            .and_then(|a| a.socket_address.as_ref())
+            .map(|s| s.address.clone());
+        let ipstr = if self.trustedhops > 0 {
+            extract_ip(self.trustedhops, &headers).or(peer_ip)
+        } else {
+            peer_ip
+        }
+        .ok_or_else(|| "Could not determine the client address".to_string())?;
+
+        let mut metamap = HashMap::new();
+        metamap.insert("method".to_string(), http.method.clone());
+        metamap.insert("path".to_string(), http.path.clone());
+        if !http.host.is_empty() {
+            metamap.insert("authority".to_string(), http.host.clone());
+        }
+        if !http.id.is_empty() {
+            metamap.insert("x-request-id".to_string(), http.id.clone());
+        }
+        if !http.protocol.is_empty() {
+            metamap.insert("protocol".to_string(), http.protocol.clone());
+        }
+        let meta = RequestMeta::from_map(metamap)?;
+
+        // the body is only present when the filter is configured with with_request_body
+        let body: Option<Vec<u8>> = if !http.raw_body.is_empty() {
+            Some(http.raw_body.clone())
+        } else if !http.body.is_empty() {
+            Some(http.body.clone().into_bytes())
+        } else {
+            None
+        };
+
+        let raw = RawRequest {
+            ipstr,
+            headers,
+            meta,
+            mbody: body.as_deref(),
+        };
+        let mut logs = Logs::new(self.loglevel);
+        let result =
+            inspect_generic_request_map_async(Some(&DynGrasshopper {}), raw, &mut logs, None, None, HashMap::new())
+                .await;
+
+        let metadata = decision_metadata(&result);
+        let response = match &result.decision.maction {
+            Some(a) if a.block_mode => CheckResponse {
+                status: Some(RpcStatus {
+                    code: RPC_PERMISSION_DENIED,
+                    message: BlockReason::block_reason_desc(&result.decision.reasons).unwrap_or_default(),
+                }),
+                dynamic_metadata: Some(metadata),
+                http_response: Some(check_response::HttpResponse::DeniedResponse(DeniedHttpResponse {
+                    status: Some(HttpStatus { code: a.status as i32 }),
+                    headers: a.headers.as_ref().map(header_options).unwrap_or_default(),
+                    body: a.content.clone(),
+                })),
+            },
+            maction => CheckResponse {
+                status: Some(RpcStatus {
+                    code: RPC_OK,
+                    message: String::new(),
+                }),
+                dynamic_metadata: Some(metadata),
+                http_response: Some(check_response::HttpResponse::OkResponse(OkHttpResponse {
+                    // non blocking actions can still inject request headers
+                    headers: maction
+                        .as_ref()
+                        .and_then(|a| a.headers.as_ref())
+                        .map(header_options)
+                        .unwrap_or_default(),
+                    response_headers_to_add: Vec::new(),
+                })),
+            },
+        };
+
+        // ext_authz has no response phase, so the upstream status is unknown
+        // and deferred limits can not be updated here
+        let block_code = result.decision.maction.as_ref().filter(|a| a.block_mode).map(|a| a.status);
+        let (v, _) = jsonlog(
+            &result.decision,
+            Some(&result.rinfo),
+            block_code,
+            &result.tags,
+            &result.stats,
+            &logs,
+            HashMap::new(),
+        )
+        .await;
+        for l in logs.to_stringvec() {
+            debug!("{}", l);
+        }
+        info!("CFLOG {}", String::from_utf8_lossy(&v));
+
+        Ok(response)
+    }
+}
+
+#[tonic::async_trait]
+impl Authorization for MyAuthz {
+    async fn check(&self, request: Request<CheckRequest>) -> Result<Response<CheckResponse>, Status> {
+        match self.run_check(request.into_inner()).await {
+            Ok(response) => Ok(Response::new(response)),
+            Err(msg) => {
+                error!("{}", msg);
+                // failing closed: malformed check requests are denied
+                Ok(Response::new(CheckResponse {
+                    status: Some(RpcStatus {
+                        code: RPC_PERMISSION_DENIED,
+                        message: msg.clone(),
+                    }),
+                    dynamic_metadata: None,
+                    http_response: Some(check_response::HttpResponse::DeniedResponse(DeniedHttpResponse {
+                        status: Some(HttpStatus { code: 403 }),
+                        headers: Vec::new(),
+                        body: msg,
+                    })),
+                }))
+            }
+        }
+    }
+}
+
+fn show_logs(logs: Logs) {
+    let vlogs = logs.to_stringvec();
+    if !vlogs.is_empty() {
+        warn!("CONFIGURATION LOGS:");
+        for l in vlogs {
+            warn!("{}", l);
+        }
+    }
+}
+
+#[derive(Debug, StructOpt)]
+#[structopt(
+    name = "cf-extauthz",
+    about = "An envoy ext_authz gRPC server for curiefense, for deployments that can not run ext_proc."
+)]
+struct Opt {
+    #[structopt(long, default_value = "0.0.0.0:50052")]
+    listen: String,
+    #[structopt(long, default_value = "info")]
+    loglevel: String,
+    /// number of trusted proxies in front of envoy, 0 uses the peer address
+    #[structopt(long, default_value = "0")]
+    trustedhops: usize,
+    #[structopt(long)]
+    syslog: bool,
+}
+
+#[tokio::main]
+async fn main() -> Result<(), Box<dyn std::error::Error>> {
+    let opt = Opt::from_args();
+    let loglevel = opt.loglevel.parse()?;
+    let level_filter = match &loglevel {
+        LogLevel::Debug => LevelFilter::Debug,
+        _ => LevelFilter::Info,
+    };
+    // initial configuration loading
+    let mut logs = Logs::new(loglevel);
+    with_config(&mut logs, |_, _| {});
+    show_logs(logs);
+
+    if opt.syslog {
+        syslog::init_unix(Facility::LOG_USER, level_filter)?;
+    } else {
+        simplelog::TermLogger::init(
+            level_filter,
+            simplelog::Config::default(),
+            simplelog::TerminalMode::Stdout,
+            simplelog::ColorChoice::Auto,
+        )?;
+    };
+
+    let authz = MyAuthz {
+        loglevel,
+        trustedhops: opt.trustedhops,
+    };
+
+    info!("Listening on {}", opt.listen);
+    let shutdown = async {
+        let mut sigterm = signal(SignalKind::terminate()).expect("could not install the SIGTERM handler");
+        sigterm.recv().await;
+        info!("SIGTERM received, shutting down");
+    };
+    Server::builder()
+        .accept_http1(true)
+        .add_service(AuthorizationServer::new(authz))
+        .serve_with_shutdown(opt.listen.parse()?, shutdown)
+        .await?;
+
+    // flush the aggregated statistics before exiting
+    info!("CFAGGREGATED {}", aggregated_values().await);
+
+    Ok(())
+}
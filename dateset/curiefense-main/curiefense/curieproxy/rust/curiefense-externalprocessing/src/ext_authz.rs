@@ -0,0 +1,331 @@
+/// Hand-trimmed messages for the `envoy.service.auth.v3.Authorization`
+/// protocol (ext_authz). Field tags match the upstream envoy protos, only the
+/// messages and fields used by the server are kept.
+///
+/// Header value, as used in responses to add or override headers.
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct HeaderValue {
+    /// Header name.
+    #[prost(string, tag = "1")]
+    pub key: ::prost::alloc::string::String,
+    /// Header value.
+    #[prost(string, tag = "2")]
+    pub value: ::prost::alloc::string::String,
+}
+/// Header name/value pair plus option to control append behavior.
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct HeaderValueOption {
+    /// Header name/value pair that this option applies to.
+    #[prost(message, optional, tag = "1")]
+    pub header: ::core::option::Option<HeaderValue>,
+    /// Should the value be appended?
+    #[prost(message, optional, tag = "2")]
+    pub append: ::core::option::Option<bool>,
+    /// Describes the action taken to append/overwrite the given value for an existing header
+    /// or to only add this header if it's absent.
+    #[prost(enumeration = "HeaderAppendAction", tag = "3")]
+    pub append_action: i32,
+}
+/// Action to be taken when a header is present.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, PartialOrd, Ord, ::prost::Enumeration)]
+#[repr(i32)]
+pub enum HeaderAppendAction {
+    /// This action will append the specified value to the existing values if the header
+    /// already exists.
+    AppendIfExistsOrAdd = 0,
+    /// This action will add the header if it doesn't already exist.
+    AddIfAbsent = 1,
+    /// This action will overwrite the specified value by discarding any existing values if
+    /// the header already exists.
+    OverwriteIfExistsOrAdd = 2,
+}
+/// Envoy uses SocketAddress to represent an internet address.
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct SocketAddress {
+    /// The address for this socket.
+    #[prost(string, tag = "2")]
+    pub address: ::prost::alloc::string::String,
+    #[prost(oneof = "socket_address::PortSpecifier", tags = "3, 4")]
+    pub port_specifier: ::core::option::Option<socket_address::PortSpecifier>,
+}
+/// Nested message and enum types in `SocketAddress`.
+pub mod socket_address {
+    #[derive(Clone, PartialEq, ::prost::Oneof)]
+    pub enum PortSpecifier {
+        #[prost(uint32, tag = "3")]
+        PortValue(u32),
+        #[prost(string, tag = "4")]
+        NamedPort(::prost::alloc::string::String),
+    }
+}
+/// Addresses specify either a logical or physical address and port.
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct Address {
+    #[prost(message, optional, tag = "1")]
+    pub socket_address: ::core::option::Option<SocketAddress>,
+}
+/// HTTP status.
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct HttpStatus {
+    /// Supplies HTTP response code.
+    #[prost(int32, tag = "1")]
+    pub code: i32,
+}
+/// The `Status` type defines a logical error model, as used by the gRPC APIs.
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct RpcStatus {
+    /// The status code.
+    #[prost(int32, tag = "1")]
+    pub code: i32,
+    /// A developer-facing error message.
+    #[prost(string, tag = "2")]
+    pub message: ::prost::alloc::string::String,
+}
+/// An attribute is a piece of metadata that describes an activity on a network.
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct AttributeContext {
+    /// The source of a network activity, such as starting a TCP connection.
+    #[prost(message, optional, tag = "1")]
+    pub source: ::core::option::Option<attribute_context::Peer>,
+    /// The destination of a network activity, such as accepting a TCP connection.
+    #[prost(message, optional, tag = "2")]
+    pub destination: ::core::option::Option<attribute_context::Peer>,
+    /// Represents a network request, such as an HTTP request.
+    #[prost(message, optional, tag = "4")]
+    pub request: ::core::option::Option<attribute_context::Request>,
+    /// Supplies the parameters of the ext_authz filter.
+    #[prost(map = "string, string", tag = "10")]
+    pub context_extensions:
+        ::std::collections::HashMap<::prost::alloc::string::String, ::prost::alloc::string::String>,
+}
+/// Nested message and enum types in `AttributeContext`.
+pub mod attribute_context {
+    /// This message defines attributes for a node that handles a network request.
+    #[derive(Clone, PartialEq, ::prost::Message)]
+    pub struct Peer {
+        /// The address of the peer, this is typically the IP address.
+        #[prost(message, optional, tag = "1")]
+        pub address: ::core::option::Option<super::Address>,
+        /// The canonical service name of the peer.
+        #[prost(string, tag = "2")]
+        pub service: ::prost::alloc::string::String,
+        /// The authenticated identity of this peer.
+        #[prost(string, tag = "4")]
+        pub principal: ::prost::alloc::string::String,
+    }
+    /// Represents a network request, such as an HTTP request.
+    #[derive(Clone, PartialEq, ::prost::Message)]
+    pub struct Request {
+        /// Represents an HTTP request or an HTTP-like request.
+        #[prost(message, optional, tag = "2")]
+        pub http: ::core::option::Option<HttpRequest>,
+    }
+    /// This message defines attributes for an HTTP request.
+    #[derive(Clone, PartialEq, ::prost::Message)]
+    pub struct HttpRequest {
+        /// The unique ID for a request.
+        #[prost(string, tag = "1")]
+        pub id: ::prost::alloc::string::String,
+        /// The HTTP request method, such as "GET", "POST".
+        #[prost(string, tag = "2")]
+        pub method: ::prost::alloc::string::String,
+        /// The HTTP request headers.
+        #[prost(map = "string, string", tag = "3")]
+        pub headers:
+            ::std::collections::HashMap<::prost::alloc::string::String, ::prost::alloc::string::String>,
+        /// The request target, as it appears in the first line of the HTTP request.
+        #[prost(string, tag = "4")]
+        pub path: ::prost::alloc::string::String,
+        /// The HTTP request "Host" or ":authority" header value.
+        #[prost(string, tag = "5")]
+        pub host: ::prost::alloc::string::String,
+        /// The HTTP URL scheme, such as "http" and "https".
+        #[prost(string, tag = "6")]
+        pub scheme: ::prost::alloc::string::String,
+        /// The network protocol used with the request, such as "HTTP/1.1".
+        #[prost(string, tag = "10")]
+        pub protocol: ::prost::alloc::string::String,
+        /// The HTTP request body.
+        #[prost(string, tag = "11")]
+        pub body: ::prost::alloc::string::String,
+        /// The HTTP request body in bytes, used when the body is non-UTF-8.
+        #[prost(bytes = "vec", tag = "12")]
+        pub raw_body: ::prost::alloc::vec::Vec<u8>,
+    }
+}
+/// Request for the Check method.
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct CheckRequest {
+    /// The request attributes.
+    #[prost(message, optional, tag = "1")]
+    pub attributes: ::core::option::Option<AttributeContext>,
+}
+/// HTTP attributes for a denied response.
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct DeniedHttpResponse {
+    /// This field allows the authorization service to send an HTTP response status code to the
+    /// downstream client.
+    #[prost(message, optional, tag = "1")]
+    pub status: ::core::option::Option<HttpStatus>,
+    /// This field allows the authorization service to send HTTP response headers to the downstream
+    /// client.
+    #[prost(message, repeated, tag = "2")]
+    pub headers: ::prost::alloc::vec::Vec<HeaderValueOption>,
+    /// This field allows the authorization service to send a response body to the downstream client.
+    #[prost(string, tag = "3")]
+    pub body: ::prost::alloc::string::String,
+}
+/// HTTP attributes for an OK response.
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct OkHttpResponse {
+    /// HTTP entity headers in addition to the original request headers.
+    #[prost(message, repeated, tag = "1")]
+    pub headers: ::prost::alloc::vec::Vec<HeaderValueOption>,
+    /// This field allows the authorization service to send HTTP response headers to the downstream
+    /// client on success.
+    #[prost(message, repeated, tag = "7")]
+    pub response_headers_to_add: ::prost::alloc::vec::Vec<HeaderValueOption>,
+}
+/// Intended for gRPC and Network Authorization servers only.
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct CheckResponse {
+    /// Status `OK` allows the request. Any other status indicates the request should be denied.
+    #[prost(message, optional, tag = "1")]
+    pub status: ::core::option::Option<RpcStatus>,
+    /// Optional response metadata that will be emitted as dynamic metadata to be consumed by the next
+    /// filter.
+    #[prost(message, optional, tag = "4")]
+    pub dynamic_metadata: ::core::option::Option<::prost_types::Struct>,
+    /// An message that contains HTTP response attributes.
+    #[prost(oneof = "check_response::HttpResponse", tags = "2, 3")]
+    pub http_response: ::core::option::Option<check_response::HttpResponse>,
+}
+/// Nested message and enum types in `CheckResponse`.
+pub mod check_response {
+    /// An message that contains HTTP response attributes.
+    #[derive(Clone, PartialEq, ::prost::Oneof)]
+    pub enum HttpResponse {
+        /// Supplies http attributes for a denied response.
+        #[prost(message, tag = "2")]
+        DeniedResponse(super::DeniedHttpResponse),
+        /// Supplies http attributes for an ok response.
+        #[prost(message, tag = "3")]
+        OkResponse(super::OkHttpResponse),
+    }
+}
+/// Generated server implementations.
+pub mod authorization_server {
+    #![allow(unused_variables, dead_code, missing_docs, clippy::let_unit_value)]
+    use tonic::codegen::*;
+    ///Generated trait containing gRPC methods that should be implemented for use with AuthorizationServer.
+    #[async_trait]
+    pub trait Authorization: Send + Sync + 'static {
+        /// Performs authorization check based on the attributes associated with the
+        /// incoming request, and returns status `OK` or not `OK`.
+        async fn check(
+            &self,
+            request: tonic::Request<super::CheckRequest>,
+        ) -> Result<tonic::Response<super::CheckResponse>, tonic::Status>;
+    }
+    #[derive(Debug)]
+    pub struct AuthorizationServer<T: Authorization> {
+        inner: _Inner<T>,
+        accept_compression_encodings: (),
+        send_compression_encodings: (),
+    }
+    struct _Inner<T>(Arc<T>);
+    impl<T: Authorization> AuthorizationServer<T> {
+        pub fn new(inner: T) -> Self {
+            Self::from_arc(Arc::new(inner))
+        }
+        pub fn from_arc(inner: Arc<T>) -> Self {
+            let inner = _Inner(inner);
+            Self {
+                inner,
+                accept_compression_encodings: Default::default(),
+                send_compression_encodings: Default::default(),
+            }
+        }
+        pub fn with_interceptor<F>(inner: T, interceptor: F) -> InterceptedService<Self, F>
+        where
+            F: tonic::service::Interceptor,
+        {
+            InterceptedService::new(Self::new(inner), interceptor)
+        }
+    }
+    impl<T, B> tonic::codegen::Service<http::Request<B>> for AuthorizationServer<T>
+    where
+        T: Authorization,
+        B: Body + Send + 'static,
+        B::Error: Into<StdError> + Send + 'static,
+    {
+        type Response = http::Response<tonic::body::BoxBody>;
+        type Error = std::convert::Infallible;
+        type Future = BoxFuture<Self::Response, Self::Error>;
+        fn poll_ready(&mut self, _cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+            Poll::Ready(Ok(()))
+        }
+        fn call(&mut self, req: http::Request<B>) -> Self::Future {
+            let inner = self.inner.clone();
+            match req.uri().path() {
+                "/envoy.service.auth.v3.Authorization/Check" => {
+                    #[allow(non_camel_case_types)]
+                    struct CheckSvc<T: Authorization>(pub Arc<T>);
+                    impl<T: Authorization> tonic::server::UnaryService<super::CheckRequest> for CheckSvc<T> {
+                        type Response = super::CheckResponse;
+                        type Future = BoxFuture<tonic::Response<Self::Response>, tonic::Status>;
+                        fn call(&mut self, request: tonic::Request<super::CheckRequest>) -> Self::Future {
+                            let inner = self.0.clone();
+                            let fut = async move { (*inner).check(request).await };
+                            Box::pin(fut)
+                        }
+                    }
+                    let accept_compression_encodings = self.accept_compression_encodings;
+                    let send_compression_encodings = self.send_compression_encodings;
+                    let inner = self.inner.clone();
+                    let fut = async move {
+                        let inner = inner.0;
+                        let method = CheckSvc(inner);
+                        let codec = tonic::codec::ProstCodec::default();
+                        let mut grpc = tonic::server::Grpc::new(codec)
+                            .apply_compression_config(accept_compression_encodings, send_compression_encodings);
+                        let res = grpc.unary(method, req).await;
+                        Ok(res)
+                    };
+                    Box::pin(fut)
+                }
+                _ => Box::pin(async move {
+                    Ok(http::Response::builder()
+                        .status(200)
+                        .header("grpc-status", "12")
+                        .header("content-type", "application/grpc")
+                        .body(empty_body())
+                        .unwrap())
+                }),
+            }
+        }
+    }
+    impl<T: Authorization> Clone for AuthorizationServer<T> {
+        fn clone(&self) -> Self {
+            let inner = self.inner.clone();
+            Self {
+                inner,
+                accept_compression_encodings: self.accept_compression_encodings,
+                send_compression_encodings: self.send_compression_encodings,
+            }
+        }
+    }
+    impl<T: Authorization> Clone for _Inner<T> {
+        fn clone(&self) -> Self {
+            Self(self.0.clone())
+        }
+    }
+    impl<T: std::fmt::Debug> std::fmt::Debug for _Inner<T> {
+        fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+            write!(f, "{:?}", self.0)
+        }
+    }
+    impl<T: Authorization> tonic::transport::NamedService for AuthorizationServer<T> {
+        const NAME: &'static str = "envoy.service.auth.v3.Authorization";
+    }
+}
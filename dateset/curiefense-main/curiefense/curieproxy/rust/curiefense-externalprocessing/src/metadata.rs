@@ -0,0 +1,51 @@
+//! dynamic metadata shared by the ext_proc and ext_authz binaries
+//!
+//! Both servers attach the same verdict metadata to their responses, so that
+//! downstream filters (rate limiters, routers, access loggers) can use the
+//! WAF verdict without having to parse headers; keeping one copy here avoids
+//! the two binaries drifting apart.
+
+use curiefense::interface::{AnalyzeResult, BlockReason};
+
+/// builds the dynamic metadata attached to responses
+pub fn decision_metadata(result: &AnalyzeResult) -> prost_types::Struct {
+    use prost_types::{value::Kind, ListValue, Value};
+
+    fn string_value(s: &str) -> Value {
+        Value {
+            kind: Some(Kind::StringValue(s.to_string())),
+        }
+    }
+
+    let mut tags: Vec<&str> = result.tags.as_hash_ref().keys().map(|s| s.as_str()).collect();
+    tags.sort_unstable();
+
+    let mut fields = std::collections::BTreeMap::new();
+    fields.insert(
+        "tags".to_string(),
+        Value {
+            kind: Some(Kind::ListValue(ListValue {
+                values: tags.into_iter().map(string_value).collect(),
+            })),
+        },
+    );
+    fields.insert(
+        "secpolid".to_string(),
+        string_value(&result.rinfo.rinfo.secpolicy.policy.id),
+    );
+    fields.insert(
+        "secpolentryid".to_string(),
+        string_value(&result.rinfo.rinfo.secpolicy.entry.id),
+    );
+    fields.insert("fingerprint".to_string(), string_value(&result.rinfo.session));
+    fields.insert(
+        "blocked".to_string(),
+        Value {
+            kind: Some(Kind::BoolValue(result.decision.blocked())),
+        },
+    );
+    if let Some(desc) = BlockReason::block_reason_desc(&result.decision.reasons) {
+        fields.insert("reason".to_string(), string_value(&desc));
+    }
+    prost_types::Struct { fields }
+}
@@ -1,31 +1,57 @@
 use chrono::{DateTime, Utc};
 use curiefense::{
-    config::{flow::FlowMap, globalfilter::GlobalFilterSection, virtualtags::VirtualTags, with_config},
+    config::{
+        flow::{FirstSeen, FlowMap},
+        stickytags::StickyTag,
+        globalfilter::GlobalFilterSection,
+        virtualtags::VirtualTags,
+        with_config,
+    },
     grasshopper::DynGrasshopper,
-    incremental::{add_body, add_headers, finalize, inspect_init, IData, IPInfo},
-    interface::{jsonlog, AnalyzeResult},
+    incremental::{add_body, add_headers, finalize, inspect_init, matched_policy, IData, IPInfo},
+    interface::{aggregator::aggregated_values, jsonlog, AnalyzeResult},
+    limit::limit_report_status,
     logs::{LogLevel, Logs},
-    utils::RequestMeta,
+    outbound::{with_backoff, CircuitBreaker, OutboundConfig},
+    utils::{header_order_fingerprint, RequestMeta, HEADERS_FP_ATTR},
 };
 use elasticsearch::{http::transport::Transport, Elasticsearch};
 use lazy_static::lazy_static;
 use log::{debug, error, info, warn, LevelFilter};
-use std::{collections::HashMap, sync::RwLock};
+use std::{
+    collections::HashMap,
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc, RwLock,
+    },
+    time::{Duration, Instant},
+};
 use structopt::StructOpt;
 use syslog::{Facility, Formatter3164, LoggerBackend};
 use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    signal::unix::{signal, SignalKind},
     spawn,
-    sync::mpsc::{self, error::SendError, Receiver, Sender},
+    sync::{
+        mpsc::{self, error::SendError, Receiver, Sender},
+        Notify,
+    },
+};
+use tokio_stream::{
+    wrappers::{ReceiverStream, TcpListenerStream, UnixListenerStream},
+    Stream, StreamExt,
 };
-use tokio_stream::wrappers::ReceiverStream;
 use tonic::{transport::Server, Request, Status};
 
 mod ext_proc;
+mod metadata;
+
+use metadata::decision_metadata;
 
 use ext_proc::{
     external_processor_server::{ExternalProcessor, ExternalProcessorServer},
-    processing_response, BodyResponse, HeaderMutation, HeaderValue, HeaderValueOption, HeadersResponse, HttpStatus,
-    ImmediateResponse, ProcessingRequest, ProcessingResponse,
+    processing_mode, processing_response, BodyResponse, HeaderMutation, HeaderValue, HeaderValueOption,
+    HeadersResponse, HttpStatus, ImmediateResponse, ProcessingMode, ProcessingRequest, ProcessingResponse,
 };
 
 lazy_static! {
@@ -37,11 +63,28 @@ pub struct MyEP {
     handle_replies: bool,
     reqchannel: Sender<CfgRequest>,
     logsender: Option<Sender<(Vec<u8>, DateTime<Utc>)>>,
+    inflight: Arc<AtomicUsize>,
+}
+
+/// decrements the in-flight stream counter when a stream task completes, even if it panics
+struct InflightGuard(Arc<AtomicUsize>);
+
+impl InflightGuard {
+    fn new(counter: Arc<AtomicUsize>) -> Self {
+        counter.fetch_add(1, Ordering::SeqCst);
+        InflightGuard(counter)
+    }
+}
+
+impl Drop for InflightGuard {
+    fn drop(&mut self) {
+        self.0.fetch_sub(1, Ordering::SeqCst);
+    }
 }
 
 type CfgRequest = (
     RequestMeta,
-    Sender<Option<Result<(IData, Vec<GlobalFilterSection>, FlowMap, VirtualTags), String>>>,
+    Sender<Option<Result<(IData, Vec<GlobalFilterSection>, FlowMap, Vec<FirstSeen>, Vec<StickyTag>, VirtualTags), String>>>,
 );
 
 /// this function loops and waits for configuration queries
@@ -77,8 +120,10 @@ async fn configloop(rx: Receiver<CfgRequest>, configpath: &str, loglevel: LogLev
                 // that would not be necessary if we could avoid the autoreloading feature, but had a system for reloading the server when the configuration changes
                 let gf = cfg.globalfilters.clone();
                 let fl = cfg.flows.clone();
+                let fs = cfg.first_seen.clone();
+                let st = cfg.sticky_tags.clone();
                 let vtags = cfg.virtual_tags.clone();
-                (o, gf, fl, vtags)
+                (o, gf, fl, fs, st, vtags)
             })
         });
         show_logs(logs);
@@ -94,6 +139,8 @@ async fn configloop(rx: Receiver<CfgRequest>, configpath: &str, loglevel: LogLev
 
 async fn logloop(rx: Receiver<(Vec<u8>, DateTime<Utc>)>, client: Elasticsearch) {
     let mut mrx = rx;
+    let outbound = OutboundConfig::default();
+    let breaker = CircuitBreaker::new(&outbound);
     loop {
         match mrx.recv().await {
             None => {
@@ -102,20 +149,31 @@ async fn logloop(rx: Receiver<(Vec<u8>, DateTime<Utc>)>, client: Elasticsearch)
             }
             Some((v, now)) => {
                 let idx = now.format("curieaccesslog-%Y.%m.%d-000001").to_string();
-                match client
-                    .index(elasticsearch::IndexParts::Index(&idx))
-                    .body(v)
-                    .send()
-                    .await
-                {
-                    Err(rr) => error!("When logging to ES: {}", rr),
-                    Ok(response) => {
-                        if !response.status_code().is_success() {
-                            error!("When logging to ES: {:?}", response);
+                let sent = with_backoff(&outbound, &breaker, |attempt| {
+                    let idx = idx.clone();
+                    let body = v.clone();
+                    let client = &client;
+                    async move {
+                        if attempt > 0 {
+                            debug!("retrying log export to ES, attempt {}", attempt);
+                        }
+                        let response = client
+                            .index(elasticsearch::IndexParts::Index(&idx))
+                            .body(body)
+                            .send()
+                            .await
+                            .map_err(|rr| rr.to_string())?;
+                        if response.status_code().is_success() {
+                            Ok(response)
                         } else {
-                            info!("{:?}", response);
+                            Err(format!("{:?}", response))
                         }
                     }
+                })
+                .await;
+                match sent {
+                    Err(rr) => error!("When logging to ES: {}", rr),
+                    Ok(response) => info!("{:?}", response),
                 }
             }
         }
@@ -127,11 +185,13 @@ impl MyEP {
         reqchannel: Sender<CfgRequest>,
         handle_replies: bool,
         logsender: Option<Sender<(Vec<u8>, DateTime<Utc>)>>,
+        inflight: Arc<AtomicUsize>,
     ) -> Self {
         MyEP {
             handle_replies,
             reqchannel,
             logsender,
+            inflight,
         }
     }
 
@@ -151,6 +211,8 @@ impl MyEP {
 
         let mut meta: HashMap<String, String> = HashMap::new();
         let mut mheaders: HashMap<String, String> = HashMap::new();
+        // header names in wire order, for the order based fingerprint
+        let mut header_order: Vec<String> = Vec::new();
         let headers_only = match next_message(msg).await?.request {
             Some(ext_proc::processing_request::Request::RequestHeaders(headers)) => {
                 if let Some(hdrmap) = headers.headers {
@@ -168,6 +230,7 @@ impl MyEP {
 
                         match metakey {
                             None => {
+                                header_order.push(h.key.to_lowercase());
                                 mheaders.insert(h.key, h.value);
                             }
                             Some(m) => {
@@ -181,6 +244,9 @@ impl MyEP {
             something_else => return Err(format!("Expected a RequestHeaders, but got {:?}", something_else)),
         };
 
+        if !header_order.is_empty() {
+            meta.insert(HEADERS_FP_ATTR.to_string(), header_order_fingerprint(header_order.iter()));
+        }
         let meta = match RequestMeta::from_map(meta) {
             Ok(m) => m,
             Err(rr) => {
@@ -189,33 +255,53 @@ impl MyEP {
             }
         };
 
+        // Expect: 100-continue requests can be decided on headers alone when
+        // the matched policy does not inspect the body: envoy is told not to
+        // stream the body, and a rejection reaches the client before the body
+        // is ever sent
+        let expect_continue = mheaders
+            .get("expect")
+            .map(|v| v.eq_ignore_ascii_case("100-continue"))
+            .unwrap_or(false);
+
         // get configuration data from the dedicated task
         let (rtx, mut rrx) = mpsc::channel(1);
         self.reqchannel.send((meta, rtx)).await.unwrap();
         let midata = rrx.recv().await;
 
-        let (idata, globalfilters, flows, vtags) = midata.unwrap().unwrap().unwrap();
+        let (idata, globalfilters, flows, first_seen, sticky_tags, vtags) = midata.unwrap().unwrap().unwrap();
 
         let mut idata = match add_headers(idata, mheaders) {
             Ok(i) => i,
             Err((logs, dec)) => {
-                self.send_action(ProcessingStage::Headers, tx, &dec, &logs, None).await;
+                self.send_action(ProcessingStage::Headers, tx, &dec, &logs, None, false).await;
                 return Ok(());
             }
         };
 
-        if !headers_only {
-            stage_pass(ProcessingStage::Headers, tx).await;
+        // the WAF will not inspect the body for this route, so there is no
+        // point in buffering it
+        let body_needed = matched_policy(&idata).body_needed;
+
+        // the verdict will not depend on the body, so the headers response
+        // carries it, along with a mode override that stops envoy from
+        // streaming the body to us
+        let skip_body = !headers_only && expect_continue && !body_needed;
+
+        if !headers_only && !skip_body {
+            stage_pass(ProcessingStage::Headers, tx, None, None).await;
             loop {
                 match next_message(msg).await?.request {
                     Some(ext_proc::processing_request::Request::RequestBody(bdy)) => {
-                        idata = match add_body(idata, &bdy.body) {
-                            Ok(i) => i,
-                            Err((logs, dec)) => {
-                                self.send_action(ProcessingStage::Body, tx, &dec, &logs, None).await;
-                                return Ok(());
-                            }
-                        };
+                        if body_needed {
+                            idata = match add_body(idata, &bdy.body) {
+                                Ok(i) => i,
+                                Err((logs, dec)) => {
+                                    self.send_action(ProcessingStage::Body, tx, &dec, &logs, None, false).await;
+                                    return Ok(());
+                                }
+                            };
+                        }
                         if bdy.end_of_stream {
                             break;
                         }
@@ -225,20 +311,30 @@ impl MyEP {
             }
         }
 
-        let (dec, logs) = finalize(idata, Some(&DynGrasshopper {}), &globalfilters, &flows, None, vtags).await;
-
-        let stage = if headers_only {
+        let (dec, logs) = finalize(
+            idata,
+            Some(&DynGrasshopper {}),
+            &globalfilters,
+            &flows,
+            &first_seen,
+            &sticky_tags,
+            None,
+            vtags,
+        )
+        .await;
+
+        let stage = if headers_only || skip_body {
             ProcessingStage::Headers
         } else {
             ProcessingStage::Body
         };
-        let blocked = self.send_action(stage, tx, &dec, &logs, None).await;
+        let blocked = self.send_action(stage, tx, &dec, &logs, None, skip_body).await;
         if !blocked {
             let code = if self.handle_replies {
                 let code: Option<u32> = match next_message(msg).await {
                     Ok(nmsg) => match nmsg.request {
                         Some(ext_proc::processing_request::Request::ResponseHeaders(hdrs)) => {
-                            stage_pass(ProcessingStage::RHeaders, tx).await;
+                            stage_pass(ProcessingStage::RHeaders, tx, None, None).await;
 
                             hdrs.headers
                                 .iter()
@@ -267,7 +363,7 @@ impl MyEP {
             } else {
                 Some(0)
             };
-            self.send_action(ProcessingStage::Reply, tx, &dec, &logs, code).await;
+            self.send_action(ProcessingStage::Reply, tx, &dec, &logs, code, false).await;
         }
         Ok(())
     }
@@ -279,35 +375,45 @@ impl MyEP {
         result: &AnalyzeResult,
         logs: &Logs,
         rcode: Option<u32>,
+        skip_body: bool,
     ) -> bool {
+        let metadata = decision_metadata(result);
         let blocked = match &result.decision.maction {
-            None => {
-                stage_pass(stage, tx).await;
+            Some(a) if a.block_mode => {
+                tx.send(Ok(ProcessingResponse {
+                    response: Some(ext_proc::processing_response::Response::ImmediateResponse(
+                        ImmediateResponse {
+                            status: Some(HttpStatus { code: a.status as i32 }),
+                            details: serde_json::to_string(&result.decision.reasons).unwrap(),
+                            body: a.content.clone(),
+                            headers: a.headers.clone().map(mutate_headers),
+                            grpc_status: None,
+                        },
+                    )),
+                    dynamic_metadata: Some(metadata),
+                    ..Default::default()
+                }))
+                .await
+                .unwrap();
+                true
+            }
+            _ => {
+                let mode_override = if skip_body { Some(no_body_mode()) } else { None };
+                stage_pass(stage, tx, Some(metadata), mode_override).await;
                 false
             }
-            Some(a) => {
-                if a.block_mode {
-                    tx.send(Ok(ProcessingResponse {
-                        response: Some(ext_proc::processing_response::Response::ImmediateResponse(
-                            ImmediateResponse {
-                                status: Some(HttpStatus { code: a.status as i32 }),
-                                details: serde_json::to_string(&result.decision.reasons).unwrap(),
-                                body: a.content.clone(),
-                                headers: a.headers.clone().map(mutate_headers),
-                                grpc_status: None,
-                            },
-                        )),
-                        ..Default::default()
-                    }))
-                    .await
-                    .unwrap();
-                    true
-                } else {
-                    stage_pass(stage, tx).await;
-                    false
+        };
+
+        // report the upstream status, so that deferred limit counters are updated
+        if !blocked && !result.deferred_limits.is_empty() {
+            if let Some(code) = rcode.filter(|c| *c > 0) {
+                let mut rlogs = Logs::default();
+                limit_report_status(&mut rlogs, &result.deferred_limits, code).await;
+                for l in rlogs.to_stringvec() {
+                    debug!("{}", l);
                 }
             }
-        };
+        }
 
         if blocked || rcode.is_some() {
             let block_code = rcode.or_else(|| result.decision.maction.as_ref().map(|a| a.status));
@@ -353,14 +459,28 @@ fn mutate_headers(headers: HashMap<String, String>) -> HeaderMutation {
 async fn send_response(
     tx: &mut Sender<Result<ProcessingResponse, Status>>,
     r: processing_response::Response,
+    dynamic_metadata: Option<prost_types::Struct>,
+    mode_override: Option<ProcessingMode>,
 ) -> Result<(), SendError<Result<ext_proc::ProcessingResponse, tonic::Status>>> {
     tx.send(Ok(ProcessingResponse {
         response: Some(r),
+        dynamic_metadata,
+        mode_override,
         ..Default::default()
     }))
     .await
 }
 
+/// a mode override that stops envoy from streaming the request body, used for
+/// Expect: 100-continue requests that are decided on headers alone (requires
+/// allow_mode_override in the envoy ext_proc filter configuration)
+fn no_body_mode() -> ProcessingMode {
+    ProcessingMode {
+        request_body_mode: processing_mode::BodySendMode::None as i32,
+        ..Default::default()
+    }
+}
+
 #[derive(Clone, Copy)]
 enum ProcessingStage {
     Headers,
@@ -369,7 +489,12 @@ enum ProcessingStage {
     Reply,
 }
 
-async fn stage_pass(stage: ProcessingStage, tx: &mut Sender<Result<ProcessingResponse, Status>>) {
+async fn stage_pass(
+    stage: ProcessingStage,
+    tx: &mut Sender<Result<ProcessingResponse, Status>>,
+    dynamic_metadata: Option<prost_types::Struct>,
+    mode_override: Option<ProcessingMode>,
+) {
     send_response(
         tx,
         match stage {
@@ -382,11 +507,79 @@ async fn stage_pass(stage: ProcessingStage, tx: &mut Sender<Result<ProcessingRes
             }
             ProcessingStage::Reply => return,
         },
+        dynamic_metadata,
+        mode_override,
     )
     .await
     .unwrap();
 }
 
+/// wraps a listener stream, counting accepted connections
+fn counted_incoming<S, T, E>(
+    incoming: S,
+    listener: &'static str,
+    counter: Arc<AtomicUsize>,
+) -> impl Stream<Item = Result<T, E>>
+where
+    S: Stream<Item = Result<T, E>>,
+{
+    incoming.map(move |conn| {
+        if conn.is_ok() {
+            let total = counter.fetch_add(1, Ordering::SeqCst) + 1;
+            debug!("Accepted {} connection #{}", listener, total);
+        }
+        conn
+    })
+}
+
+/// returns the unix socket inherited through systemd socket activation, if any
+fn systemd_socket() -> Option<std::os::unix::net::UnixListener> {
+    use std::os::unix::io::FromRawFd;
+    let pid: u32 = std::env::var("LISTEN_PID").ok()?.parse().ok()?;
+    if pid != std::process::id() {
+        return None;
+    }
+    let fds: i32 = std::env::var("LISTEN_FDS").ok()?.parse().ok()?;
+    if fds < 1 {
+        return None;
+    }
+    // systemd passes sockets starting at SD_LISTEN_FDS_START (3)
+    Some(unsafe { std::os::unix::net::UnixListener::from_raw_fd(3) })
+}
+
+/// minimal admin endpoint: GET /drain triggers the same drain sequence as SIGTERM
+async fn admin_loop(listen: String, drain: Arc<Notify>) {
+    let listener = match tokio::net::TcpListener::bind(&listen).await {
+        Ok(l) => l,
+        Err(rr) => {
+            error!("Could not bind the admin endpoint on {}: {}", listen, rr);
+            return;
+        }
+    };
+    loop {
+        let (mut socket, _) = match listener.accept().await {
+            Ok(s) => s,
+            Err(rr) => {
+                error!("Admin endpoint accept error: {}", rr);
+                continue;
+            }
+        };
+        let mut buf = [0u8; 512];
+        let n = socket.read(&mut buf).await.unwrap_or(0);
+        let request = String::from_utf8_lossy(&buf[..n]);
+        let reply: &[u8] = if request.starts_with("GET /drain") || request.starts_with("POST /drain") {
+            info!("Drain requested through the admin endpoint");
+            drain.notify_waiters();
+            b"HTTP/1.1 200 OK\r\ncontent-length: 9\r\n\r\ndraining\n"
+        } else {
+            b"HTTP/1.1 404 Not Found\r\ncontent-length: 0\r\n\r\n"
+        };
+        if let Err(rr) = socket.write_all(reply).await {
+            error!("Admin endpoint write error: {}", rr);
+        }
+    }
+}
+
 fn show_logs(logs: Logs) {
     let vlogs = logs.to_stringvec();
     if !vlogs.is_empty() {
@@ -408,8 +601,10 @@ impl ExternalProcessor for MyEP {
         let mut message = request.into_inner();
 
         let cep = self.clone();
+        let guard = InflightGuard::new(self.inflight.clone());
 
         spawn(async move {
+            let _guard = guard;
             if let Err(msg) = cep.handle(&mut tx, &mut message).await {
                 error!("{}", msg);
                 send_response(
@@ -421,6 +616,8 @@ impl ExternalProcessor for MyEP {
                         grpc_status: None,
                         details: msg,
                     }),
+                    None,
+                    None,
                 )
                 .await
                 .unwrap()
@@ -453,6 +650,18 @@ struct Opt {
     syslog: bool,
     #[structopt(long)]
     elasticsearch: Option<String>,
+    /// how long to wait for in-flight inspections when draining, in seconds
+    #[structopt(long, default_value = "10")]
+    drain_timeout: u64,
+    /// admin endpoint address, exposing the /drain trigger
+    #[structopt(long)]
+    adminlisten: Option<String>,
+    /// prometheus endpoint address, exposing /metrics
+    #[structopt(long)]
+    metricslisten: Option<String>,
+    /// listen on a unix socket at this path instead of TCP
+    #[structopt(long)]
+    unixlisten: Option<String>,
 }
 
 #[tokio::main]
@@ -461,7 +670,6 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
     // the reason is that with the asynchronous code, we can't borrow anything from the configuration,
     // but have to own everything, as there is no guarantee the configuration won't move under our feet.
     let opt = Opt::from_args();
-    let addr = opt.listen.parse()?;
     let loglevel = opt.loglevel.parse()?;
     let level_filter = match &loglevel {
         LogLevel::Debug => LevelFilter::Debug,
@@ -483,26 +691,110 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
         )?;
     };
 
+    if let Some(metricslisten) = opt.metricslisten.clone() {
+        curiefense::interface::metrics::spawn_exporter(metricslisten);
+    }
+
     let (ctx, crx) = mpsc::channel(4);
 
     let _ = spawn(async move { configloop(crx, &opt.configpath, loglevel, opt.trustedhops).await });
 
     let mut logsender: Option<Sender<(Vec<u8>, DateTime<Utc>)>> = None;
+    let mut loghandle = None;
 
     if let Some(esurl) = opt.elasticsearch {
         let (logtx, logrx) = mpsc::channel(500);
         let transport = Transport::single_node(&esurl)?;
         let client = Elasticsearch::new(transport);
         logsender = Some(logtx);
-        let _ = spawn(async move { logloop(logrx, client).await });
+        loghandle = Some(spawn(async move { logloop(logrx, client).await }));
+    }
+
+    // draining is triggered by SIGTERM, or through the admin endpoint
+    let drain = Arc::new(Notify::new());
+
+    let sigdrain = drain.clone();
+    spawn(async move {
+        let mut sigterm = signal(SignalKind::terminate()).expect("could not install the SIGTERM handler");
+        sigterm.recv().await;
+        info!("SIGTERM received, draining");
+        sigdrain.notify_waiters();
+    });
+
+    if let Some(adminlisten) = opt.adminlisten {
+        let admindrain = drain.clone();
+        spawn(async move { admin_loop(adminlisten, admindrain).await });
+    }
+
+    let inflight = Arc::new(AtomicUsize::new(0));
+    let ep = MyEP::new(ctx, opt.handle_replies, logsender, inflight.clone());
+    let shutdown = drain.clone();
+    let conncount = Arc::new(AtomicUsize::new(0));
+    let router = Server::builder().accept_http1(true).add_service(ExternalProcessorServer::new(ep));
+    let listener_name = if let Some(stdlistener) = systemd_socket() {
+        info!("Listening on the systemd activation socket");
+        stdlistener.set_nonblocking(true)?;
+        let listener = tokio::net::UnixListener::from_std(stdlistener)?;
+        router
+            .serve_with_incoming_shutdown(
+                counted_incoming(UnixListenerStream::new(listener), "systemd", conncount.clone()),
+                async move { shutdown.notified().await },
+            )
+            .await?;
+        "systemd"
+    } else if let Some(path) = &opt.unixlisten {
+        // remove a stale socket from a previous run
+        let _ = std::fs::remove_file(path);
+        let listener = tokio::net::UnixListener::bind(path)?;
+        info!("Listening on the unix socket {}", path);
+        router
+            .serve_with_incoming_shutdown(
+                counted_incoming(UnixListenerStream::new(listener), "unix", conncount.clone()),
+                async move { shutdown.notified().await },
+            )
+            .await?;
+        "unix"
+    } else {
+        let listener = tokio::net::TcpListener::bind(&opt.listen).await?;
+        info!("Listening on {}", opt.listen);
+        router
+            .serve_with_incoming_shutdown(
+                counted_incoming(TcpListenerStream::new(listener), "tcp", conncount.clone()),
+                async move { shutdown.notified().await },
+            )
+            .await?;
+        "tcp"
+    };
+    info!(
+        "Listener {} accepted {} connections",
+        listener_name,
+        conncount.load(Ordering::SeqCst)
+    );
+
+    // new streams are no longer accepted, wait for in-flight inspections to complete
+    let deadline = Instant::now() + Duration::from_secs(opt.drain_timeout);
+    while inflight.load(Ordering::SeqCst) > 0 && Instant::now() < deadline {
+        tokio::time::sleep(Duration::from_millis(50)).await;
+    }
+    let remaining = inflight.load(Ordering::SeqCst);
+    if remaining > 0 {
+        warn!("Drain timeout reached with {} in-flight inspections", remaining);
+    } else {
+        info!("All in-flight inspections completed");
     }
 
-    let ep = MyEP::new(ctx, opt.handle_replies, logsender);
-    Server::builder()
-        .accept_http1(true)
-        .add_service(ExternalProcessorServer::new(ep))
-        .serve(addr)
-        .await?;
+    // flush the aggregated statistics and let the log exporter catch up before exiting
+    info!("CFAGGREGATED {}", aggregated_values().await);
+    if let Some(handle) = loghandle {
+        // the MyEP instance was dropped with the server, closing the logging channel
+        if tokio::time::timeout(Duration::from_secs(opt.drain_timeout), handle)
+            .await
+            .is_err()
+        {
+            warn!("Drain timeout reached while flushing logs");
+        }
+    }
+    info!("Drained, exiting");
 
     Ok(())
 }
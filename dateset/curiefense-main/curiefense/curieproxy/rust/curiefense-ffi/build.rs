@@ -0,0 +1,16 @@
+fn main() {
+    // the reference header is only regenerated when the generate-header
+    // feature is enabled, so that regular builds do not depend on cbindgen:
+    //
+    //   cargo build -p curiefense-ffi --features generate-header
+    //
+    // the ABI tests check that the reference header stays in sync with the
+    // exported symbols
+    #[cfg(feature = "cbindgen")]
+    {
+        let crate_dir = std::env::var("CARGO_MANIFEST_DIR").unwrap();
+        cbindgen::generate(&crate_dir)
+            .expect("Unable to generate the C header")
+            .write_to_file(std::path::Path::new(&crate_dir).join("curiefense_ffi.h"));
+    }
+}
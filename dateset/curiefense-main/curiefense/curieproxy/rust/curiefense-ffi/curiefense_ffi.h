@@ -77,6 +77,30 @@ bool curiefense_cfr_is_blocking(const struct CFResult *ptr);
  */
 uint32_t curiefense_cfr_block_status(const struct CFResult *ptr);
 
+/**
+ * # Safety
+ *
+ * Reports the upstream response status, incrementing the counters of the
+ * limits that are configured to only count matching responses (count_status).
+ * Must be called before the result is freed.
+ */
+void curiefense_cfr_report_status(struct CFResult *ptr, uint32_t status);
+
+/**
+ * # Safety
+ *
+ * Returns the machine readable reason code of the blocking trigger, 0 when the request is not blocked.
+ */
+uint32_t curiefense_cfr_reason_code(const struct CFResult *ptr);
+
+/**
+ * # Safety
+ *
+ * Returns the decision annotations as a json object, or a null pointer when
+ * there are none. The returned string can be freed with curiefense_str_free.
+ */
+char *curiefense_cfr_annotations(const struct CFResult *ptr);
+
 /**
  * # Safety
  *
@@ -92,6 +116,19 @@ uintptr_t curiefense_cfr_block_contentlength(const struct CFResult *ptr);
  */
 void curiefense_cfr_block_content(const struct CFResult *ptr, unsigned char *tgt);
 
+/**
+ * # Safety
+ *
+ * Iterates over the response headers of a blocking action, calling the
+ * callback once per key/value pair. Values are rendered from their
+ * templates and passed as null terminated strings that are only valid for
+ * the duration of the callback. Does nothing when the request is not
+ * blocked or the action has no headers.
+ */
+void curiefense_cfr_block_headers_foreach(const struct CFResult *ptr,
+                                          void (*cb)(const char *key, const char *value, void *data),
+                                          void *data);
+
 /**
  * # Safety
  *
@@ -99,6 +136,23 @@ void curiefense_cfr_block_content(const struct CFResult *ptr, unsigned char *tgt
  */
 char *curiefense_cfr_log(struct CFResult *ptr, uintptr_t *ln);
 
+/**
+ * # Safety
+ *
+ * Returns the log entry, as a buffer that must be freed with curiefense_buffer_free.
+ * When the CF_LOG_COMPRESSION_LEVEL environment variable is set to a nonzero zstd
+ * level, the buffer holds a zstd frame and *compressed is set to true, otherwise
+ * the raw json encoded log is returned and *compressed is set to false.
+ */
+unsigned char *curiefense_cfr_log_compressed(struct CFResult *ptr, uintptr_t *ln, bool *compressed);
+
+/**
+ * # Safety
+ *
+ * Frees a buffer that has been returned by this API, with the size that was returned.
+ */
+void curiefense_buffer_free(unsigned char *ptr, uintptr_t len);
+
 /**
  * # Safety
  *
@@ -122,6 +176,36 @@ char *curiefense_cfr_error(const struct CFResult *ptr);
  */
 void curiefense_str_free(char *ptr);
 
+/**
+ * # Safety
+ *
+ * Cheap accept-time pre-admission check: only consults the global filter
+ * rules that are decidable from the client address alone and the local
+ * concurrency cap, without parsing any request data.
+ *
+ * raw_ip is a null terminated string with the client address; raw_policy_hint
+ * is an optional (nullable) null terminated security policy id, letting
+ * policy-scoped ban rules participate.
+ *
+ * Returns:
+ *   0. admit, run the full analysis
+ *   1. banned client, the connection can be rejected
+ *   2. per address concurrency cap reached
+ *
+ * When 0 is returned and PREADMIT_MAX_PER_IP is set, the embedder must call
+ * curiefense_pre_admit_release once the connection closes.
+ */
+uint32_t curiefense_pre_admit(const char *raw_ip, const char *raw_policy_hint);
+
+/**
+ * # Safety
+ *
+ * Releases one admitted connection for this client address, the counterpart
+ * of a curiefense_pre_admit call that returned 0 when the concurrency cap is
+ * enabled. raw_ip is a null terminated string.
+ */
+void curiefense_pre_admit_release(const char *raw_ip);
+
 /**
  * # Safety
  *
@@ -182,6 +266,20 @@ void curiefense_async_free(struct CFExec *ptr);
  */
 struct CFStreamConfig *curiefense_stream_config_init(uint8_t loglevel, const char *raw_configpath);
 
+/**
+ * # Safety
+ *
+ * Rebuilds a configuration handle for the stream API, reloading the
+ * configuration directory and the hyperscan databases. A new handle is
+ * returned: the caller swaps it in and frees the previous handle with
+ * curiefense_stream_config_free. In-flight requests are not interrupted, as
+ * they keep refcounted pointers to the configuration they started with.
+ *
+ * Returns a null pointer when the previous handle is null.
+ */
+struct CFStreamConfig *curiefense_stream_config_reload(const struct CFStreamConfig *config,
+                                                       const char *raw_configpath);
+
 /**
  * # Safety
  *
@@ -239,6 +337,23 @@ void curiefense_stream_free(struct CFStreamHandle *ptr);
  */
 char *curiefense_stream_error(const struct CFStreamHandle *ptr);
 
+/**
+ * # Safety
+ *
+ * Writes the effective body limits of the matched security policy into
+ * *max_size, *max_depth and *ignore_body (null pointers are skipped).
+ * Meant to be called right after curiefense_stream_start, so that the caller
+ * can size its body buffers and configure its body-buffering accordingly.
+ * Unlimited values are reported as SIZE_MAX; when *ignore_body is true the
+ * body does not need to be forwarded at all.
+ * Returns CFSMore when the limits were written, CFSError when the stream
+ * handle is in an error state or already done.
+ */
+enum CFStreamStatus curiefense_stream_expected_body_limit(const struct CFStreamHandle *sh,
+                                                          uintptr_t *max_size,
+                                                          uintptr_t *max_depth,
+                                                          bool *ignore_body);
+
 /**
  * # Safety
  *
@@ -259,6 +374,19 @@ enum CFStreamStatus curiefense_stream_add_body(struct CFStreamHandle **sh,
                                                const uint8_t *body,
                                                uintptr_t body_size);
 
+/**
+ * # Safety
+ *
+ * Same as curiefense_stream_add_body, but additionally writes into *budget the
+ * amount of body bytes that will be accepted by further calls before the
+ * too-large action triggers, so that the caller can stop forwarding chunks early.
+ * The budget is set to 0 when the stream is already done or errored.
+ */
+enum CFStreamStatus curiefense_stream_add_body_nonblocking(struct CFStreamHandle **sh,
+                                                           const uint8_t *body,
+                                                           uintptr_t body_size,
+                                                           uintptr_t *budget);
+
 /**
  * # Safety
  *
@@ -1,10 +1,12 @@
 use core::ffi::c_void;
 use curiefense::config::contentfilter::ContentFilterRules;
-use curiefense::config::Config;
-use curiefense::grasshopper::{DummyGrasshopper, Grasshopper};
-use curiefense::incremental::{add_body, add_header, finalize, inspect_init, IData, IPInfo};
+use curiefense::config::{load_hsdb, Config};
+use curiefense::grasshopper::testing::TestGrasshopper;
+use curiefense::grasshopper::Grasshopper;
+use curiefense::incremental::{add_body, add_header, body_budget, body_limits, finalize, inspect_init, IData, IPInfo};
 use curiefense::inspect_generic_request_map_async;
-use curiefense::interface::{jsonlog_block, AnalyzeResult};
+use curiefense::interface::{compress_log, jsonlog_block, log_compression_level, AnalyzeResult, BlockReason};
+use curiefense::limit::limit_report_status_block;
 use curiefense::logs::{LogLevel, Logs};
 use curiefense::simple_executor::{new_executor_and_spawner, Executor, Progress, TaskCB};
 use curiefense::utils::{RawRequest, RequestMeta};
@@ -109,6 +111,46 @@ pub unsafe extern "C" fn curiefense_cfr_block_status(ptr: *const CFResult) -> u3
     }
 }
 
+/// # Safety
+///
+/// Reports the upstream response status, incrementing the counters of the
+/// limits that are configured to only count matching responses (count_status).
+/// Must be called before the result is freed.
+#[no_mangle]
+pub unsafe extern "C" fn curiefense_cfr_report_status(ptr: *mut CFResult, status: u32) {
+    if let Some(CFResult::OK(dec)) = ptr.as_mut() {
+        limit_report_status_block(&mut dec.logs, &dec.result.deferred_limits, status);
+    }
+}
+
+/// # Safety
+///
+/// Returns the machine readable reason code of the blocking trigger, 0 when the request is not blocked.
+#[no_mangle]
+pub unsafe extern "C" fn curiefense_cfr_reason_code(ptr: *const CFResult) -> u32 {
+    match ptr.as_ref() {
+        None => 0,
+        Some(CFResult::RR(_)) => 0,
+        Some(CFResult::OK(r)) => BlockReason::block_reason_code(&r.result.decision.reasons).unwrap_or(0),
+    }
+}
+
+/// # Safety
+///
+/// Returns the decision annotations as a json object, or a null pointer when
+/// there are none. The returned string can be freed with curiefense_str_free.
+#[no_mangle]
+pub unsafe extern "C" fn curiefense_cfr_annotations(ptr: *const CFResult) -> *mut c_char {
+    let manns = match ptr.as_ref() {
+        Some(CFResult::OK(r)) => r.result.decision.annotations_json(),
+        _ => None,
+    };
+    match manns.and_then(|s| CString::new(s).ok()) {
+        Some(cs) => cs.into_raw(),
+        None => std::ptr::null_mut(),
+    }
+}
+
 /// # Safety
 ///
 /// Returns the content length of a blocking action.
@@ -137,6 +179,40 @@ pub unsafe extern "C" fn curiefense_cfr_block_content(ptr: *const CFResult, tgt:
     }
 }
 
+/// # Safety
+///
+/// Iterates over the response headers of a blocking action, calling the
+/// callback once per key/value pair. Values are rendered from their
+/// templates and passed as null terminated strings that are only valid for
+/// the duration of the callback. Does nothing when the request is not
+/// blocked or the action has no headers.
+#[no_mangle]
+pub unsafe extern "C" fn curiefense_cfr_block_headers_foreach(
+    ptr: *const CFResult,
+    cb: Option<unsafe extern "C" fn(key: *const c_char, value: *const c_char, data: *mut c_void)>,
+    data: *mut c_void,
+) {
+    let cb = match cb {
+        Some(cb) => cb,
+        None => return,
+    };
+    if let Some(CFResult::OK(r)) = ptr.as_ref() {
+        if let Some(headers) = r.result.decision.maction.as_ref().and_then(|a| a.headers.as_ref()) {
+            for (k, v) in headers {
+                let ck = match CString::new(k.as_str()) {
+                    Ok(c) => c,
+                    Err(_) => continue,
+                };
+                let cv = match CString::new(v.as_str()) {
+                    Ok(c) => c,
+                    Err(_) => continue,
+                };
+                cb(ck.as_ptr(), cv.as_ptr(), data);
+            }
+        }
+    }
+}
+
 /// # Safety
 ///
 /// Returns the log string, json encoded. Can be freed with curiefense_str_free.
@@ -172,6 +248,67 @@ pub unsafe extern "C" fn curiefense_cfr_log(ptr: *mut CFResult, ln: *mut usize)
     }
 }
 
+/// # Safety
+///
+/// Returns the log entry, as a buffer that must be freed with curiefense_buffer_free.
+/// When the CF_LOG_COMPRESSION_LEVEL environment variable is set to a nonzero zstd
+/// level, the buffer holds a zstd frame and *compressed is set to true, otherwise
+/// the raw json encoded log is returned and *compressed is set to false.
+#[no_mangle]
+pub unsafe extern "C" fn curiefense_cfr_log_compressed(
+    ptr: *mut CFResult,
+    ln: *mut usize,
+    compressed: *mut bool,
+) -> *mut c_uchar {
+    *ln = 0;
+    *compressed = false;
+    if ptr.is_null() {
+        return std::ptr::null_mut();
+    }
+    let cfr = Box::from_raw(ptr);
+    let out: Vec<u8> = match *cfr {
+        CFResult::OK(dec) => {
+            jsonlog_block(
+                &dec.result.decision,
+                Some(&dec.result.rinfo),
+                None,
+                &dec.result.tags,
+                &dec.result.stats,
+                &dec.logs,
+                HashMap::new(),
+            )
+            .0
+        }
+        CFResult::RR(rr) => rr.as_bytes().to_vec(),
+    };
+    let level = log_compression_level();
+    let out = if level > 0 {
+        match compress_log(&out, level) {
+            Ok(c) => {
+                *compressed = true;
+                c
+            }
+            // on compression errors, fall back to the uncompressed log
+            Err(_) => out,
+        }
+    } else {
+        out
+    };
+    *ln = out.len();
+    Box::into_raw(out.into_boxed_slice()) as *mut c_uchar
+}
+
+/// # Safety
+///
+/// Frees a buffer that has been returned by this API, with the size that was returned.
+#[no_mangle]
+pub unsafe extern "C" fn curiefense_buffer_free(ptr: *mut c_uchar, len: usize) {
+    if ptr.is_null() {
+        return;
+    }
+    let _x = Box::from_raw(std::ptr::slice_from_raw_parts_mut(ptr, len));
+}
+
 /// # Safety
 ///
 /// Populate the curiefense log string (json encoded)
@@ -232,6 +369,55 @@ pub unsafe extern "C" fn curiefense_str_free(ptr: *mut c_char) {
     drop(CString::from_raw(ptr));
 }
 
+/// # Safety
+///
+/// Cheap accept-time pre-admission check: only consults the global filter
+/// rules that are decidable from the client address alone and the local
+/// concurrency cap, without parsing any request data.
+///
+/// raw_ip is a null terminated string with the client address; raw_policy_hint
+/// is an optional (nullable) null terminated security policy id, letting
+/// policy-scoped ban rules participate.
+///
+/// Returns:
+///   0. admit, run the full analysis
+///   1. banned client, the connection can be rejected
+///   2. per address concurrency cap reached
+///
+/// When 0 is returned and PREADMIT_MAX_PER_IP is set, the embedder must call
+/// curiefense_pre_admit_release once the connection closes.
+#[no_mangle]
+pub unsafe extern "C" fn curiefense_pre_admit(raw_ip: *const c_char, raw_policy_hint: *const c_char) -> u32 {
+    if raw_ip.is_null() {
+        return 0;
+    }
+    let ip = CStr::from_ptr(raw_ip).to_string_lossy();
+    let hint = if raw_policy_hint.is_null() {
+        None
+    } else {
+        Some(CStr::from_ptr(raw_policy_hint).to_string_lossy())
+    };
+    match curiefense::preadmit::pre_admit(&ip, hint.as_deref()) {
+        curiefense::preadmit::PreAdmitVerdict::Allow => 0,
+        curiefense::preadmit::PreAdmitVerdict::Banned => 1,
+        curiefense::preadmit::PreAdmitVerdict::OverCapacity => 2,
+    }
+}
+
+/// # Safety
+///
+/// Releases one admitted connection for this client address, the counterpart
+/// of a curiefense_pre_admit call that returned 0 when the concurrency cap is
+/// enabled. raw_ip is a null terminated string.
+#[no_mangle]
+pub unsafe extern "C" fn curiefense_pre_admit_release(raw_ip: *const c_char) {
+    if raw_ip.is_null() {
+        return;
+    }
+    let ip = CStr::from_ptr(raw_ip).to_string_lossy();
+    curiefense::preadmit::pre_admit_release(&ip);
+}
+
 /// Simple wrapper to return the reqinfo data
 pub async fn inspect_wrapper<GH: Grasshopper>(logs: Logs, raw: RawRequest<'_>, mgh: Option<&GH>) -> CFDecision {
     let mut mlogs = logs;
@@ -315,7 +501,7 @@ pub unsafe extern "C" fn curiefense_async_init(
         mbody,
     };
     let (executor, spawner) = new_executor_and_spawner::<TaskCB<CFDecision>>();
-    spawner.spawn_cb(inspect_wrapper(logs, raw_request, Some(&DummyGrasshopper {})), cb, data);
+    spawner.spawn_cb(inspect_wrapper(logs, raw_request, Some(&TestGrasshopper::unavailable())), cb, data);
     drop(spawner);
     Box::into_raw(Box::new(CFExec { inner: executor }))
 }
@@ -382,6 +568,20 @@ pub struct CFStreamConfig {
     content_filter_rules: Arc<HashMap<String, ContentFilterRules>>,
 }
 
+/// loads the configuration directory, building a fresh configuration handle
+unsafe fn stream_config_load(loglevel: LogLevel, raw_configpath: *const c_char) -> *mut CFStreamConfig {
+    let configpath = CStr::from_ptr(raw_configpath).to_string_lossy().to_string();
+    let mut config = Config::load(Logs::new(loglevel), &configpath);
+    let mut jsonpath = std::path::PathBuf::from(&configpath);
+    jsonpath.push("json");
+    let content_filter_rules = load_hsdb(&mut config.logs, &jsonpath, &config.content_filter_profiles);
+    Box::into_raw(Box::new(CFStreamConfig {
+        loglevel,
+        config: Arc::new(config),
+        content_filter_rules: Arc::new(content_filter_rules),
+    }))
+}
+
 /// # Safety
 ///
 /// Returns a configuration handle for the stream API. Must be called when configuration changes.
@@ -398,18 +598,28 @@ pub unsafe extern "C" fn curiefense_stream_config_init(
         3 => LogLevel::Error,
         _ => return std::ptr::null_mut(),
     };
-    // TODO: fix this by properly reloading the configuration
-    let config = Config::empty();
-    let content_filter_rules = HashMap::new();
-    // let configpath = CStr::from_ptr(raw_configpath).to_string_lossy().to_string();
-    // let config = curiefense::config::Config::load(Logs::new(lloglevel), &configpath);
-    // let content_filter_rules =
-    //     curiefense::config::load_hsdb(&mut Logs::new(lloglevel), &configpath).unwrap_or_default();
-    Box::into_raw(Box::new(CFStreamConfig {
-        loglevel: lloglevel,
-        config: Arc::new(config),
-        content_filter_rules: Arc::new(content_filter_rules),
-    }))
+    stream_config_load(lloglevel, raw_configpath)
+}
+
+/// # Safety
+///
+/// Rebuilds a configuration handle for the stream API, reloading the
+/// configuration directory and the hyperscan databases. A new handle is
+/// returned: the caller swaps it in and frees the previous handle with
+/// curiefense_stream_config_free. In-flight requests are not interrupted, as
+/// they keep refcounted pointers to the configuration they started with.
+///
+/// Returns a null pointer when the previous handle is null.
+#[no_mangle]
+pub unsafe extern "C" fn curiefense_stream_config_reload(
+    config: *const CFStreamConfig,
+    raw_configpath: *const c_char,
+) -> *mut CFStreamConfig {
+    let loglevel = match config.as_ref() {
+        None => return std::ptr::null_mut(),
+        Some(c) => c.loglevel,
+    };
+    stream_config_load(loglevel, raw_configpath)
 }
 
 /// # Safety
@@ -517,6 +727,41 @@ pub unsafe extern "C" fn curiefense_stream_error(ptr: *const CFStreamHandle) ->
     out.into_raw()
 }
 
+/// # Safety
+///
+/// Writes the effective body limits of the matched security policy into
+/// *max_size, *max_depth and *ignore_body (null pointers are skipped).
+/// Meant to be called right after curiefense_stream_start, so that the caller
+/// can size its body buffers and configure its body-buffering accordingly.
+/// Unlimited values are reported as SIZE_MAX; when *ignore_body is true the
+/// body does not need to be forwarded at all.
+/// Returns CFSMore when the limits were written, CFSError when the stream
+/// handle is in an error state or already done.
+#[no_mangle]
+pub unsafe extern "C" fn curiefense_stream_expected_body_limit(
+    sh: *const CFStreamHandle,
+    max_size: *mut usize,
+    max_depth: *mut usize,
+    ignore_body: *mut bool,
+) -> CFStreamStatus {
+    match sh.as_ref() {
+        Some(CFStreamHandle::InitPhase(idata)) => {
+            let limits = body_limits(idata);
+            if !max_size.is_null() {
+                *max_size = limits.max_size;
+            }
+            if !max_depth.is_null() {
+                *max_depth = limits.max_depth;
+            }
+            if !ignore_body.is_null() {
+                *ignore_body = limits.ignore_body;
+            }
+            CFStreamStatus::CFSMore
+        }
+        _ => CFStreamStatus::CFSError,
+    }
+}
+
 unsafe fn handle_streaming<F>(handle: CFStreamHandle, out: *mut *mut CFStreamHandle, f: F) -> CFStreamStatus
 where
     F: FnOnce(IData) -> Result<IData, (Logs, AnalyzeResult)>,
@@ -580,6 +825,33 @@ pub unsafe extern "C" fn curiefense_stream_add_body(
     handle_streaming(*boxedhandle, sh, |idata| add_body(idata, body))
 }
 
+/// # Safety
+///
+/// Same as curiefense_stream_add_body, but additionally writes into *budget the
+/// amount of body bytes that will be accepted by further calls before the
+/// too-large action triggers, so that the caller can stop forwarding chunks early.
+/// The budget is set to 0 when the stream is already done or errored.
+#[no_mangle]
+pub unsafe extern "C" fn curiefense_stream_add_body_nonblocking(
+    sh: *mut *mut CFStreamHandle,
+    body: *const u8,
+    body_size: usize,
+    budget: *mut usize,
+) -> CFStreamStatus {
+    let status = curiefense_stream_add_body(sh, body, body_size);
+    if !budget.is_null() {
+        *budget = if sh.is_null() {
+            0
+        } else {
+            match (*sh).as_ref() {
+                Some(CFStreamHandle::InitPhase(idata)) => body_budget(idata),
+                _ => 0,
+            }
+        };
+    }
+    status
+}
+
 /// Simple wrapper to return the reqinfo data
 pub async fn stream_wrapper<GH: Grasshopper>(
     config: &CFStreamConfig,
@@ -593,6 +865,8 @@ pub async fn stream_wrapper<GH: Grasshopper>(
                 mgh,
                 &config.config.globalfilters,
                 &config.config.flows,
+                &config.config.first_seen,
+                &config.config.sticky_tags,
                 Some(&config.content_filter_rules),
                 config.config.virtual_tags.clone(),
             )
@@ -633,7 +907,54 @@ pub unsafe extern "C" fn curiefense_stream_exec(
         CFStreamHandle::Done(rl) => Err(rl),
     };
     let (executor, spawner) = new_executor_and_spawner::<TaskCB<CFDecision>>();
-    spawner.spawn_cb(stream_wrapper(iconfig, dt, Some(&DummyGrasshopper {})), cb, data);
+    spawner.spawn_cb(stream_wrapper(iconfig, dt, Some(&TestGrasshopper::unavailable())), cb, data);
     drop(spawner);
     Box::into_raw(Box::new(CFExec { inner: executor }))
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const REFERENCE_HEADER: &str = include_str!("../curiefense_ffi.h");
+
+    /// extracts the names of all exported C functions from this file
+    fn exported_symbols() -> Vec<&'static str> {
+        let src = include_str!("lib.rs");
+        let mut out = Vec::new();
+        for line in src.lines() {
+            if let Some(rest) = line.trim_start().strip_prefix("pub unsafe extern \"C\" fn ") {
+                if let Some(idx) = rest.find('(') {
+                    out.push(&rest[..idx]);
+                }
+            }
+        }
+        out
+    }
+
+    #[test]
+    fn reference_header_lists_all_symbols() {
+        let symbols = exported_symbols();
+        assert!(!symbols.is_empty());
+        for sym in symbols {
+            assert!(
+                REFERENCE_HEADER.contains(&format!("{}(", sym)),
+                "symbol {} is missing from curiefense_ffi.h, rebuild with --features generate-header",
+                sym
+            );
+        }
+    }
+
+    #[test]
+    fn enum_layouts_are_stable() {
+        use std::os::raw::c_int;
+        assert_eq!(std::mem::size_of::<CFProgress>(), std::mem::size_of::<c_int>());
+        assert_eq!(std::mem::size_of::<CFStreamStatus>(), std::mem::size_of::<c_int>());
+        assert_eq!(CFProgress::CFDone as c_int, 0);
+        assert_eq!(CFProgress::CFMore as c_int, 1);
+        assert_eq!(CFProgress::CFError as c_int, 2);
+        assert_eq!(CFStreamStatus::CFSDone as c_int, 0);
+        assert_eq!(CFStreamStatus::CFSMore as c_int, 1);
+        assert_eq!(CFStreamStatus::CFSError as c_int, 2);
+    }
+}
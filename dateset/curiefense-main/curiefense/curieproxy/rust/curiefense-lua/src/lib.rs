@@ -10,10 +10,8 @@ use curiefense::analyze::APhase3;
 use curiefense::analyze::CfRulesArg;
 use curiefense::analyze::InitResult;
 use curiefense::config::reload_config;
+use curiefense::grasshopper::testing::TestGrasshopper;
 use curiefense::grasshopper::DynGrasshopper;
-use curiefense::grasshopper::GHMode;
-use curiefense::grasshopper::GHQuery;
-use curiefense::grasshopper::GHResponse;
 use curiefense::grasshopper::Grasshopper;
 use curiefense::grasshopper::PrecisionLevel;
 use curiefense::inspect_generic_request_map;
@@ -23,7 +21,7 @@ use curiefense::logs::LogLevel;
 use curiefense::logs::Logs;
 use curiefense::requestfields::RequestField;
 use curiefense::utils::RequestMeta;
-use curiefense::utils::{InspectionResult, RawRequest};
+use curiefense::utils::{header_order_fingerprint, InspectionResult, RawRequest, HEADERS_FP_ATTR};
 use mlua::prelude::*;
 use mlua::FromLua;
 use std::collections::HashMap;
@@ -55,6 +53,7 @@ struct LuaArgs<'l> {
 /// * loglevel, mandatory, can be debug, info, warn or err
 /// * meta, table, contains keys "method", "path" and optionally "authority" and "x-request-id"
 /// * headers, table
+/// * orderedheaders, optional array of {name, value} pairs in wire order, used to compute the header order fingerprint (headersfp); entries missing from the headers table are added
 /// * body, optional string
 /// * ip, string representation of the IP address
 /// * hops, optional number. When set the IP is computed from the x-forwarded-for header, defaulting to the ip argument on failure
@@ -93,10 +92,31 @@ fn lua_convert_args<'l>(lua: &'l Lua, args: LuaTable<'l>) -> Result<LuaArgs<'l>,
         Err(rr) => return Err(format!("Could not convert the meta argument: {}", rr)),
         Ok(m) => m,
     };
-    let headers = match FromLua::from_lua(vheaders, lua) {
+    let mut headers: HashMap<String, String> = match FromLua::from_lua(vheaders, lua) {
         Err(rr) => return Err(format!("Could not convert the headers argument: {}", rr)),
         Ok(h) => h,
     };
+    let vordered = args
+        .get("orderedheaders")
+        .map_err(|_| "Missing orderedheaders argument".to_string())?;
+    let mordered: Option<Vec<Vec<String>>> = match FromLua::from_lua(vordered, lua) {
+        Err(rr) => return Err(format!("Could not convert the orderedheaders argument: {}", rr)),
+        Ok(o) => o,
+    };
+    let mut meta = meta;
+    if let Some(ordered) = mordered {
+        meta.insert(
+            HEADERS_FP_ATTR.to_string(),
+            header_order_fingerprint(ordered.iter().filter_map(|p| p.first())),
+        );
+        for mut pair in ordered {
+            if pair.len() == 2 {
+                let value = pair.pop().unwrap();
+                let key = pair.pop().unwrap();
+                headers.entry(key.to_lowercase()).or_insert(value);
+            }
+        }
+    }
     let lua_body: Option<LuaString> = match FromLua::from_lua(vlua_body, lua) {
         Err(rr) => return Err(format!("Could not convert the body argument: {}", rr)),
         Ok(b) => b,
@@ -285,36 +305,6 @@ fn lua_reload_conf(lua: &Lua, args: (LuaValue, LuaValue)) -> LuaResult<Option<St
     Ok(None)
 }
 
-struct DummyGrasshopper {
-    humanity: PrecisionLevel,
-}
-
-impl Grasshopper for DummyGrasshopper {
-    fn is_human(&self, _input: GHQuery) -> Result<PrecisionLevel, String> {
-        Ok(self.humanity)
-    }
-
-    fn verify_challenge(&self, _headers: HashMap<&str, &str>) -> Result<String, String> {
-        if self.humanity == PrecisionLevel::Invalid {
-            Err("Bad".to_string())
-        } else {
-            Ok("OK".to_string())
-        }
-    }
-
-    fn init_challenge(&self, _input: GHQuery, _mode: GHMode) -> Result<GHResponse, String> {
-        Ok(GHResponse::invalid())
-    }
-
-    fn should_provide_app_sig(&self, _headers: HashMap<&str, &str>) -> Result<GHResponse, String> {
-        Ok(GHResponse::invalid())
-    }
-
-    fn handle_bio_report(&self, _input: GHQuery, _precision_leve: PrecisionLevel) -> Result<GHResponse, String> {
-        Err("not implemented".into())
-    }
-}
-
 /// Lua TEST interface to the inspection function
 /// allows settings the Grasshopper result!
 #[allow(clippy::type_complexity)]
@@ -322,9 +312,7 @@ impl Grasshopper for DummyGrasshopper {
 fn lua_test_inspect_request(lua: &Lua, args: LuaTable) -> LuaResult<LuaInspectionResult> {
     match lua_convert_args(lua, args) {
         Ok(lua_args) => {
-            let gh = DummyGrasshopper {
-                humanity: lua_args.humanity,
-            };
+            let gh = TestGrasshopper::with_humanity(lua_args.humanity);
             let res = inspect_request(
                 lua_args.meta,
                 lua_args.headers,
@@ -49,7 +49,10 @@ impl mlua::UserData for LuaInspectionResult {
             })
         });
         fields.add_field_method_get("logs", |_, this| this.get_with(|r| r.logs.to_stringvec()));
-        fields.add_field_method_get("response", |_, this| this.get_with(|r| r.decision.response_json()));
+        fields.add_field_method_get("response", |_, this| this.get_with(|r| r.decision.response_json_with_tags(r.tags.as_ref())));
+        fields.add_field_method_get("annotations", |_, this| {
+            this.get_with_o(|r| r.decision.annotations_json())
+        });
     }
 
     fn add_methods<'lua, M: mlua::UserDataMethods<'lua, Self>>(methods: &mut M) {
@@ -119,7 +122,10 @@ impl mlua::UserData for LInitResult<APhase1> {
             })
         });
         fields.add_field_method_get("logs", |_, this| this.get_with(|r| r.logs.to_stringvec()));
-        fields.add_field_method_get("response", |_, this| this.get_with(|r| r.decision.response_json()));
+        fields.add_field_method_get("response", |_, this| this.get_with(|r| r.decision.response_json_with_tags(r.tags.as_ref())));
+        fields.add_field_method_get("annotations", |_, this| {
+            this.get_with_o(|r| r.decision.annotations_json())
+        });
 
         fields.add_field_method_get("flows", |_, this| {
             Ok(match this {
@@ -179,7 +185,10 @@ impl mlua::UserData for LInitResult<APhase2I> {
             })
         });
         fields.add_field_method_get("logs", |_, this| this.get_with(|r| r.logs.to_stringvec()));
-        fields.add_field_method_get("response", |_, this| this.get_with(|r| r.decision.response_json()));
+        fields.add_field_method_get("response", |_, this| this.get_with(|r| r.decision.response_json_with_tags(r.tags.as_ref())));
+        fields.add_field_method_get("annotations", |_, this| {
+            this.get_with_o(|r| r.decision.annotations_json())
+        });
 
         fields.add_field_method_get("limits", |_, this| {
             Ok(match this {
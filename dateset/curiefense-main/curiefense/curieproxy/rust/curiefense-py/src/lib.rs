@@ -60,7 +60,7 @@ fn py_inspect_request(
         rinfo: Some(dec.rinfo),
         stats: dec.stats,
     };
-    let response = res.decision.response_json();
+    let response = res.decision.response_json_with_tags(res.tags.as_ref());
     let request_map = res.log_json_block(HashMap::new());
     let merr = res.err;
     match merr {
@@ -0,0 +1,106 @@
+use std::collections::HashMap;
+
+use rutie::{methods, module, AnyObject, Array, Class, Encoding, Hash, Module, NilClass, Object, RString, VM};
+
+use curiefense::config::reload_config;
+use curiefense::grasshopper::DynGrasshopper;
+use curiefense::inspect_generic_request_map;
+use curiefense::logs::{LogLevel, Logs};
+use curiefense::utils::{InspectionResult, RawRequest, RequestMeta};
+
+fn hash_to_map(hash: &Hash) -> HashMap<String, String> {
+    let mut out = HashMap::new();
+    hash.each(|k, v| {
+        if let (Ok(key), Ok(value)) = (k.try_convert_to::<RString>(), v.try_convert_to::<RString>()) {
+            out.insert(key.to_string(), value.to_string());
+        }
+    });
+    out
+}
+
+fn raise_argument_error(msg: &str) -> ! {
+    VM::raise(Class::from_existing("ArgumentError"), msg);
+    unreachable!()
+}
+
+module!(Curiefense);
+
+methods!(
+    Curiefense,
+    _rtself,
+    fn rb_reload_config(configpath: RString, files: Array) -> NilClass {
+        let path = configpath.map_err(VM::raise_ex).unwrap().to_string();
+        let filenames = files
+            .map(|fs| {
+                fs.into_iter()
+                    .filter_map(|f| f.try_convert_to::<RString>().ok().map(|s| s.to_string()))
+                    .collect()
+            })
+            .unwrap_or_default();
+        reload_config(&path, filenames);
+        NilClass::new()
+    }
+    fn rb_inspect_request(
+        loglevel: RString,
+        meta: Hash,
+        headers: Hash,
+        mbody: AnyObject,
+        ip: RString
+    ) -> Array {
+        let real_loglevel = match loglevel.map_err(VM::raise_ex).unwrap().to_string().as_str() {
+            "debug" => LogLevel::Debug,
+            "info" => LogLevel::Info,
+            "warn" | "warning" => LogLevel::Warning,
+            "err" | "error" => LogLevel::Error,
+            lvl => raise_argument_error(&format!("Can't recognize log level: {}", lvl)),
+        };
+        let mut logs = Logs::new(real_loglevel);
+        logs.debug("Inspection init");
+        let meta_map = hash_to_map(&meta.map_err(VM::raise_ex).unwrap());
+        let headers_map = hash_to_map(&headers.map_err(VM::raise_ex).unwrap());
+        let body = mbody
+            .ok()
+            .and_then(|o| o.try_convert_to::<RString>().ok())
+            .map(|s| s.to_vec_u8_unchecked());
+        let ipstr = ip.map_err(VM::raise_ex).unwrap().to_string();
+
+        let rmeta: RequestMeta = match RequestMeta::from_map(meta_map) {
+            Ok(m) => m,
+            Err(rr) => raise_argument_error(rr),
+        };
+
+        let raw = RawRequest {
+            ipstr,
+            meta: rmeta,
+            headers: headers_map,
+            mbody: body.as_deref(),
+        };
+
+        let grasshopper = DynGrasshopper {};
+        let dec = inspect_generic_request_map(Some(&grasshopper), raw, &mut logs, None, None, HashMap::new());
+        let res = InspectionResult {
+            decision: dec.decision,
+            tags: Some(dec.tags),
+            logs,
+            err: None,
+            rinfo: Some(dec.rinfo),
+            stats: dec.stats,
+        };
+        let response = res.decision.response_json_with_tags(res.tags.as_ref());
+        let request_map = res.log_json_block(HashMap::new());
+
+        let mut out = Array::with_capacity(2);
+        out.push(RString::new_utf8(&response));
+        out.push(RString::from_bytes(&request_map, &Encoding::utf8()));
+        out
+    }
+);
+
+#[allow(non_snake_case)]
+#[no_mangle]
+pub extern "C" fn Init_curiefense_rb() {
+    Module::new("Curiefense").define(|module| {
+        module.def_self("reload_config", rb_reload_config);
+        module.def_self("inspect_request", rb_inspect_request);
+    });
+}
@@ -1,12 +1,22 @@
+use curiefense::config::custom::Site;
+use curiefense::configtest::{load_tests, run_tests};
+use curiefense::config::limit::{key_collisions, Limit};
+use curiefense::config::virtualtags::VirtualTags;
+use curiefense::config::{reload_config, with_config};
+use curiefense::interface::{render_blockpage_preview, Tags};
+use curiefense::learning::suggest_exclusions;
+use curiefense::limit::example_key;
 use curiefense::logs::Logs;
-use curiefense::config::with_config;
+use curiefense::securitypolicy::match_securitypolicy;
+use curiefense::utils::{map_request, RawRequest, RequestMeta};
+use std::collections::HashMap;
 use std::env;
+use std::sync::Arc;
 
-fn main() {
-    let args: Vec<String> = env::args().collect();
-    let path = &args[1];
+fn show_config(path: &str) {
     let mut logs = Logs::default();
-    with_config(path, &mut logs, |_, cfg| {
+    reload_config(path, Vec::new());
+    with_config(&mut logs, |_, cfg| {
         println!("security policies:");
         for securitypolicy in &cfg.securitypolicies {
             println!("{:?}", securitypolicy);
@@ -16,3 +26,189 @@ fn main() {
         println!("{}", l);
     }
 }
+
+fn learn(logpath: &str, min_hits: usize) {
+    let content = match std::fs::read_to_string(logpath) {
+        Ok(c) => c,
+        Err(rr) => {
+            eprintln!("Could not read {}: {}", logpath, rr);
+            std::process::exit(1);
+        }
+    };
+    let suggestions = suggest_exclusions(&content, min_hits);
+    println!(
+        "{}",
+        serde_json::to_string_pretty(&suggestions).unwrap_or_else(|_| "[]".to_string())
+    );
+}
+
+fn render_blockpage(confpath: &str, actionid: &str, path: &str) {
+    let mut logs = Logs::default();
+    reload_config(confpath, Vec::new());
+    with_config(&mut logs, |logs, cfg| {
+        let action = match cfg.actions.get(actionid) {
+            Some(a) => a,
+            None => {
+                eprintln!("No action named {} in the configuration", actionid);
+                std::process::exit(1);
+            }
+        };
+        let raw = RawRequest {
+            ipstr: "203.0.113.1".to_string(),
+            headers: HashMap::new(),
+            meta: RequestMeta {
+                authority: Some("preview.example.com".to_string()),
+                method: "GET".to_string(),
+                path: path.to_string(),
+                requestid: Some("preview-request-id".to_string()),
+                protocol: None,
+                client_cert_subject: None,
+                client_cert_san: None,
+                client_cert_fingerprint: None,
+                extra: HashMap::new(),
+            },
+            mbody: None,
+        };
+        let host = raw.get_host();
+        let secpolicy = match match_securitypolicy(&host, path, cfg, logs, None) {
+            Some(p) => p,
+            None => {
+                eprintln!("No security policy matches {}{}", host, path);
+                std::process::exit(1);
+            }
+        };
+        let rinfo = map_request(logs, secpolicy, Arc::new(Site::default()), None, &raw, None, HashMap::new());
+        let tags = Tags::new(&VirtualTags::default());
+        println!("{}", render_blockpage_preview(action, &rinfo, &tags));
+    });
+    for l in logs.to_stringvec() {
+        eprintln!("{}", l);
+    }
+}
+
+fn lint_limits(confpath: &str, path: &str) {
+    let mut logs = Logs::default();
+    reload_config(confpath, Vec::new());
+    let found_collisions = with_config(&mut logs, |logs, cfg| {
+        let raw = RawRequest {
+            ipstr: "203.0.113.1".to_string(),
+            headers: HashMap::new(),
+            meta: RequestMeta {
+                authority: Some("preview.example.com".to_string()),
+                method: "GET".to_string(),
+                path: path.to_string(),
+                requestid: Some("preview-request-id".to_string()),
+                protocol: None,
+                client_cert_subject: None,
+                client_cert_san: None,
+                client_cert_fingerprint: None,
+                extra: HashMap::new(),
+            },
+            mbody: None,
+        };
+        let host = raw.get_host();
+        let secpolicy = match match_securitypolicy(&host, path, cfg, logs, None) {
+            Some(p) => p,
+            None => {
+                eprintln!("No security policy matches {}{}", host, path);
+                std::process::exit(1);
+            }
+        };
+        let rinfo = map_request(logs, secpolicy, Arc::new(Site::default()), None, &raw, None, HashMap::new());
+        let tags = Tags::new(&VirtualTags::default());
+        let mut limits: Vec<&Limit> = cfg.limits.values().collect();
+        limits.sort_by(|a, b| a.id.cmp(&b.id));
+        for lm in &limits {
+            let example =
+                example_key(&rinfo, &tags, lm).unwrap_or_else(|| "<selector missing from sample request>".to_string());
+            println!("{} ({}) {} -> {}", lm.id, lm.name, lm.key_template(), example);
+        }
+        let collisions = key_collisions(&limits);
+        for (template, ids) in &collisions {
+            eprintln!("colliding key template {} shared by limits: {}", template, ids.join(", "));
+        }
+        !collisions.is_empty()
+    });
+    for l in logs.to_stringvec() {
+        eprintln!("{}", l);
+    }
+    if found_collisions == Some(true) {
+        std::process::exit(1);
+    }
+}
+
+fn run_config_tests(confpath: &str) {
+    let mut logs = Logs::default();
+    reload_config(confpath, Vec::new());
+    let tests = load_tests(&mut logs, confpath);
+    if tests.is_empty() {
+        println!("no tests found in {}/json/tests.json", confpath);
+        for l in logs.to_stringvec() {
+            eprintln!("{}", l);
+        }
+        return;
+    }
+    let failures = run_tests(&mut logs, &tests);
+    for failure in &failures {
+        eprintln!("FAIL {}: {}", failure.test, failure.problem);
+    }
+    println!("{} tests, {} failures", tests.len(), failures.len());
+    for l in logs.to_stringvec() {
+        eprintln!("{}", l);
+    }
+    if !failures.is_empty() {
+        std::process::exit(1);
+    }
+}
+
+fn main() {
+    let args: Vec<String> = env::args().collect();
+    match args.get(1).map(|s| s.as_str()) {
+        Some("suggest-exclusions") => {
+            let logpath = match args.get(2) {
+                Some(p) => p,
+                None => {
+                    eprintln!("Usage: {} suggest-exclusions LOGFILE [MIN_HITS]", args[0]);
+                    std::process::exit(1);
+                }
+            };
+            let min_hits = args.get(3).and_then(|s| s.parse().ok()).unwrap_or(10);
+            learn(logpath, min_hits);
+        }
+        Some("render-blockpage") => match (args.get(2), args.get(3)) {
+            (Some(confpath), Some(actionid)) => {
+                let path = args.get(4).map(|s| s.as_str()).unwrap_or("/");
+                render_blockpage(confpath, actionid, path);
+            }
+            _ => {
+                eprintln!("Usage: {} render-blockpage CONFIGPATH ACTIONID [PATH]", args[0]);
+                std::process::exit(1);
+            }
+        },
+        Some("lint-limits") => match args.get(2) {
+            Some(confpath) => {
+                let path = args.get(3).map(|s| s.as_str()).unwrap_or("/");
+                lint_limits(confpath, path);
+            }
+            None => {
+                eprintln!("Usage: {} lint-limits CONFIGPATH [PATH]", args[0]);
+                std::process::exit(1);
+            }
+        },
+        Some("test") => match args.get(2) {
+            Some(confpath) => run_config_tests(confpath),
+            None => {
+                eprintln!("Usage: {} test CONFIGPATH", args[0]);
+                std::process::exit(1);
+            }
+        },
+        Some(path) => show_config(path),
+        None => {
+            eprintln!(
+                "Usage: {} CONFIGPATH | suggest-exclusions LOGFILE [MIN_HITS] | render-blockpage CONFIGPATH ACTIONID [PATH] | lint-limits CONFIGPATH [PATH] | test CONFIGPATH",
+                args[0]
+            );
+            std::process::exit(1);
+        }
+    }
+}
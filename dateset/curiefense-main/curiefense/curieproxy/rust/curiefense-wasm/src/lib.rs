@@ -0,0 +1,321 @@
+/* proxy-wasm bindings, so the engine can run inside Envoy/Istio sidecars
+   without native FFI
+
+   The filter drives the incremental API (inspect_init/add_header/add_body/
+   finalize): headers are held until a decision is reached, and the body is
+   only buffered when the matched content filter profile needs it.
+
+   Deployment constraints, compared to the native embeddings:
+    * the configuration bundle must be mounted into the wasm VM's WASI
+      filesystem, at the path given in the plugin configuration
+    * the wasm sandbox has no sockets, so the redis backed features (rate
+      limits, flows, session tracking) degrade to logged errors
+*/
+
+use std::collections::HashMap;
+
+use proxy_wasm::traits::{Context, HttpContext, RootContext};
+use proxy_wasm::types::{Action, ContextType, LogLevel as WasmLogLevel};
+use serde::Deserialize;
+
+use curiefense::config::{reload_config, with_config};
+use curiefense::grasshopper::testing::TestGrasshopper;
+use curiefense::incremental::{add_body, add_header, finalize, inspect_init, matched_policy, IData, IPInfo};
+use curiefense::interface::{jsonlog, AnalyzeResult};
+use curiefense::logs::{LogLevel, Logs};
+use curiefense::utils::RequestMeta;
+
+/// plugin configuration, as JSON in the envoy `configuration` field
+#[derive(Deserialize)]
+struct RawSettings {
+    /// path of the configuration bundle within the WASI filesystem
+    configpath: String,
+    #[serde(default)]
+    loglevel: Option<String>,
+    /// number of trusted proxies, 0 uses the downstream peer address
+    #[serde(default)]
+    trustedhops: usize,
+}
+
+#[derive(Clone)]
+struct FilterSettings {
+    loglevel: LogLevel,
+    configpath: String,
+    trustedhops: usize,
+}
+
+impl Default for FilterSettings {
+    fn default() -> Self {
+        FilterSettings {
+            loglevel: LogLevel::Info,
+            configpath: "/cf-config/current/config".to_string(),
+            trustedhops: 0,
+        }
+    }
+}
+
+#[derive(Default)]
+struct CuriefenseRoot {
+    settings: FilterSettings,
+}
+
+impl Context for CuriefenseRoot {}
+
+impl RootContext for CuriefenseRoot {
+    fn on_configure(&mut self, _plugin_configuration_size: usize) -> bool {
+        if let Some(bytes) = self.get_plugin_configuration() {
+            match serde_json::from_slice::<RawSettings>(&bytes) {
+                Ok(raw) => {
+                    if let Some(ll) = raw.loglevel.as_deref() {
+                        match ll.parse() {
+                            Ok(loglevel) => self.settings.loglevel = loglevel,
+                            Err(rr) => {
+                                wlog(WasmLogLevel::Error, &format!("Invalid loglevel {}: {}", ll, rr));
+                                return false;
+                            }
+                        }
+                    }
+                    self.settings.configpath = raw.configpath;
+                    self.settings.trustedhops = raw.trustedhops;
+                }
+                Err(rr) => {
+                    wlog(WasmLogLevel::Error, &format!("Invalid plugin configuration: {}", rr));
+                    return false;
+                }
+            }
+        }
+        reload_config(&self.settings.configpath, Vec::new());
+        let mut logs = Logs::new(self.settings.loglevel);
+        with_config(&mut logs, |_, _| {});
+        for l in logs.to_stringvec() {
+            wlog(WasmLogLevel::Warn, &l);
+        }
+        true
+    }
+
+    fn create_http_context(&self, _context_id: u32) -> Option<Box<dyn HttpContext>> {
+        Some(Box::new(CuriefenseFilter {
+            settings: self.settings.clone(),
+            state: StreamState::Init,
+        }))
+    }
+
+    fn get_type(&self) -> Option<ContextType> {
+        Some(ContextType::HttpContext)
+    }
+}
+
+enum StreamState {
+    Init,
+    Streaming(Box<IData>),
+    Done,
+}
+
+struct CuriefenseFilter {
+    settings: FilterSettings,
+    state: StreamState,
+}
+
+fn wlog(level: WasmLogLevel, msg: &str) {
+    let _ = proxy_wasm::hostcalls::log(level, msg);
+}
+
+impl CuriefenseFilter {
+    /// the downstream peer address, stripped of its port
+    fn peer_address(&self) -> Option<String> {
+        self.get_property(vec!["source", "address"])
+            .map(|raw| String::from_utf8_lossy(&raw).to_string())
+            .map(|addr| match addr.rsplit_once(':') {
+                Some((ip, _)) => ip.trim_matches(|c| c == '[' || c == ']').to_string(),
+                None => addr,
+            })
+    }
+
+    fn run_finalize(&mut self, idata: IData) -> Action {
+        // the global HSDB is used for the content filter rules, like the
+        // lua embedding does
+        let cloned = {
+            let mut logs = Logs::new(self.settings.loglevel);
+            with_config(&mut logs, |_, cfg| {
+                (
+                    cfg.globalfilters.clone(),
+                    cfg.flows.clone(),
+                    cfg.first_seen.clone(),
+                    cfg.sticky_tags.clone(),
+                    cfg.virtual_tags.clone(),
+                )
+            })
+        };
+        let (result, logs) = match cloned {
+            Some((globalfilters, flows, first_seen, sticky_tags, vtags)) => async_std::task::block_on(finalize(
+                idata,
+                None::<&TestGrasshopper>,
+                &globalfilters,
+                &flows,
+                &first_seen,
+                &sticky_tags,
+                None,
+                vtags,
+            )),
+            None => {
+                wlog(WasmLogLevel::Error, "Could not get the configuration");
+                self.state = StreamState::Done;
+                self.resume_http_request();
+                return Action::Continue;
+            }
+        };
+        self.apply_decision(result, logs)
+    }
+
+    fn apply_decision(&mut self, result: AnalyzeResult, logs: Logs) -> Action {
+        self.state = StreamState::Done;
+        let block_code = result
+            .decision
+            .maction
+            .as_ref()
+            .filter(|a| a.block_mode)
+            .map(|a| a.status);
+        let (v, _) = async_std::task::block_on(jsonlog(
+            &result.decision,
+            Some(&result.rinfo),
+            block_code,
+            &result.tags,
+            &result.stats,
+            &logs,
+            HashMap::new(),
+        ));
+        for l in logs.to_stringvec() {
+            wlog(WasmLogLevel::Debug, &l);
+        }
+        wlog(WasmLogLevel::Info, &format!("CFLOG {}", String::from_utf8_lossy(&v)));
+        match &result.decision.maction {
+            Some(a) if a.block_mode => {
+                let headers: Vec<(&str, &str)> = a
+                    .headers
+                    .iter()
+                    .flatten()
+                    .map(|(k, v)| (k.as_str(), v.as_str()))
+                    .collect();
+                self.send_http_response(a.status, headers, Some(a.content.as_bytes()));
+                Action::Pause
+            }
+            maction => {
+                // non blocking actions can still inject request headers
+                if let Some(headers) = maction.as_ref().and_then(|a| a.headers.as_ref()) {
+                    for (k, v) in headers {
+                        self.add_http_request_header(k, v);
+                    }
+                }
+                self.resume_http_request();
+                Action::Continue
+            }
+        }
+    }
+}
+
+impl Context for CuriefenseFilter {}
+
+impl HttpContext for CuriefenseFilter {
+    fn on_http_request_headers(&mut self, _num_headers: usize, end_of_stream: bool) -> Action {
+        let mut metamap = HashMap::new();
+        let mut headers = HashMap::new();
+        for (k, v) in self.get_http_request_headers() {
+            match k.as_str() {
+                ":method" => {
+                    metamap.insert("method".to_string(), v);
+                }
+                ":path" => {
+                    metamap.insert("path".to_string(), v);
+                }
+                ":authority" => {
+                    metamap.insert("authority".to_string(), v);
+                }
+                ":scheme" => (),
+                _ => {
+                    headers.insert(k.to_lowercase(), v);
+                }
+            }
+        }
+        if let Some(rid) = headers.get("x-request-id") {
+            metamap.insert("x-request-id".to_string(), rid.clone());
+        }
+        let meta = match RequestMeta::from_map(metamap) {
+            Ok(m) => m,
+            Err(rr) => {
+                wlog(WasmLogLevel::Error, &format!("Invalid request metadata: {}", rr));
+                self.state = StreamState::Done;
+                return Action::Continue;
+            }
+        };
+        let ipinfo = if self.settings.trustedhops > 0 {
+            IPInfo::Hops(self.settings.trustedhops)
+        } else {
+            match self.peer_address() {
+                Some(ip) => IPInfo::Ip(ip),
+                None => IPInfo::Hops(0),
+            }
+        };
+        let loglevel = self.settings.loglevel;
+        let mut logs = Logs::new(loglevel);
+        let init = with_config(&mut logs, |_, cfg| {
+            inspect_init(cfg, loglevel, meta, ipinfo, None, None, None, HashMap::new())
+        });
+        let mut idata = match init {
+            Some(Ok(idata)) => idata,
+            Some(Err(rr)) => {
+                wlog(WasmLogLevel::Error, &rr);
+                self.state = StreamState::Done;
+                return Action::Continue;
+            }
+            None => {
+                wlog(WasmLogLevel::Error, "Could not get the configuration");
+                self.state = StreamState::Done;
+                return Action::Continue;
+            }
+        };
+        for (k, v) in headers {
+            match add_header(idata, k, v) {
+                Ok(ndata) => idata = ndata,
+                Err((logs, result)) => return self.apply_decision(result, logs),
+            }
+        }
+        if end_of_stream || !matched_policy(&idata).body_needed {
+            return self.run_finalize(idata);
+        }
+        self.state = StreamState::Streaming(Box::new(idata));
+        // hold the headers until the body has been analyzed
+        Action::Pause
+    }
+
+    fn on_http_request_body(&mut self, body_size: usize, end_of_stream: bool) -> Action {
+        let idata = match std::mem::replace(&mut self.state, StreamState::Done) {
+            StreamState::Streaming(idata) => idata,
+            other => {
+                self.state = other;
+                return Action::Continue;
+            }
+        };
+        let idata = if body_size > 0 {
+            match self.get_http_request_body(0, body_size) {
+                Some(chunk) => match add_body(*idata, &chunk) {
+                    Ok(ndata) => Box::new(ndata),
+                    Err((logs, result)) => return self.apply_decision(result, logs),
+                },
+                None => idata,
+            }
+        } else {
+            idata
+        };
+        if end_of_stream {
+            self.run_finalize(*idata)
+        } else {
+            self.state = StreamState::Streaming(idata);
+            Action::Pause
+        }
+    }
+}
+
+proxy_wasm::main! {{
+    proxy_wasm::set_log_level(WasmLogLevel::Info);
+    proxy_wasm::set_root_context(|_| -> Box<dyn RootContext> { Box::new(CuriefenseRoot::default()) });
+}}
@@ -0,0 +1,69 @@
+use criterion::*;
+use curiefense::config::custom::Site;
+use curiefense::config::hostmap::SecurityPolicy;
+use curiefense::config::virtualtags::VirtualTags;
+use curiefense::interface::aggregator::aggregate;
+use curiefense::interface::{Decision, Tags};
+use curiefense::logs::{LogLevel, Logs};
+use curiefense::utils::{map_request, RawRequest, RequestMeta};
+use std::collections::HashMap;
+use std::sync::Arc;
+
+/// measures the cost of concurrent aggregation, which is dominated by mutex
+/// contention when many tasks target the same shard
+fn aggregate_contended(c: &mut Criterion) {
+    let raw = RawRequest {
+        ipstr: "1.2.3.4".into(),
+        headers: HashMap::new(),
+        meta: RequestMeta {
+            authority: Some("x.com".into()),
+            method: "GET".into(),
+            path: "/some/path/to?x=1&y=2".into(),
+            requestid: None,
+            client_cert_subject: None,
+            client_cert_san: None,
+            client_cert_fingerprint: None,
+            extra: HashMap::new(),
+            protocol: None,
+        },
+        mbody: None,
+    };
+    let mut logs = Logs::new(LogLevel::Debug);
+    let rinfo = map_request(
+        &mut logs,
+        Arc::new(SecurityPolicy::default()),
+        Arc::new(Site::default()),
+        None,
+        &raw,
+        None,
+        HashMap::new(),
+    );
+    let tags = Tags::new(&VirtualTags::default());
+
+    for ntasks in [1usize, 8, 32] {
+        c.bench_with_input(BenchmarkId::new("aggregate_concurrent", ntasks), &ntasks, |b, &n| {
+            b.iter(|| {
+                async_std::task::block_on(async {
+                    let handles: Vec<_> = (0..n)
+                        .map(|_| {
+                            let rinfo = rinfo.clone();
+                            let tags = tags.clone();
+                            async_std::task::spawn(async move {
+                                let dec = Decision::pass(Vec::new());
+                                for _ in 0..64 {
+                                    aggregate(&dec, Some(200), &rinfo, &tags, Some(512)).await;
+                                }
+                            })
+                        })
+                        .collect();
+                    for h in handles {
+                        h.await;
+                    }
+                })
+            })
+        });
+    }
+}
+
+criterion_group!(aggregator, aggregate_contended);
+criterion_main!(aggregator);
@@ -1,4 +1,5 @@
 use curiefense::body::parse_body;
+use curiefense::config::contentfilter::GraphqlLimits;
 use curiefense::logs::Logs;
 use curiefense::requestfields::RequestField;
 
@@ -9,7 +10,17 @@ use std::fmt::Write;
 fn body_test(mcontent_type: Option<&str>, body: &[u8], expected_size: Option<usize>) {
     let mut logs = Logs::default();
     let mut args = RequestField::new(&[]);
-    parse_body(&mut logs, &mut args, 500, mcontent_type, &[], body).unwrap();
+    parse_body(
+        &mut logs,
+        &mut args,
+        500,
+        mcontent_type,
+        &[],
+        "",
+        &GraphqlLimits::default(),
+        body,
+    )
+    .unwrap();
     if let Some(sz) = expected_size {
         assert_eq!(args.len(), sz);
     }
@@ -1,11 +1,13 @@
 use criterion::*;
 use curiefense::analyze::{analyze, APhase0, CfRulesArg};
 use curiefense::config::contentfilter::{ContentFilterProfile, ContentFilterRules};
+use curiefense::config::custom::Site;
 use curiefense::config::hostmap::{PolicyId, SecurityPolicy};
 use curiefense::config::raw::AclProfile;
 use curiefense::config::virtualtags::VirtualTags;
-use curiefense::grasshopper::{DummyGrasshopper, PrecisionLevel};
-use curiefense::interface::{SecpolStats, SimpleDecision, StatsCollect};
+use curiefense::grasshopper::testing::TestGrasshopper;
+use curiefense::grasshopper::PrecisionLevel;
+use curiefense::interface::{compress_log, SecpolStats, StatsCollect};
 use curiefense::logs::{LogLevel, Logs};
 use curiefense::tagging::tag_request;
 use curiefense::utils::{map_request, RawRequest, RequestMeta};
@@ -23,6 +25,9 @@ fn logging_empty(c: &mut Criterion) {
             method: "GET".into(),
             path: "/some/path/to?x=1&y=2&z=ZHFzcXNkcXNk".into(),
             requestid: None,
+            client_cert_subject: None,
+            client_cert_san: None,
+            client_cert_fingerprint: None,
             extra: HashMap::new(),
             protocol: None,
         },
@@ -37,23 +42,30 @@ fn logging_empty(c: &mut Criterion) {
             id: "__default__".into(),
             name: "__default__".into(),
         },
-        tags: Vec::new(),
         acl_active: true,
         acl_profile: AclProfile::default(),
         content_filter_active: true,
         content_filter_profile: ContentFilterProfile::default_from_seed("seedqszqsdqsdd"),
-        limits: Vec::new(),
-        session: Vec::new(),
-        session_ids: Vec::new(),
+        ..SecurityPolicy::empty()
     });
     let mut logs = Logs::new(LogLevel::Debug);
     let stats =
         StatsCollect::new(std::time::Instant::now(), "QSDQSDQSD".into()).secpol(SecpolStats::build(&secpolicy, 0));
-    let reqinfo = map_request(&mut logs, secpolicy, None, &raw, None, HashMap::new());
+    let reqinfo = map_request(
+        &mut logs,
+        secpolicy,
+        Arc::new(Site::default()),
+        None,
+        &raw,
+        None,
+        HashMap::new(),
+    );
     let (itags, globalfilter_dec, stats) =
         tag_request(stats, PrecisionLevel::Invalid, &[], &reqinfo, &VirtualTags::default());
     let p0 = APhase0 {
         flows: HashMap::new(),
+        first_seen: Vec::new(),
+        sticky_tags: Vec::new(),
         globalfilter_dec,
         precision_level: PrecisionLevel::Invalid,
         itags,
@@ -63,13 +75,29 @@ fn logging_empty(c: &mut Criterion) {
     let rules = ContentFilterRules::empty();
     let result = async_std::task::block_on(analyze(
         &mut logs,
-        Some(&DummyGrasshopper {}),
+        Some(&TestGrasshopper::unavailable()),
         p0,
         CfRulesArg::Get(Some(&rules)),
     ));
     c.bench_with_input(BenchmarkId::new("log_json", "empty_request"), &result, |b, r| {
         b.iter(|| async_std::task::block_on(r.decision.log_json(&r.rinfo, &r.tags, &r.stats, &logs, HashMap::new())))
     });
+
+    // measures the CPU cost of compressing the serialized log at various zstd levels
+    let serialized = async_std::task::block_on(result.decision.log_json(
+        &result.rinfo,
+        &result.tags,
+        &result.stats,
+        &logs,
+        HashMap::new(),
+    ));
+    for level in [1, 3, 9] {
+        c.bench_with_input(
+            BenchmarkId::new("log_json_zstd", level),
+            &serialized,
+            |b, r: &Vec<u8>| b.iter(|| compress_log(r, level).unwrap()),
+        );
+    }
 }
 
 criterion_group!(logging, logging_empty);
@@ -53,14 +53,9 @@ fn gen_bogus_config(sz: usize) -> Config {
                         id: format!("id{}", i),
                         name: format!("Dummy securitypolicy {}", i),
                     },
-                    tags: Vec::new(),
-                    acl_active: false,
                     acl_profile: acl_profile.clone(),
-                    content_filter_active: false,
                     content_filter_profile: ContentFilterProfile::default_from_seed("seed"),
-                    session: Vec::new(),
-                    session_ids: Vec::new(),
-                    limits: Vec::new(),
+                    ..SecurityPolicy::empty()
                 }),
             )
             .unwrap()
@@ -79,14 +74,9 @@ fn gen_bogus_config(sz: usize) -> Config {
                 id: "default".into(),
                 name: "selected".into(),
             },
-            tags: Vec::new(),
-            acl_active: false,
             acl_profile,
-            content_filter_active: false,
             content_filter_profile: ContentFilterProfile::default_from_seed("seed"),
-            session: Vec::new(),
-            session_ids: Vec::new(),
-            limits: Vec::new(),
+            ..SecurityPolicy::empty()
         })),
     });
 
@@ -2,20 +2,26 @@ use std::collections::HashSet;
 
 use crate::acl::check_acl;
 use crate::config::contentfilter::ContentFilterRules;
-use crate::config::flow::FlowMap;
+use crate::config::flow::{FirstSeen, FlowMap};
+use crate::config::mirrors::mirror_annotation;
+use crate::config::stickytags::StickyTag;
 use crate::config::CONFIGS;
 use crate::contentfilter::{content_filter_check, masking};
-use crate::flow::{flow_build_query, flow_info, flow_process, flow_resolve_query, FlowCheck, FlowResult};
+use crate::filescan::scan_failure;
+use crate::counterstore::counter_store;
+use crate::flow::{first_seen_info, flow_info, flow_process, flow_queries, flow_resolve_query, FlowCheck, FlowResult};
 use crate::grasshopper::{
     challenge_phase01, challenge_phase02, check_app_sig, handle_bio_reports, GHMode, Grasshopper, PrecisionLevel,
 };
-use crate::interface::stats::{BStageMapped, StatsCollect};
+use crate::interface::stats::{BStageMapped, StatsCollect, STAGE_BUDGETS};
 use crate::interface::{
     merge_decisions, AclStage, AnalyzeResult, BStageFlow, BlockReason, Decision, Location, SimpleDecision, Tags,
 };
-use crate::limit::{limit_build_query, limit_info, limit_process, limit_resolve_query, LimitCheck, LimitResult};
+use crate::limit::{limit_info, limit_process, limit_queries, limit_quota, limit_resolve, LimitCheck, LimitResult};
 use crate::logs::Logs;
+use crate::pluginpipeline::{check_pipeline, pipeline_failure};
 use crate::redis::redis_async_conn;
+use crate::stickytags::{sticky_info, sticky_recall, sticky_record, StickyCheck};
 use crate::utils::{eat_errors, BodyDecodingResult, BodyProblem, RequestInfo};
 
 /*
@@ -48,6 +54,8 @@ pub enum CfRulesArg<'t> {
 
 pub struct APhase0 {
     pub flows: FlowMap,
+    pub first_seen: Vec<FirstSeen>,
+    pub sticky_tags: Vec<StickyTag>,
     pub globalfilter_dec: SimpleDecision,
     pub precision_level: PrecisionLevel,
     pub itags: Tags,
@@ -62,6 +70,7 @@ pub struct AnalysisInfo {
     reqinfo: RequestInfo,
     stats: StatsCollect<BStageMapped>,
     tags: Tags,
+    sticky: Vec<StickyCheck>,
 }
 
 #[derive(Clone)]
@@ -115,6 +124,34 @@ pub fn analyze_init<GH: Grasshopper>(logs: &mut Logs, mgh: Option<&GH>, p0: APha
         Location::Request,
     );
 
+    // positive security mode: block everything that is not explicitly allowed
+    if let Some(allowlist) = &securitypolicy.allowlist {
+        let violation = allowlist.violation(
+            &reqinfo.rinfo.meta.method,
+            &reqinfo.rinfo.qinfo.qpath,
+            reqinfo.headers.get_str("content-type"),
+        );
+        if let Some((tpe, value)) = violation {
+            let br = BlockReason::allowlist(
+                securitypolicy.entry.id.clone(),
+                securitypolicy.entry.name.clone(),
+                allowlist.action.atype.to_raw(),
+                tpe,
+                value,
+            );
+            let decision = allowlist
+                .action
+                .to_decision(logs, precision_level, mgh, &reqinfo, &mut tags, vec![br]);
+            return InitResult::Res(AnalyzeResult {
+                decision,
+                tags,
+                rinfo: masking(reqinfo),
+                stats: stats.mapped_stage_build(),
+                deferred_limits: Vec::new(),
+            });
+        }
+    }
+
     //if /c365 then call gh phase01 with mode passive
     if reqinfo.rinfo.qinfo.uri.starts_with("/c3650cdf") {
         if let Some(gh) = mgh {
@@ -125,6 +162,7 @@ pub fn analyze_init<GH: Grasshopper>(logs: &mut Logs, mgh: Option<&GH>, p0: APha
                 tags,
                 rinfo: masking(reqinfo),
                 stats: stats.mapped_stage_build(),
+                deferred_limits: Vec::new(),
             });
         } else {
             logs.debug("Passive challenge detected: can't challenge");
@@ -134,23 +172,55 @@ pub fn analyze_init<GH: Grasshopper>(logs: &mut Logs, mgh: Option<&GH>, p0: APha
     if !securitypolicy.content_filter_profile.content_type.is_empty() {
         // note that having no body is perfectly OK
         if let BodyDecodingResult::DecodingFailed(rr) = &reqinfo.rinfo.qinfo.body_decoding {
-            let reason = match rr {
-                BodyProblem::DecodingError(actual, expected) => BlockReason::body_malformed(
-                    securitypolicy.content_filter_profile.id.clone(),
-                    securitypolicy.content_filter_profile.name.clone(),
-                    securitypolicy.content_filter_profile.action.atype.to_raw(),
-                    actual,
-                    expected.as_deref(),
+            let (reason, annotation) = match rr {
+                BodyProblem::DecodingError(actual, expected) => (
+                    BlockReason::body_malformed(
+                        securitypolicy.content_filter_profile.id.clone(),
+                        securitypolicy.content_filter_profile.name.clone(),
+                        securitypolicy.content_filter_profile.action.atype.to_raw(),
+                        actual,
+                        expected.as_deref(),
+                    ),
+                    serde_json::json!({
+                        "problem": "decoding error",
+                        "actual": actual,
+                        "expected": expected,
+                    }),
                 ),
-                BodyProblem::TooDeep => BlockReason::body_too_deep(
-                    securitypolicy.content_filter_profile.id.clone(),
-                    securitypolicy.content_filter_profile.name.clone(),
-                    securitypolicy.content_filter_profile.action.atype.to_raw(),
-                    securitypolicy.content_filter_profile.max_body_depth,
+                BodyProblem::TooDeep => (
+                    BlockReason::body_too_deep(
+                        securitypolicy.content_filter_profile.id.clone(),
+                        securitypolicy.content_filter_profile.name.clone(),
+                        securitypolicy.content_filter_profile.action.atype.to_raw(),
+                        securitypolicy.content_filter_profile.max_body_depth,
+                    ),
+                    serde_json::json!({
+                        "problem": "too deep",
+                        "max_depth": securitypolicy.content_filter_profile.max_body_depth,
+                    }),
+                ),
+                BodyProblem::GraphqlBudget {
+                    counter,
+                    actual,
+                    expected,
+                } => (
+                    BlockReason::graphql_budget(
+                        securitypolicy.content_filter_profile.id.clone(),
+                        securitypolicy.content_filter_profile.name.clone(),
+                        securitypolicy.content_filter_profile.action.atype.to_raw(),
+                        *counter,
+                        actual.clone(),
+                        expected.clone(),
+                    ),
+                    serde_json::json!({
+                        "problem": counter,
+                        "actual": actual,
+                        "expected": expected,
+                    }),
                 ),
             };
             // we expect the body to be properly decoded
-            let decision = securitypolicy.content_filter_profile.action.to_decision(
+            let mut decision = securitypolicy.content_filter_profile.action.to_decision(
                 logs,
                 precision_level,
                 mgh,
@@ -158,6 +228,7 @@ pub fn analyze_init<GH: Grasshopper>(logs: &mut Logs, mgh: Option<&GH>, p0: APha
                 &mut tags,
                 vec![reason],
             );
+            decision.annotate("body_decoding", annotation);
             // add extra tags
             for t in &securitypolicy.content_filter_profile.tags {
                 tags.insert(t, Location::Body);
@@ -167,6 +238,7 @@ pub fn analyze_init<GH: Grasshopper>(logs: &mut Logs, mgh: Option<&GH>, p0: APha
                 tags,
                 rinfo: masking(reqinfo),
                 stats: stats.mapped_stage_build(),
+                deferred_limits: Vec::new(),
             });
         }
     }
@@ -192,6 +264,7 @@ pub fn analyze_init<GH: Grasshopper>(logs: &mut Logs, mgh: Option<&GH>, p0: APha
                 tags,
                 rinfo: masking(reqinfo),
                 stats: stats.mapped_stage_build(),
+                deferred_limits: Vec::new(),
             });
         }
         logs.debug("challenge phase2 ignored");
@@ -209,6 +282,7 @@ pub fn analyze_init<GH: Grasshopper>(logs: &mut Logs, mgh: Option<&GH>, p0: APha
                 tags,
                 rinfo: masking(reqinfo),
                 stats: stats.mapped_stage_build(),
+                deferred_limits: Vec::new(),
             });
         }
         logs.debug("check_app_sig ignored");
@@ -228,20 +302,24 @@ pub fn analyze_init<GH: Grasshopper>(logs: &mut Logs, mgh: Option<&GH>, p0: APha
                 tags,
                 rinfo: masking(reqinfo),
                 stats: stats.mapped_stage_build(),
+                deferred_limits: Vec::new(),
             });
         }
         logs.debug("handle_bio_report ignored");
     }
 
-    let decision = if let SimpleDecision::Action(action, reason) = globalfilter_dec {
+    let mut decision = if let SimpleDecision::Action(action, reason) = globalfilter_dec {
         logs.debug(|| format!("Global filter decision {:?}", reason));
-        let decision = action.to_decision(logs, precision_level, mgh, &reqinfo, &mut tags, reason);
+        let mut decision = action.to_decision(logs, precision_level, mgh, &reqinfo, &mut tags, reason);
+        let verdict = decision.verdict();
+        decision.trace_stage("globalfilter", verdict);
         if decision.is_final() {
             return InitResult::Res(AnalyzeResult {
                 decision,
                 tags,
                 rinfo: masking(reqinfo),
                 stats: stats.mapped_stage_build(),
+                deferred_limits: Vec::new(),
             });
         }
         // if the decision was not adopted, get the reason vector back
@@ -251,13 +329,112 @@ pub fn analyze_init<GH: Grasshopper>(logs: &mut Logs, mgh: Option<&GH>, p0: APha
         Decision::pass(Vec::new())
     };
 
-    let flow_checks = flow_info(logs, &p0.flows, &reqinfo, &tags);
+    // plugin pipeline checks: plugin outputs are supplied by the embedder, the
+    // core only verifies them against the configured pipeline
+    let pipeline = match CONFIGS.config.read() {
+        Ok(cfg) => cfg.plugin_pipeline.clone(),
+        Err(_) => Vec::new(),
+    };
+    if !pipeline.is_empty() {
+        let reports = check_pipeline(&reqinfo, &pipeline);
+        logs.debug(|| format!("Plugin pipeline reports: {:?}", reports));
+        decision.annotate(
+            "plugins",
+            serde_json::to_value(&reports).unwrap_or(serde_json::Value::Null),
+        );
+        if let Some((action, br)) = pipeline_failure(&pipeline, &reports) {
+            let pdecision = action.to_decision(logs, precision_level, mgh, &reqinfo, &mut tags, vec![br]);
+            let verdict = pdecision.verdict();
+            decision = merge_decisions(decision, pdecision);
+            decision.trace_stage("plugins", verdict);
+            if decision.is_final() {
+                return InitResult::Res(AnalyzeResult {
+                    decision,
+                    tags,
+                    rinfo: masking(reqinfo),
+                    stats: stats.mapped_stage_build(),
+                    deferred_limits: Vec::new(),
+                });
+            }
+        }
+    }
+
+    // embedded WASM plugins run between tagging and the ACL stages, so that
+    // the tags they emit feed the later stages like any other tag source
+    #[cfg(feature = "wasm-plugins")]
+    for step in pipeline.iter().filter(|s| s.module.is_some()) {
+        use crate::wasmplugins::{request_json, DecisionHint, WASM_HOST};
+        match WASM_HOST.inspect(step, &request_json(&reqinfo, &tags)) {
+            Err(rr) => logs.error(|| format!("WASM plugin {}: {}", step.id, rr)),
+            Ok(inspection) => {
+                logs.debug(|| format!("WASM plugin {} inspection: {:?}", step.id, inspection));
+                for tag in &inspection.tags {
+                    tags.insert(tag, Location::Plugin(step.id.clone()));
+                }
+                // a block hint is advisory unless the step carries a failure action
+                if inspection.hint == Some(DecisionHint::Block) {
+                    if let Some(action) = &step.failure_action {
+                        let br = BlockReason::restricted(
+                            step.id.clone(),
+                            step.name.clone(),
+                            action.atype.to_raw(),
+                            Location::Plugin(step.id.clone()),
+                            "block".to_string(),
+                            "wasm hint".to_string(),
+                        );
+                        let wdecision = action.to_decision(logs, precision_level, mgh, &reqinfo, &mut tags, vec![br]);
+                        let verdict = wdecision.verdict();
+                        decision = merge_decisions(decision, wdecision);
+                        decision.trace_stage("plugins", verdict);
+                        if decision.is_final() {
+                            return InitResult::Res(AnalyzeResult {
+                                decision,
+                                tags,
+                                rinfo: masking(reqinfo),
+                                stats: stats.mapped_stage_build(),
+                                deferred_limits: Vec::new(),
+                            });
+                        }
+                    }
+                }
+            }
+        }
+    }
+
+    // uploaded files are checked against the configured scanners before the
+    // ACL and content filter stages
+    if !reqinfo.rinfo.qinfo.uploads.is_empty() {
+        let scanners = match CONFIGS.config.read() {
+            Ok(cfg) => cfg.file_scanners.clone(),
+            Err(_) => Vec::new(),
+        };
+        if let Some((action, br)) = scan_failure(logs, &scanners, &reqinfo.rinfo.qinfo.uploads) {
+            let sdecision = action.to_decision(logs, precision_level, mgh, &reqinfo, &mut tags, vec![br]);
+            let verdict = sdecision.verdict();
+            decision = merge_decisions(decision, sdecision);
+            decision.trace_stage("filescan", verdict);
+            if decision.is_final() {
+                return InitResult::Res(AnalyzeResult {
+                    decision,
+                    tags,
+                    rinfo: masking(reqinfo),
+                    stats: stats.mapped_stage_build(),
+                    deferred_limits: Vec::new(),
+                });
+            }
+        }
+    }
+
+    let mut flow_checks = flow_info(logs, &p0.flows, &reqinfo, &tags);
+    flow_checks.extend(first_seen_info(logs, &p0.first_seen, &reqinfo, &tags));
+    let sticky = sticky_info(logs, &p0.sticky_tags, &reqinfo, &tags);
     let info = AnalysisInfo {
         precision_level,
         p0_decision: decision,
         reqinfo,
         stats,
         tags,
+        sticky,
     };
     InitResult::Phase1(APhase1::new(flow_checks, (), info))
 }
@@ -295,23 +472,66 @@ pub async fn analyze_query_flows<'t>(logs: &mut Logs, p1: APhase1) -> APhase2O {
         info,
     };
 
-    let info = p1.info;
+    let mut info = p1.info;
+    if p1.flows.is_empty() && info.sticky.is_empty() {
+        return empty(info);
+    }
+
+    // re-apply the tags that were remembered for this client, before the flow,
+    // limit, acl and content filter stages run (sticky tags live in redis,
+    // independently of the counter store backend)
+    if !info.sticky.is_empty() {
+        match redis_async_conn().await {
+            Ok(mut redis) => {
+                let sticky = std::mem::take(&mut info.sticky);
+                sticky_recall(logs, &mut redis, &sticky, &mut info.tags).await;
+                info.sticky = sticky;
+            }
+            Err(rr) => logs.error(|| format!("Could not connect to the redis server {}", rr)),
+        }
+    }
+
     if p1.flows.is_empty() {
         return empty(info);
     }
 
-    let mut redis = match redis_async_conn().await {
-        Ok(c) => c,
+    // the budget is checked before the queries are issued: in enforce mode
+    // no counter is incremented once the budget is blown, so that discarded
+    // results do not skew later decisions
+    if let Some(budget) = STAGE_BUDGETS.flow {
+        if info.stats.stage_elapsed() > budget {
+            info.stats.overrun("flow");
+            logs.warning("flow stage time budget exceeded");
+            if STAGE_BUDGETS.enforce {
+                return empty(info);
+            }
+        }
+    }
+
+    let mut store = match counter_store().await {
+        Ok(s) => s,
         Err(rr) => {
-            logs.error(|| format!("Could not connect to the redis server {}", rr));
+            logs.error(|| format!("Could not connect to the counter store {}", rr));
             return empty(info);
         }
     };
-
-    let mut pipe = redis::pipe();
-    flow_build_query(&mut pipe, &p1.flows);
-    let res: Result<Vec<Option<i64>>, _> = pipe.query_async(&mut redis).await;
-    let mut lst = match res {
+    // in enforce mode the store call itself is bounded by the remaining
+    // budget, so that a single slow backend call can not stall the request
+    let queries = match STAGE_BUDGETS.flow.filter(|_| STAGE_BUDGETS.enforce) {
+        Some(budget) => {
+            let remaining = budget.saturating_sub(info.stats.stage_elapsed());
+            match async_std::future::timeout(remaining, store.run(flow_queries(&p1.flows))).await {
+                Ok(r) => r,
+                Err(_) => {
+                    info.stats.overrun("flow");
+                    logs.warning("flow stage counter store call timed out");
+                    return empty(info);
+                }
+            }
+        }
+        None => store.run(flow_queries(&p1.flows)).await,
+    };
+    let mut lst = match queries {
         Ok(l) => l.into_iter(),
         Err(rr) => {
             logs.error(|| format!("{}", rr));
@@ -319,7 +539,7 @@ pub async fn analyze_query_flows<'t>(logs: &mut Logs, p1: APhase1) -> APhase2O {
         }
     };
 
-    let flow_results = eat_errors(logs, flow_resolve_query(&mut redis, &mut lst, p1.flows).await);
+    let flow_results = eat_errors(logs, flow_resolve_query(store.as_mut(), &mut lst, p1.flows).await);
     logs.debug("query - flow checks done");
 
     AnalysisPhase {
@@ -349,23 +569,56 @@ pub async fn analyze_query_limits<'t>(logs: &mut Logs, p2: APhase2I) -> APhase3
 
     let flows = p2.flows;
 
-    let info = p2.info;
+    let mut info = p2.info;
     if p2.limits.is_empty() {
         return empty(info, flows);
     }
 
-    let mut redis = match redis_async_conn().await {
-        Ok(c) => c,
+    if *crate::limit_memory::MEMORY_LIMITS {
+        let limit_results = crate::limit_memory::limit_check(logs, p2.limits);
+        logs.debug("query - limit checks done (memory backend)");
+        return AnalysisPhase {
+            flows,
+            limits: limit_results,
+            info,
+        };
+    }
+
+    // as in the flow stage, the budget is checked before the counters are
+    // touched, and in enforce mode the store call itself is bounded
+    if let Some(budget) = STAGE_BUDGETS.limit {
+        if info.stats.stage_elapsed() > budget {
+            info.stats.overrun("limit");
+            logs.warning("limit stage time budget exceeded");
+            if STAGE_BUDGETS.enforce {
+                return empty(info, flows);
+            }
+        }
+    }
+
+    let mut store = match counter_store().await {
+        Ok(s) => s,
         Err(rr) => {
-            logs.error(|| format!("Could not connect to the redis server {}", rr));
+            logs.error(|| format!("Could not connect to the counter store {}", rr));
             return empty(info, flows);
         }
     };
 
-    let mut pipe = redis::pipe();
-    limit_build_query(&mut pipe, &p2.limits);
-    let res: Result<Vec<Option<i64>>, _> = pipe.query_async(&mut redis).await;
-    let mut lst = match res {
+    let queries = match STAGE_BUDGETS.limit.filter(|_| STAGE_BUDGETS.enforce) {
+        Some(budget) => {
+            let remaining = budget.saturating_sub(info.stats.stage_elapsed());
+            match async_std::future::timeout(remaining, store.run(limit_queries(&p2.limits))).await {
+                Ok(r) => r,
+                Err(_) => {
+                    info.stats.overrun("limit");
+                    logs.warning("limit stage counter store call timed out");
+                    return empty(info, flows);
+                }
+            }
+        }
+        None => store.run(limit_queries(&p2.limits)).await,
+    };
+    let mut lst = match queries {
         Ok(l) => l.into_iter(),
         Err(rr) => {
             logs.error(|| format!("{}", rr));
@@ -373,7 +626,7 @@ pub async fn analyze_query_limits<'t>(logs: &mut Logs, p2: APhase2I) -> APhase3
         }
     };
 
-    let limit_results_err = limit_resolve_query(logs, &mut redis, &mut lst, p2.limits).await;
+    let limit_results_err = limit_resolve(logs, &mut lst, p2.limits);
     let limit_results = eat_errors(logs, limit_results_err);
     logs.debug("query - limit checks done");
 
@@ -401,15 +654,33 @@ pub fn analyze_finish<GH: Grasshopper>(
 
     let (limit_check, stats) = limit_process(p3.flows, 0, &p3.limits, &mut tags);
 
+    // quota reporting for calendar window limits: remaining calls and reset
+    // delay, for embedders that emit X-RateLimit style response headers
+    if let Some(quota) = limit_quota(&p3.limits) {
+        cumulated_decision.annotate("quota", quota);
+    }
+
+    // deferred limits are carried over in the result, so that the embedder can
+    // report the upstream status once it is known
+    let deferred_limits: Vec<LimitCheck> = p3
+        .limits
+        .iter()
+        .filter(|r| r.check.deferred())
+        .map(|r| r.check.clone())
+        .collect();
+
     if let SimpleDecision::Action(action, curbrs) = limit_check {
         let limit_decision = action.to_decision(logs, precision_level, mgh, &reqinfo, &mut tags, curbrs);
+        let verdict = limit_decision.verdict();
         cumulated_decision = merge_decisions(cumulated_decision, limit_decision);
+        cumulated_decision.trace_stage("limit", verdict);
         if cumulated_decision.is_final() {
             return AnalyzeResult {
                 decision: cumulated_decision,
                 tags,
                 rinfo: masking(reqinfo),
                 stats: stats.limit_stage_build(),
+                deferred_limits: Vec::new(),
             };
         }
     }
@@ -435,7 +706,9 @@ pub fn analyze_finish<GH: Grasshopper>(
         let is_final = br.action.is_final();
 
         let acl_decision = Decision::pass(vec![br]);
+        let verdict = acl_decision.verdict();
         cumulated_decision = merge_decisions(cumulated_decision, acl_decision);
+        cumulated_decision.trace_stage("acl", verdict);
 
         // insert the extra tags
         if !secpol.acl_profile.tags.is_empty() {
@@ -456,6 +729,7 @@ pub fn analyze_finish<GH: Grasshopper>(
                 tags,
                 rinfo: masking(reqinfo),
                 stats: stats.acl_stage_build(),
+                deferred_limits,
             };
         }
 
@@ -476,23 +750,29 @@ pub fn analyze_finish<GH: Grasshopper>(
                 acl_block(&mut tags, logs)
             };
 
+            let verdict = decision.verdict();
             cumulated_decision = merge_decisions(cumulated_decision, decision);
+            cumulated_decision.trace_stage("acl", verdict);
             return AnalyzeResult {
                 decision: cumulated_decision,
                 tags,
                 rinfo: masking(reqinfo),
                 stats: stats.acl_stage_build(),
+                deferred_limits: Vec::new(),
             };
         }
 
         if is_final {
             let decision = acl_block(&mut tags, logs);
+            let verdict = decision.verdict();
             cumulated_decision = merge_decisions(cumulated_decision, decision);
+            cumulated_decision.trace_stage("acl", verdict);
             return AnalyzeResult {
                 decision: cumulated_decision,
                 tags,
                 rinfo: masking(reqinfo),
                 stats: stats.acl_stage_build(),
+                deferred_limits: Vec::new(),
             };
         }
     };
@@ -556,12 +836,30 @@ pub fn analyze_finish<GH: Grasshopper>(
         }
     };
 
+    let verdict = content_filter_decision.verdict();
     cumulated_decision = merge_decisions(cumulated_decision, content_filter_decision);
+    cumulated_decision.trace_stage("content_filter", verdict);
+
+    // request mirroring: suspicious requests that were not blocked are
+    // annotated with the mirror target, the embedder performs the actual
+    // duplication (envoy request mirroring, nginx post_action)
+    if !cumulated_decision.is_final() {
+        let mirrors = match CONFIGS.config.read() {
+            Ok(cfg) => cfg.mirrors.clone(),
+            Err(_) => Vec::new(),
+        };
+        if let Some(annotation) = mirror_annotation(&mirrors, &tags) {
+            logs.debug(|| format!("Mirror annotation: {}", annotation));
+            cumulated_decision.annotate("mirror", annotation);
+        }
+    }
+
     AnalyzeResult {
         decision: cumulated_decision,
         tags,
         rinfo: masking(reqinfo),
         stats: stats.cf_stage_build(),
+        deferred_limits,
     }
 }
 
@@ -576,10 +874,14 @@ pub async fn analyze<GH: Grasshopper>(
     match init_result {
         InitResult::Res(result) => result,
         InitResult::Phase1(p1) => {
+            let sticky = p1.info.sticky.clone();
             let p2i = analyze_query_flows(logs, p1).await;
             let p2o = analyze_flows(logs, p2i);
             let p3 = analyze_query_limits(logs, p2o).await;
-            analyze_finish(logs, mgh, cfrules, p3)
+            let result = analyze_finish(logs, mgh, cfrules, p3);
+            // remember the watched tags that this request triggered
+            sticky_record(logs, &sticky, &result.tags).await;
+            result
         }
     }
 }
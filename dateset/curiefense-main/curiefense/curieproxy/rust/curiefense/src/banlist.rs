@@ -0,0 +1,109 @@
+/* automated ban list
+
+   A "ban" action blocks the request like a custom action and additionally
+   records the offending client (ip and session) in the counter store,
+   with the configured duration as TTL. Banned clients are then rejected
+   by `inspect_generic_request_map_init` before the analysis pipeline
+   runs.
+
+   Ban keys live in the limit/flow counter backend (COUNTER_BACKEND), so
+   bans are shared between instances and expire on their own; the user
+   derived key components are masked like all other redis keys. The
+   accept path lookup is bounded by BANLIST_CHECK_TIMEOUT_MS (default
+   100), so that a slow backend can not stall request processing; lookups
+   that fail or time out admit the request, it is then handled by the
+   regular pipeline.
+*/
+
+use lazy_static::lazy_static;
+use std::sync::atomic::{AtomicU64, Ordering};
+
+use crate::counterstore::{counter_store, CounterQuery};
+use crate::redis::{mask_user_value, REDIS_KEY_PREFIX};
+
+lazy_static! {
+    /// maximal time spent looking up the ban list on the request path
+    static ref CHECK_TIMEOUT: std::time::Duration = std::time::Duration::from_millis(
+        std::env::var("BANLIST_CHECK_TIMEOUT_MS")
+            .ok()
+            .and_then(|s| s.parse().ok())
+            .filter(|&n| n > 0)
+            .unwrap_or(100),
+    );
+}
+
+/// bans that could not be recorded because the counter store failed
+static ERRORS: AtomicU64 = AtomicU64::new(0);
+
+pub fn record_errors() -> u64 {
+    ERRORS.load(Ordering::Relaxed)
+}
+
+/// the counter store key recording a ban for this entity
+fn ban_key(entity: &str) -> String {
+    format!("{}ban_{}", *REDIS_KEY_PREFIX, mask_user_value(entity))
+}
+
+/// records a ban for the client ip and session, off the request path
+pub fn record(ip: &str, session: &str, duration: u64) {
+    let queries = vec![
+        CounterQuery::Incr {
+            key: ban_key(ip),
+            ttl: duration,
+        },
+        CounterQuery::Incr {
+            key: ban_key(session),
+            ttl: duration,
+        },
+    ];
+    async_std::task::spawn(async move {
+        match counter_store().await {
+            Ok(mut store) => {
+                if store.run(queries).await.is_err() {
+                    ERRORS.fetch_add(1, Ordering::Relaxed);
+                }
+            }
+            Err(_) => {
+                ERRORS.fetch_add(1, Ordering::Relaxed);
+            }
+        }
+    });
+}
+
+/// is the client currently banned; store failures admit
+pub async fn banned(ip: &str, session: &str) -> bool {
+    let queries = vec![
+        CounterQuery::Read { key: ban_key(ip) },
+        CounterQuery::Read { key: ban_key(session) },
+    ];
+    match counter_store().await {
+        Ok(mut store) => match store.run(queries).await {
+            Ok(values) => values.iter().any(|&v| v > 0),
+            Err(_) => false,
+        },
+        Err(_) => false,
+    }
+}
+
+/// bounded synchronous ban lookup, for the accept path
+pub fn is_banned(ip: &str, session: &str) -> bool {
+    async_std::task::block_on(async {
+        async_std::future::timeout(*CHECK_TIMEOUT, banned(ip, session))
+            .await
+            .unwrap_or(false)
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn ban_keys_are_masked_and_distinct() {
+        let k1 = ban_key("1.2.3.4");
+        let k2 = ban_key("5.6.7.8");
+        assert!(k1.contains("ban_"));
+        assert_ne!(k1, k2);
+        assert!(!k1.contains("1.2.3.4"));
+    }
+}
@@ -0,0 +1,126 @@
+/// support for GraphQL automatic persisted queries (APQ)
+///
+/// APQ clients send a sha256 hash of the query instead of the full document.
+/// The document is only transmitted once, when the upstream server reports a
+/// cache miss. We mirror the protocol here: registration requests (hash +
+/// full query) populate a local cache, so that later hash-only requests can
+/// be inspected as if they carried the original document.
+use lazy_static::lazy_static;
+use serde_json::Value;
+use sha2::{Digest, Sha256};
+use std::collections::{HashMap, VecDeque};
+use std::sync::Mutex;
+
+lazy_static! {
+    /// maximum number of persisted queries kept in the local cache, 0 disables APQ support
+    static ref APQ_CACHE_SIZE: usize = std::env::var("GRAPHQL_APQ_CACHE_SIZE")
+        .ok()
+        .and_then(|s| s.parse().ok())
+        .unwrap_or(1024);
+    static ref CACHE: Mutex<ApqCache> = Mutex::new(ApqCache::default());
+}
+
+/// FIFO-bounded hash -> query map, the bound keeps a hostile client from
+/// growing the cache without limit
+#[derive(Default)]
+struct ApqCache {
+    entries: HashMap<String, String>,
+    order: VecDeque<String>,
+}
+
+impl ApqCache {
+    fn insert(&mut self, hash: String, query: String) {
+        if self.entries.contains_key(&hash) {
+            return;
+        }
+        while self.entries.len() >= *APQ_CACHE_SIZE {
+            match self.order.pop_front() {
+                Some(oldest) => {
+                    self.entries.remove(&oldest);
+                }
+                None => break,
+            }
+        }
+        self.order.push_back(hash.clone());
+        self.entries.insert(hash, query);
+    }
+}
+
+/// extracts the sha256Hash from the APQ extensions object, if present
+fn persisted_hash(value: &Value) -> Option<&str> {
+    value
+        .get("extensions")?
+        .get("persistedQuery")?
+        .get("sha256Hash")?
+        .as_str()
+}
+
+/// handles the APQ side of a graphql json body
+///
+/// Registration requests (hash and full query) populate the cache and return
+/// None, as the query itself is already visible to the caller. Hash-only
+/// requests return the previously registered query, when known.
+pub fn persisted_query(body: &[u8]) -> Option<String> {
+    if *APQ_CACHE_SIZE == 0 {
+        return None;
+    }
+    let value: Value = serde_json::from_slice(body).ok()?;
+    let hash = persisted_hash(&value)?.to_lowercase();
+    match value.get("query").and_then(|q| q.as_str()) {
+        Some(query) => {
+            // only remember the query when the hash matches, a forged hash
+            // would otherwise poison the cache
+            let actual = format!("{:x}", Sha256::digest(query.as_bytes()));
+            if actual == hash {
+                if let Ok(mut cache) = CACHE.lock() {
+                    cache.insert(actual, query.to_string());
+                }
+            }
+            None
+        }
+        None => CACHE.lock().ok()?.entries.get(&hash).cloned(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn apq_body(query: Option<&str>, hash: &str) -> Vec<u8> {
+        let mut mp = serde_json::Map::new();
+        if let Some(q) = query {
+            mp.insert("query".to_string(), Value::String(q.to_string()));
+        }
+        mp.insert(
+            "extensions".to_string(),
+            serde_json::json!({ "persistedQuery": { "version": 1, "sha256Hash": hash } }),
+        );
+        serde_json::to_vec(&Value::Object(mp)).unwrap()
+    }
+
+    #[test]
+    fn apq_roundtrip() {
+        let query = "query { hero { name } }";
+        let hash = format!("{:x}", Sha256::digest(query.as_bytes()));
+        // unknown hash: miss
+        assert_eq!(persisted_query(&apq_body(None, &hash)), None);
+        // registration populates the cache and exposes nothing new
+        assert_eq!(persisted_query(&apq_body(Some(query), &hash)), None);
+        // hash-only requests now resolve to the full query
+        assert_eq!(persisted_query(&apq_body(None, &hash)), Some(query.to_string()));
+    }
+
+    #[test]
+    fn apq_forged_hash_ignored() {
+        let query = "query { login { jwt } }";
+        let forged = "0".repeat(64);
+        assert_eq!(persisted_query(&apq_body(Some(query), &forged)), None);
+        assert_eq!(persisted_query(&apq_body(None, &forged)), None);
+    }
+
+    #[test]
+    fn apq_not_graphql() {
+        assert_eq!(persisted_query(br#"{"a": "b"}"#), None);
+        assert_eq!(persisted_query(b"not json"), None);
+    }
+}
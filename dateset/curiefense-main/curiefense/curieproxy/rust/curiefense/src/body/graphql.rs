@@ -1,10 +1,87 @@
 use async_graphql_parser::{
     parse_query,
-    types::{Directive, DocumentOperations, OperationDefinition, Selection, SelectionSet},
+    types::{Directive, DocumentOperations, ExecutableDocument, OperationDefinition, Selection, SelectionSet},
     Positioned,
 };
 
-use crate::{interface::Location, requestfields::RequestField, utils::BodyProblem};
+use crate::{
+    config::contentfilter::GraphqlLimits, interface::Location, requestfields::RequestField, utils::BodyProblem,
+};
+
+/// counters for the complexity budgets, collected before the document is
+/// flattened into arguments
+#[derive(Default)]
+struct Complexity {
+    fields: usize,
+    aliases: usize,
+    introspection: Option<String>,
+}
+
+fn count_selection_set(set: &SelectionSet, c: &mut Complexity) {
+    for psel in &set.items {
+        match &psel.node {
+            Selection::Field(pfield) => {
+                let field = &pfield.node;
+                c.fields += 1;
+                if field.alias.is_some() {
+                    c.aliases += 1;
+                }
+                let nm = field.name.node.as_str();
+                if c.introspection.is_none() && (nm == "__schema" || nm == "__type") {
+                    c.introspection = Some(nm.to_string());
+                }
+                count_selection_set(&field.selection_set.node, c);
+            }
+            Selection::FragmentSpread(_) => (),
+            Selection::InlineFragment(pinline) => count_selection_set(&pinline.node.selection_set.node, c),
+        }
+    }
+}
+
+/// checks the complexity budgets on a parsed document, fragments count
+/// towards the field and alias budgets
+fn check_budgets(limits: &GraphqlLimits, document: &ExecutableDocument) -> Result<(), BodyProblem> {
+    let budget_error = |counter: &'static str, actual: String, expected: usize| BodyProblem::GraphqlBudget {
+        counter,
+        actual,
+        expected: expected.to_string(),
+    };
+    let mut c = Complexity::default();
+    for pfrag in document.fragments.values() {
+        count_selection_set(&pfrag.node.selection_set.node, &mut c);
+    }
+    let batch = match &document.operations {
+        DocumentOperations::Single(op) => {
+            count_selection_set(&op.node.selection_set.node, &mut c);
+            1
+        }
+        DocumentOperations::Multiple(ops) => {
+            for op in ops.values() {
+                count_selection_set(&op.node.selection_set.node, &mut c);
+            }
+            ops.len()
+        }
+    };
+    if batch > limits.max_batch {
+        return Err(budget_error("graphql batch", batch.to_string(), limits.max_batch));
+    }
+    if c.fields > limits.max_fields {
+        return Err(budget_error("graphql fields", c.fields.to_string(), limits.max_fields));
+    }
+    if c.aliases > limits.max_aliases {
+        return Err(budget_error("graphql aliases", c.aliases.to_string(), limits.max_aliases));
+    }
+    if limits.block_introspection {
+        if let Some(field) = c.introspection {
+            return Err(BodyProblem::GraphqlBudget {
+                counter: "graphql introspection",
+                actual: field,
+                expected: "no introspection".to_string(),
+            });
+        }
+    }
+    Ok(())
+}
 
 fn insert_directive(args: &mut RequestField, prefix: String, dir: Directive) {
     for (n, v) in dir.arguments {
@@ -108,14 +185,25 @@ fn insert_operation(
 }
 
 // invariant, max_depth > 0
-pub fn graphql_body(max_depth: usize, args: &mut RequestField, body: &[u8]) -> Result<(), BodyProblem> {
+pub fn graphql_body(
+    max_depth: usize,
+    limits: &GraphqlLimits,
+    args: &mut RequestField,
+    body: &[u8],
+) -> Result<(), BodyProblem> {
     let body_utf8 = std::str::from_utf8(body).map_err(|rr| BodyProblem::DecodingError(rr.to_string(), None))?;
-    graphql_body_str(max_depth, args, body_utf8)
+    graphql_body_str(max_depth, limits, args, body_utf8)
 }
 
 //same as graphql_body, but receives the body param as str
-pub fn graphql_body_str(max_depth: usize, args: &mut RequestField, body: &str) -> Result<(), BodyProblem> {
+pub fn graphql_body_str(
+    max_depth: usize,
+    limits: &GraphqlLimits,
+    args: &mut RequestField,
+    body: &str,
+) -> Result<(), BodyProblem> {
     let document = parse_query(body).map_err(|rr| BodyProblem::DecodingError(rr.to_string(), None))?;
+    check_budgets(limits, &document)?;
     for (nm, pdef) in document.fragments {
         let basename = "gfrag-".to_string() + &nm;
         insert_dirsels(
@@ -3,6 +3,7 @@
 /// This module contains body parsing for the following mime types:
 ///
 ///  * json
+///  * newline-delimited json (NDJSON / JSON Lines)
 ///  * xml
 ///  * multipart/form-data
 ///  * urlencoded forms
@@ -14,6 +15,7 @@ use serde_json::Value;
 use std::io::Read;
 use xmlparser::{ElementEnd, EntityDefinition, ExternalId, Token};
 
+use crate::config::contentfilter::GraphqlLimits;
 use crate::config::raw::ContentType;
 use crate::interface::Location;
 use crate::logs::Logs;
@@ -24,6 +26,7 @@ use jsonpath_rust::JsonPathFinder;
 use lazy_static::lazy_static;
 use regex::Regex;
 
+mod apq;
 mod graphql;
 
 fn json_path(prefix: &[String]) -> String {
@@ -109,6 +112,45 @@ fn json_body(mxdepth: usize, args: &mut RequestField, body: &[u8]) -> Result<(),
     flatten_json(mxdepth, args, &mut prefix, value).map_err(|()| BodyProblem::TooDeep)
 }
 
+/// parses newline-delimited JSON, as sent by bulk APIs: every line holds a
+/// separate JSON document, flattened under its line index, with the depth
+/// budget applied per line
+fn ndjson_body(mxdepth: usize, args: &mut RequestField, body: &[u8]) -> Result<(), BodyProblem> {
+    for (i, line) in body.split(|c| *c == b'\n').enumerate() {
+        if line.iter().all(|c| c.is_ascii_whitespace()) {
+            continue;
+        }
+        let value: Value = serde_json::from_slice(line)
+            .map_err(|rr| BodyProblem::DecodingError(format!("line {}: {}", i, rr), None))?;
+        let mut prefix = vec![format!("{}", i)];
+        flatten_json(mxdepth, args, &mut prefix, value).map_err(|()| BodyProblem::TooDeep)?;
+    }
+    Ok(())
+}
+
+/// prefix used when flattening the "variables" object of a graphql json body,
+/// so that content filter profiles can apply distinct rules to graphql
+/// variables (using regex entries such as "gvars_.*") and to regular arguments
+const GRAPHQL_VARIABLES_PREFIX: &str = "gvars";
+
+/// same as json_body, but for bodies holding a graphql document: the top level
+/// "variables" object is flattened under the GRAPHQL_VARIABLES_PREFIX prefix
+fn json_body_graphql(mxdepth: usize, args: &mut RequestField, body: &[u8]) -> Result<(), BodyProblem> {
+    let value: Value = serde_json::from_slice(body).map_err(|rr| BodyProblem::DecodingError(rr.to_string(), None))?;
+
+    if let Value::Object(mut mp) = value {
+        if let Some(vars) = mp.remove("variables") {
+            let mut prefix = vec![GRAPHQL_VARIABLES_PREFIX.to_string()];
+            flatten_json(mxdepth, args, &mut prefix, vars).map_err(|()| BodyProblem::TooDeep)?;
+        }
+        let mut prefix = Vec::new();
+        flatten_json(mxdepth, args, &mut prefix, Value::Object(mp)).map_err(|()| BodyProblem::TooDeep)
+    } else {
+        let mut prefix = Vec::new();
+        flatten_json(mxdepth, args, &mut prefix, value).map_err(|()| BodyProblem::TooDeep)
+    }
+}
+
 /// builds the XML path for a given stack, by appending key names with their indices
 fn xml_path(stack: &[(String, u64)]) -> String {
     let mut out = String::new();
@@ -294,12 +336,21 @@ fn multipart_form_encoded(boundary: &str, args: &mut RequestField, body: &[u8])
 fn parse_graphql_array(
     matches: Vec<&str>,
     max_depth: usize,
+    graphql_limits: &GraphqlLimits,
     args: &mut RequestField,
     logs: &mut Logs,
 ) -> Result<(), BodyProblem> {
+    // arrays of queries count towards the batch budget
+    if matches.len() > graphql_limits.max_batch {
+        return Err(BodyProblem::GraphqlBudget {
+            counter: "graphql batch",
+            actual: matches.len().to_string(),
+            expected: graphql_limits.max_batch.to_string(),
+        });
+    }
     let mut graphql_res = Ok(());
     for item in matches.iter() {
-        graphql_res = graphql::graphql_body_str(max_depth, args, &item);
+        graphql_res = graphql::graphql_body_str(max_depth, graphql_limits, args, &item);
         if graphql_res.is_err() {
             logs.debug(|| format!("error while parsing with graphql:  {:?}", graphql_res));
             return graphql_res;
@@ -316,6 +367,7 @@ pub fn parse_body(
     mcontent_type: Option<&str>,
     accepted_types: &[ContentType],
     graphql_path: &str,
+    graphql_limits: &GraphqlLimits,
     body: &[u8],
 ) -> Result<(), BodyProblem> {
     logs.debug("body parsing started");
@@ -335,46 +387,55 @@ pub fn parse_body(
             match t {
                 ContentType::Graphql => {
                     if content_type == "application/graphql" {
-                        return graphql::graphql_body(max_depth, args, body);
+                        return graphql::graphql_body(max_depth, graphql_limits, args, body);
                     }
                 }
                 ContentType::Json => {
+                    if content_type.ends_with("/x-ndjson") || content_type.ends_with("/ndjson") {
+                        return ndjson_body(max_depth, args, body);
+                    }
                     if content_type.ends_with("/json") {
-                        let json_body_res = json_body(max_depth, args, body);
-                        if let Ok(res) = json_body_res {
-                            //result of string body
-                            let body_json_str = std::str::from_utf8(body)
-                                .map_err(|rr| BodyProblem::DecodingError(rr.to_string(), None))?;
+                        // graphql detection happens before flattening, so that graphql
+                        // variables can be flattened under their own prefix
+                        let mut matches: Vec<String> = Vec::new();
+                        if let Ok(body_json_str) = std::str::from_utf8(body) {
                             // use default regex - if has no graphql_path (jsonpath filter)
                             if graphql_path.is_empty() {
-                                let mut matches: Vec<String> = Vec::new();
                                 for capture in GRAPHQL_REGEX.captures_iter(body_json_str) {
                                     if let Some(m) = capture.get(1) {
                                         let matched_str = m.as_str().replace("\\n", "\n");
                                         matches.push(matched_str);
                                     }
                                 }
-                                let matches_vec: Vec<&str> = matches.iter().map(|s| s.as_str()).collect();
-                                if !matches_vec.is_empty() {
-                                    return parse_graphql_array(matches_vec, max_depth, args, logs);
-                                }
-                                //else - there are no graphql matches, return original json_body_res
                             } else {
                                 match JsonPathFinder::from_str(body_json_str, graphql_path) {
                                     Ok(finder) => {
                                         let found_queries = finder.find();
                                         if let Value::Array(arr) = found_queries {
-                                            let matches: Vec<&str> = arr.iter().filter_map(|v| v.as_str()).collect();
-                                            return parse_graphql_array(matches, max_depth, args, logs);
+                                            matches.extend(
+                                                arr.iter().filter_map(|v| v.as_str()).map(|s| s.to_string()),
+                                            );
                                         }
-                                        //else (it's not an array) - there are no graphql matches, return original json_body_res
+                                        //else (it's not an array) - there are no graphql matches
                                     }
                                     Err(e) => logs.debug(|| format!("error while parsing with jsonpath: {}", e)),
                                 }
                             }
                         }
-                        //regular /json body
-                        return json_body_res;
+                        // APQ: registration requests populate the persisted
+                        // query cache, hash-only requests are resolved
+                        // against it so that the full query gets inspected
+                        if let Some(persisted) = apq::persisted_query(body) {
+                            logs.debug("graphql persisted query resolved from the APQ cache");
+                            matches.push(persisted);
+                        }
+                        if matches.is_empty() {
+                            //regular /json body
+                            return json_body(max_depth, args, body);
+                        }
+                        json_body_graphql(max_depth, args, body)?;
+                        let matches_vec: Vec<&str> = matches.iter().map(|s| s.as_str()).collect();
+                        return parse_graphql_array(matches_vec, max_depth, graphql_limits, args, logs);
                     }
                 }
                 ContentType::MultipartForm => {
@@ -400,8 +461,17 @@ pub fn parse_body(
 
     // content-type not found
     if accepted_types.is_empty() {
-        // we had no particular expection, so blindly try json, and urlencoded
-        json_body(max_depth, args, body).or_else(|_| forms_body(args, body))
+        // we had no particular expection, so blindly try json, ndjson for
+        // multi line bodies, and urlencoded
+        json_body(max_depth, args, body)
+            .or_else(|rr| {
+                if body.contains(&b'\n') {
+                    ndjson_body(max_depth, args, body)
+                } else {
+                    Err(rr)
+                }
+            })
+            .or_else(|_| forms_body(args, body))
     } else {
         // we expected a specific content type!
         Err(BodyProblem::DecodingError(
@@ -429,7 +499,16 @@ mod tests {
     ) -> RequestField {
         let mut logs = Logs::default();
         let mut args = RequestField::new(dec);
-        parse_body(&mut logs, &mut args, max_depth, mcontent_type, accepted_types, "", body).unwrap();
+        parse_body(
+            &mut logs,
+            &mut args,
+            max_depth,
+            mcontent_type,
+            accepted_types,
+            "",
+            &GraphqlLimits::default(),
+            body,
+        ).unwrap();
         for lg in logs.logs {
             if lg.level > LogLevel::Debug {
                 panic!("unexpected log: {:?}", lg);
@@ -441,7 +520,16 @@ mod tests {
     fn test_parse_bad(mcontent_type: Option<&str>, accepted_types: &[ContentType], body: &[u8], max_depth: usize) {
         let mut logs = Logs::default();
         let mut args = RequestField::new(&[]);
-        assert!(parse_body(&mut logs, &mut args, max_depth, mcontent_type, accepted_types, "", body).is_err());
+        assert!(parse_body(
+            &mut logs,
+            &mut args,
+            max_depth,
+            mcontent_type,
+            accepted_types,
+            "",
+            &GraphqlLimits::default(),
+            body,
+        ).is_err());
     }
 
     fn test_parse_dec(
@@ -531,6 +619,41 @@ mod tests {
         );
     }
 
+    #[test]
+    fn ndjson_bulk() {
+        test_parse(
+            Some("application/x-ndjson"),
+            b"{\"index\": {\"_id\": \"1\"}}\n{\"user\": \"kimchy\"}\n",
+            &[("0_index__id", "1"), ("1_user", "kimchy")],
+        );
+    }
+
+    #[test]
+    fn ndjson_bad_line() {
+        test_parse_bad(Some("application/x-ndjson"), &[], b"{\"a\": \"b\"}\n{oops}\n", 500);
+    }
+
+    #[test]
+    fn ndjson_depth_is_per_line() {
+        test_parse_ok_dec(
+            &[],
+            Some("application/x-ndjson"),
+            &[],
+            b"{\"a\": \"1\"}\n{\"b\": \"2\"}",
+            2,
+        );
+        test_parse_bad(Some("application/x-ndjson"), &[], b"{\"a\": {\"b\": \"1\"}}", 2);
+    }
+
+    #[test]
+    fn ndjson_fallback_without_content_type() {
+        test_parse(
+            None,
+            b"{\"a\": \"1\"}\n{\"b\": \"2\"}",
+            &[("0_a", "1"), ("1_b", "2")],
+        );
+    }
+
     #[test]
     fn arguments_collision() {
         let mut logs = Logs::default();
@@ -543,6 +666,7 @@ mod tests {
             Some("application/json"),
             &[],
             "",
+            &GraphqlLimits::default(),
             br#"{"a": "body_arg"}"#,
         )
         .unwrap();
@@ -848,6 +972,22 @@ mod tests {
         );
     }
 
+    #[test]
+    fn graphql_json_variables() {
+        test_parse_dec(
+            &[],
+            Some("application/json"),
+            &[ContentType::Json],
+            br#"{"query": "query Hero($ep: String) { hero(episode: $ep) { name } }", "variables": {"ep": "EMPIRE' or 1=1"}}"#,
+            &[
+                ("query", "query Hero($ep: String) { hero(episode: $ep) { name } }"),
+                ("gvars_ep", "EMPIRE' or 1=1"),
+                ("gdir-Hero-s0-hero-episode", "$ep"),
+                ("gdir-Hero-s0-hero-s0", "name"),
+            ],
+        );
+    }
+
     #[test]
     fn graphql_dump_schema() {
         test_parse_dec(
@@ -906,6 +1046,68 @@ mod tests {
         );
     }
 
+    fn test_parse_graphql_limits(limits: &GraphqlLimits, body: &[u8]) -> Result<(), BodyProblem> {
+        let mut logs = Logs::default();
+        let mut args = RequestField::new(&[]);
+        parse_body(
+            &mut logs,
+            &mut args,
+            500,
+            Some("application/graphql"),
+            &[ContentType::Graphql],
+            "",
+            limits,
+            body,
+        )
+    }
+
+    #[test]
+    fn graphql_field_budget() {
+        let limits = GraphqlLimits {
+            max_fields: 2,
+            ..GraphqlLimits::default()
+        };
+        assert!(test_parse_graphql_limits(&limits, b"{ a b }").is_ok());
+        assert!(test_parse_graphql_limits(&limits, b"{ a b c }").is_err());
+    }
+
+    #[test]
+    fn graphql_alias_budget() {
+        let limits = GraphqlLimits {
+            max_aliases: 1,
+            ..GraphqlLimits::default()
+        };
+        assert!(test_parse_graphql_limits(&limits, b"{ x: a b }").is_ok());
+        assert!(test_parse_graphql_limits(&limits, b"{ x: a y: b }").is_err());
+    }
+
+    #[test]
+    fn graphql_batch_budget() {
+        let limits = GraphqlLimits {
+            max_batch: 1,
+            ..GraphqlLimits::default()
+        };
+        assert!(test_parse_graphql_limits(&limits, b"query a { x }").is_ok());
+        assert!(test_parse_graphql_limits(&limits, b"query a { x } query b { y }").is_err());
+    }
+
+    #[test]
+    fn graphql_introspection_budget() {
+        let limits = GraphqlLimits {
+            block_introspection: true,
+            ..GraphqlLimits::default()
+        };
+        assert!(test_parse_graphql_limits(&limits, b"{ user { name } }").is_ok());
+        assert_eq!(
+            test_parse_graphql_limits(&limits, b"{ __schema { types { name } } }"),
+            Err(BodyProblem::GraphqlBudget {
+                counter: "graphql introspection",
+                actual: "__schema".to_string(),
+                expected: "no introspection".to_string(),
+            })
+        );
+    }
+
     #[test]
     fn json_indent_too_deep_array() {
         test_parse_bad(Some("application/json"), &[], br#"[["a"]]"#, 2);
@@ -932,6 +1134,7 @@ mod tests {
             Some("application/x-www-form-urlencoded"),
             &[],
             "",
+            &GraphqlLimits::default(),
             b"a=1&b=2&c=3",
         )
         .unwrap();
@@ -0,0 +1,62 @@
+/// embargo compliance stage
+///
+/// Checks the request origin against the embargo rules (a configuration file
+/// managed independently of the security rules) before any security
+/// processing, and appends an immutable audit entry for every block: who (ip,
+/// country/region), what (authority, method, path), when, and which rule set
+/// version was in force.
+use lazy_static::lazy_static;
+use std::io::Write;
+
+use crate::config::compliance::EmbargoPolicy;
+use crate::interface::{BlockReason, SimpleAction};
+use crate::logs::Logs;
+use crate::utils::RequestInfo;
+
+lazy_static! {
+    static ref AUDIT_LOG_PATH: String = std::env::var("COMPLIANCE_AUDIT_LOG")
+        .ok()
+        .unwrap_or_else(|| "/var/log/curiefense/embargo-audit.log".to_string());
+}
+
+/// appends an audit entry to the compliance audit log, one JSON document per
+/// line. The file is append-only so that entries can not be rewritten by the
+/// proxy once emitted.
+fn audit_entry(logs: &mut Logs, embargo: &EmbargoPolicy, reqinfo: &RequestInfo, tpe: &str, value: &str) {
+    let entry = serde_json::json!({
+        "timestamp": reqinfo.timestamp.to_rfc3339(),
+        "ip": reqinfo.rinfo.geoip.ipstr,
+        "dimension": tpe,
+        "value": value,
+        "authority": reqinfo.rinfo.meta.authority,
+        "method": reqinfo.rinfo.meta.method,
+        "path": reqinfo.rinfo.qinfo.qpath,
+        "rules_version": embargo.version,
+        "action": embargo.action.atype.to_raw(),
+    });
+    let written = std::fs::OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(&*AUDIT_LOG_PATH)
+        .and_then(|mut f| writeln!(f, "{}", entry));
+    if let Err(rr) = written {
+        // the block stands, but the missing audit entry must be visible
+        logs.error(|| format!("Could not write compliance audit entry to {}: {}", *AUDIT_LOG_PATH, rr));
+    }
+}
+
+/// checks the request origin against the embargo rules, returning the action
+/// to apply when it is embargoed. Every positive match produces an audit entry.
+pub fn embargo_check(
+    logs: &mut Logs,
+    embargo: &EmbargoPolicy,
+    reqinfo: &RequestInfo,
+) -> Option<(SimpleAction, BlockReason)> {
+    let geoip = &reqinfo.rinfo.geoip;
+    let (tpe, value) = embargo.matched(geoip.country_iso.as_deref(), geoip.region.as_deref())?;
+    audit_entry(logs, embargo, reqinfo, tpe, &value);
+    Some((
+        embargo.action.clone(),
+        BlockReason::compliance(embargo.action.atype.to_raw(), tpe, value, embargo.version.clone()),
+    ))
+}
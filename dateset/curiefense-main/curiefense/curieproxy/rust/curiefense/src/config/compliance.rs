@@ -0,0 +1,102 @@
+use std::collections::{HashMap, HashSet};
+
+use crate::config::raw::RawEmbargo;
+use crate::interface::SimpleAction;
+use crate::logs::Logs;
+
+/// embargo compliance rules, loaded from their own configuration file so that
+/// legal teams can manage them independently of the security rules
+///
+/// matching requests are blocked before any security processing, and every
+/// block produces an audit log entry carrying the rule set version
+#[derive(Debug, Clone, Default)]
+pub struct EmbargoPolicy {
+    pub active: bool,
+    /// version of the rule set, recorded in audit entries and block reasons
+    pub version: String,
+    /// embargoed country ISO codes, lowercased
+    pub countries: HashSet<String>,
+    /// embargoed region names, lowercased
+    pub regions: HashSet<String>,
+    pub action: SimpleAction,
+}
+
+impl EmbargoPolicy {
+    pub fn resolve(logs: &mut Logs, actions: &HashMap<String, SimpleAction>, raw: RawEmbargo) -> Self {
+        let action = match &raw.action {
+            None => SimpleAction::default(),
+            Some(aid) => actions.get(aid).cloned().unwrap_or_else(|| {
+                logs.error(|| format!("Could not resolve action {} in the embargo configuration", aid));
+                SimpleAction::default()
+            }),
+        };
+        EmbargoPolicy {
+            active: raw.active,
+            version: raw.version.unwrap_or_else(|| "unversioned".to_string()),
+            countries: raw.countries.into_iter().map(|c| c.to_lowercase()).collect(),
+            regions: raw.regions.into_iter().map(|r| r.to_lowercase()).collect(),
+            action,
+        }
+    }
+
+    /// returns the matched dimension and value when the request origin is embargoed
+    pub fn matched(&self, country: Option<&str>, region: Option<&str>) -> Option<(&'static str, String)> {
+        if !self.active {
+            return None;
+        }
+        if let Some(c) = country {
+            if self.countries.contains(&c.to_lowercase()) {
+                return Some(("country", c.to_string()));
+            }
+        }
+        if let Some(r) = region {
+            if self.regions.contains(&r.to_lowercase()) {
+                return Some(("region", r.to_string()));
+            }
+        }
+        None
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn mkpolicy() -> EmbargoPolicy {
+        let mut logs = Logs::default();
+        EmbargoPolicy::resolve(
+            &mut logs,
+            &HashMap::new(),
+            RawEmbargo {
+                active: true,
+                version: Some("2026-01".to_string()),
+                countries: vec!["KP".to_string()],
+                regions: vec!["Crimea".to_string()],
+                action: None,
+            },
+        )
+    }
+
+    #[test]
+    fn embargoed_country_matches() {
+        let policy = mkpolicy();
+        assert_eq!(policy.matched(Some("kp"), None), Some(("country", "kp".to_string())));
+        assert_eq!(policy.matched(Some("fr"), None), None);
+    }
+
+    #[test]
+    fn embargoed_region_matches() {
+        let policy = mkpolicy();
+        assert_eq!(
+            policy.matched(Some("fr"), Some("crimea")),
+            Some(("region", "crimea".to_string()))
+        );
+    }
+
+    #[test]
+    fn inactive_policy_never_matches() {
+        let mut policy = mkpolicy();
+        policy.active = false;
+        assert_eq!(policy.matched(Some("kp"), None), None);
+    }
+}
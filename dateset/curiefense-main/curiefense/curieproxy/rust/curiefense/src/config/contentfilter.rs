@@ -1,17 +1,33 @@
 use crate::config::matchers::Matching;
 use crate::config::raw::{
-    ContentType, RawContentFilterEntryMatch, RawContentFilterProfile, RawContentFilterProperties, RawContentFilterRule,
+    BodyTooLarge, ContentType, HeaderValidation, RawCollisionPolicy, RawContentFilterEntryMatch,
+    RawContentFilterProfile, RawContentFilterProperties, RawContentFilterRule, RawMaskingSeed,
 };
-use crate::interface::{RawTags, SimpleAction};
+use crate::interface::{BlockReason, RawTags, SimpleAction};
 use crate::logs::Logs;
 
 use hyperscan::prelude::{pattern, Builder, CompileFlags, Pattern, Patterns, VectoredDatabase};
 use hyperscan::Vectored;
+use lazy_static::lazy_static;
 use regex::{Regex, RegexBuilder};
 use serde::Serialize;
+use std::borrow::Cow;
 use std::collections::{HashMap, HashSet};
 use std::iter::FromIterator;
 
+lazy_static! {
+    /// set CF_PREFILTER=true to build a literal prefilter database alongside
+    /// the content filter rules, skipping the full scan for requests that
+    /// contain none of the rule literals
+    static ref PREFILTER_ENABLED: bool = std::env::var("CF_PREFILTER")
+        .map(|s| s.parse().unwrap_or(false))
+        .unwrap_or(false);
+    /// minimal length for an extracted literal to be worth prefiltering on
+    static ref PREFILTER_MIN_LITERAL: usize = std::env::var("CF_PREFILTER_MIN_LITERAL")
+        .map(|s| s.parse().unwrap_or(3))
+        .unwrap_or(3);
+}
+
 #[derive(Debug, Clone)]
 pub struct Section<A> {
     pub headers: A,
@@ -21,6 +37,43 @@ pub struct Section<A> {
     pub plugins: A,
 }
 
+/// a masking seed with an optional identifier: when present, the identifier is
+/// included in the masker output, so that logs stay correlatable across seed rotations
+#[derive(Debug, Clone)]
+pub struct MaskingSeed {
+    pub id: Option<String>,
+    pub seed: Vec<u8>,
+}
+
+impl MaskingSeed {
+    fn resolve(legacy: String, mut seeds: Vec<RawMaskingSeed>) -> (MaskingSeed, Vec<MaskingSeed>) {
+        if seeds.is_empty() {
+            (
+                MaskingSeed {
+                    id: None,
+                    seed: legacy.into_bytes(),
+                },
+                Vec::new(),
+            )
+        } else {
+            let current = seeds.remove(0);
+            (
+                MaskingSeed {
+                    id: Some(current.id),
+                    seed: current.seed.into_bytes(),
+                },
+                seeds
+                    .into_iter()
+                    .map(|r| MaskingSeed {
+                        id: Some(r.id),
+                        seed: r.seed.into_bytes(),
+                    })
+                    .collect(),
+            )
+        }
+    }
+}
+
 #[derive(Debug, Clone)]
 pub struct ContentFilterProfile {
     pub id: String,
@@ -31,15 +84,63 @@ pub struct ContentFilterProfile {
     pub ignore_alphanum: bool,
     pub sections: Section<ContentFilterSection>,
     pub decoding: Vec<Transformation>,
-    pub masking_seed: Vec<u8>,
+    /// how colliding keys are merged when a name is sent several times
+    pub collision_policy: CollisionPolicy,
+    pub masking_seed: MaskingSeed,
+    /// seeds from previous rotations, kept during overlap windows for retroactive detokenization
+    pub previous_seeds: Vec<MaskingSeed>,
     pub content_type: Vec<ContentType>,
     pub ignore_body: bool,
     pub max_body_size: usize,
+    /// what happens when the body exceeds max_body_size: apply the profile
+    /// action, or truncate and inspect the prefix
+    pub body_too_large: BodyTooLarge,
     pub max_body_depth: usize,
+    /// pre-parse restriction on the raw path length
+    pub max_uri_length: usize,
+    /// pre-parse restriction on the amount of query parameters
+    pub max_uri_args: usize,
+    /// when set, aggregated exports mask uri argument values and IPs with the
+    /// masking seed, following the same privacy rules as request logs
+    pub mask_aggregates: bool,
+    /// strict RFC 7230 validation of header names and values, a common
+    /// smuggling vector that upstream frameworks tolerate unevenly
+    pub header_validation: HeaderValidation,
     pub referer_as_uri: bool,
     pub graphql_path: String,
+    /// complexity budgets applied to graphql documents before flattening
+    pub graphql: GraphqlLimits,
     pub action: SimpleAction,
     pub tags: HashSet<String>,
+    /// when set, matched rules contribute their risk level to a request score,
+    /// and blocking only happens when the score reaches this threshold
+    pub anomaly_threshold: Option<u32>,
+}
+
+/// complexity budgets for graphql documents, checked before the query is
+/// flattened into arguments
+#[derive(Debug, Clone)]
+pub struct GraphqlLimits {
+    /// maximal amount of fields in a document
+    pub max_fields: usize,
+    /// maximal amount of aliased fields in a document
+    pub max_aliases: usize,
+    /// maximal amount of batched queries, counting both multi-operation
+    /// documents and arrays of queries
+    pub max_batch: usize,
+    /// when true, introspection queries (__schema / __type) are rejected
+    pub block_introspection: bool,
+}
+
+impl Default for GraphqlLimits {
+    fn default() -> Self {
+        GraphqlLimits {
+            max_fields: usize::MAX,
+            max_aliases: usize::MAX,
+            max_batch: usize::MAX,
+            block_introspection: false,
+        }
+    }
 }
 
 #[derive(Debug, Clone)]
@@ -61,6 +162,41 @@ pub enum Transformation {
     UrlDecode,
 }
 
+/// how colliding request field keys are handled when the same name is
+/// inserted several times
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum CollisionPolicy {
+    /// only the first value is kept
+    KeepFirst,
+    /// each new value replaces the previous one
+    KeepLast,
+    /// values are concatenated with the separator; the historical behavior
+    /// is merging with a single space
+    Merge(String),
+    /// each extra value gets its own `name:<index>` entry
+    IndexSuffix,
+}
+
+impl Default for CollisionPolicy {
+    fn default() -> Self {
+        CollisionPolicy::Merge(" ".to_string())
+    }
+}
+
+impl CollisionPolicy {
+    /// resolves the raw configuration, defaulting to the historical
+    /// space-separated merge
+    pub fn resolve(raw: Option<RawCollisionPolicy>, separator: Option<String>) -> Self {
+        match raw {
+            None => CollisionPolicy::default(),
+            Some(RawCollisionPolicy::KeepFirst) => CollisionPolicy::KeepFirst,
+            Some(RawCollisionPolicy::KeepLast) => CollisionPolicy::KeepLast,
+            Some(RawCollisionPolicy::Merge) => CollisionPolicy::Merge(separator.unwrap_or_else(|| " ".to_string())),
+            Some(RawCollisionPolicy::IndexSuffix) => CollisionPolicy::IndexSuffix,
+        }
+    }
+}
+
 impl ContentFilterProfile {
     pub fn default_from_seed(seed: &str) -> Self {
         ContentFilterProfile {
@@ -73,45 +209,104 @@ impl ContentFilterProfile {
                     max_length: 1024,
                     names: HashMap::new(),
                     regex: Vec::new(),
+                    libinjection: true,
+                    folding: NameFolding::CaseInsensitive,
                 },
                 args: ContentFilterSection {
                     max_count: 512,
                     max_length: 1024,
                     names: HashMap::new(),
                     regex: Vec::new(),
+                    libinjection: true,
+                    folding: NameFolding::Exact,
                 },
                 cookies: ContentFilterSection {
                     max_count: 42,
                     max_length: 1024,
                     names: HashMap::new(),
                     regex: Vec::new(),
+                    libinjection: true,
+                    folding: NameFolding::Exact,
                 },
                 path: ContentFilterSection {
                     max_count: 42,
                     max_length: 1024,
                     names: HashMap::new(),
                     regex: Vec::new(),
+                    libinjection: true,
+                    folding: NameFolding::Exact,
                 },
                 plugins: ContentFilterSection {
                     max_count: usize::MAX,
                     max_length: usize::MAX,
                     names: HashMap::new(),
                     regex: Vec::new(),
+                    libinjection: true,
+                    folding: NameFolding::Exact,
                 },
             },
             decoding: vec![Transformation::Base64Decode, Transformation::UrlDecode],
-            masking_seed: seed.as_bytes().to_vec(),
+            collision_policy: CollisionPolicy::default(),
+            masking_seed: MaskingSeed {
+                id: None,
+                seed: seed.as_bytes().to_vec(),
+            },
+            previous_seeds: Vec::new(),
             active: HashSet::default(),
             ignore: HashSet::default(),
             report: HashSet::default(),
             content_type: Vec::new(),
             ignore_body: false,
             max_body_size: usize::MAX,
+            body_too_large: BodyTooLarge::default(),
             max_body_depth: usize::MAX,
+            max_uri_length: usize::MAX,
+            max_uri_args: usize::MAX,
+            mask_aggregates: false,
+            header_validation: HeaderValidation::default(),
             referer_as_uri: false,
             graphql_path: "".to_string(),
+            graphql: GraphqlLimits::default(),
             action: SimpleAction::default(),
             tags: HashSet::new(),
+            anomaly_threshold: None,
+        }
+    }
+}
+
+/// how argument names are folded before they are matched against the name
+/// rules of a section
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum NameFolding {
+    /// names are matched as-is
+    Exact,
+    /// ascii case-insensitive matching
+    CaseInsensitive,
+    /// unicode-aware lowercasing, for frameworks where ascii folding is not
+    /// enough
+    Unicode,
+}
+
+impl Default for NameFolding {
+    fn default() -> Self {
+        NameFolding::Exact
+    }
+}
+
+impl NameFolding {
+    /// folds a name, the same function is applied to the configured keys and
+    /// to the request field names so that lookups stay consistent
+    pub fn fold<'t>(&self, name: &'t str) -> Cow<'t, str> {
+        match self {
+            NameFolding::Exact => Cow::Borrowed(name),
+            NameFolding::CaseInsensitive => {
+                if name.bytes().any(|b| b.is_ascii_uppercase()) {
+                    Cow::Owned(name.to_ascii_lowercase())
+                } else {
+                    Cow::Borrowed(name)
+                }
+            }
+            NameFolding::Unicode => Cow::Owned(name.to_lowercase()),
         }
     }
 }
@@ -122,6 +317,11 @@ pub struct ContentFilterSection {
     pub max_length: usize,
     pub names: HashMap<String, ContentFilterEntryMatch>,
     pub regex: Vec<(Regex, ContentFilterEntryMatch)>,
+    /// when false, the libinjection SQLi/XSS checks skip this section
+    pub libinjection: bool,
+    /// folding applied to names before they are matched, the keys in names
+    /// are folded at resolution time
+    pub folding: NameFolding,
 }
 
 #[derive(Debug, Clone)]
@@ -132,7 +332,7 @@ pub struct ContentFilterEntryMatch {
     pub exclusions: HashSet<String>,
 }
 
-#[derive(Debug, Clone, Eq, Serialize, PartialEq, Copy)]
+#[derive(Debug, Clone, Eq, Serialize, PartialEq, Copy, Hash)]
 #[serde(rename_all = "snake_case")]
 pub enum SectionIdx {
     Headers,
@@ -197,6 +397,9 @@ where
 pub struct ContentFilterRules {
     pub db: VectoredDatabase,
     pub ids: Vec<ContentFilterRule>,
+    /// optional literal prefilter: a database of literals that any rule match
+    /// must contain, so that the full scan can be skipped when none occurs
+    pub prefilter: Option<VectoredDatabase>,
 }
 
 impl ContentFilterRules {
@@ -205,10 +408,90 @@ impl ContentFilterRules {
         ContentFilterRules {
             db: pattern.build().unwrap(),
             ids: Vec::new(),
+            prefilter: None,
         }
     }
 }
 
+/// conservatively extracts a literal substring that any match of the pattern
+/// must contain, returning None when no safe literal can be determined
+fn extract_literal(operand: &str) -> Option<String> {
+    // alternations and groups would make a single literal unsound
+    if operand.contains('|') || operand.contains('(') {
+        return None;
+    }
+    let mut best = String::new();
+    let mut current = String::new();
+    let mut in_class = false;
+    let mut chars = operand.chars();
+    let keep_best = |best: &mut String, current: &mut String| {
+        if current.len() > best.len() {
+            *best = std::mem::take(current);
+        } else {
+            current.clear();
+        }
+    };
+    while let Some(c) = chars.next() {
+        if in_class {
+            if c == '\\' {
+                chars.next();
+            } else if c == ']' {
+                in_class = false;
+            }
+            continue;
+        }
+        match c {
+            // escape sequences may stand for character classes, end the run
+            '\\' => {
+                chars.next();
+                keep_best(&mut best, &mut current);
+            }
+            '[' => {
+                in_class = true;
+                keep_best(&mut best, &mut current);
+            }
+            '.' | '^' | '$' => keep_best(&mut best, &mut current),
+            // a quantifier makes the previous character optional or repeated
+            '*' | '+' | '?' | '{' => {
+                current.pop();
+                if c == '{' {
+                    for n in chars.by_ref() {
+                        if n == '}' {
+                            break;
+                        }
+                    }
+                }
+                keep_best(&mut best, &mut current);
+            }
+            _ => current.push(c),
+        }
+    }
+    keep_best(&mut best, &mut current);
+    if best.len() >= *PREFILTER_MIN_LITERAL {
+        Some(best)
+    } else {
+        None
+    }
+}
+
+/// builds the literal prefilter database for a rule set
+///
+/// every rule must contribute a literal, otherwise prefiltering would be
+/// unsound and None is returned
+fn build_prefilter(rules: &[ContentFilterRule]) -> Option<VectoredDatabase> {
+    let mut literals: HashSet<String> = HashSet::new();
+    for rule in rules {
+        // patterns are compiled caseless, compare literals caseless too
+        literals.insert(extract_literal(&rule.operand)?.to_lowercase());
+    }
+    let patterns = literals
+        .iter()
+        .map(|l| Pattern::with_flags(&regex::escape(l), CompileFlags::CASELESS))
+        .collect::<Result<Vec<_>, _>>()
+        .ok()?;
+    Patterns::from_iter(patterns).build::<Vectored>().ok()
+}
+
 const fn nonzero(value: usize) -> usize {
     if value == 0 {
         usize::MAX
@@ -219,7 +502,7 @@ const fn nonzero(value: usize) -> usize {
 
 fn mk_entry_match(
     em: RawContentFilterEntryMatch,
-    lowercase_key: bool,
+    folding: NameFolding,
 ) -> anyhow::Result<(String, ContentFilterEntryMatch)> {
     let reg = match em.reg {
         None => None,
@@ -232,12 +515,12 @@ fn mk_entry_match(
         }
     };
 
+    let key = match folding.fold(&em.key) {
+        Cow::Borrowed(_) => em.key,
+        Cow::Owned(folded) => folded,
+    };
     Ok((
-        if lowercase_key {
-            em.key.to_ascii_lowercase()
-        } else {
-            em.key
-        },
+        key,
         ContentFilterEntryMatch {
             restrict: em.restrict,
             mask: em.mask.unwrap_or(false),
@@ -248,26 +531,50 @@ fn mk_entry_match(
 }
 
 fn mk_section(
+    logs: &mut Logs,
+    pid: &str,
+    sname: &str,
     allsections: &RawContentFilterProperties,
     props: RawContentFilterProperties,
     lowercase_key: bool,
 ) -> anyhow::Result<ContentFilterSection> {
+    // header names are already lowercased when the request is mapped, so the
+    // headers section always folds case
+    let folding = if props.unicode_folding {
+        NameFolding::Unicode
+    } else if props.case_insensitive || lowercase_key {
+        NameFolding::CaseInsensitive
+    } else {
+        NameFolding::Exact
+    };
     // allsections entries are iterated first, so that they are replaced by entries in prop in case of colision
     // however, max_count and max_length in allsections are ignored
-    let mnames: anyhow::Result<HashMap<String, ContentFilterEntryMatch>> = allsections
-        .names
-        .iter()
-        .cloned()
-        .chain(props.names.into_iter())
-        .map(|em| mk_entry_match(em, lowercase_key))
-        .collect();
+    let mut names: HashMap<String, ContentFilterEntryMatch> = HashMap::new();
+    let mut originals: HashMap<String, String> = HashMap::new();
+    for em in allsections.names.iter().cloned().chain(props.names.into_iter()) {
+        let raw_key = em.key.clone();
+        let (key, v) = mk_entry_match(em, folding)?;
+        if let Some(prev) = originals.insert(key.clone(), raw_key.clone()) {
+            // entries overriding themselves (allsections vs. the section) are
+            // expected, distinct names merging after folding deserve a warning
+            if prev != raw_key {
+                logs.warning(|| {
+                    format!(
+                        "content filter profile {}: names {} and {} in the {} section collide after folding, the last one wins",
+                        pid, prev, raw_key, sname
+                    )
+                });
+            }
+        }
+        names.insert(key, v);
+    }
     let mregex: anyhow::Result<Vec<(Regex, ContentFilterEntryMatch)>> = allsections
         .regex
         .iter()
         .cloned()
         .chain(props.regex.into_iter())
         .map(|e| {
-            let (s, v) = mk_entry_match(e, lowercase_key)?;
+            let (s, v) = mk_entry_match(e, folding)?;
             let re = RegexBuilder::new(&s).case_insensitive(true).build()?;
             Ok((re, v))
         })
@@ -275,8 +582,10 @@ fn mk_section(
     Ok(ContentFilterSection {
         max_count: nonzero(props.max_count.0),
         max_length: nonzero(props.max_length.0),
-        names: mnames?,
+        names,
         regex: mregex?,
+        libinjection: props.libinjection.0,
+        folding,
     })
 }
 
@@ -301,6 +610,8 @@ fn convert_entry(
     }
     let max_body_size = nonzero(entry.max_body_size.unwrap_or(usize::MAX));
     let max_body_depth = nonzero(entry.max_body_depth.unwrap_or(usize::MAX));
+    let max_uri_length = nonzero(entry.max_uri_length.unwrap_or(usize::MAX));
+    let max_uri_args = nonzero(entry.max_uri_args.unwrap_or(usize::MAX));
     let id = entry.id;
     let action = match entry.action {
         None => SimpleAction::default(),
@@ -314,37 +625,89 @@ fn convert_entry(
             SimpleAction::default()
         }),
     };
+    let (masking_seed, previous_seeds) = MaskingSeed::resolve(entry.masking_seed, entry.masking_seeds);
+    let sections = Section {
+        headers: mk_section(logs, &id, "headers", &entry.allsections, entry.headers, true)?,
+        cookies: mk_section(logs, &id, "cookies", &entry.allsections, entry.cookies, false)?,
+        args: mk_section(logs, &id, "args", &entry.allsections, entry.args, false)?,
+        path: mk_section(logs, &id, "path", &entry.allsections, entry.path, false)?,
+        plugins: mk_section(logs, &id, "plugins", &entry.allsections, entry.plugins, false)?,
+    };
     Ok((
         id.clone(),
         ContentFilterProfile {
             id,
             name: entry.name,
             ignore_alphanum: entry.ignore_alphanum,
-            sections: Section {
-                headers: mk_section(&entry.allsections, entry.headers, true)?,
-                cookies: mk_section(&entry.allsections, entry.cookies, false)?,
-                args: mk_section(&entry.allsections, entry.args, false)?,
-                path: mk_section(&entry.allsections, entry.path, false)?,
-                plugins: mk_section(&entry.allsections, entry.plugins, false)?,
-            },
+            sections,
             decoding,
-            masking_seed: entry.masking_seed.as_bytes().to_vec(),
+            collision_policy: CollisionPolicy::resolve(entry.collision_policy, entry.merge_separator),
+            masking_seed,
+            previous_seeds,
             active: entry.active.into_iter().collect(),
             ignore: entry.ignore.into_iter().collect(),
             report: entry.report.into_iter().collect(),
             content_type: entry.content_type,
             ignore_body: entry.ignore_body,
             max_body_size,
+            body_too_large: entry.body_too_large,
             max_body_depth,
+            max_uri_length,
+            max_uri_args,
+            mask_aggregates: entry.mask_aggregates,
+            header_validation: entry.header_validation,
             referer_as_uri: entry.referer_as_uri,
             graphql_path: entry.graphql_path,
+            graphql: GraphqlLimits {
+                max_fields: nonzero(entry.graphql_max_fields.unwrap_or(usize::MAX)),
+                max_aliases: nonzero(entry.graphql_max_aliases.unwrap_or(usize::MAX)),
+                max_batch: nonzero(entry.graphql_max_batch.unwrap_or(usize::MAX)),
+                block_introspection: entry.graphql_block_introspection,
+            },
             action,
             tags: entry.tags.into_iter().collect(),
+            anomaly_threshold: entry.anomaly_threshold,
         },
     ))
 }
 
+/// counts the query parameters from the raw path, without allocating
+fn raw_query_param_count(path: &str) -> usize {
+    match path.split_once('?') {
+        None => 0,
+        Some((_, query)) => query.as_bytes().iter().filter(|&&b| b == b'&').count() + 1,
+    }
+}
+
 impl ContentFilterProfile {
+    /// fast pre-parse guard on the raw path, checked before the uri is
+    /// flattened so that adversarially huge query strings do not burn CPU
+    pub fn uri_restriction(&self, path: &str) -> Option<BlockReason> {
+        if path.len() > self.max_uri_length {
+            return Some(BlockReason::entry_too_large(
+                self.id.clone(),
+                self.name.clone(),
+                self.action.atype.to_raw(),
+                SectionIdx::Path,
+                "uri",
+                path.len(),
+                self.max_uri_length,
+            ));
+        }
+        let nargs = raw_query_param_count(path);
+        if nargs > self.max_uri_args {
+            return Some(BlockReason::too_many_entries(
+                self.id.clone(),
+                self.name.clone(),
+                self.action.atype.to_raw(),
+                SectionIdx::Args,
+                nargs,
+                self.max_uri_args,
+            ));
+        }
+        None
+    }
+
     pub fn resolve(
         logs: &mut Logs,
         actions: &HashMap<String, SimpleAction>,
@@ -449,9 +812,14 @@ pub fn resolve_rules(
         if ids.is_empty() {
             return Err(anyhow::anyhow!("no rules were selected, empty profile"));
         }
+        let prefilter = if *PREFILTER_ENABLED {
+            build_prefilter(&ids)
+        } else {
+            None
+        };
         Patterns::from_iter(ids.iter().map(|i| i.pattern.clone()))
             .build::<Vectored>()
-            .map(|db| ContentFilterRules { db, ids })
+            .map(|db| ContentFilterRules { db, ids, prefilter })
     };
 
     let mut out: HashMap<String, ContentFilterRules> = HashMap::new();
@@ -459,7 +827,14 @@ pub fn resolve_rules(
     for v in profiles.values() {
         match build_from_profile(v) {
             Ok(p) => {
-                logs.debug(|| format!("Loaded profile {} with {} rules", v.id, p.ids.len()));
+                logs.debug(|| {
+                    format!(
+                        "Loaded profile {} with {} rules (prefilter: {})",
+                        v.id,
+                        p.ids.len(),
+                        p.prefilter.is_some()
+                    )
+                });
                 out.insert(v.id.to_string(), p);
             }
             Err(rr) => logs.warning(|| format!("When building profile {}, error: {}", v.id, rr)),
@@ -468,3 +843,93 @@ pub fn resolve_rules(
 
     out
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn literal_plain() {
+        assert_eq!(extract_literal("select"), Some("select".to_string()));
+    }
+
+    #[test]
+    fn literal_alternation_unsound() {
+        assert_eq!(extract_literal("foo|bar"), None);
+        assert_eq!(extract_literal("(foo)bar"), None);
+    }
+
+    #[test]
+    fn literal_longest_run() {
+        assert_eq!(extract_literal(r"union\s+select"), Some("select".to_string()));
+    }
+
+    #[test]
+    fn literal_too_short() {
+        assert_eq!(extract_literal("a.b"), None);
+    }
+
+    #[test]
+    fn literal_counted_repetition() {
+        assert_eq!(extract_literal("passwd{1,3}"), Some("passw".to_string()));
+    }
+
+    #[test]
+    fn folding_modes() {
+        assert_eq!(NameFolding::Exact.fold("UserName"), "UserName");
+        assert_eq!(NameFolding::CaseInsensitive.fold("UserName"), "username");
+        assert_eq!(NameFolding::CaseInsensitive.fold("STRASSE"), "strasse");
+        assert_eq!(NameFolding::Unicode.fold("STRAßE"), "straße");
+        assert_eq!(NameFolding::Unicode.fold("ÜSER"), "üser");
+    }
+
+    fn name_entry(key: &str) -> RawContentFilterEntryMatch {
+        RawContentFilterEntryMatch {
+            key: key.to_string(),
+            reg: None,
+            restrict: true,
+            mask: None,
+            exclusions: Vec::new(),
+        }
+    }
+
+    #[test]
+    fn folding_collision_warns() {
+        let mut logs = Logs::default();
+        let props = RawContentFilterProperties {
+            names: vec![name_entry("Token"), name_entry("TOKEN")],
+            case_insensitive: true,
+            ..RawContentFilterProperties::default()
+        };
+        let section = mk_section(
+            &mut logs,
+            "profile1",
+            "args",
+            &RawContentFilterProperties::default(),
+            props,
+            false,
+        )
+        .unwrap();
+        assert_eq!(section.folding, NameFolding::CaseInsensitive);
+        assert_eq!(section.names.len(), 1);
+        assert!(section.names.contains_key("token"));
+        assert!(logs.logs.iter().any(|l| l.message.contains("collide after folding")));
+    }
+
+    #[test]
+    fn folding_same_name_override_does_not_warn() {
+        let mut logs = Logs::default();
+        let allsections = RawContentFilterProperties {
+            names: vec![name_entry("token")],
+            ..RawContentFilterProperties::default()
+        };
+        let props = RawContentFilterProperties {
+            names: vec![name_entry("token")],
+            case_insensitive: true,
+            ..RawContentFilterProperties::default()
+        };
+        let section = mk_section(&mut logs, "profile1", "args", &allsections, props, false).unwrap();
+        assert_eq!(section.names.len(), 1);
+        assert!(!logs.logs.iter().any(|l| l.message.contains("collide after folding")));
+    }
+}
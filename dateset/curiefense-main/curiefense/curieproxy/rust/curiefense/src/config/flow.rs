@@ -2,7 +2,7 @@ use std::collections::{HashMap, HashSet};
 
 use crate::config::limit::resolve_selectors;
 use crate::config::matchers::{RequestSelector, RequestSelectorCondition};
-use crate::config::raw::{RawFlowEntry, RawFlowStep, RawLimitSelector};
+use crate::config::raw::{RawFirstSeen, RawFlowEntry, RawFlowStep, RawLimitSelector};
 use crate::logs::Logs;
 
 #[derive(Debug, Clone, PartialEq, Eq, Hash)]
@@ -18,6 +18,8 @@ struct FlowEntry {
     timeframe: u64,
     tags: Vec<String>,
     sequence: Vec<FlowStep>,
+    key_prefix: Option<String>,
+    ttl_jitter: u64,
 }
 
 #[derive(Debug, Clone)]
@@ -50,6 +52,10 @@ pub struct FlowElement {
     pub select: Vec<RequestSelectorCondition>,
     /// marker for the last step
     pub is_last: bool,
+    /// extra namespace inserted in the redis key
+    pub key_prefix: Option<String>,
+    /// percentage of the timeframe that is randomly added to the key TTL
+    pub ttl_jitter: u64,
 }
 
 impl FlowEntry {
@@ -72,6 +78,8 @@ impl FlowEntry {
             tags: rawentry.tags,
             key: mkey?,
             sequence,
+            key_prefix: rawentry.key_prefix,
+            ttl_jitter: rawentry.ttl_jitter.unwrap_or(0),
         })
     }
 }
@@ -128,6 +136,8 @@ pub fn flow_resolve(logs: &mut Logs, rawentries: Vec<RawFlowEntry>) -> FlowMap {
                         select: step.select,
                         step: stepid as u32,
                         is_last: stepid + 1 == nsteps,
+                        key_prefix: entry.key_prefix.clone(),
+                        ttl_jitter: entry.ttl_jitter,
                     })
                 }
             }
@@ -141,3 +151,50 @@ pub fn flow_resolve(logs: &mut Logs, rawentries: Vec<RawFlowEntry>) -> FlowMap {
 
     out
 }
+
+/// a lightweight "seen before" check: requests whose key was never seen
+/// within the timeframe get the entry tags (`new-visitor` by default)
+#[derive(Debug, Clone)]
+pub struct FirstSeen {
+    pub id: String,
+    pub name: String,
+    pub include: HashSet<String>,
+    pub exclude: HashSet<String>,
+    pub key: Vec<RequestSelector>,
+    pub timeframe: u64,
+    pub tags: Vec<String>,
+    /// extra namespace inserted in the redis key
+    pub key_prefix: Option<String>,
+    /// percentage of the timeframe that is randomly added to the key TTL
+    pub ttl_jitter: u64,
+}
+
+pub fn first_seen_resolve(logs: &mut Logs, rawentries: Vec<RawFirstSeen>) -> Vec<FirstSeen> {
+    let mut out = Vec::new();
+    for rawentry in rawentries {
+        if !rawentry.active {
+            continue;
+        }
+        let id = rawentry.id;
+        let mkey: anyhow::Result<Vec<RequestSelector>> = rawentry
+            .key
+            .into_iter()
+            .map(RequestSelector::resolve_selector_map)
+            .collect();
+        match mkey {
+            Err(rr) => logs.warning(|| format!("first seen entry {}: {}", id, rr)),
+            Ok(key) => out.push(FirstSeen {
+                id,
+                name: rawentry.name,
+                include: rawentry.include.into_iter().collect(),
+                exclude: rawentry.exclude.into_iter().collect(),
+                key,
+                timeframe: rawentry.timeframe,
+                tags: rawentry.tags,
+                key_prefix: rawentry.key_prefix,
+                ttl_jitter: rawentry.ttl_jitter.unwrap_or(0),
+            }),
+        }
+    }
+    out
+}
@@ -1,7 +1,7 @@
 use anyhow::Context;
 use ipnet::{IpNet, Ipv4Net, Ipv6Net};
 use iprange::IpRange;
-use regex::{Regex, RegexBuilder};
+use regex::{Regex, RegexBuilder, RegexSet, RegexSetBuilder};
 use serde_json::{from_value, Value};
 use std::collections::HashMap;
 use std::net::IpAddr;
@@ -43,6 +43,27 @@ pub struct SingleEntry {
     pub re: Option<Regex>,
 }
 
+/// the request part a "single" condition applies to, used when merging regexes into sets
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum SingleEntryType {
+    Path,
+    Query,
+    Uri,
+    Country,
+    Region,
+    SubRegion,
+    Method,
+    Company,
+    Authority,
+}
+
+/// several "single" conditions of the same type, with their regexes compiled as one set
+#[derive(Debug, Clone)]
+pub struct SetEntry {
+    pub exact: Vec<String>,
+    pub set: RegexSet,
+}
+
 #[derive(Debug, Clone)]
 pub struct PairEntry {
     pub key: String,
@@ -81,6 +102,9 @@ pub enum GlobalFilterEntryE {
     Tag(SingleEntry),
     SecurityPolicyId(String),
     SecurityPolicyEntryId(String),
+
+    // internal usage for the optimizer, merges several "single" regexes of the same type
+    SingleSet(SingleEntryType, SetEntry),
 }
 
 /// tries to aggregate ip ranges
@@ -203,6 +227,149 @@ pub fn optimize_ipranges(rel: Relation, unoptimized: Vec<GlobalFilterRule>) -> V
     other
 }
 
+/// merges same-typed regex entries of an "or" relation into a single RegexSet,
+/// so that large pattern lists are matched in a single pass
+pub fn optimize_regexsets(rel: Relation, unoptimized: Vec<GlobalFilterRule>) -> Vec<GlobalFilterRule> {
+    // the merge is only semantically valid for the "or" relation
+    if rel != Relation::Or {
+        return unoptimized;
+    }
+
+    fn rebuild(tp: SingleEntryType, se: SingleEntry) -> GlobalFilterRule {
+        GlobalFilterRule::Entry(GlobalFilterEntry {
+            negated: false,
+            entry: match tp {
+                SingleEntryType::Path => GlobalFilterEntryE::Path(se),
+                SingleEntryType::Query => GlobalFilterEntryE::Query(se),
+                SingleEntryType::Uri => GlobalFilterEntryE::Uri(se),
+                SingleEntryType::Country => GlobalFilterEntryE::Country(se),
+                SingleEntryType::Region => GlobalFilterEntryE::Region(se),
+                SingleEntryType::SubRegion => GlobalFilterEntryE::SubRegion(se),
+                SingleEntryType::Method => GlobalFilterEntryE::Method(se),
+                SingleEntryType::Company => GlobalFilterEntryE::Company(se),
+                SingleEntryType::Authority => GlobalFilterEntryE::Authority(se),
+            },
+        })
+    }
+
+    let mut groups: HashMap<SingleEntryType, Vec<SingleEntry>> = HashMap::new();
+    let mut other: Vec<GlobalFilterRule> = Vec::new();
+
+    for r in unoptimized {
+        match r {
+            GlobalFilterRule::Entry(GlobalFilterEntry { negated: false, entry }) => {
+                let (tp, se) = match entry {
+                    GlobalFilterEntryE::Path(se) => (SingleEntryType::Path, se),
+                    GlobalFilterEntryE::Query(se) => (SingleEntryType::Query, se),
+                    GlobalFilterEntryE::Uri(se) => (SingleEntryType::Uri, se),
+                    GlobalFilterEntryE::Country(se) => (SingleEntryType::Country, se),
+                    GlobalFilterEntryE::Region(se) => (SingleEntryType::Region, se),
+                    GlobalFilterEntryE::SubRegion(se) => (SingleEntryType::SubRegion, se),
+                    GlobalFilterEntryE::Method(se) => (SingleEntryType::Method, se),
+                    GlobalFilterEntryE::Company(se) => (SingleEntryType::Company, se),
+                    GlobalFilterEntryE::Authority(se) => (SingleEntryType::Authority, se),
+                    e => {
+                        other.push(GlobalFilterRule::Entry(GlobalFilterEntry {
+                            negated: false,
+                            entry: e,
+                        }));
+                        continue;
+                    }
+                };
+                if se.re.is_some() {
+                    groups.entry(tp).or_default().push(se);
+                } else {
+                    // entries without a valid regex only perform exact matches
+                    other.push(rebuild(tp, se));
+                }
+            }
+            _ => other.push(r),
+        }
+    }
+
+    for (tp, entries) in groups {
+        if entries.len() < 2 {
+            for se in entries {
+                other.push(rebuild(tp, se));
+            }
+            continue;
+        }
+        let patterns: Vec<&str> = entries.iter().map(|se| se.exact.as_str()).collect();
+        match RegexSetBuilder::new(&patterns).case_insensitive(true).build() {
+            Ok(set) => other.push(GlobalFilterRule::Entry(GlobalFilterEntry {
+                negated: false,
+                entry: GlobalFilterEntryE::SingleSet(
+                    tp,
+                    SetEntry {
+                        exact: entries.into_iter().map(|se| se.exact).collect(),
+                        set,
+                    },
+                ),
+            })),
+            // all patterns compiled individually, but keep the entries on set failure anyway
+            Err(_) => {
+                for se in entries {
+                    other.push(rebuild(tp, se));
+                }
+            }
+        }
+    }
+
+    other
+}
+
+/// compiles a list of addresses or CIDR ranges (mixed families allowed) into
+/// prefix tries, so that large ignore/allow lists are matched in logarithmic
+/// time instead of a linear scan; a leading '!' on the first element negates
+/// the whole list, following the convention of the "single" entries
+fn ip_list(mut raws: Vec<String>) -> anyhow::Result<GlobalFilterRule> {
+    let negated = match raws.first() {
+        Some(f) if f.starts_with('!') => {
+            raws[0] = raws[0].trim_start_matches('!').to_string();
+            true
+        }
+        _ => false,
+    };
+    let mut r4 = IpRange::<Ipv4Net>::new();
+    let mut r6 = IpRange::<Ipv6Net>::new();
+    for raw in &raws {
+        let net: IpNet = if raw.contains('/') {
+            raw.parse().with_context(|| format!("net: {}", raw))?
+        } else {
+            IpNet::from(raw.parse::<IpAddr>().with_context(|| format!("ip: {}", raw))?)
+        };
+        match net {
+            IpNet::V4(n4) => {
+                r4.add(n4);
+            }
+            IpNet::V6(n6) => {
+                r6.add(n6);
+            }
+        }
+    }
+    r4.simplify();
+    r6.simplify();
+    let entry4 = GlobalFilterRule::Entry(GlobalFilterEntry {
+        negated,
+        entry: GlobalFilterEntryE::Range4(r4.clone()),
+    });
+    let entry6 = GlobalFilterRule::Entry(GlobalFilterEntry {
+        negated,
+        entry: GlobalFilterEntryE::Range6(r6.clone()),
+    });
+    match (r4.iter().next().is_some(), r6.iter().next().is_some()) {
+        (true, false) => Ok(entry4),
+        (false, true) => Ok(entry6),
+        // a mixed list matches when either family trie matches, and its
+        // negation when both negated tries match (De Morgan)
+        (true, true) => Ok(GlobalFilterRule::Rel(GlobalFilterRelation {
+            relation: if negated { Relation::And } else { Relation::Or },
+            entries: vec![entry4, entry6],
+        })),
+        (false, false) => Err(anyhow::anyhow!("empty ip list")),
+    }
+}
+
 impl GlobalFilterSection {
     // what an ugly function :(
     pub fn resolve(
@@ -347,10 +514,17 @@ impl GlobalFilterSection {
                         .collect::<Result<Vec<_>, _>>()?;
                     Ok(GlobalFilterRule::Rel(GlobalFilterRelation {
                         relation: rl.relation,
-                        entries: optimize_ipranges(rl.relation, entries),
+                        entries: optimize_regexsets(rl.relation, optimize_ipranges(rl.relation, entries)),
                     }))
                 }
-                RawGlobalFilterRule::Entry(e) => convert_entry(logs, e.tp, e.vl).map(GlobalFilterRule::Entry),
+                RawGlobalFilterRule::Entry(e) => {
+                    // list-valued ip entries compile into per-family prefix tries
+                    if matches!(e.tp, GlobalFilterEntryType::Ip) && e.vl.is_array() {
+                        ip_list(from_value(e.vl)?)
+                    } else {
+                        convert_entry(logs, e.tp, e.vl).map(GlobalFilterRule::Entry)
+                    }
+                }
             }
         }
 
@@ -384,3 +558,76 @@ impl GlobalFilterSection {
         out
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::{Ipv4Addr, Ipv6Addr};
+
+    fn mklist(raws: &[&str]) -> GlobalFilterRule {
+        ip_list(raws.iter().map(|s| s.to_string()).collect()).unwrap()
+    }
+
+    #[test]
+    fn ip_list_single_family_collapses_to_one_trie() {
+        match mklist(&["10.0.0.0/8", "192.168.1.1"]) {
+            GlobalFilterRule::Entry(GlobalFilterEntry {
+                negated: false,
+                entry: GlobalFilterEntryE::Range4(rng),
+            }) => {
+                assert!(rng.contains(&"10.20.30.40".parse::<Ipv4Addr>().unwrap()));
+                assert!(rng.contains(&"192.168.1.1".parse::<Ipv4Addr>().unwrap()));
+                assert!(!rng.contains(&"192.168.1.2".parse::<Ipv4Addr>().unwrap()));
+            }
+            r => panic!("unexpected rule {:?}", r),
+        }
+    }
+
+    #[test]
+    fn ip_list_mixed_families_split_per_trie() {
+        match mklist(&["10.0.0.0/8", "2001:db8::/32", "2001:db8:1::1"]) {
+            GlobalFilterRule::Rel(GlobalFilterRelation {
+                relation: Relation::Or,
+                entries,
+            }) => match entries.as_slice() {
+                [GlobalFilterRule::Entry(GlobalFilterEntry {
+                    negated: false,
+                    entry: GlobalFilterEntryE::Range4(r4),
+                }), GlobalFilterRule::Entry(GlobalFilterEntry {
+                    negated: false,
+                    entry: GlobalFilterEntryE::Range6(r6),
+                })] => {
+                    assert!(r4.contains(&"10.1.1.1".parse::<Ipv4Addr>().unwrap()));
+                    assert!(r6.contains(&"2001:db8:ffff::1".parse::<Ipv6Addr>().unwrap()));
+                    assert!(!r6.contains(&"2001:db9::1".parse::<Ipv6Addr>().unwrap()));
+                }
+                e => panic!("unexpected entries {:?}", e),
+            },
+            r => panic!("unexpected rule {:?}", r),
+        }
+    }
+
+    #[test]
+    fn ip_list_negation_uses_de_morgan() {
+        match mklist(&["!10.0.0.0/8", "2001:db8::/32"]) {
+            GlobalFilterRule::Rel(GlobalFilterRelation {
+                relation: Relation::And,
+                entries,
+            }) => {
+                for e in &entries {
+                    match e {
+                        GlobalFilterRule::Entry(ge) => assert!(ge.negated),
+                        r => panic!("unexpected rule {:?}", r),
+                    }
+                }
+            }
+            r => panic!("unexpected rule {:?}", r),
+        }
+    }
+
+    #[test]
+    fn ip_list_rejects_garbage() {
+        assert!(ip_list(vec!["not an ip".to_string()]).is_err());
+        assert!(ip_list(Vec::new()).is_err());
+    }
+}
@@ -0,0 +1,94 @@
+use ipnet::{Ipv4Net, Ipv6Net};
+use iprange::IpRange;
+use std::net::IpAddr;
+
+use crate::config::raw::RawHealthCheck;
+use crate::logs::Logs;
+
+/// allowlist of well known health check and monitoring agents
+///
+/// matching requests are passed before body parsing, and only carry the
+/// `healthcheck` tag so that they can be excluded from aggregation
+#[derive(Debug, Clone)]
+pub struct HealthCheckAllowlist {
+    /// user agent prefixes
+    pub agents: Vec<String>,
+    pub range4: IpRange<Ipv4Net>,
+    pub range6: IpRange<Ipv6Net>,
+}
+
+/// curated user agent prefixes, used unless the configuration overrides them
+fn default_agents() -> Vec<String> {
+    [
+        "kube-probe/",
+        "ELB-HealthChecker/",
+        "GoogleHC/",
+        "Amazon-Route53-Health-Check-Service",
+        "Consul Health Check",
+    ]
+    .iter()
+    .map(|s| s.to_string())
+    .collect()
+}
+
+impl Default for HealthCheckAllowlist {
+    fn default() -> Self {
+        HealthCheckAllowlist {
+            agents: default_agents(),
+            range4: IpRange::new(),
+            range6: IpRange::new(),
+        }
+    }
+}
+
+impl HealthCheckAllowlist {
+    pub fn resolve(logs: &mut Logs, raw: RawHealthCheck) -> Self {
+        let agents = raw.agents.unwrap_or_else(default_agents);
+        let mut range4: IpRange<Ipv4Net> = IpRange::new();
+        let mut range6: IpRange<Ipv6Net> = IpRange::new();
+        for rawrange in raw.ranges {
+            let valid = if rawrange.contains('/') {
+                if let Ok(n) = rawrange.parse::<Ipv4Net>() {
+                    range4.add(n);
+                    true
+                } else if let Ok(n) = rawrange.parse::<Ipv6Net>() {
+                    range6.add(n);
+                    true
+                } else {
+                    false
+                }
+            } else {
+                match rawrange.parse::<IpAddr>() {
+                    Ok(IpAddr::V4(v4)) => {
+                        range4.add(Ipv4Net::from(v4));
+                        true
+                    }
+                    Ok(IpAddr::V6(v6)) => {
+                        range6.add(Ipv6Net::from(v6));
+                        true
+                    }
+                    Err(_) => false,
+                }
+            };
+            if !valid {
+                logs.error(|| format!("Invalid health check range {}", rawrange));
+            }
+        }
+        range4.simplify();
+        range6.simplify();
+        HealthCheckAllowlist { agents, range4, range6 }
+    }
+
+    /// does the request come from a known health checker?
+    pub fn matches(&self, ip: &IpAddr, user_agent: Option<&str>) -> bool {
+        if let Some(ua) = user_agent {
+            if self.agents.iter().any(|a| ua.starts_with(a)) {
+                return true;
+            }
+        }
+        match ip {
+            IpAddr::V4(v4) => self.range4.contains(v4),
+            IpAddr::V6(v6) => self.range6.contains(v6),
+        }
+    }
+}
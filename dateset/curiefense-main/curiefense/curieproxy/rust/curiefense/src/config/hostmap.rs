@@ -1,9 +1,14 @@
+use regex::Regex;
+use serde::Serialize;
+use std::collections::{HashMap, HashSet};
 use std::sync::Arc;
 
 use crate::config::contentfilter::ContentFilterProfile;
 use crate::config::limit::Limit;
 use crate::config::matchers::Matching;
-use crate::config::raw::AclProfile;
+use crate::config::raw::{AclProfile, RawAllowlist, RawApiKey, RawLogProfile, RawOriginProtection, RawTagInjection};
+use crate::interface::SimpleAction;
+use crate::logs::Logs;
 
 use super::matchers::RequestSelector;
 
@@ -21,6 +26,36 @@ pub struct PolicyId {
     pub name: String,
 }
 
+/// hash algorithm applied to the session values
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum SessionHash {
+    Sha224,
+    Sha256,
+    Sha512,
+}
+
+impl Default for SessionHash {
+    fn default() -> Self {
+        SessionHash::Sha224
+    }
+}
+
+impl SessionHash {
+    /// parses the raw configuration value, defaulting to sha224
+    pub fn resolve(logs: &mut Logs, mname: Option<&str>) -> SessionHash {
+        match mname {
+            None | Some("sha224") => SessionHash::Sha224,
+            Some("sha256") => SessionHash::Sha256,
+            Some("sha512") => SessionHash::Sha512,
+            Some(other) => {
+                logs.error(|| format!("unknown session hash algorithm {}, using sha224", other));
+                SessionHash::Sha224
+            }
+        }
+    }
+}
+
 /// a map entry, with links to the acl and content filter profiles
 #[derive(Debug)]
 pub struct SecurityPolicy {
@@ -34,6 +69,259 @@ pub struct SecurityPolicy {
     pub limits: Vec<Limit>,
     pub session: Vec<RequestSelector>,
     pub session_ids: Vec<RequestSelector>,
+    /// hash algorithm applied to the session values
+    pub session_hash: SessionHash,
+    /// seed for the session hashes, the masking seed of the content filter
+    /// profile when absent
+    pub session_seed: Option<String>,
+    /// when true, the raw session value is kept in memory so that limit keys
+    /// can use it; it is never written to the logs
+    pub session_keep_raw: bool,
+    /// HS256 secrets used to verify bearer tokens; when empty, tokens are
+    /// parsed but not verified
+    pub jwt_keys: Vec<String>,
+    /// trusted proxy tag injection, None when disabled
+    pub tag_injection: Option<TagInjection>,
+    /// log shaping, None when the full log record is emitted
+    pub log_profile: Option<LogProfile>,
+    /// API key extraction, None when the policy defines no key sources
+    pub api_key: Option<ApiKey>,
+    /// shadow mode: every blocking decision is downgraded to monitor and
+    /// marked with would_block in the logs, so that new rules can be staged
+    /// without affecting traffic
+    pub shadow: bool,
+    pub allowlist: Option<Allowlist>,
+    /// arbitrary feature flags, exposed to templates, selectors and the decision output
+    pub features: HashMap<String, String>,
+    /// automatic origin protection, None when disabled
+    pub origin_protection: Option<OriginProtection>,
+}
+
+/// automatic origin protection settings: when consecutive upstream failures
+/// (5xx statuses or timeouts) reach the threshold, enforcement is tightened
+/// for the cooldown period and affected requests are tagged origin-protection
+#[derive(Debug, Clone)]
+pub struct OriginProtection {
+    /// consecutive upstream failures before protection engages
+    pub threshold: usize,
+    /// seconds during which protection stays engaged
+    pub cooldown: u64,
+    /// divisor applied to rate limit thresholds while protection is engaged
+    pub limit_divisor: u64,
+}
+
+impl OriginProtection {
+    pub fn resolve(raw: RawOriginProtection) -> Option<OriginProtection> {
+        if !raw.active {
+            return None;
+        }
+        Some(OriginProtection {
+            threshold: raw.threshold.unwrap_or(10),
+            cooldown: raw.cooldown.unwrap_or(60),
+            limit_divisor: raw.limit_divisor.unwrap_or(2).max(1),
+        })
+    }
+}
+
+/// trusted proxy tag injection: a request header carries pre-computed tags
+/// (for example CDN bot scores) as `tag1,tag2|<unix timestamp>|<hex hmac>`,
+/// where the HMAC-SHA256 signature covers `tag1,tag2|<timestamp>`; stale or
+/// badly signed headers are ignored
+#[derive(Debug, Clone)]
+pub struct TagInjection {
+    /// lowercased name of the header carrying the signed tags
+    pub header: String,
+    /// secret used to verify the header signature
+    pub key: String,
+    /// maximum accepted age of the header timestamp, in seconds
+    pub max_age: i64,
+}
+
+impl TagInjection {
+    /// returns None when tag injection is inactive or misconfigured
+    pub fn resolve(raw: RawTagInjection) -> Option<TagInjection> {
+        if !raw.active {
+            return None;
+        }
+        match (raw.header, raw.key) {
+            (Some(header), Some(key)) if !key.is_empty() => Some(TagInjection {
+                header: header.to_lowercase(),
+                key,
+                max_age: raw.max_age.unwrap_or(60),
+            }),
+            _ => None,
+        }
+    }
+
+    /// verifies a signed header value, returning the tags when the signature
+    /// matches and the timestamp is within the accepted age
+    pub fn verified_tags(&self, value: &str, now: i64) -> Option<Vec<String>> {
+        let (signed, signature) = value.rsplit_once('|')?;
+        let (taglist, timestamp) = signed.rsplit_once('|')?;
+        let ts: i64 = timestamp.parse().ok()?;
+        // stale headers are rejected, as are timestamps too far in the future
+        if (now - ts).abs() > self.max_age {
+            return None;
+        }
+        let expected: String = crate::jwt::hmac_sha256(self.key.as_bytes(), signed.as_bytes())
+            .iter()
+            .map(|b| format!("{:02x}", b))
+            .collect();
+        // the comparison must not leak how many leading bytes matched, as
+        // the signature is attacker supplied
+        if !crate::jwt::ct_eq(signature.to_ascii_lowercase().as_bytes(), expected.as_bytes()) {
+            return None;
+        }
+        Some(
+            taglist
+                .split(',')
+                .map(|t| t.trim())
+                .filter(|t| !t.is_empty())
+                .map(|t| t.to_string())
+                .collect(),
+        )
+    }
+}
+
+/// resolved API key extraction settings: the sources are probed in order
+/// and the first value found, with its prefix stripped, is the canonical
+/// api_key identity
+#[derive(Debug, Clone)]
+pub struct ApiKey {
+    /// selectors probed in order: headers, then arguments, then cookies
+    pub sources: Vec<RequestSelector>,
+    /// prefixes stripped from the value before it is used
+    pub strip_prefixes: Vec<String>,
+}
+
+impl ApiKey {
+    /// None when no source is defined, so that the extraction can be skipped
+    pub fn resolve(raw: RawApiKey) -> Option<ApiKey> {
+        let mut sources = Vec::new();
+        sources.extend(
+            raw.headers
+                .iter()
+                .map(|h| RequestSelector::Header(h.to_ascii_lowercase())),
+        );
+        sources.extend(raw.args.into_iter().map(RequestSelector::Args));
+        sources.extend(raw.cookies.into_iter().map(RequestSelector::Cookie));
+        if sources.is_empty() {
+            return None;
+        }
+        Some(ApiKey {
+            sources,
+            strip_prefixes: raw.strip_prefixes,
+        })
+    }
+
+    /// strips the first matching prefix from the extracted value
+    pub fn strip<'t>(&self, value: &'t str) -> &'t str {
+        for prefix in &self.strip_prefixes {
+            if let Some(stripped) = value.strip_prefix(prefix.as_str()) {
+                return stripped;
+            }
+        }
+        value
+    }
+}
+
+/// per-policy log shaping: selects which request content fields are kept in
+/// the log record, and whether header/cookie/argument values are masked;
+/// identity and decision fields are never affected
+#[derive(Debug, Clone)]
+pub struct LogProfile {
+    /// names of log fields omitted from the record
+    pub omit: HashSet<String>,
+    /// when true, header values are masked in the logs, keeping the names
+    pub redact_headers: bool,
+    /// when true, cookie values are masked in the logs, keeping the names
+    pub redact_cookies: bool,
+    /// when true, argument values are masked in the logs, keeping the names
+    pub redact_arguments: bool,
+}
+
+impl LogProfile {
+    /// returns None when the profile does not alter the log record
+    pub fn resolve(raw: RawLogProfile) -> Option<LogProfile> {
+        if raw.omit.is_empty() && !raw.redact_headers && !raw.redact_cookies && !raw.redact_arguments {
+            return None;
+        }
+        Some(LogProfile {
+            omit: raw.omit.into_iter().collect(),
+            redact_headers: raw.redact_headers,
+            redact_cookies: raw.redact_cookies,
+            redact_arguments: raw.redact_arguments,
+        })
+    }
+
+    /// true when the field is part of the log record
+    pub fn keeps(&self, field: &str) -> bool {
+        !self.omit.contains(field)
+    }
+}
+
+/// a positive security allowlist: when present, only requests matching the
+/// allowed paths/methods/content types pass, everything else is blocked
+#[derive(Debug, Clone)]
+pub struct Allowlist {
+    /// regexes on the request path, empty means all paths are allowed
+    pub paths: Vec<Regex>,
+    /// allowed methods, empty means all methods are allowed
+    pub methods: HashSet<String>,
+    /// allowed content type prefixes, empty means all content types are allowed
+    pub content_types: Vec<String>,
+    pub action: SimpleAction,
+}
+
+impl Allowlist {
+    /// returns None when the allowlist is inactive
+    pub fn resolve(
+        logs: &mut Logs,
+        actions: &HashMap<String, SimpleAction>,
+        entry_name: &str,
+        raw: RawAllowlist,
+    ) -> Option<Allowlist> {
+        if !raw.active {
+            return None;
+        }
+        let mut paths = Vec::new();
+        for p in raw.paths {
+            match Regex::new(&p) {
+                Ok(re) => paths.push(re),
+                Err(rr) => logs.warning(|| format!("Invalid regex {} in allowlist of entry {}: {}", p, entry_name, rr)),
+            }
+        }
+        let action = match &raw.action {
+            None => SimpleAction::default(),
+            Some(aid) => actions.get(aid).cloned().unwrap_or_else(|| {
+                logs.error(|| format!("Could not resolve action {} in allowlist of entry {}", aid, entry_name));
+                SimpleAction::default()
+            }),
+        };
+        Some(Allowlist {
+            paths,
+            methods: raw.methods.into_iter().map(|m| m.to_uppercase()).collect(),
+            content_types: raw.content_types.into_iter().map(|c| c.to_lowercase()).collect(),
+            action,
+        })
+    }
+
+    /// returns the first dimension that is not explicitly allowed
+    pub fn violation(&self, method: &str, path: &str, content_type: Option<&str>) -> Option<(&'static str, String)> {
+        if !self.methods.is_empty() && !self.methods.contains(&method.to_uppercase()) {
+            return Some(("method", method.to_string()));
+        }
+        if !self.paths.is_empty() && !self.paths.iter().any(|re| re.is_match(path)) {
+            return Some(("path", path.to_string()));
+        }
+        if !self.content_types.is_empty() {
+            let ctype = content_type.unwrap_or("").to_lowercase();
+            if !self.content_types.iter().any(|c| ctype.starts_with(c)) {
+                return Some(("content-type", ctype));
+            }
+        }
+        None
+    }
 }
 
 impl Default for SecurityPolicy {
@@ -55,6 +343,17 @@ impl Default for SecurityPolicy {
             limits: Vec::new(),
             session: Vec::new(),
             session_ids: Vec::new(),
+            session_hash: SessionHash::default(),
+            session_seed: None,
+            session_keep_raw: false,
+            jwt_keys: Vec::new(),
+            tag_injection: None,
+            log_profile: None,
+            api_key: None,
+            shadow: false,
+            allowlist: None,
+            features: HashMap::new(),
+            origin_protection: None,
         }
     }
 }
@@ -78,6 +377,17 @@ impl SecurityPolicy {
             limits: Vec::new(),
             session: Vec::new(),
             session_ids: Vec::new(),
+            session_hash: SessionHash::default(),
+            session_seed: None,
+            session_keep_raw: false,
+            jwt_keys: Vec::new(),
+            tag_injection: None,
+            log_profile: None,
+            api_key: None,
+            shadow: false,
+            allowlist: None,
+            features: HashMap::new(),
+            origin_protection: None,
         };
         out.content_filter_profile.content_type = Vec::new();
         out.content_filter_profile.decoding = Vec::new();
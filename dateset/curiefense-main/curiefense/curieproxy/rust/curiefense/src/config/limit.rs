@@ -6,9 +6,10 @@ use std::collections::HashSet;
 use crate::config::matchers::{
     decode_request_selector_condition, RequestSelector, RequestSelectorCondition, SelectorType,
 };
-use crate::config::raw::{RawLimit, RawLimitSelector};
+use crate::config::raw::{RawLimit, RawLimitCondition, RawLimitSelector, Relation};
 use crate::interface::SimpleAction;
 use crate::logs::Logs;
+use crate::timewindow::TimeWindow;
 
 #[derive(Debug, Clone)]
 pub struct Limit {
@@ -21,6 +22,50 @@ pub struct Limit {
     pub pairwith: Option<RequestSelector>,
     pub key: Vec<RequestSelector>,
     pub tags: Vec<String>,
+    /// extra namespace inserted in the redis key
+    pub key_prefix: Option<String>,
+    /// percentage of the timeframe that is randomly added to the key TTL
+    pub ttl_jitter: u64,
+    /// when non empty, the counter is only incremented when the embedder
+    /// reports an upstream response status from this list
+    pub count_status: Vec<u32>,
+    /// when set, the limit only applies to requests matching the condition,
+    /// in addition to the include/exclude checks
+    pub condition: Option<LimitCondition>,
+    /// counting window; calendar windows reset on clock boundaries
+    pub window: TimeWindow,
+    /// minutes east of UTC for calendar window alignment
+    pub window_offset: i32,
+}
+
+/// applicability condition for a limit, with selectors resolved and regexes
+/// compiled at configuration load
+#[derive(Debug, Clone)]
+pub enum LimitCondition {
+    Rel(Relation, Vec<LimitCondition>),
+    /// the negation flag, and the ANDed selector conditions of a single entry
+    Entry(bool, Vec<RequestSelectorCondition>),
+}
+
+impl LimitCondition {
+    fn resolve(raw: RawLimitCondition) -> anyhow::Result<LimitCondition> {
+        match raw {
+            RawLimitCondition::Rel(r) => Ok(LimitCondition::Rel(
+                r.relation,
+                r.entries
+                    .into_iter()
+                    .map(LimitCondition::resolve)
+                    .collect::<anyhow::Result<_>>()?,
+            )),
+            RawLimitCondition::Entry(e) => {
+                let conds = resolve_selectors(e.select)?;
+                if conds.is_empty() {
+                    return Err(anyhow::anyhow!("empty condition entry"));
+                }
+                Ok(LimitCondition::Entry(e.negated, conds))
+            }
+        }
+    }
 }
 
 #[derive(Debug, Clone)]
@@ -55,6 +100,13 @@ impl Limit {
             .collect();
         let key = mkey.with_context(|| "when converting the key entry")?;
         let pairwith = RequestSelector::resolve_selector_map(rawlimit.pairwith).ok();
+        let condition = rawlimit
+            .condition
+            .map(LimitCondition::resolve)
+            .transpose()
+            .with_context(|| "when converting the condition entry")?;
+        let window =
+            TimeWindow::from_raw(rawlimit.window.as_deref()).with_context(|| "when converting the window entry")?;
         let mut thresholds: Vec<LimitThreshold> = Vec::new();
         let id = rawlimit.id;
 
@@ -95,11 +147,34 @@ impl Limit {
                 pairwith,
                 key,
                 tags: rawlimit.tags,
+                key_prefix: rawlimit.key_prefix,
+                ttl_jitter: rawlimit.ttl_jitter.map(|j| j.inner).unwrap_or(0),
+                count_status: rawlimit.count_status,
+                condition,
+                window,
+                window_offset: rawlimit.window_offset.unwrap_or(0),
             },
             rawlimit.active,
         ))
     }
 
+    /// canonical representation of the counting scope of this limit: the key
+    /// selectors, the extra key prefix and the pairwith selector. Two limits
+    /// with the same template count the same population.
+    pub fn key_template(&self) -> String {
+        let mut selectors: Vec<String> = self.key.iter().map(|s| s.to_string()).collect();
+        selectors.sort();
+        format!(
+            "key=[{}] prefix={} pairwith={}",
+            selectors.join(","),
+            self.key_prefix.as_deref().unwrap_or("-"),
+            self.pairwith
+                .as_ref()
+                .map(|s| s.to_string())
+                .unwrap_or_else(|| "-".to_string()),
+        )
+    }
+
     /// returns the limit table, list of global limits, set of inactive limits
     pub fn resolve(
         logs: &mut Logs,
@@ -135,6 +210,21 @@ pub fn limit_order(a: &LimitThreshold, b: &LimitThreshold) -> Ordering {
     b.limit.cmp(&a.limit)
 }
 
+/// groups limits whose key templates collide (identical selectors and scope),
+/// as such limits silently count the same population twice
+pub fn key_collisions(limits: &[&Limit]) -> Vec<(String, Vec<String>)> {
+    let mut groups: HashMap<String, Vec<String>> = HashMap::new();
+    for lm in limits {
+        groups.entry(lm.key_template()).or_default().push(lm.id.clone());
+    }
+    let mut out: Vec<(String, Vec<String>)> = groups.into_iter().filter(|(_, ids)| ids.len() > 1).collect();
+    for (_, ids) in out.iter_mut() {
+        ids.sort();
+    }
+    out.sort();
+    out
+}
+
 #[cfg(test)]
 mod tests {
     use crate::interface::SimpleActionT;
@@ -152,6 +242,7 @@ mod tests {
                     },
                     headers: None,
                     status: v as u32,
+                    status_by_tag: Vec::new(),
                     extra_tags: None,
                 },
             }
@@ -166,4 +257,64 @@ mod tests {
         let expected: Vec<u64> = vec![8, 4, 1, 0];
         assert_eq!(status, expected);
     }
+
+    #[test]
+    fn test_condition_resolution() {
+        let raw: RawLimitCondition = serde_json::from_str(
+            r#"{"relation": "AND", "entries": [
+                {"select": {"attrs": {"tags": "internal"}}, "negated": true},
+                {"select": {"attrs": {"path": "/api/.*"}}}
+            ]}"#,
+        )
+        .unwrap();
+        match LimitCondition::resolve(raw).unwrap() {
+            LimitCondition::Rel(Relation::And, entries) => match entries.as_slice() {
+                [LimitCondition::Entry(true, tagconds), LimitCondition::Entry(false, pathconds)] => {
+                    assert!(matches!(tagconds.as_slice(), [RequestSelectorCondition::Tag(t)] if t == "internal"));
+                    assert!(
+                        matches!(pathconds.as_slice(), [RequestSelectorCondition::N(RequestSelector::Path, _)])
+                    );
+                }
+                e => panic!("unexpected entries {:?}", e),
+            },
+            c => panic!("unexpected condition {:?}", c),
+        }
+    }
+
+    #[test]
+    fn test_key_collisions() {
+        fn mklimit(id: &str, key: Vec<RequestSelector>, prefix: Option<&str>) -> Limit {
+            Limit {
+                id: id.to_string(),
+                name: id.to_string(),
+                timeframe: 60,
+                thresholds: Vec::new(),
+                exclude: HashSet::new(),
+                include: HashSet::new(),
+                pairwith: None,
+                key,
+                tags: Vec::new(),
+                key_prefix: prefix.map(|p| p.to_string()),
+                ttl_jitter: 0,
+                count_status: Vec::new(),
+                condition: None,
+                window: TimeWindow::Rolling,
+                window_offset: 0,
+            }
+        }
+        let l1 = mklimit("l1", vec![RequestSelector::Ip, RequestSelector::Path], None);
+        // same selectors in a different order: still the same scope
+        let l2 = mklimit("l2", vec![RequestSelector::Path, RequestSelector::Ip], None);
+        // same selectors but a different prefix: distinct scope
+        let l3 = mklimit("l3", vec![RequestSelector::Ip, RequestSelector::Path], Some("ns"));
+        let collisions = key_collisions(&[&l1, &l2, &l3]);
+        assert_eq!(collisions.len(), 1);
+        assert_eq!(collisions[0].1, vec!["l1".to_string(), "l2".to_string()]);
+    }
+
+    #[test]
+    fn test_condition_empty_entry() {
+        let raw: RawLimitCondition = serde_json::from_str(r#"{"select": {}}"#).unwrap();
+        assert!(LimitCondition::resolve(raw).is_err());
+    }
 }
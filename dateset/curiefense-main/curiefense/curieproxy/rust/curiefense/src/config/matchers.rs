@@ -21,8 +21,24 @@ pub enum RequestSelector {
     Authority,
     Tags,
     Session,
+    /// the canonical api key identity, only available when the policy
+    /// defines api key sources and the request carried one
+    ApiKey,
+    /// a claim from the bearer token, only available when the request carried
+    /// a parsable JWT
+    JwtClaim(String),
+    /// order based fingerprint of the header names, only available when the
+    /// embedder provided the headers as an ordered list
+    HeadersFingerprint,
+    /// mTLS client certificate attributes, only available when the proxy
+    /// forwards them in the request metadata
+    ClientCertSubject,
+    ClientCertSan,
+    ClientCertFingerprint,
     SecpolId,
     SecpolEntryId,
+    PolicyFeature(String),
+    SampleBucket,
 }
 
 #[derive(Debug, Clone)]
@@ -38,6 +54,8 @@ pub enum SelectorType {
     Args,
     Attrs,
     Plugins,
+    Features,
+    Jwt,
 }
 
 fn resolve_selector_type(k: &str) -> anyhow::Result<SelectorType> {
@@ -49,6 +67,9 @@ fn resolve_selector_type(k: &str) -> anyhow::Result<SelectorType> {
         "arguments" => Ok(SelectorType::Args),
         "attrs" => Ok(SelectorType::Attrs),
         "attributes" => Ok(SelectorType::Attrs),
+        "features" => Ok(SelectorType::Features),
+        "jwt" => Ok(SelectorType::Jwt),
+        "jwtclaim" => Ok(SelectorType::Jwt),
         _ => Err(anyhow::anyhow!("Unknown selector type {}", k)),
     }
 }
@@ -71,8 +92,14 @@ impl RequestSelector {
             "authority" => Some(RequestSelector::Authority),
             "tags" => Some(RequestSelector::Tags),
             "session" => Some(RequestSelector::Session),
+            "api_key" | "apikey" => Some(RequestSelector::ApiKey),
+            "headersfp" | "headers_fingerprint" => Some(RequestSelector::HeadersFingerprint),
+            "clientcertsubject" | "client_cert_subject" => Some(RequestSelector::ClientCertSubject),
+            "clientcertsan" | "client_cert_san" => Some(RequestSelector::ClientCertSan),
+            "clientcertfp" | "client_cert_fingerprint" => Some(RequestSelector::ClientCertFingerprint),
             "secpolid" | "securitypolicyid" | "securitypolicy" => Some(RequestSelector::SecpolId),
             "secpolentryid" | "securitypolicyentryid" | "securitypolicyentry" => Some(RequestSelector::SecpolEntryId),
+            "samplebucket" | "sample_bucket" => Some(RequestSelector::SampleBucket),
             _ => None,
         }
     }
@@ -88,6 +115,8 @@ impl RequestSelector {
             SelectorType::Cookies => Ok(RequestSelector::Cookie(v.to_string())),
             SelectorType::Args => Ok(RequestSelector::Args(v.to_string())),
             SelectorType::Plugins => Ok(RequestSelector::Plugins(v.to_string())),
+            SelectorType::Features => Ok(RequestSelector::PolicyFeature(v.to_string())),
+            SelectorType::Jwt => Ok(RequestSelector::JwtClaim(v.to_string())),
             SelectorType::Attrs => Self::decode_attribute(v).ok_or_else(|| anyhow::anyhow!("Unknown attribute {}", v)),
         }
     }
@@ -123,7 +152,15 @@ impl std::fmt::Display for RequestSelector {
             RequestSelector::Region => write!(f, "region"),
             RequestSelector::SubRegion => write!(f, "subregion"),
             RequestSelector::Session => write!(f, "session"),
+            RequestSelector::ApiKey => write!(f, "api_key"),
+            RequestSelector::JwtClaim(n) => write!(f, "jwt_{}", n),
+            RequestSelector::HeadersFingerprint => write!(f, "headersfp"),
+            RequestSelector::ClientCertSubject => write!(f, "client_cert_subject"),
+            RequestSelector::ClientCertSan => write!(f, "client_cert_san"),
+            RequestSelector::ClientCertFingerprint => write!(f, "client_cert_fingerprint"),
             RequestSelector::Plugins(n) => write!(f, "plugins_{}", n),
+            RequestSelector::PolicyFeature(n) => write!(f, "feature_{}", n),
+            RequestSelector::SampleBucket => write!(f, "sample_bucket"),
         }
     }
 }
@@ -0,0 +1,133 @@
+use std::collections::HashSet;
+
+use crate::config::raw::RawMirrorRule;
+use crate::interface::Tags;
+use crate::logs::Logs;
+
+/// a request mirroring rule: requests that were not blocked and match one of
+/// the watched tags are annotated with the mirror target, so that the
+/// embedder can duplicate them to a sandbox (envoy request mirroring, nginx
+/// post_action)
+#[derive(Debug, Clone)]
+pub struct MirrorRule {
+    pub id: String,
+    pub name: String,
+    /// tags that select the requests to mirror
+    pub tags: HashSet<String>,
+    /// where the mirrored copy should be sent
+    pub target: String,
+    /// fraction of the matching requests that are mirrored
+    pub sample: f64,
+}
+
+pub fn mirrors_resolve(logs: &mut Logs, rawentries: Vec<RawMirrorRule>) -> Vec<MirrorRule> {
+    let mut out = Vec::new();
+    for rawentry in rawentries {
+        if !rawentry.active {
+            continue;
+        }
+        let id = rawentry.id;
+        if rawentry.tags.is_empty() {
+            logs.warning(|| format!("mirror rule {}: no tags to match", id));
+            continue;
+        }
+        if rawentry.target.is_empty() {
+            logs.warning(|| format!("mirror rule {}: empty target", id));
+            continue;
+        }
+        let sample = rawentry.sample.unwrap_or(1.0);
+        if !(0.0..=1.0).contains(&sample) {
+            logs.warning(|| format!("mirror rule {}: sample {} is not within [0;1]", id, sample));
+            continue;
+        }
+        out.push(MirrorRule {
+            id,
+            name: rawentry.name,
+            tags: rawentry.tags.into_iter().collect(),
+            target: rawentry.target,
+            sample,
+        });
+    }
+    out
+}
+
+/// the mirror annotation for this request, from the first rule matching its
+/// tags, subject to sampling
+pub fn mirror_annotation(rules: &[MirrorRule], tags: &Tags) -> Option<serde_json::Value> {
+    let rule = rules.iter().find(|r| tags.has_intersection(&r.tags))?;
+    if rule.sample < 1.0 && rand::random::<f64>() >= rule.sample {
+        return None;
+    }
+    Some(serde_json::json!({
+        "id": rule.id,
+        "name": rule.name,
+        "target": rule.target,
+        "sample": rule.sample,
+    }))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::virtualtags::VirtualTags;
+    use crate::interface::Location;
+
+    fn mkrule(id: &str, tags: &[&str], sample: f64) -> MirrorRule {
+        MirrorRule {
+            id: id.to_string(),
+            name: id.to_string(),
+            tags: tags.iter().map(|t| t.to_string()).collect(),
+            target: "http://sandbox.internal".to_string(),
+            sample,
+        }
+    }
+
+    fn mktags(tags: &[&str]) -> Tags {
+        let slice: Vec<(String, Location)> = tags.iter().map(|t| (t.to_string(), Location::Request)).collect();
+        Tags::from_slice(&slice, VirtualTags::default())
+    }
+
+    #[test]
+    fn matching_rule_is_annotated() {
+        let rules = vec![mkrule("m1", &["suspicious"], 1.0)];
+        let annotation = mirror_annotation(&rules, &mktags(&["all", "suspicious"])).expect("should match");
+        assert_eq!(annotation["target"], "http://sandbox.internal");
+        assert_eq!(annotation["id"], "m1");
+    }
+
+    #[test]
+    fn unrelated_tags_do_not_mirror() {
+        let rules = vec![mkrule("m1", &["suspicious"], 1.0)];
+        assert!(mirror_annotation(&rules, &mktags(&["all"])).is_none());
+    }
+
+    #[test]
+    fn zero_sample_never_mirrors() {
+        let rules = vec![mkrule("m1", &["suspicious"], 0.0)];
+        assert!(mirror_annotation(&rules, &mktags(&["suspicious"])).is_none());
+    }
+
+    #[test]
+    fn invalid_entries_are_dropped() {
+        let mut logs = Logs::default();
+        let raws = vec![
+            RawMirrorRule {
+                id: "nosample".to_string(),
+                name: "nosample".to_string(),
+                active: true,
+                tags: vec!["suspicious".to_string()],
+                target: "http://sandbox.internal".to_string(),
+                sample: Some(7.0),
+            },
+            RawMirrorRule {
+                id: "inactive".to_string(),
+                name: "inactive".to_string(),
+                active: false,
+                tags: vec!["suspicious".to_string()],
+                target: "http://sandbox.internal".to_string(),
+                sample: None,
+            },
+        ];
+        assert!(mirrors_resolve(&mut logs, raws).is_empty());
+    }
+}
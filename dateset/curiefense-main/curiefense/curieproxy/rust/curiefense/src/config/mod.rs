@@ -1,11 +1,15 @@
+pub mod compliance;
 pub mod contentfilter;
 pub mod custom;
 pub mod flow;
 pub mod globalfilter;
+pub mod healthcheck;
 pub mod hostmap;
 pub mod limit;
 pub mod matchers;
+pub mod mirrors;
 pub mod raw;
+pub mod stickytags;
 pub mod virtualtags;
 
 use lazy_static::lazy_static;
@@ -17,17 +21,27 @@ use std::sync::Arc;
 use std::sync::RwLock;
 
 use crate::config::limit::Limit;
+use crate::filescan::FileScanEntry;
 use crate::interface::SimpleAction;
 use crate::logs::Logs;
+use crate::pluginpipeline::PluginStep;
+use compliance::EmbargoPolicy;
 use contentfilter::{resolve_rules, ContentFilterProfile, ContentFilterRules};
 use custom::Site;
-use flow::flow_resolve;
+use flow::{first_seen_resolve, flow_resolve};
+use stickytags::{sticky_tags_resolve, StickyTag};
 use globalfilter::GlobalFilterSection;
-use hostmap::{HostMap, PolicyId, SecurityPolicy};
+use hostmap::{
+    Allowlist, ApiKey, HostMap, LogProfile, OriginProtection, PolicyId, SecurityPolicy, SessionHash, TagInjection,
+};
 use jsonpath_rust::JsonPathFinder;
 use matchers::Matching;
+use mirrors::{mirrors_resolve, MirrorRule};
+use healthcheck::HealthCheckAllowlist;
 use raw::{
-    AclProfile, RawFlowEntry, RawGlobalFilterSection, RawHostMap, RawLimit, RawSecurityPolicy, RawSite, RawVirtualTag,
+    AclProfile, RawEmbargo, RawFileScanner, RawFirstSeen, RawFlowEntry, RawGlobalFilterSection, RawHealthCheck,
+    RawHostMap, RawLimit, RawMirrorRule, RawPluginStep, RawSecurityPolicy, RawSite, RawStickyTag, RawTelemetry,
+    RawVirtualTag,
 };
 use virtualtags::{vtags_resolve, VirtualTags};
 
@@ -36,20 +50,168 @@ use self::matchers::RequestSelector;
 use self::raw::RawAclProfile;
 use self::raw::RawManifest;
 
-static ALL_CONFIG_FILES: [&str; 11] = [
-    "actions.json",
-    "acl-profiles.json",
-    "contentfilter-profiles.json",
-    "contentfilter-rules.json",
-    "globalfilter-lists.json",
-    "limits.json",
-    "manifest.json",
-    "securitypolicy.json",
-    "flow-control.json",
-    "virtual-tags.json",
-    "custom.json",
+/// a configuration section: one json file, and the files whose resolved
+/// content it reads when it is loaded. The reload set for a changed file is
+/// derived from these descriptors as its transitive dependents, so adding a
+/// section here is enough to keep partial reloads correct.
+struct ConfigSection {
+    file: &'static str,
+    depends_on: &'static [&'static str],
+}
+
+static CONFIG_SECTIONS: [ConfigSection; 19] = [
+    ConfigSection {
+        file: "actions.json",
+        depends_on: &[],
+    },
+    ConfigSection {
+        file: "acl-profiles.json",
+        depends_on: &["actions.json"],
+    },
+    ConfigSection {
+        file: "contentfilter-profiles.json",
+        depends_on: &["actions.json"],
+    },
+    ConfigSection {
+        file: "contentfilter-rules.json",
+        depends_on: &["actions.json", "contentfilter-profiles.json"],
+    },
+    ConfigSection {
+        file: "globalfilter-lists.json",
+        depends_on: &["actions.json"],
+    },
+    ConfigSection {
+        file: "limits.json",
+        depends_on: &["actions.json"],
+    },
+    // the manifest carries the configuration revision, so it is refreshed
+    // whenever any other section changes
+    ConfigSection {
+        file: "manifest.json",
+        depends_on: &[
+            "actions.json",
+            "acl-profiles.json",
+            "contentfilter-profiles.json",
+            "contentfilter-rules.json",
+            "globalfilter-lists.json",
+            "limits.json",
+            "securitypolicy.json",
+            "flow-control.json",
+            "virtual-tags.json",
+            "custom.json",
+            "telemetry.json",
+            "healthcheck.json",
+            "first-seen.json",
+            "sticky-tags.json",
+            "embargo.json",
+            "plugins.json",
+            "mirrors.json",
+            "filescanners.json",
+        ],
+    },
+    ConfigSection {
+        file: "securitypolicy.json",
+        depends_on: &[
+            "actions.json",
+            "acl-profiles.json",
+            "contentfilter-profiles.json",
+            "limits.json",
+        ],
+    },
+    ConfigSection {
+        file: "flow-control.json",
+        depends_on: &[],
+    },
+    ConfigSection {
+        file: "virtual-tags.json",
+        depends_on: &[],
+    },
+    ConfigSection {
+        file: "custom.json",
+        depends_on: &[],
+    },
+    ConfigSection {
+        file: "telemetry.json",
+        depends_on: &[],
+    },
+    ConfigSection {
+        file: "healthcheck.json",
+        depends_on: &[],
+    },
+    ConfigSection {
+        file: "first-seen.json",
+        depends_on: &[],
+    },
+    ConfigSection {
+        file: "sticky-tags.json",
+        depends_on: &[],
+    },
+    ConfigSection {
+        file: "embargo.json",
+        depends_on: &["actions.json"],
+    },
+    ConfigSection {
+        file: "plugins.json",
+        depends_on: &["actions.json"],
+    },
+    ConfigSection {
+        file: "mirrors.json",
+        depends_on: &[],
+    },
+    ConfigSection {
+        file: "filescanners.json",
+        depends_on: &["actions.json"],
+    },
 ];
 
+/// validates the section descriptors and derives, for each file, the set of
+/// files that must be reloaded along with it (its transitive dependents)
+fn resolve_dependents(sections: &[ConfigSection]) -> Result<HashMap<&'static str, Vec<&'static str>>, String> {
+    let files: HashSet<&'static str> = sections.iter().map(|s| s.file).collect();
+    if files.len() != sections.len() {
+        return Err("duplicate file in CONFIG_SECTIONS".to_string());
+    }
+    let edges: HashMap<&'static str, &'static [&'static str]> =
+        sections.iter().map(|s| (s.file, s.depends_on)).collect();
+    for section in sections {
+        for dep in section.depends_on {
+            if !files.contains(dep) {
+                return Err(format!("{} depends on unknown file {}", section.file, dep));
+            }
+        }
+    }
+
+    // transitive dependency closure of a file, also detecting cycles
+    fn closure(
+        file: &'static str,
+        edges: &HashMap<&'static str, &'static [&'static str]>,
+        seen: &mut Vec<&'static str>,
+        out: &mut HashSet<&'static str>,
+    ) -> Result<(), String> {
+        if seen.contains(&file) {
+            return Err(format!("dependency cycle through {}", file));
+        }
+        seen.push(file);
+        for &dep in edges[file] {
+            if out.insert(dep) {
+                closure(dep, edges, seen, out)?;
+            }
+        }
+        seen.pop();
+        Ok(())
+    }
+
+    let mut dependents: HashMap<&'static str, Vec<&'static str>> = files.iter().map(|f| (*f, Vec::new())).collect();
+    for section in sections {
+        let mut deps = HashSet::new();
+        closure(section.file, &edges, &mut Vec::new(), &mut deps)?;
+        for dep in deps {
+            dependents.get_mut(dep).unwrap().push(section.file);
+        }
+    }
+    Ok(dependents)
+}
+
 pub struct LockedConfig {
     pub config: RwLock<Config>,
     pub hsdb: RwLock<HashMap<String, ContentFilterRules>>,
@@ -69,47 +231,44 @@ impl LockedConfig {
 
 lazy_static! {
     pub static ref CONFIGS: LockedConfig = LockedConfig::initial();
-    static ref CONFIG_DEPENDENCIES: HashMap<&'static str, Vec<String>> = {
-        let mut map = HashMap::new();
-
-        map.insert(
-            "actions.json",
-            vec![
-                "acl-profiles.json".to_string(),
-                "contentfilter-profiles.json".to_string(),
-                "contentfilter-rules.json".to_string(),
-                "globalfilter-lists.json".to_string(),
-                "limits.json".to_string(),
-                "securitypolicy.json".to_string(),
-                "manifest.json".to_string(),
-            ],
-        );
-        map.insert(
-            "contentfilter-profiles.json",
-            vec![
-                "contentfilter-rules.json".to_string(),
-                "securitypolicy.json".to_string(),
-                "manifest.json".to_string(),
-            ],
-        );
-        map.insert(
-            "limits.json",
-            vec!["securitypolicy.json".to_string(), "manifest.json".to_string()],
-        );
-        map.insert(
-            "acl-profiles.json",
-            vec!["securitypolicy.json".to_string(), "manifest.json".to_string()],
-        );
+    /// which files must be reloaded when a given file changes, derived from
+    /// CONFIG_SECTIONS; an invalid descriptor table is a programming error
+    /// and aborts startup
+    static ref CONFIG_DEPENDENTS: HashMap<&'static str, Vec<&'static str>> = match resolve_dependents(&CONFIG_SECTIONS) {
+        Ok(dependents) => dependents,
+        Err(rr) => panic!("invalid CONFIG_SECTIONS: {}", rr),
+    };
+}
 
-        // add generic dependency to the manifest
-        for f in ALL_CONFIG_FILES {
-            if !map.contains_key(f) && f != "manifest.json" {
-                map.insert(f, vec!["manifest.json".to_string()]);
+/// loads the HTML block page templates from the templates directory of the
+/// configuration, keyed by file stem, so that actions can reference them by id
+fn load_templates(logs: &mut Logs, basepath: &str) -> HashMap<String, String> {
+    let mut tpath = PathBuf::from(basepath);
+    tpath.push("templates");
+    let mut out = HashMap::new();
+    let entries = match std::fs::read_dir(&tpath) {
+        // having no templates directory is perfectly fine
+        Err(_) => return out,
+        Ok(entries) => entries,
+    };
+    for entry in entries.flatten() {
+        let path = entry.path();
+        if path.extension().and_then(|e| e.to_str()) != Some("html") {
+            continue;
+        }
+        let id = match path.file_stem().and_then(|s| s.to_str()) {
+            None => continue,
+            Some(s) => s.to_string(),
+        };
+        match std::fs::read_to_string(&path) {
+            Ok(content) => {
+                logs.debug(|| format!("Loaded template {} from {}", id, path.display()));
+                out.insert(id, content);
             }
+            Err(rr) => logs.error(|| format!("Could not read template {}: {}", path.display(), rr)),
         }
-
-        map
-    };
+    }
+    out
 }
 
 fn container_name() -> Option<String> {
@@ -151,11 +310,12 @@ pub fn reload_config(basepath: &str, filenames: Vec<String>) {
     let mut files_to_reload = HashSet::new();
     if filenames.is_empty() {
         // if filename was not provided, reload all config files
-        files_to_reload.extend(ALL_CONFIG_FILES.iter().map(|s| s.to_string()));
+        files_to_reload.extend(CONFIG_SECTIONS.iter().map(|s| s.file.to_string()));
     } else {
         for filename in filenames.iter() {
-            if let Some(deps) = CONFIG_DEPENDENCIES.get(filename.as_str()) {
-                files_to_reload.extend(deps.clone());
+            match CONFIG_DEPENDENTS.get(filename.as_str()) {
+                Some(deps) => files_to_reload.extend(deps.iter().map(|s| s.to_string())),
+                None => logs.warning(|| format!("Reload requested for unknown file {}", filename)),
             }
         }
         files_to_reload.extend(filenames);
@@ -191,8 +351,9 @@ pub fn reload_config(basepath: &str, filenames: Vec<String>) {
         config.revision = revision;
     }
     if files_to_reload.contains("actions.json") {
+        let templates = load_templates(&mut logs, basepath);
         let rawactions = Config::load_config_file(&mut logs, &bjson, "actions.json");
-        let actions = SimpleAction::resolve_actions(&mut logs, rawactions);
+        let actions = SimpleAction::resolve_actions(&mut logs, &templates, rawactions);
         config.actions = actions;
     }
     if files_to_reload.contains("acl-profiles.json") {
@@ -229,6 +390,7 @@ pub fn reload_config(basepath: &str, filenames: Vec<String>) {
         let (securitypolicies_map, securitypolicies, default) = sec_pol_resolve(
             &mut logs,
             raw_sec_pol,
+            &config.actions,
             &config.limits,
             &config.global_limits,
             &config.inactive_limits,
@@ -244,6 +406,14 @@ pub fn reload_config(basepath: &str, filenames: Vec<String>) {
         let flows = flow_resolve(&mut logs, raw_flows);
         config.flows = flows;
     }
+    if files_to_reload.contains("first-seen.json") {
+        let raw_first_seen = load_first_seen(&mut logs, &bjson);
+        config.first_seen = first_seen_resolve(&mut logs, raw_first_seen);
+    }
+    if files_to_reload.contains("sticky-tags.json") {
+        let raw_sticky_tags = load_sticky_tags(&mut logs, &bjson);
+        config.sticky_tags = sticky_tags_resolve(&mut logs, raw_sticky_tags);
+    }
     if files_to_reload.contains("virtual-tags.json") {
         let raw_virtual_tags = Config::load_config_file(&mut logs, &bjson, "virtual-tags.json");
         let virtual_tags = vtags_resolve(&mut logs, raw_virtual_tags);
@@ -254,6 +424,29 @@ pub fn reload_config(basepath: &str, filenames: Vec<String>) {
         let servergroups_map = Site::resolve(&mut logs, rawsites);
         config.servergroups_map = servergroups_map;
     }
+    if files_to_reload.contains("telemetry.json") {
+        load_telemetry(&mut logs, &bjson);
+    }
+    if files_to_reload.contains("healthcheck.json") {
+        let rawhealthcheck = load_healthcheck(&mut logs, &bjson);
+        config.healthcheck = HealthCheckAllowlist::resolve(&mut logs, rawhealthcheck);
+    }
+    if files_to_reload.contains("embargo.json") {
+        let rawembargo = load_embargo(&mut logs, &bjson);
+        config.embargo = EmbargoPolicy::resolve(&mut logs, &config.actions, rawembargo);
+    }
+    if files_to_reload.contains("plugins.json") {
+        let rawplugins = load_plugin_steps(&mut logs, &bjson);
+        config.plugin_pipeline = PluginStep::resolve(&mut logs, &config.actions, Path::new(basepath), rawplugins);
+    }
+    if files_to_reload.contains("mirrors.json") {
+        let rawmirrors = load_mirrors(&mut logs, &bjson);
+        config.mirrors = mirrors_resolve(&mut logs, rawmirrors);
+    }
+    if files_to_reload.contains("filescanners.json") {
+        let rawfilescanners = load_file_scanners(&mut logs, &bjson);
+        config.file_scanners = FileScanEntry::resolve(&mut logs, &config.actions, rawfilescanners);
+    }
 
     config.logs = logs.clone();
 
@@ -278,10 +471,17 @@ pub struct Config {
     pub default: Option<HostMap>,
     pub container_name: Option<String>,
     pub flows: FlowMap,
+    pub first_seen: Vec<flow::FirstSeen>,
+    pub sticky_tags: Vec<StickyTag>,
     pub content_filter_profiles: HashMap<String, ContentFilterProfile>,
     pub virtual_tags: VirtualTags,
     pub logs: Logs,
     pub servergroups_map: HashMap<String, Site>,
+    pub healthcheck: HealthCheckAllowlist,
+    pub embargo: EmbargoPolicy,
+    pub plugin_pipeline: Vec<PluginStep>,
+    pub mirrors: Vec<MirrorRule>,
+    pub file_scanners: Vec<FileScanEntry>,
 
     // Not used when processing request, but to optimize reloading config
     pub actions: HashMap<String, SimpleAction>,
@@ -306,6 +506,7 @@ impl Config {
         policyname: &str,
         rawmaps: Vec<RawSecurityPolicy>,
         tags: Vec<String>,
+        actions: &HashMap<String, SimpleAction>,
         limits: &HashMap<String, Limit>,
         global_limits: &[Limit],
         inactive_limits: &HashSet<String>,
@@ -313,6 +514,14 @@ impl Config {
         contentfilterprofiles: &HashMap<String, ContentFilterProfile>,
         session: Vec<RequestSelector>,
         session_ids: Vec<RequestSelector>,
+        session_hash: SessionHash,
+        session_seed: Option<String>,
+        session_keep_raw: bool,
+        jwt_keys: Vec<String>,
+        tag_injection: Option<TagInjection>,
+        log_profile: Option<LogProfile>,
+        api_key: Option<ApiKey>,
+        shadow: bool,
     ) -> (Vec<Matching<Arc<SecurityPolicy>>>, Option<Arc<SecurityPolicy>>) {
         let mut default: Option<Arc<SecurityPolicy>> = None;
         let mut entries: Vec<Matching<Arc<SecurityPolicy>>> = Vec::new();
@@ -349,6 +558,9 @@ impl Config {
                     logs.debug(|| format!("Trying to add inactive limit {} in map {}", lid, mapname))
                 }
             }
+            let allowlist = rawmap
+                .allowlist
+                .and_then(|raw| Allowlist::resolve(logs, actions, &mapname, raw));
             let securitypolicy = SecurityPolicy {
                 policy: PolicyId {
                     id: policyid.to_string(),
@@ -361,11 +573,22 @@ impl Config {
                 tags: tags.clone(),
                 session: session.clone(),
                 session_ids: session_ids.clone(),
+                session_hash,
+                session_seed: session_seed.clone(),
+                session_keep_raw,
+                jwt_keys: jwt_keys.clone(),
+                tag_injection: tag_injection.clone(),
+                log_profile: log_profile.clone(),
+                api_key: api_key.clone(),
+                shadow,
                 acl_active: rawmap.acl_active,
                 acl_profile,
                 content_filter_active: rawmap.content_filter_active,
                 content_filter_profile,
                 limits: olimits,
+                allowlist,
+                features: rawmap.features,
+                origin_protection: rawmap.origin_protection.and_then(OriginProtection::resolve),
             };
             if rawmap.match_ == "__default__"
                 || securitypolicy.entry.id == "__default__"
@@ -404,6 +627,14 @@ impl Config {
         rawflows: Vec<RawFlowEntry>,
         rawvirtualtags: Vec<RawVirtualTag>,
         rawsites: Vec<RawSite>,
+        rawhealthcheck: RawHealthCheck,
+        rawfirstseen: Vec<RawFirstSeen>,
+        rawstickytags: Vec<RawStickyTag>,
+        rawembargo: RawEmbargo,
+        rawplugins: Vec<RawPluginStep>,
+        rawmirrors: Vec<RawMirrorRule>,
+        rawfilescanners: Vec<RawFileScanner>,
+        basepath: &str,
     ) -> Config {
         let mut logs = logs;
 
@@ -416,6 +647,7 @@ impl Config {
         let (securitypolicies_map, securitypolicies, default) = sec_pol_resolve(
             &mut logs,
             rawmaps,
+            &actions,
             &limits,
             &global_limits,
             &inactive_limits,
@@ -427,10 +659,24 @@ impl Config {
 
         let flows = flow_resolve(&mut logs, rawflows);
 
+        let first_seen = first_seen_resolve(&mut logs, rawfirstseen);
+
+        let sticky_tags = sticky_tags_resolve(&mut logs, rawstickytags);
+
         let virtual_tags = vtags_resolve(&mut logs, rawvirtualtags);
 
         let servergroups_map = Site::resolve(&mut logs, rawsites);
 
+        let healthcheck = HealthCheckAllowlist::resolve(&mut logs, rawhealthcheck);
+
+        let embargo = EmbargoPolicy::resolve(&mut logs, &actions, rawembargo);
+
+        let plugin_pipeline = PluginStep::resolve(&mut logs, &actions, Path::new(basepath), rawplugins);
+
+        let mirrors = mirrors_resolve(&mut logs, rawmirrors);
+
+        let file_scanners = FileScanEntry::resolve(&mut logs, &actions, rawfilescanners);
+
         Config {
             revision,
             securitypolicies_map,
@@ -439,6 +685,8 @@ impl Config {
             default,
             container_name,
             flows,
+            first_seen,
+            sticky_tags,
             content_filter_profiles,
             logs,
             virtual_tags,
@@ -448,6 +696,11 @@ impl Config {
             inactive_limits,
             acls,
             servergroups_map,
+            healthcheck,
+            embargo,
+            plugin_pipeline,
+            mirrors,
+            file_scanners,
         }
     }
 
@@ -531,7 +784,7 @@ impl Config {
         out
     }
 
-    fn load(mut logs: Logs, basepath: &str) -> Config {
+    pub fn load(mut logs: Logs, basepath: &str) -> Config {
         let mut bjson = PathBuf::from(basepath);
         bjson.push("json");
 
@@ -565,10 +818,19 @@ impl Config {
         let virtualtags = Config::load_config_file(&mut logs, &bjson, "virtual-tags.json");
         // let rawsites: Vec<RawSite> = Config::load_custom_config_file(&mut logs, &bjson, "custom.json");
         let (rawsites,) = Config::load_custom_config_file(&mut logs, &bjson, "custom.json");
+        load_telemetry(&mut logs, &bjson);
+        let rawhealthcheck = load_healthcheck(&mut logs, &bjson);
+        let rawfirstseen = load_first_seen(&mut logs, &bjson);
+        let rawstickytags = load_sticky_tags(&mut logs, &bjson);
+        let rawembargo = load_embargo(&mut logs, &bjson);
+        let rawplugins = load_plugin_steps(&mut logs, &bjson);
+        let rawmirrors = load_mirrors(&mut logs, &bjson);
+        let rawfilescanners = load_file_scanners(&mut logs, &bjson);
 
         let container_name = container_name();
 
-        let actions = SimpleAction::resolve_actions(&mut logs, rawactions);
+        let templates = load_templates(&mut logs, basepath);
+        let actions = SimpleAction::resolve_actions(&mut logs, &templates, rawactions);
         let content_filter_profiles = ContentFilterProfile::resolve(&mut logs, &actions, rawcontentfilterprofiles);
 
         Config::resolve(
@@ -584,6 +846,14 @@ impl Config {
             flows,
             virtualtags,
             rawsites,
+            rawhealthcheck,
+            rawfirstseen,
+            rawstickytags,
+            rawembargo,
+            rawplugins,
+            rawmirrors,
+            rawfilescanners,
+            basepath,
         )
     }
 
@@ -596,6 +866,8 @@ impl Config {
             default: None,
             container_name: container_name(),
             flows: HashMap::new(),
+            first_seen: Vec::new(),
+            sticky_tags: Vec::new(),
             content_filter_profiles: HashMap::new(),
             logs: Logs::default(),
             virtual_tags: Arc::new(HashMap::new()),
@@ -605,10 +877,117 @@ impl Config {
             inactive_limits: HashSet::new(),
             acls: HashMap::new(),
             servergroups_map: HashMap::new(),
+            healthcheck: HealthCheckAllowlist::default(),
+            embargo: EmbargoPolicy::default(),
+            plugin_pipeline: Vec::new(),
+            mirrors: Vec::new(),
+            file_scanners: Vec::new(),
         }
     }
 }
 
+/// loads the telemetry section and swaps the active aggregator configuration
+/// missing entries (or a missing file) fall back to the environment variables
+fn load_telemetry(logs: &mut Logs, bjson: &Path) {
+    let mut path = bjson.to_path_buf();
+    path.push("telemetry.json");
+    let rawtelemetry = if path.is_file() {
+        Config::load_config_file::<RawTelemetry>(logs, bjson, "telemetry.json")
+            .into_iter()
+            .next()
+            .unwrap_or_default()
+    } else {
+        RawTelemetry::default()
+    };
+    crate::interface::aggregator::set_aggregator_config(crate::interface::aggregator::AggregatorConfig::from_raw(
+        &rawtelemetry,
+    ));
+}
+
+/// loads the first seen entries, tolerating a missing file
+fn load_first_seen(logs: &mut Logs, bjson: &Path) -> Vec<RawFirstSeen> {
+    let mut path = bjson.to_path_buf();
+    path.push("first-seen.json");
+    if path.is_file() {
+        Config::load_config_file::<RawFirstSeen>(logs, bjson, "first-seen.json")
+    } else {
+        Vec::new()
+    }
+}
+
+/// loads the request mirroring rules, tolerating a missing file
+fn load_mirrors(logs: &mut Logs, bjson: &Path) -> Vec<RawMirrorRule> {
+    let mut path = bjson.to_path_buf();
+    path.push("mirrors.json");
+    if path.is_file() {
+        Config::load_config_file::<RawMirrorRule>(logs, bjson, "mirrors.json")
+    } else {
+        Vec::new()
+    }
+}
+
+/// loads the file upload scanner entries, tolerating a missing file
+fn load_file_scanners(logs: &mut Logs, bjson: &Path) -> Vec<RawFileScanner> {
+    let mut path = bjson.to_path_buf();
+    path.push("filescanners.json");
+    if path.is_file() {
+        Config::load_config_file::<RawFileScanner>(logs, bjson, "filescanners.json")
+    } else {
+        Vec::new()
+    }
+}
+
+/// loads the plugin pipeline stages, tolerating a missing file
+fn load_plugin_steps(logs: &mut Logs, bjson: &Path) -> Vec<RawPluginStep> {
+    let mut path = bjson.to_path_buf();
+    path.push("plugins.json");
+    if path.is_file() {
+        Config::load_config_file::<RawPluginStep>(logs, bjson, "plugins.json")
+    } else {
+        Vec::new()
+    }
+}
+
+/// loads the sticky tag entries, tolerating a missing file
+fn load_sticky_tags(logs: &mut Logs, bjson: &Path) -> Vec<RawStickyTag> {
+    let mut path = bjson.to_path_buf();
+    path.push("sticky-tags.json");
+    if path.is_file() {
+        Config::load_config_file::<RawStickyTag>(logs, bjson, "sticky-tags.json")
+    } else {
+        Vec::new()
+    }
+}
+
+/// loads the embargo compliance rules, tolerating a missing file
+fn load_embargo(logs: &mut Logs, bjson: &Path) -> RawEmbargo {
+    let mut path = bjson.to_path_buf();
+    path.push("embargo.json");
+    if path.is_file() {
+        Config::load_config_file::<RawEmbargo>(logs, bjson, "embargo.json")
+            .into_iter()
+            .next()
+            .unwrap_or_default()
+    } else {
+        RawEmbargo::default()
+    }
+}
+
+/// loads the health check allowlist overrides, defaulting to the curated list
+/// when the file is absent
+fn load_healthcheck(logs: &mut Logs, bjson: &Path) -> RawHealthCheck {
+    let mut path = bjson.to_path_buf();
+    path.push("healthcheck.json");
+    if path.is_file() {
+        Config::load_config_file::<RawHealthCheck>(logs, bjson, "healthcheck.json")
+            .into_iter()
+            .next()
+            .unwrap_or_default()
+    } else {
+        RawHealthCheck::default()
+    }
+}
+
 pub fn load_hsdb(
     logs: &mut Logs,
     configpath: &Path,
@@ -630,6 +1009,7 @@ pub fn load_hsdb(
 fn sec_pol_resolve(
     logs: &mut Logs,
     rawmaps: Vec<RawHostMap>,
+    actions: &HashMap<String, SimpleAction>,
     limits: &HashMap<String, Limit>,
     global_limits: &[Limit],
     inactive_limits: &HashSet<String>,
@@ -665,12 +1045,14 @@ fn sec_pol_resolve(
             logs.error(|| format!("error when decoding session_ids in {}, {}", &mapname, rr));
             Vec::new()
         });
+        let session_hash = SessionHash::resolve(logs, rawmap.session_hash.as_deref());
         let (entries, default_entry) = Config::resolve_security_policies(
             logs,
             &rawmap.id,
             &rawmap.name,
             rawmap.map,
             rawmap.tags,
+            actions,
             limits,
             global_limits,
             inactive_limits,
@@ -678,6 +1060,14 @@ fn sec_pol_resolve(
             content_filter_profiles,
             session,
             session_ids,
+            session_hash,
+            rawmap.session_seed,
+            rawmap.session_keep_raw,
+            rawmap.jwt_keys,
+            rawmap.tag_injection.and_then(TagInjection::resolve),
+            rawmap.log_profile.and_then(LogProfile::resolve),
+            rawmap.api_key.and_then(ApiKey::resolve),
+            rawmap.shadow,
         );
         if default_entry.is_none() {
             logs.warning(format!("HostMap entry '{}' does not have a default entry", &rawmap.name).as_str());
@@ -708,3 +1098,48 @@ fn sec_pol_resolve(
 
     (securitypolicies_map, securitypolicies, default)
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn config_sections_are_valid() {
+        let dependents = resolve_dependents(&CONFIG_SECTIONS).unwrap();
+        // changing a profile forces the sections that read it to reload
+        let acl = &dependents["acl-profiles.json"];
+        assert!(acl.contains(&"securitypolicy.json"));
+        assert!(acl.contains(&"manifest.json"));
+        // transitive: the manifest depends on securitypolicy.json, which
+        // depends on limits.json
+        assert!(dependents["limits.json"].contains(&"manifest.json"));
+        // standalone sections only refresh the revision
+        assert_eq!(dependents["virtual-tags.json"], vec!["manifest.json"]);
+    }
+
+    #[test]
+    fn config_sections_cycle_is_rejected() {
+        let sections = [
+            ConfigSection {
+                file: "a.json",
+                depends_on: &["b.json"],
+            },
+            ConfigSection {
+                file: "b.json",
+                depends_on: &["a.json"],
+            },
+        ];
+        let rr = resolve_dependents(&sections).unwrap_err();
+        assert!(rr.contains("cycle"), "{}", rr);
+    }
+
+    #[test]
+    fn config_sections_unknown_dependency_is_rejected() {
+        let sections = [ConfigSection {
+            file: "a.json",
+            depends_on: &["missing.json"],
+        }];
+        let rr = resolve_dependents(&sections).unwrap_err();
+        assert!(rr.contains("unknown file"), "{}", rr);
+    }
+}
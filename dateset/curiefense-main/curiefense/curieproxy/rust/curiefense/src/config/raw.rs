@@ -71,6 +71,92 @@ pub struct RawHostMap {
     pub session: Vec<HashMap<String, String>>,
     #[serde(default)]
     pub session_ids: Vec<HashMap<String, String>>,
+    /// hash algorithm for the session values: sha224 (default), sha256 or sha512
+    #[serde(default)]
+    pub session_hash: Option<String>,
+    /// seed for the session hashes, the masking seed of the content filter
+    /// profile when absent
+    #[serde(default)]
+    pub session_seed: Option<String>,
+    /// when true, the raw session value is kept in memory so that limit keys
+    /// can use it; it is never written to the logs
+    #[serde(default)]
+    pub session_keep_raw: bool,
+    /// HS256 secrets used to verify bearer tokens; when empty, tokens are
+    /// parsed but not verified
+    #[serde(default)]
+    pub jwt_keys: Vec<String>,
+    /// trusted proxy tag injection settings, disabled when absent
+    #[serde(default)]
+    pub tag_injection: Option<RawTagInjection>,
+    /// log shaping settings, the full log record is emitted when absent
+    #[serde(default)]
+    pub log_profile: Option<RawLogProfile>,
+    /// API key extraction settings, no api_key identity when absent
+    #[serde(default)]
+    pub api_key: Option<RawApiKey>,
+    /// shadow mode: blocking decisions are downgraded to monitor and the log
+    /// records what would have happened, for staging new rules
+    #[serde(default)]
+    pub shadow: bool,
+}
+
+/// per-policy API key extraction: the listed sources are probed in order
+/// and the first value found becomes the canonical api_key identity, which
+/// is hashed like the session before it reaches the logs
+#[derive(Debug, Deserialize, Serialize, Clone)]
+pub struct RawApiKey {
+    /// header names carrying the key, probed first
+    #[serde(default)]
+    pub headers: Vec<String>,
+    /// query argument names carrying the key
+    #[serde(default)]
+    pub args: Vec<String>,
+    /// cookie names carrying the key
+    #[serde(default)]
+    pub cookies: Vec<String>,
+    /// prefixes stripped from the value before it is used, for example
+    /// "Bearer "
+    #[serde(default)]
+    pub strip_prefixes: Vec<String>,
+}
+
+/// per-policy log shaping: high traffic endpoints can omit heavy fields to
+/// emit slim records, while sensitive endpoints can redact request data
+/// without dropping the record structure
+#[derive(Debug, Deserialize, Serialize, Clone)]
+pub struct RawLogProfile {
+    /// names of log fields that are omitted from the record; only request
+    /// content fields can be omitted, identity and decision fields are
+    /// always kept, and unknown names are ignored
+    #[serde(default)]
+    pub omit: Vec<String>,
+    /// when true, header values are masked in the logs, keeping the names
+    #[serde(default)]
+    pub redact_headers: bool,
+    /// when true, cookie values are masked in the logs, keeping the names
+    #[serde(default)]
+    pub redact_cookies: bool,
+    /// when true, argument values are masked in the logs, keeping the names
+    #[serde(default)]
+    pub redact_arguments: bool,
+}
+
+/// trusted proxy tag injection: pre-computed tags (for example CDN bot
+/// scores) are accepted from a request header when it carries a valid
+/// HMAC-SHA256 signature over the tag list and a timestamp
+#[derive(Debug, Deserialize, Serialize, Clone)]
+pub struct RawTagInjection {
+    pub active: bool,
+    /// name of the header carrying the signed tags
+    #[serde(default)]
+    pub header: Option<String>,
+    /// secret used to verify the header signature
+    #[serde(default)]
+    pub key: Option<String>,
+    /// maximum accepted age of the header timestamp in seconds, default 60
+    #[serde(default)]
+    pub max_age: Option<i64>,
 }
 
 /// a mapping of the configuration file for security policies
@@ -86,6 +172,50 @@ pub struct RawSecurityPolicy {
     pub acl_active: bool,
     pub content_filter_active: bool,
     pub limit_ids: Vec<String>,
+    #[serde(default)]
+    pub allowlist: Option<RawAllowlist>,
+    /// arbitrary feature flags, exposed to templates, selectors and the decision output
+    #[serde(default)]
+    pub features: HashMap<String, String>,
+    /// automatic origin protection settings, disabled when absent
+    #[serde(default)]
+    pub origin_protection: Option<RawOriginProtection>,
+}
+
+/// automatic origin protection: when upstream failures (5xx statuses or
+/// timeouts reported by the proxy) exceed the threshold, enforcement is
+/// tightened for the cooldown period
+#[derive(Debug, Deserialize, Serialize, Clone)]
+pub struct RawOriginProtection {
+    pub active: bool,
+    /// consecutive upstream failures before protection engages, default 10
+    #[serde(default)]
+    pub threshold: Option<usize>,
+    /// seconds during which protection stays engaged, default 60
+    #[serde(default)]
+    pub cooldown: Option<u64>,
+    /// divisor applied to rate limit thresholds while engaged, default 2
+    #[serde(default)]
+    pub limit_divisor: Option<u64>,
+}
+
+/// a positive security allowlist: when active, only requests matching the
+/// allowed paths/methods/content types pass, everything else is blocked
+#[derive(Debug, Deserialize, Serialize, Clone)]
+pub struct RawAllowlist {
+    pub active: bool,
+    /// regexes on the request path, empty means all paths are allowed
+    #[serde(default)]
+    pub paths: Vec<String>,
+    /// allowed methods, empty means all methods are allowed
+    #[serde(default)]
+    pub methods: Vec<String>,
+    /// allowed content type prefixes, empty means all content types are allowed
+    #[serde(default)]
+    pub content_types: Vec<String>,
+    /// action id, the default action is used when absent
+    #[serde(default)]
+    pub action: Option<String>,
 }
 
 /** a mapping of elements in the custom document **/
@@ -107,7 +237,7 @@ pub struct RawSite {
 
 // Add other necessary structs for the remaining objects in the JSON file
 
-#[derive(Debug, Deserialize, Clone, Copy, PartialEq, Eq)]
+#[derive(Debug, Deserialize, Serialize, Clone, Copy, PartialEq, Eq)]
 #[serde(rename_all = "UPPERCASE")]
 pub enum Relation {
     And,
@@ -220,6 +350,51 @@ pub struct RawLimit {
     pub active: bool,
     #[serde(default)]
     pub tags: Vec<String>,
+    /// extra namespace inserted in the redis key, for deployments sharing a redis server
+    #[serde(default)]
+    pub key_prefix: Option<String>,
+    /// percentage of the timeframe that is randomly added to the key TTL
+    #[serde(default)]
+    pub ttl_jitter: Option<Repru64>,
+    /// when non empty, the counter is only incremented when the embedder reports
+    /// an upstream response status from this list (ie. 401/403 for failed logins)
+    #[serde(default)]
+    pub count_status: Vec<u32>,
+    /// structured applicability condition, a richer alternative to the
+    /// include/exclude tag lists
+    #[serde(default)]
+    pub condition: Option<RawLimitCondition>,
+    /// counting window: "rolling" (the default), "minute", "hour" or "day";
+    /// calendar windows are aligned on clock boundaries instead of starting
+    /// at the first hit
+    #[serde(default)]
+    pub window: Option<String>,
+    /// minutes east of UTC used to align calendar windows, default 0
+    #[serde(default)]
+    pub window_offset: Option<i32>,
+}
+
+#[derive(Debug, Deserialize, Serialize, Clone)]
+#[serde(untagged)]
+pub enum RawLimitCondition {
+    Rel(RawLimitConditionRelation),
+    Entry(RawLimitConditionEntry),
+}
+
+#[derive(Debug, Deserialize, Serialize, Clone)]
+pub struct RawLimitConditionRelation {
+    pub relation: Relation,
+    pub entries: Vec<RawLimitCondition>,
+}
+
+#[derive(Debug, Deserialize, Serialize, Clone)]
+pub struct RawLimitConditionEntry {
+    /// selectors with their conditions, as in `pairwith` entries
+    /// ({"attrs": {"tags": "internal"}} or {"attrs": {"path": "/api/.*"}}),
+    /// several selectors in a single entry are ANDed together
+    pub select: RawLimitSelector,
+    #[serde(default)]
+    pub negated: bool,
 }
 
 #[derive(Debug, Deserialize, Serialize, Clone)]
@@ -259,6 +434,7 @@ pub enum RawActionType {
     Custom,
     Challenge,
     Ichallenge,
+    Ban,
 }
 
 impl RawActionType {
@@ -283,6 +459,75 @@ pub struct RawActionParams {
     #[serde(default)]
     pub headers: Option<HashMap<String, String>>,
     pub content: Option<String>,
+    /// overrides the status code when the request carries one of these tags
+    #[serde(default)]
+    pub status_by_tag: HashMap<String, u32>,
+    /// id of an HTML file from the templates directory of the configuration,
+    /// used as the block page instead of the inline content
+    #[serde(default)]
+    pub template: Option<String>,
+    /// ban duration in seconds, for ban actions
+    #[serde(default)]
+    pub duration: Option<u64>,
+}
+
+/// one stage of the plugin pipeline, in pipeline order
+#[derive(Debug, Deserialize, Serialize, Clone)]
+pub struct RawPluginStep {
+    pub id: String,
+    #[serde(default)]
+    pub name: String,
+    /// when true, a missing, failed or overrunning plugin triggers the failure action
+    #[serde(default)]
+    pub required: bool,
+    /// time budget in microseconds, checked against the duration reported by the plugin
+    #[serde(default)]
+    pub timeout_us: Option<u64>,
+    /// id of the action applied when a required plugin did not deliver
+    #[serde(default)]
+    pub failure_action: Option<String>,
+    /// file name of a WASM module from the wasm directory of the configuration,
+    /// executed by the embedded runtime instead of an embedder callout
+    #[serde(default)]
+    pub module: Option<String>,
+    /// fuel budget for a single execution of the WASM module
+    #[serde(default)]
+    pub fuel: Option<u64>,
+}
+
+#[derive(Debug, Deserialize, Serialize, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum RawFileScannerKind {
+    /// rejects uploads based on the file name extension
+    Extension,
+    /// rejects uploads based on the sniffed mime type
+    Mime,
+    /// submits uploads to an ICAP server
+    Icap,
+    /// pipes uploads to an external command
+    Command,
+}
+
+/// a file upload scanner, defined in filescanners.json
+#[derive(Debug, Deserialize, Serialize, Clone)]
+pub struct RawFileScanner {
+    pub id: String,
+    #[serde(default)]
+    pub name: String,
+    pub active: bool,
+    pub kind: RawFileScannerKind,
+    /// denied extensions or sniffed mime types, for the extension and mime kinds
+    #[serde(default)]
+    pub denied: Vec<String>,
+    /// icap://host:port/service URL, for the icap kind
+    #[serde(default)]
+    pub url: Option<String>,
+    /// command line, for the command kind
+    #[serde(default)]
+    pub command: Vec<String>,
+    /// id of the action applied when an upload is rejected
+    #[serde(default)]
+    pub action: Option<String>,
 }
 
 #[derive(Debug, Deserialize, Serialize, Clone)]
@@ -395,6 +640,13 @@ pub struct RawContentFilterProfile {
     pub allsections: RawContentFilterProperties,
     #[serde(default)]
     pub decoding: ContentFilterDecoding,
+    /// how colliding keys are merged, the historical space-separated merge
+    /// when absent
+    #[serde(default)]
+    pub collision_policy: Option<RawCollisionPolicy>,
+    /// separator used by the merge collision policy, a single space when absent
+    #[serde(default)]
+    pub merge_separator: Option<String>,
     #[serde(default)]
     pub active: Vec<String>,
     #[serde(default)]
@@ -403,18 +655,100 @@ pub struct RawContentFilterProfile {
     pub report: Vec<String>,
     pub masking_seed: String,
     #[serde(default)]
+    pub masking_seeds: Vec<RawMaskingSeed>,
+    #[serde(default)]
     pub content_type: Vec<ContentType>,
     #[serde(default)]
     pub ignore_body: bool,
     pub max_body_size: Option<usize>,
+    #[serde(default)]
+    pub body_too_large: BodyTooLarge,
     pub max_body_depth: Option<usize>,
     #[serde(default)]
+    pub max_uri_length: Option<usize>,
+    #[serde(default)]
+    pub max_uri_args: Option<usize>,
+    #[serde(default)]
+    pub mask_aggregates: bool,
+    #[serde(default)]
+    pub header_validation: HeaderValidation,
+    #[serde(default)]
     pub referer_as_uri: bool,
     pub action: Option<String>,
     #[serde(default)]
     pub tags: Vec<String>,
     #[serde(default)]
     pub graphql_path: String,
+    /// maximal amount of fields in a graphql document, 0 or absent means unlimited
+    #[serde(default)]
+    pub graphql_max_fields: Option<usize>,
+    /// maximal amount of aliases in a graphql document, 0 or absent means unlimited
+    #[serde(default)]
+    pub graphql_max_aliases: Option<usize>,
+    /// maximal amount of batched graphql queries, 0 or absent means unlimited
+    #[serde(default)]
+    pub graphql_max_batch: Option<usize>,
+    /// when true, graphql introspection queries are rejected
+    #[serde(default)]
+    pub graphql_block_introspection: bool,
+    #[serde(default)]
+    pub anomaly_threshold: Option<u32>,
+}
+
+/// how colliding request field keys are handled, the historical
+/// space-separated merge when absent
+#[derive(Debug, Deserialize, Serialize, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "kebab-case")]
+pub enum RawCollisionPolicy {
+    /// only the first value is kept
+    KeepFirst,
+    /// each new value replaces the previous one
+    KeepLast,
+    /// values are concatenated with the configured separator
+    Merge,
+    /// each extra value gets its own `name:<index>` entry
+    IndexSuffix,
+}
+
+/// strict RFC 7230 header name/value validation mode, off by default as
+/// upstream frameworks differ in what they tolerate
+#[derive(Debug, Deserialize, Serialize, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum HeaderValidation {
+    Off,
+    Monitor,
+    Block,
+}
+
+impl Default for HeaderValidation {
+    fn default() -> Self {
+        HeaderValidation::Off
+    }
+}
+
+/// behavior when the request body exceeds max_body_size: block applies the
+/// profile action, truncate keeps the first max_body_size bytes, inspects
+/// that prefix and tags the request `body-truncated`, which is more useful
+/// for large uploads where the interesting payload is in the first bytes
+#[derive(Debug, Deserialize, Serialize, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum BodyTooLarge {
+    Block,
+    Truncate,
+}
+
+impl Default for BodyTooLarge {
+    fn default() -> Self {
+        BodyTooLarge::Block
+    }
+}
+
+/// an identified masking seed, the first entry of the list is the seed used for masking,
+/// the other entries are kept during rotation overlap windows
+#[derive(Debug, Deserialize, Serialize, Clone)]
+pub struct RawMaskingSeed {
+    pub id: String,
+    pub seed: String,
 }
 
 #[derive(Debug, Deserialize, Serialize, Clone)]
@@ -435,6 +769,16 @@ impl Default for MaxLength {
     }
 }
 
+/// per section toggle for the libinjection checks, enabled by default
+#[derive(Debug, Deserialize, Serialize, Clone)]
+pub struct LibInjection(pub bool);
+
+impl Default for LibInjection {
+    fn default() -> Self {
+        LibInjection(true)
+    }
+}
+
 #[derive(Debug, Deserialize, Serialize, Clone, Default)]
 pub struct RawContentFilterProperties {
     pub names: Vec<RawContentFilterEntryMatch>,
@@ -443,6 +787,15 @@ pub struct RawContentFilterProperties {
     pub max_count: MaxCount,
     #[serde(default)]
     pub max_length: MaxLength,
+    #[serde(default)]
+    pub libinjection: LibInjection,
+    /// ascii case-insensitive matching of the names in this section
+    #[serde(default)]
+    pub case_insensitive: bool,
+    /// unicode-aware lowercasing of the names in this section, implies case
+    /// insensitivity
+    #[serde(default)]
+    pub unicode_folding: bool,
 }
 
 #[derive(Debug, Deserialize, Serialize, Clone, Copy, PartialEq, Eq)]
@@ -501,6 +854,12 @@ pub struct RawFlowEntry {
     pub timeframe: u64,
     pub tags: Vec<String>,
     pub sequence: Vec<RawFlowStep>,
+    /// extra namespace inserted in the redis key, for deployments sharing a redis server
+    #[serde(default)]
+    pub key_prefix: Option<String>,
+    /// percentage of the timeframe that is randomly added to the key TTL
+    #[serde(default)]
+    pub ttl_jitter: Option<u64>,
 }
 
 #[derive(Debug, Deserialize, Serialize, Clone)]
@@ -539,3 +898,128 @@ pub struct RawMetaManifest {
     pub id: String,
     pub version: String,
 }
+
+/// telemetry section, tuning the aggregation engine
+#[derive(Debug, Deserialize, Serialize, Clone, Default)]
+pub struct RawTelemetry {
+    #[serde(default)]
+    pub samples_kept: Option<i64>,
+    #[serde(default)]
+    pub sample_duration: Option<i64>,
+    #[serde(default)]
+    pub top_amount: Option<usize>,
+    #[serde(default)]
+    pub hyperloglog_size: Option<usize>,
+    /// when true, zero-valued samples are emitted for every known security
+    /// policy entry, so that "no traffic" can be told apart from "no data"
+    #[serde(default)]
+    pub heartbeat: Option<bool>,
+    /// maximum number of distinct branches tracked by the aggregator, less
+    /// active branches are folded into the `other` pseudo branch (0 disables
+    /// the cap)
+    #[serde(default)]
+    pub max_branches: Option<usize>,
+    /// per branch overrides for the number of samples kept
+    #[serde(default)]
+    pub branch_samples_kept: Option<HashMap<String, i64>>,
+}
+
+/// compliance section, listing embargoed countries and regions. It lives in
+/// its own configuration file so that legal teams can manage it independently
+/// of the security rules.
+#[derive(Debug, Deserialize, Serialize, Clone, Default)]
+pub struct RawEmbargo {
+    #[serde(default)]
+    pub active: bool,
+    /// version of the embargo rule set, recorded in every audit entry
+    #[serde(default)]
+    pub version: Option<String>,
+    /// embargoed country ISO codes
+    #[serde(default)]
+    pub countries: Vec<String>,
+    /// embargoed region names
+    #[serde(default)]
+    pub regions: Vec<String>,
+    /// action id, the default action is used when absent
+    #[serde(default)]
+    pub action: Option<String>,
+}
+
+/// health check allowlist, overriding the curated defaults
+#[derive(Debug, Deserialize, Serialize, Clone, Default)]
+pub struct RawHealthCheck {
+    /// user agent prefixes, replacing the built-in list when present
+    #[serde(default)]
+    pub agents: Option<Vec<String>>,
+    /// source addresses or CIDR ranges
+    #[serde(default)]
+    pub ranges: Vec<String>,
+}
+
+fn default_first_seen_tags() -> Vec<String> {
+    vec!["new-visitor".to_string()]
+}
+
+/// sticky tag entries: when a request triggers one of the watched tags, the tag
+/// is remembered for the client key and re-applied to its subsequent requests
+#[derive(Debug, Deserialize, Serialize, Clone)]
+pub struct RawStickyTag {
+    pub id: String,
+    pub name: String,
+    pub active: bool,
+    /// tags that are remembered when triggered
+    #[serde(default)]
+    pub tags: Vec<String>,
+    /// how long the tags are remembered, in seconds
+    pub timeframe: u64,
+    /// how the client is identified, defaults to the source ip
+    #[serde(default)]
+    pub key: Vec<HashMap<String, String>>,
+    /// extra namespace inserted in the redis key, for deployments sharing a redis server
+    #[serde(default)]
+    pub key_prefix: Option<String>,
+    /// percentage of the timeframe that is randomly added to the key TTL
+    #[serde(default)]
+    pub ttl_jitter: Option<u64>,
+}
+
+/// request mirroring rules: requests matching the watched tags are annotated
+/// with a mirror target that the embedder can act on
+#[derive(Debug, Deserialize, Serialize, Clone)]
+pub struct RawMirrorRule {
+    pub id: String,
+    pub name: String,
+    pub active: bool,
+    /// tags that select the requests to mirror
+    #[serde(default)]
+    pub tags: Vec<String>,
+    /// where the mirrored copy should be sent
+    pub target: String,
+    /// fraction of the matching requests that are mirrored, defaults to all of them
+    #[serde(default)]
+    pub sample: Option<f64>,
+}
+
+/// first seen entries: tag requests whose key was never seen within the timeframe
+#[derive(Debug, Deserialize, Serialize, Clone)]
+pub struct RawFirstSeen {
+    pub id: String,
+    pub name: String,
+    #[serde(default)]
+    pub include: Vec<String>,
+    #[serde(default)]
+    pub exclude: Vec<String>,
+    #[serde(default)]
+    pub key: Vec<HashMap<String, String>>,
+    pub active: bool,
+    pub timeframe: u64,
+    /// tags added when the key is new
+    #[serde(default = "default_first_seen_tags")]
+    pub tags: Vec<String>,
+    /// extra namespace inserted in the redis key, for deployments sharing a redis server
+    #[serde(default)]
+    pub key_prefix: Option<String>,
+    /// percentage of the timeframe that is randomly added to the key TTL
+    #[serde(default)]
+    pub ttl_jitter: Option<u64>,
+}
@@ -0,0 +1,60 @@
+use std::collections::HashSet;
+
+use crate::config::matchers::RequestSelector;
+use crate::config::raw::RawStickyTag;
+use crate::logs::Logs;
+
+/// a sticky tag entry: when a request triggers one of the watched tags, the
+/// tag is remembered for the client key in redis and re-applied to its
+/// subsequent requests until the TTL expires
+#[derive(Debug, Clone)]
+pub struct StickyTag {
+    pub id: String,
+    pub name: String,
+    /// tags that are remembered when triggered
+    pub tags: HashSet<String>,
+    /// how the client is identified, defaults to the source ip
+    pub key: Vec<RequestSelector>,
+    /// how long the tags are remembered, in seconds
+    pub timeframe: u64,
+    /// extra namespace inserted in the redis key
+    pub key_prefix: Option<String>,
+    /// percentage of the timeframe that is randomly added to the key TTL
+    pub ttl_jitter: u64,
+}
+
+pub fn sticky_tags_resolve(logs: &mut Logs, rawentries: Vec<RawStickyTag>) -> Vec<StickyTag> {
+    let mut out = Vec::new();
+    for rawentry in rawentries {
+        if !rawentry.active {
+            continue;
+        }
+        let id = rawentry.id;
+        if rawentry.tags.is_empty() {
+            logs.warning(|| format!("sticky tag entry {}: no tags to remember", id));
+            continue;
+        }
+        let mkey: anyhow::Result<Vec<RequestSelector>> = rawentry
+            .key
+            .into_iter()
+            .map(RequestSelector::resolve_selector_map)
+            .collect();
+        match mkey {
+            Err(rr) => logs.warning(|| format!("sticky tag entry {}: {}", id, rr)),
+            Ok(key) => out.push(StickyTag {
+                id,
+                name: rawentry.name,
+                tags: rawentry.tags.into_iter().collect(),
+                key: if key.is_empty() {
+                    vec![RequestSelector::Ip]
+                } else {
+                    key
+                },
+                timeframe: rawentry.timeframe,
+                key_prefix: rawentry.key_prefix,
+                ttl_jitter: rawentry.ttl_jitter.unwrap_or(0),
+            }),
+        }
+    }
+    out
+}
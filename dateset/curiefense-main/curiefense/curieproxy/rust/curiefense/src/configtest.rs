@@ -0,0 +1,172 @@
+/// configuration test runner ("policy unit tests")
+///
+/// A `tests.json` file in the configuration bundle describes request fixtures
+/// and their expected outcomes (decision, tags present or absent), so that
+/// configuration changes can ship with their own tests. The runner replays
+/// each fixture against the loaded configuration and reports the assertions
+/// that failed.
+use serde::Deserialize;
+use std::collections::HashMap;
+
+use crate::grasshopper::testing::TestGrasshopper;
+use crate::interface::BlockReason;
+use crate::logs::Logs;
+use crate::utils::{RawRequest, RequestMeta};
+
+#[derive(Debug, Deserialize)]
+pub struct ConfigTest {
+    pub name: String,
+    #[serde(default)]
+    pub request: TestRequest,
+    #[serde(default)]
+    pub expect: TestExpect,
+}
+
+/// the request fixture, all fields are optional and default to a plain GET /
+#[derive(Debug, Deserialize)]
+pub struct TestRequest {
+    #[serde(default = "default_ip")]
+    pub ip: String,
+    #[serde(default = "default_method")]
+    pub method: String,
+    #[serde(default = "default_path")]
+    pub path: String,
+    #[serde(default)]
+    pub authority: Option<String>,
+    #[serde(default)]
+    pub headers: HashMap<String, String>,
+    #[serde(default)]
+    pub body: Option<String>,
+}
+
+fn default_ip() -> String {
+    "203.0.113.1".to_string()
+}
+
+fn default_method() -> String {
+    "GET".to_string()
+}
+
+fn default_path() -> String {
+    "/".to_string()
+}
+
+impl Default for TestRequest {
+    fn default() -> Self {
+        TestRequest {
+            ip: default_ip(),
+            method: default_method(),
+            path: default_path(),
+            authority: None,
+            headers: HashMap::new(),
+            body: None,
+        }
+    }
+}
+
+#[derive(Debug, Deserialize, Default)]
+pub struct TestExpect {
+    /// expected decision, either "pass" or "block"
+    #[serde(default)]
+    pub decision: Option<String>,
+    /// tags that must be present on the request
+    #[serde(default)]
+    pub tags: Vec<String>,
+    /// tags that must not be present on the request
+    #[serde(default)]
+    pub no_tags: Vec<String>,
+}
+
+/// a failed assertion, with the test name and a description of the mismatch
+#[derive(Debug)]
+pub struct TestFailure {
+    pub test: String,
+    pub problem: String,
+}
+
+/// loads the test fixtures from the `tests.json` file of a configuration
+/// bundle, tolerating a missing file
+pub fn load_tests(logs: &mut Logs, basepath: &str) -> Vec<ConfigTest> {
+    let mut path = std::path::PathBuf::from(basepath);
+    path.push("json");
+    path.push("tests.json");
+    if !path.is_file() {
+        return Vec::new();
+    }
+    let loaded = std::fs::File::open(&path)
+        .map_err(|rr| rr.to_string())
+        .and_then(|f| serde_json::from_reader(std::io::BufReader::new(f)).map_err(|rr| rr.to_string()));
+    match loaded {
+        Ok(tests) => tests,
+        Err(rr) => {
+            logs.error(|| format!("when loading {}: {}", path.display(), rr));
+            Vec::new()
+        }
+    }
+}
+
+/// runs the test fixtures against the currently loaded configuration,
+/// returning the assertions that failed
+pub fn run_tests(logs: &mut Logs, tests: &[ConfigTest]) -> Vec<TestFailure> {
+    let mut failures = Vec::new();
+    for test in tests {
+        let body = test.request.body.as_ref().map(|b| b.as_bytes().to_vec());
+        let raw = RawRequest {
+            ipstr: test.request.ip.clone(),
+            headers: test
+                .request
+                .headers
+                .iter()
+                .map(|(k, v)| (k.to_lowercase(), v.clone()))
+                .collect(),
+            meta: RequestMeta {
+                authority: test.request.authority.clone(),
+                method: test.request.method.clone(),
+                path: test.request.path.clone(),
+                requestid: Some(format!("configtest-{}", test.name)),
+                protocol: None,
+                client_cert_subject: None,
+                client_cert_san: None,
+                client_cert_fingerprint: None,
+                extra: HashMap::new(),
+            },
+            mbody: body.as_deref(),
+        };
+        let result = crate::inspect_generic_request_map(None::<&TestGrasshopper>, raw, logs, None, None, HashMap::new());
+        if let Some(expected) = &test.expect.decision {
+            let blocking = result.decision.is_blocking();
+            let problem = match expected.as_str() {
+                "pass" if blocking => Some(format!(
+                    "expected pass, got block ({})",
+                    BlockReason::block_reason_desc(&result.decision.reasons).unwrap_or_else(|| "no reason".to_string())
+                )),
+                "block" if !blocking => Some("expected block, got pass".to_string()),
+                "pass" | "block" => None,
+                other => Some(format!("unknown expected decision {:?}, use pass or block", other)),
+            };
+            if let Some(problem) = problem {
+                failures.push(TestFailure {
+                    test: test.name.clone(),
+                    problem,
+                });
+            }
+        }
+        for tag in &test.expect.tags {
+            if !result.tags.contains(tag) {
+                failures.push(TestFailure {
+                    test: test.name.clone(),
+                    problem: format!("expected tag {} is missing", tag),
+                });
+            }
+        }
+        for tag in &test.expect.no_tags {
+            if result.tags.contains(tag) {
+                failures.push(TestFailure {
+                    test: test.name.clone(),
+                    problem: format!("unexpected tag {} is present", tag),
+                });
+            }
+        }
+    }
+    failures
+}
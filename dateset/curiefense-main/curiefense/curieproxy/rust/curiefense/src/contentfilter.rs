@@ -1,15 +1,17 @@
-use hyperscan::Matching;
+use hyperscan::{Matching, Scratch};
 use lazy_static::lazy_static;
 use libinjection::{sqli, xss};
+use rayon::prelude::*;
 use std::collections::{HashMap, HashSet};
+use std::sync::atomic::{AtomicU64, Ordering};
 
 use crate::config::contentfilter::{
-    rule_tags, ContentFilterEntryMatch, ContentFilterProfile, ContentFilterRules, ContentFilterSection, Section,
-    SectionIdx, ALL_SECTION_IDX, ALL_SECTION_IDX_NO_PLUGINS,
+    rule_tags, ContentFilterEntryMatch, ContentFilterProfile, ContentFilterRules, ContentFilterSection, MaskingSeed,
+    Section, SectionIdx, ALL_SECTION_IDX, ALL_SECTION_IDX_NO_PLUGINS,
 };
-use crate::config::raw::RawActionType;
-use crate::interface::stats::{BStageAcl, BStageContentFilter, StatsCollect};
-use crate::interface::{BlockReason, Initiator, Location, Tags};
+use crate::config::raw::{HeaderValidation, RawActionType};
+use crate::interface::stats::{BStageAcl, BStageContentFilter, StatsCollect, STAGE_BUDGETS};
+use crate::interface::{sets_intersect, BlockReason, Initiator, Location, Tags};
 use crate::requestfields::RequestField;
 use crate::utils::{masker, RequestInfo};
 use crate::Logs;
@@ -34,6 +36,29 @@ lazy_static! {
     .map(|s| s.to_string())
     .collect();
     pub static ref LIBINJECTION_RULES_LEN: usize = LIBINJECTION_SQLI_TAGS.len() + LIBINJECTION_XSS_TAGS.len();
+    /// number of scanned values above which the per value hyperscan and libinjection
+    /// checks run in parallel, settable through the CF_PARALLEL_SCAN environment variable
+    pub static ref PARALLEL_SCAN: usize = std::env::var("CF_PARALLEL_SCAN")
+        .map(|s| s.parse().unwrap_or(1000))
+        .unwrap_or(1000);
+}
+
+/// hyperscan databases are immutable and documented as safe for concurrent use,
+/// only the scratch spaces must not be shared between threads
+struct SharedRules<'t>(&'t ContentFilterRules);
+unsafe impl Sync for SharedRules<'_> {}
+
+/// prefilter effectiveness counters: amount of requests that went through the
+/// literal prefilter, and amount of requests where the full scan was skipped
+static PREFILTER_CHECKED: AtomicU64 = AtomicU64::new(0);
+static PREFILTER_SKIPPED: AtomicU64 = AtomicU64::new(0);
+
+/// returns the (checked, skipped) prefilter counters, for effectiveness reporting
+pub fn prefilter_stats() -> (u64, u64) {
+    (
+        PREFILTER_CHECKED.load(Ordering::Relaxed),
+        PREFILTER_SKIPPED.load(Ordering::Relaxed),
+    )
 }
 
 #[derive(Default)]
@@ -53,6 +78,28 @@ fn get_section(idx: SectionIdx, rinfo: &RequestInfo) -> &RequestField {
     }
 }
 
+/// checks a header name and value for RFC 7230 conformance, returning a
+/// description of the first violation found
+fn header_violation(name: &str, value: &str) -> Option<String> {
+    // token characters, as per RFC 7230 section 3.2.6
+    fn is_tchar(c: char) -> bool {
+        c.is_ascii_alphanumeric() || "!#$%&'*+-.^_`|~".contains(c)
+    }
+    if name.is_empty() {
+        return Some("empty header name".to_string());
+    }
+    if let Some(c) = name.chars().find(|c| !is_tchar(*c)) {
+        return Some(format!("illegal character {:?} in header name", c));
+    }
+    if value.contains('\r') || value.contains('\n') {
+        return Some("obs-fold or bare CR/LF in header value".to_string());
+    }
+    if let Some(c) = value.chars().find(|c| c.is_ascii_control() && *c != '\t') {
+        return Some(format!("control character {:?} in header value", c));
+    }
+    None
+}
+
 fn is_blocking(reasons: &[BlockReason]) -> bool {
     reasons.iter().any(|r| r.action >= RawActionType::Custom)
 }
@@ -81,6 +128,32 @@ pub fn content_filter_check(
         return (Ok(()), stats.no_content_filter());
     }
 
+    // strict RFC 7230 header validation, when enabled by the profile
+    if profile.header_validation != HeaderValidation::Off {
+        for (name, value) in rinfo.headers.iter() {
+            if let Some(violation) = header_violation(name, value) {
+                let location = Location::from_name(SectionIdx::Headers, name);
+                if profile.header_validation == HeaderValidation::Block {
+                    return (
+                        Err(CfBlock {
+                            blocking: true,
+                            reasons: vec![BlockReason::restricted(
+                                profile.id.clone(),
+                                profile.name.clone(),
+                                profile.action.atype.to_raw(),
+                                location,
+                                violation,
+                                "RFC 7230 header".to_string(),
+                            )],
+                        }),
+                        stats.no_content_filter(),
+                    );
+                }
+                tags.insert_qualified("invalid-header", name, location);
+            }
+        }
+    }
+
     // check section profiles
     for idx in &ALL_SECTION_IDX {
         if let Err(reason) = section_check(
@@ -106,10 +179,10 @@ pub fn content_filter_check(
     }
 
     let kept = profile.active.union(&profile.report).cloned().collect::<HashSet<_>>();
-    let test_xss = LIBINJECTION_XSS_TAGS.intersection(&profile.ignore).next().is_none()
-        && LIBINJECTION_XSS_TAGS.intersection(&kept).next().is_some();
-    let test_sqli = LIBINJECTION_SQLI_TAGS.intersection(&profile.ignore).next().is_none()
-        && LIBINJECTION_SQLI_TAGS.intersection(&kept).next().is_some();
+    let test_xss =
+        !sets_intersect(&LIBINJECTION_XSS_TAGS, &profile.ignore) && sets_intersect(&LIBINJECTION_XSS_TAGS, &kept);
+    let test_sqli =
+        !sets_intersect(&LIBINJECTION_SQLI_TAGS, &profile.ignore) && sets_intersect(&LIBINJECTION_SQLI_TAGS, &kept);
 
     let mut hca_keys: HashMap<String, (SectionIdx, String)> = HashMap::new();
 
@@ -129,6 +202,7 @@ pub fn content_filter_check(
             &profile.id,
             &profile.name,
             profile.action.atype.to_raw(),
+            &profile.sections,
             tags,
             &hca_keys,
             &omit,
@@ -147,6 +221,19 @@ pub fn content_filter_check(
         );
     }
 
+    // hyperscan is the expensive part of this stage: when the time budget is
+    // already exceeded, record the overrun and optionally skip the scan
+    let mut stats = stats;
+    if let Some(budget) = STAGE_BUDGETS.content_filter {
+        if stats.stage_elapsed() > budget {
+            stats.overrun("content_filter");
+            logs.warning("content filter stage time budget exceeded");
+            if STAGE_BUDGETS.enforce {
+                return (Ok(()), stats.no_content_filter());
+            }
+        }
+    }
+
     let mut specific_tags = tags.new_with_vtags();
 
     // finally, hyperscan check
@@ -270,8 +357,9 @@ fn section_check(
             Ok(())
         };
 
-        // check name rules
-        if let Some(entry) = section.names.get(name) {
+        // check name rules, folding the name the same way the keys were
+        // folded at resolution time
+        if let Some(entry) = section.names.get(section.folding.fold(name).as_ref()) {
             check_entry(entry)?;
             // if an argument was matched by exact check, we do not try to match it against regex rules
             continue;
@@ -290,57 +378,103 @@ fn section_check(
     Ok(())
 }
 
+/// a libinjection match on a single value, collected before the tags are
+/// updated so that the scanning loop itself can run in parallel
+enum InjectionHit {
+    Sqli(Location, String),
+    Xss(Location),
+}
+
+fn injection_check_value(
+    sections: &Section<ContentFilterSection>,
+    omit: &Omitted,
+    test_xss: bool,
+    test_sqli: bool,
+    value: &str,
+    idx: SectionIdx,
+    name: &str,
+) -> Vec<InjectionHit> {
+    let mut out = Vec::new();
+    // per-section toggle, for teams with known benign patterns in a section
+    if !sections.get(idx).libinjection {
+        return out;
+    }
+    let omit_tags = omit.exclusions.get(idx).get(name);
+    let rtest_xss = test_xss
+        && !omit_tags
+            .map(|tgs| sets_intersect(&LIBINJECTION_XSS_TAGS, tgs))
+            .unwrap_or(false);
+    let rtest_sqli = test_sqli
+        && !omit_tags
+            .map(|tgs| sets_intersect(&LIBINJECTION_SQLI_TAGS, tgs))
+            .unwrap_or(false);
+    if rtest_sqli {
+        if let Some((true, fp)) = sqli(value) {
+            out.push(InjectionHit::Sqli(Location::from_value(idx, name, value), fp));
+        }
+    }
+    if rtest_xss {
+        if xss(value) == Some(true) {
+            out.push(InjectionHit::Xss(Location::from_value(idx, name, value)));
+        }
+    }
+    out
+}
+
 /// TODO: This also populates the hca_keys map
 /// this is stupid and needs to be changed
+#[allow(clippy::too_many_arguments)]
 fn injection_check(
     cfid: &str,
     cfname: &str,
     action: RawActionType,
+    sections: &Section<ContentFilterSection>,
     tags: &mut Tags,
     hca_keys: &HashMap<String, (SectionIdx, String)>,
     omit: &Omitted,
     test_xss: bool,
     test_sqli: bool,
 ) -> Vec<BlockReason> {
+    let mut entries: Vec<(&String, &(SectionIdx, String))> = hca_keys.iter().collect();
+    // sorted so that the parallel and sequential paths produce reasons in the same order
+    entries.sort_unstable_by(|a, b| a.0.cmp(b.0));
+    let hits: Vec<InjectionHit> = if entries.len() >= *PARALLEL_SCAN {
+        entries
+            .par_iter()
+            .flat_map_iter(|(value, (idx, name))| {
+                injection_check_value(sections, omit, test_xss, test_sqli, value, *idx, name)
+            })
+            .collect()
+    } else {
+        entries
+            .iter()
+            .flat_map(|(value, (idx, name))| {
+                injection_check_value(sections, omit, test_xss, test_sqli, value, *idx, name)
+            })
+            .collect()
+    };
     let mut out = Vec::new();
-    for (value, (idx, name)) in hca_keys.iter() {
-        let omit_tags = omit.exclusions.get(*idx).get(name);
-        let rtest_xss = test_xss
-            && !omit_tags
-                .map(|tgs| LIBINJECTION_XSS_TAGS.intersection(tgs).next().is_some())
-                .unwrap_or(false);
-        let rtest_sqli = test_sqli
-            && !omit_tags
-                .map(|tgs| LIBINJECTION_SQLI_TAGS.intersection(tgs).next().is_some())
-                .unwrap_or(false);
-        if rtest_sqli {
-            if let Some((b, fp)) = sqli(value) {
-                if b {
-                    let locs = Location::from_value(*idx, name, value);
-                    tags.insert_qualified("cf-rule-id", "libinjection-sqli", locs.clone());
-                    tags.insert_qualified("cf-rule-category", "libinjection", locs.clone());
-                    tags.insert_qualified("cf-rule-subcategory", "libinjection-sqli", locs.clone());
-                    tags.insert_qualified("cf-rule-risk", "libinjection", locs.clone());
-                    out.push(BlockReason::sqli(
-                        cfid.to_string(),
-                        cfname.to_string(),
-                        action,
-                        locs,
-                        fp,
-                    ));
-                }
+    for hit in hits {
+        match hit {
+            InjectionHit::Sqli(locs, fp) => {
+                tags.insert_qualified("cf-rule-id", "libinjection-sqli", locs.clone());
+                tags.insert_qualified("cf-rule-category", "libinjection", locs.clone());
+                tags.insert_qualified("cf-rule-subcategory", "libinjection-sqli", locs.clone());
+                tags.insert_qualified("cf-rule-risk", "libinjection", locs.clone());
+                out.push(BlockReason::sqli(
+                    cfid.to_string(),
+                    cfname.to_string(),
+                    action,
+                    locs,
+                    fp,
+                ));
             }
-        }
-        if rtest_xss {
-            if let Some(b) = xss(value) {
-                if b {
-                    let locs = Location::from_value(*idx, name, value);
-                    tags.insert_qualified("cf-rule-id", "libinjection-xss", locs.clone());
-                    tags.insert_qualified("cf-rule-category", "libinjection", locs.clone());
-                    tags.insert_qualified("cf-rule-subcategory", "libinjection-xss", locs.clone());
-                    tags.insert_qualified("cf-rule-risk", "libinjection", locs.clone());
-                    out.push(BlockReason::xss(cfid.to_string(), cfname.to_string(), action, locs));
-                }
+            InjectionHit::Xss(locs) => {
+                tags.insert_qualified("cf-rule-id", "libinjection-xss", locs.clone());
+                tags.insert_qualified("cf-rule-category", "libinjection", locs.clone());
+                tags.insert_qualified("cf-rule-subcategory", "libinjection-xss", locs.clone());
+                tags.insert_qualified("cf-rule-risk", "libinjection", locs.clone());
+                out.push(BlockReason::xss(cfid.to_string(), cfname.to_string(), action, locs));
             }
         }
     }
@@ -365,6 +499,31 @@ fn hyperscan(
     };
     // TODO: use `intersperse` when this stabilizes
     let to_scan = hca_keys.keys().cloned().collect::<Vec<_>>().join("\n");
+
+    // optional literal prefilter: every rule match must contain one of the
+    // literals, so when none occurs in the request the full scan is skipped
+    if let Some(prefilter) = &sigs.prefilter {
+        let pfscratch = match prefilter.alloc_scratch() {
+            Err(rr) => return (Err(rr), stats.no_content_filter()),
+            Ok(s) => s,
+        };
+        PREFILTER_CHECKED.fetch_add(1, Ordering::Relaxed);
+        let mut literal_found = false;
+        #[allow(clippy::needless_borrow)]
+        if let Err(rr) = prefilter.scan(&[to_scan.clone()], &pfscratch, |_, _, _, _| {
+            literal_found = true;
+            Matching::Continue
+        }) {
+            return (Err(rr), stats.no_content_filter());
+        }
+        if !literal_found {
+            PREFILTER_SKIPPED.fetch_add(1, Ordering::Relaxed);
+            logs.debug("content filter prefilter: no rule literal present, scan skipped");
+            return (Ok(Vec::new()), stats.cf_no_match(sigs.ids.len()));
+        }
+        logs.debug("content filter prefilter: rule literal present");
+    }
+
     let mut found = false;
     #[allow(clippy::needless_borrow)]
     if let Err(rr) = sigs.db.scan(&[to_scan], &scratch, |_, _, _, _| {
@@ -384,10 +543,43 @@ fn hyperscan(
     let mut matches = 0;
     let mut nactive = 0;
     // something matched! but what?
-    for (k, (sid, name)) in hca_keys {
-        // for some reason, from is always set to 0 in my tests, so we can't accurately capture substrings
-        #[allow(clippy::needless_borrow)]
-        let scanr = sigs.db.scan(&[k.as_bytes()], &scratch, |id, from, to, _flags| {
+    let mut entries: Vec<(String, SectionIdx, String)> =
+        hca_keys.into_iter().map(|(k, (sid, name))| (k, sid, name)).collect();
+    // sorted so that the parallel and sequential paths produce reasons in the same order
+    entries.sort_unstable_by(|a, b| a.0.cmp(&b.0));
+    let scanresults: Vec<anyhow::Result<Vec<(u32, u64, u64)>>> = if entries.len() >= *PARALLEL_SCAN {
+        let shared = SharedRules(sigs);
+        entries
+            .par_chunks(64)
+            .flat_map_iter(|chunk| match shared.0.db.alloc_scratch() {
+                Err(rr) => {
+                    let errs: Vec<anyhow::Result<Vec<(u32, u64, u64)>>> = chunk
+                        .iter()
+                        .map(|_| Err(anyhow::anyhow!("could not allocate hyperscan scratch: {}", rr)))
+                        .collect();
+                    errs
+                }
+                Ok(cscratch) => chunk.iter().map(|(k, _, _)| scan_value(shared.0, &cscratch, k)).collect(),
+            })
+            .collect()
+    } else {
+        entries.iter().map(|(k, _, _)| scan_value(sigs, &scratch, k)).collect()
+    };
+    for ((k, sid, name), scanr) in entries.iter().zip(scanresults) {
+        let hits = match scanr {
+            Err(rr) => {
+                return (
+                    Err(rr),
+                    stats.cf_matches(
+                        sigs.ids.len() + *LIBINJECTION_RULES_LEN,
+                        matches,
+                        nactive + *LIBINJECTION_RULES_LEN,
+                    ),
+                )
+            }
+            Ok(hits) => hits,
+        };
+        for (id, from, to) in hits {
             match sigs.ids.get(id as usize) {
                 None => logs.error(|| format!("Should not happen, invalid hyperscan index {}", id)),
                 Some(sig) => {
@@ -398,15 +590,15 @@ fn hyperscan(
                     let (new_specific_tags, new_tags) = rule_tags(sig);
                     if (new_tags.has_intersection(global_kept) || new_specific_tags.has_intersection(global_kept))
                         && exclusions
-                            .get(sid)
-                            .get(&name)
+                            .get(*sid)
+                            .get(name)
                             .map(|ex| new_tags.has_intersection(ex) || new_specific_tags.has_intersection(ex))
                             != Some(true)
                         && !new_tags.has_intersection(&profile.ignore)
                         && !new_specific_tags.has_intersection(&profile.ignore)
                     {
                         matches += 1;
-                        let location = Location::from_value(sid, &name, &k);
+                        let location = Location::from_value(*sid, name, k);
                         tags.merge(tags.new_with_vtags().with_raw_tags(new_tags, &location));
                         specific_tags.merge(tags.new_with_vtags().with_raw_tags(new_specific_tags, &location));
                         let decision = if specific_tags.has_intersection(&profile.active) {
@@ -424,19 +616,27 @@ fn hyperscan(
                     }
                 }
             }
-            Matching::Continue
-        });
-        if let Err(rr) = scanr {
-            return (
-                Err(rr),
-                stats.cf_matches(
-                    sigs.ids.len() + *LIBINJECTION_RULES_LEN,
-                    matches,
-                    nactive + *LIBINJECTION_RULES_LEN,
-                ),
-            );
         }
     }
+    // anomaly scoring mode: each matched rule contributes its risk level once,
+    // and the per rule decisions are replaced by a threshold check on the total
+    if let Some(threshold) = profile.anomaly_threshold {
+        let mut per_rule: HashMap<&str, u8> = HashMap::new();
+        for (sigid, _, _, risk) in &founds {
+            per_rule.insert(sigid, *risk);
+        }
+        let score: u32 = per_rule.values().map(|r| *r as u32).sum();
+        tags.insert_qualified("cf-anomaly-score", &score.to_string(), Location::Request);
+        let decision = if score >= threshold {
+            RawActionType::Custom
+        } else {
+            RawActionType::Monitor
+        };
+        founds = founds
+            .into_iter()
+            .map(|(sigid, location, _, risk)| (sigid, location, decision, risk))
+            .collect();
+    }
     (
         Ok(founds
             .into_iter()
@@ -461,11 +661,27 @@ fn hyperscan(
     )
 }
 
-fn mask_section(masking_seed: &[u8], sec: &mut RequestField, section: &ContentFilterSection) -> HashSet<Location> {
+/// scans a single value, returning the list of matches as (id, from, to) triples
+fn scan_value(sigs: &ContentFilterRules, scratch: &Scratch, k: &str) -> anyhow::Result<Vec<(u32, u64, u64)>> {
+    let mut hits = Vec::new();
+    // for some reason, from is always set to 0 in my tests, so we can't accurately capture substrings
+    #[allow(clippy::needless_borrow)]
+    sigs.db.scan(&[k.as_bytes()], scratch, |id, from, to, _flags| {
+        hits.push((id, from, to));
+        Matching::Continue
+    })?;
+    Ok(hits)
+}
+
+fn mask_section(
+    masking_seed: &MaskingSeed,
+    sec: &mut RequestField,
+    section: &ContentFilterSection,
+) -> HashSet<Location> {
     let to_mask: Vec<String> = sec
         .iter()
         .filter(|&(name, _)| {
-            if let Some(e) = section.names.get(name) {
+            if let Some(e) = section.names.get(section.folding.fold(name).as_ref()) {
                 e.mask
             } else {
                 section.regex.iter().any(|(re, e)| e.mask && re.is_match(name))
@@ -534,6 +750,7 @@ mod test {
     use std::sync::Arc;
 
     use super::*;
+    use crate::config::contentfilter::NameFolding;
     use crate::config::custom::Site;
     use crate::config::hostmap::SecurityPolicy;
     use crate::config::virtualtags::VirtualTags;
@@ -547,6 +764,9 @@ mod test {
             authority: Some("myhost".to_string()),
             method: "GET".to_string(),
             path: "/foo?arg1=avalue1&arg2=a%20value2".to_string(),
+            client_cert_subject: None,
+            client_cert_san: None,
+            client_cert_fingerprint: None,
             extra: HashMap::default(),
             requestid: None,
             protocol: None,
@@ -715,6 +935,39 @@ mod test {
         );
     }
 
+    #[test]
+    fn masking_named_arg_case_insensitive() {
+        let mut profile = ContentFilterProfile::default_from_seed("test");
+        let mut section = profile.sections.at(SectionIdx::Args).clone();
+        section.folding = NameFolding::CaseInsensitive;
+        section.names = ["arg1"].iter().map(|k| (k.to_string(), maskentry())).collect();
+        let mut args = RequestField::raw_create(
+            &[],
+            &[(
+                "ARG1",
+                &Location::UriArgumentValue("ARG1".to_string(), "avalue1".to_string()),
+                "avalue1",
+            )],
+        );
+        mask_section(&profile.masking_seed, &mut args, &section);
+        assert_eq!(args.get_str("ARG1"), Some("MASKED{e8efcceb}"));
+    }
+
+    #[test]
+    fn masking_with_seed_id() {
+        let mut profile = ContentFilterProfile::default_from_seed("test");
+        profile.masking_seed.id = Some("2024-01".to_string());
+        profile.decoding = Vec::new();
+        let asection = profile.sections.at(SectionIdx::Args);
+        asection.names = ["arg1"].iter().map(|k| (k.to_string(), maskentry())).collect();
+        let rinfo = test_request_info(profile);
+        let masked = masking(rinfo);
+        assert_eq!(
+            masked.rinfo.qinfo.args.get_str("arg1"),
+            Some("MASKED{2024-01:e8efcceb}")
+        );
+    }
+
     #[test]
     fn masking_all_args_names() {
         let mut profile = ContentFilterProfile::default_from_seed("test");
@@ -752,6 +1005,9 @@ mod test {
             method: "GET".to_string(),
             protocol: None,
             path: "/foo/pth/ddd?arg1=SECRETa1&arg2=U0VDUkVUYTI%3D".to_string(),
+            client_cert_subject: None,
+            client_cert_san: None,
+            client_cert_fingerprint: None,
             extra: HashMap::default(),
             requestid: None,
         };
@@ -0,0 +1,253 @@
+/* pluggable key/value backend for limits and flows
+
+   The limit and flow stages only need a small set of counter operations:
+   incrementing a windowed counter, reading it, and tracking set
+   cardinality for paired limits. The CounterStore trait captures those
+   operations as a batch, so that backends can pipeline them; the backend
+   is selected with the COUNTER_BACKEND environment variable ("redis", the
+   default, or "memcached").
+
+   The TTL is part of the creating operations instead of a separate expire
+   step, so that backends without TTL introspection (memcached) can be
+   supported.
+*/
+
+use anyhow::Result;
+use futures::future::BoxFuture;
+use lazy_static::lazy_static;
+use std::collections::{HashMap, HashSet};
+
+use crate::redis::redis_async_conn;
+
+#[derive(Debug, Clone)]
+pub enum CounterQuery {
+    /// increments a counter, setting the TTL when the key is created,
+    /// and returns the new value
+    Incr { key: String, ttl: u64 },
+    /// reads a counter without incrementing it
+    Read { key: String },
+    /// adds a member to a set, setting the TTL when the key is created,
+    /// and returns the set cardinality
+    AddMember { key: String, member: String, ttl: u64 },
+    /// reads the set cardinality without adding a member
+    MemberCount { key: String },
+}
+
+impl CounterQuery {
+    /// key and TTL for the queries that create keys
+    fn creates(&self) -> Option<(&str, u64)> {
+        match self {
+            CounterQuery::Incr { key, ttl } => Some((key, *ttl)),
+            CounterQuery::AddMember { key, ttl, .. } => Some((key, *ttl)),
+            CounterQuery::Read { .. } | CounterQuery::MemberCount { .. } => None,
+        }
+    }
+}
+
+pub trait CounterStore: Send {
+    /// runs a batch of queries, returning one value per query
+    fn run<'t>(&'t mut self, queries: Vec<CounterQuery>) -> BoxFuture<'t, Result<Vec<i64>>>;
+}
+
+lazy_static! {
+    static ref COUNTER_BACKEND: String = std::env::var("COUNTER_BACKEND").unwrap_or_else(|_| "redis".to_string());
+    static ref MEMCACHED: Result<memcache::Client> = {
+        let server = std::env::var("MEMCACHED_HOST").unwrap_or_else(|_| "memcached".to_string());
+        let port = std::env::var("MEMCACHED_PORT").unwrap_or_else(|_| "11211".to_string());
+        memcache::Client::connect(format!("memcache://{}:{}?timeout=5", server, port)).map_err(anyhow::Error::from)
+    };
+}
+
+/// returns the configured counter store
+pub async fn counter_store() -> Result<Box<dyn CounterStore>> {
+    match COUNTER_BACKEND.as_str() {
+        "redis" => Ok(Box::new(RedisStore {
+            conn: redis_async_conn().await?,
+        })),
+        "memcached" => match &*MEMCACHED {
+            Ok(client) => Ok(Box::new(MemcachedStore { client: client.clone() })),
+            Err(rr) => Err(anyhow::anyhow!("{}", rr)),
+        },
+        other => Err(anyhow::anyhow!("Unknown COUNTER_BACKEND {}", other)),
+    }
+}
+
+pub struct RedisStore {
+    conn: redis::aio::ConnectionManager,
+}
+
+impl CounterStore for RedisStore {
+    fn run<'t>(&'t mut self, queries: Vec<CounterQuery>) -> BoxFuture<'t, Result<Vec<i64>>> {
+        Box::pin(async move {
+            if queries.is_empty() {
+                return Ok(Vec::new());
+            }
+            let mut pipe = redis::pipe();
+            for q in &queries {
+                match q {
+                    CounterQuery::Incr { key, .. } => {
+                        pipe.cmd("INCR").arg(key).cmd("TTL").arg(key);
+                    }
+                    CounterQuery::Read { key } => {
+                        pipe.cmd("GET").arg(key).cmd("TTL").arg(key);
+                    }
+                    CounterQuery::AddMember { key, member, .. } => {
+                        pipe.cmd("SADD")
+                            .arg(key)
+                            .arg(member)
+                            .ignore()
+                            .cmd("SCARD")
+                            .arg(key)
+                            .cmd("TTL")
+                            .arg(key);
+                    }
+                    CounterQuery::MemberCount { key } => {
+                        pipe.cmd("SCARD").arg(key).cmd("TTL").arg(key);
+                    }
+                }
+            }
+            let raw: Vec<Option<i64>> = pipe.query_async(&mut self.conn).await?;
+            let mut iter = raw.into_iter();
+            let mut out = Vec::with_capacity(queries.len());
+            let mut epipe = redis::pipe();
+            let mut has_expires = false;
+            for q in &queries {
+                let value = iter.next().flatten().unwrap_or(0);
+                let ttl = iter.next().flatten().unwrap_or(-1);
+                if ttl < 0 {
+                    if let Some((key, qttl)) = q.creates() {
+                        epipe.cmd("EXPIRE").arg(key).arg(qttl).ignore();
+                        has_expires = true;
+                    }
+                }
+                out.push(value);
+            }
+            if has_expires {
+                epipe.query_async::<_, ()>(&mut self.conn).await?;
+            }
+            Ok(out)
+        })
+    }
+}
+
+/* memcached has no sets, so cardinality is emulated with one flag key per
+   member ("key@member") and a counter that is only incremented when the
+   flag key did not exist yet */
+pub struct MemcachedStore {
+    client: memcache::Client,
+}
+
+impl MemcachedStore {
+    fn incr(&self, key: &str, ttl: u64) -> Result<i64> {
+        match self.client.increment(key, 1) {
+            Ok(v) => Ok(v as i64),
+            // the key does not exist yet, create it with its TTL; on a
+            // creation race, fall back to incrementing the winner's key
+            Err(_) => match self.client.add(key, 1u64, ttl as u32) {
+                Ok(()) => Ok(1),
+                Err(_) => Ok(self.client.increment(key, 1)? as i64),
+            },
+        }
+    }
+
+    fn read(&self, key: &str) -> Result<i64> {
+        Ok(self.client.get::<u64>(key)?.unwrap_or(0) as i64)
+    }
+}
+
+impl CounterStore for MemcachedStore {
+    fn run<'t>(&'t mut self, queries: Vec<CounterQuery>) -> BoxFuture<'t, Result<Vec<i64>>> {
+        Box::pin(async move {
+            let mut out = Vec::with_capacity(queries.len());
+            for q in &queries {
+                let value = match q {
+                    CounterQuery::Incr { key, ttl } => self.incr(key, *ttl)?,
+                    CounterQuery::Read { key } => self.read(key)?,
+                    CounterQuery::AddMember { key, member, ttl } => {
+                        let flag = format!("{}@{}", key, member);
+                        if self.client.add(&flag, 1u64, *ttl as u32).is_ok() {
+                            self.incr(key, *ttl)?
+                        } else {
+                            self.read(key)?
+                        }
+                    }
+                    CounterQuery::MemberCount { key } => self.read(key)?,
+                };
+                out.push(value);
+            }
+            Ok(out)
+        })
+    }
+}
+
+/// simple in-process store, used by the unit tests to exercise the limit and
+/// flow logic without a live backend (TTLs are ignored)
+#[derive(Default)]
+pub struct MemoryStore {
+    counters: HashMap<String, i64>,
+    members: HashMap<String, HashSet<String>>,
+}
+
+impl CounterStore for MemoryStore {
+    fn run<'t>(&'t mut self, queries: Vec<CounterQuery>) -> BoxFuture<'t, Result<Vec<i64>>> {
+        Box::pin(async move {
+            let mut out = Vec::with_capacity(queries.len());
+            for q in &queries {
+                let value = match q {
+                    CounterQuery::Incr { key, .. } => {
+                        let c = self.counters.entry(key.clone()).or_insert(0);
+                        *c += 1;
+                        *c
+                    }
+                    CounterQuery::Read { key } => self.counters.get(key).copied().unwrap_or(0),
+                    CounterQuery::AddMember { key, member, .. } => {
+                        let s = self.members.entry(key.clone()).or_default();
+                        s.insert(member.clone());
+                        s.len() as i64
+                    }
+                    CounterQuery::MemberCount { key } => self.members.get(key).map(|s| s.len()).unwrap_or(0) as i64,
+                };
+                out.push(value);
+            }
+            Ok(out)
+        })
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn memory_store_counters() {
+        let mut store = MemoryStore::default();
+        let incr = |store: &mut MemoryStore| {
+            async_std::task::block_on(store.run(vec![CounterQuery::Incr {
+                key: "k".to_string(),
+                ttl: 60,
+            }]))
+            .unwrap()
+        };
+        assert_eq!(incr(&mut store), vec![1]);
+        assert_eq!(incr(&mut store), vec![2]);
+        let read =
+            async_std::task::block_on(store.run(vec![CounterQuery::Read { key: "k".to_string() }])).unwrap();
+        assert_eq!(read, vec![2]);
+    }
+
+    #[test]
+    fn memory_store_members() {
+        let mut store = MemoryStore::default();
+        let add = |store: &mut MemoryStore, member: &str| {
+            async_std::task::block_on(store.run(vec![CounterQuery::AddMember {
+                key: "s".to_string(),
+                member: member.to_string(),
+                ttl: 60,
+            }]))
+            .unwrap()
+        };
+        assert_eq!(add(&mut store, "a"), vec![1]);
+        assert_eq!(add(&mut store, "b"), vec![2]);
+        assert_eq!(add(&mut store, "a"), vec![2]);
+    }
+}
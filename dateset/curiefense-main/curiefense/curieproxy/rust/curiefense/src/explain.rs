@@ -0,0 +1,162 @@
+/* Per-request decision explanation
+
+   `inspect_request_explain` runs a normal inspection and returns, next to
+   the usual result, a structured JSON document describing how the decision
+   was reached: every global filter with its match outcome, the ACL stages
+   with the tags that fed them, every rate limit with its counter key and
+   whether it applied, the content filter triggers, and the per-stage
+   timings. Global filters and ACL stages are re-evaluated against the
+   final tag set, which is cheap and avoids threading a recorder through
+   the analysis.
+
+   This is a debugging aid for rule authors, not something to enable on the
+   hot path: it takes an extra pass over the configuration.
+*/
+
+use serde_json::json;
+use std::collections::{HashMap, HashSet};
+
+use crate::acl::check_acl;
+use crate::config::with_config;
+use crate::grasshopper::Grasshopper;
+use crate::inspect_generic_request_map_async;
+use crate::interface::{AnalyzeResult, Initiator, Tags};
+use crate::limit::{example_key, limit_applies};
+use crate::logs::Logs;
+use crate::tagging::globalfilter_matched;
+use crate::utils::RawRequest;
+
+/// runs an inspection and explains the outcome
+pub async fn inspect_request_explain<GH: Grasshopper>(
+    mgh: Option<&GH>,
+    raw: RawRequest<'_>,
+    logs: &mut Logs,
+    selected_secpol: Option<&str>,
+    selected_sergrp: Option<&str>,
+    plugins: HashMap<String, String>,
+) -> (AnalyzeResult, serde_json::Value) {
+    let result = inspect_generic_request_map_async(mgh, raw, logs, selected_secpol, selected_sergrp, plugins).await;
+    let explanation = explain(logs, &result);
+    (result, explanation)
+}
+
+fn sorted_tags(tags: &Tags) -> Vec<&str> {
+    let mut out: Vec<&str> = tags.as_hash_ref().keys().map(|s| s.as_str()).collect();
+    out.sort_unstable();
+    out
+}
+
+fn acl_stage(tags: &Tags, name: &str, configured: &HashSet<String>) -> serde_json::Value {
+    let mut cfg: Vec<&str> = configured.iter().map(|s| s.as_str()).collect();
+    cfg.sort_unstable();
+    json!({
+        "stage": name,
+        "configured": cfg,
+        "matching": sorted_tags(&tags.intersect_tags(configured)),
+    })
+}
+
+/// builds the explanation for an already analyzed request
+pub fn explain(logs: &mut Logs, result: &AnalyzeResult) -> serde_json::Value {
+    let rinfo = &result.rinfo;
+    let tags = &result.tags;
+    let secpolicy = &rinfo.rinfo.secpolicy;
+
+    // global filters, re-evaluated against the final tag set
+    let globalfilters = with_config(logs, |_, cfg| cfg.globalfilters.clone()).unwrap_or_default();
+    let gf_explain: Vec<serde_json::Value> = globalfilters
+        .iter()
+        .map(|section| {
+            json!({
+                "id": &section.id,
+                "name": &section.name,
+                "matched": globalfilter_matched(rinfo, tags, section),
+                "has_action": section.action.is_some(),
+            })
+        })
+        .collect();
+
+    let acl = &secpolicy.acl_profile;
+    let acl_explain = json!({
+        "active": secpolicy.acl_active,
+        "id": &acl.id,
+        "name": &acl.name,
+        "matched": check_acl(tags, acl).has_matched(),
+        "stages": [
+            acl_stage(tags, "force_deny", &acl.force_deny),
+            acl_stage(tags, "passthrough", &acl.passthrough),
+            acl_stage(tags, "deny_bot", &acl.deny_bot),
+            acl_stage(tags, "allow_bot", &acl.allow_bot),
+            acl_stage(tags, "deny", &acl.deny),
+            acl_stage(tags, "allow", &acl.allow),
+        ],
+    });
+
+    let limits_explain: Vec<serde_json::Value> = secpolicy
+        .limits
+        .iter()
+        .map(|limit| {
+            let triggered = result
+                .decision
+                .reasons
+                .iter()
+                .any(|r| matches!(r.initiator, Initiator::Limit { .. }) && r.id == limit.id);
+            json!({
+                "id": &limit.id,
+                "name": &limit.name,
+                "timeframe": limit.timeframe,
+                "applies": limit_applies(rinfo, tags, limit),
+                "deferred": !limit.count_status.is_empty(),
+                "key": example_key(rinfo, tags, limit),
+                "triggered": triggered,
+            })
+        })
+        .collect();
+
+    // the signatures that matched are the only ones that leave a trace, the
+    // full list of signatures tested is not recorded
+    let cf_triggers: Vec<serde_json::Value> = result
+        .decision
+        .reasons
+        .iter()
+        .filter_map(|r| match &r.initiator {
+            Initiator::ContentFilter { ruleid, risk_level } => Some(json!({
+                "ruleid": ruleid,
+                "risk_level": risk_level,
+                "location": &r.location,
+            })),
+            _ => None,
+        })
+        .collect();
+    let cf = &secpolicy.content_filter_profile;
+    let cf_explain = json!({
+        "active": secpolicy.content_filter_active,
+        "id": &cf.id,
+        "name": &cf.name,
+        "triggers": cf_triggers,
+    });
+
+    let timings: serde_json::Map<String, serde_json::Value> = result
+        .stats
+        .timing
+        .stages()
+        .into_iter()
+        .map(|(name, stamp)| (name.to_string(), json!(stamp)))
+        .collect();
+
+    json!({
+        "decision": {
+            "blocked": result.decision.blocked(),
+            "reasons": &result.decision.reasons,
+            "annotations": &result.decision.annotations,
+        },
+        "secpolid": &secpolicy.policy.id,
+        "secpolentryid": &secpolicy.entry.id,
+        "tags": sorted_tags(tags),
+        "global_filters": gf_explain,
+        "acl": acl_explain,
+        "limits": limits_explain,
+        "content_filter": cf_explain,
+        "timings_us": timings,
+    })
+}
@@ -0,0 +1,467 @@
+/* file upload scanning
+
+   Uploaded files are the multipart/form-data parts that carry a file name.
+   They are extracted when the request is mapped and checked against the
+   scanners from filescanners.json before the ACL and content filter stages.
+   A scanner is anything implementing the FileScanner trait: the built-in
+   implementations cover extension deny lists, mime sniffing, ICAP servers
+   (clamav through c-icap for example) and external commands.
+*/
+
+use std::collections::{HashMap, HashSet};
+use std::io::{Read, Write};
+use std::net::TcpStream;
+use std::process::{Command, Stdio};
+use std::time::Duration;
+
+use multipart::server::Multipart;
+
+use crate::config::raw::{RawFileScanner, RawFileScannerKind};
+use crate::interface::{BlockReason, Location, SimpleAction};
+use crate::logs::Logs;
+
+/// a file extracted from a multipart/form-data body
+#[derive(Debug, Clone)]
+pub struct UploadedFile {
+    /// name of the form field carrying the file
+    pub field: String,
+    /// file name declared by the client
+    pub filename: String,
+    /// content type declared by the client
+    pub content_type: Option<String>,
+    pub content: Vec<u8>,
+}
+
+/// extracts the uploaded files from a multipart/form-data body, returning an
+/// empty vector for other content types or unparsable bodies
+pub fn extract_uploaded_files(mcontent_type: Option<&str>, body: &[u8]) -> Vec<UploadedFile> {
+    let boundary = match mcontent_type.and_then(|ct| ct.strip_prefix("multipart/form-data; boundary=")) {
+        None => return Vec::new(),
+        Some(b) => b,
+    };
+    let mut out = Vec::new();
+    let mut multipart = Multipart::with_body(body, boundary);
+    let _ = multipart.foreach_entry(|mut entry| {
+        if let Some(filename) = entry.headers.filename.clone() {
+            let mut content = Vec::new();
+            let _ = entry.data.read_to_end(&mut content);
+            out.push(UploadedFile {
+                field: entry.headers.name.to_string(),
+                filename,
+                content_type: entry.headers.content_type.as_ref().map(|ct| ct.to_string()),
+                content,
+            });
+        }
+    });
+    out
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ScanVerdict {
+    Clean,
+    /// the upload must be rejected, with the reason
+    Rejected(String),
+}
+
+/// a pluggable upload scanner
+///
+/// errors are treated as an unavailable scanner: they are logged and the
+/// upload passes, so that a broken AV backend does not take the service down
+pub trait FileScanner {
+    fn scan(&self, file: &UploadedFile) -> Result<ScanVerdict, String>;
+}
+
+/// rejects uploads whose file name extension is denied
+#[derive(Debug, Clone)]
+pub struct ExtensionPolicy {
+    /// denied extensions, lowercase, without the leading dot
+    pub denied: HashSet<String>,
+}
+
+impl FileScanner for ExtensionPolicy {
+    fn scan(&self, file: &UploadedFile) -> Result<ScanVerdict, String> {
+        let extension = file.filename.rsplit('.').next().unwrap_or_default().to_lowercase();
+        if extension != file.filename.to_lowercase() && self.denied.contains(&extension) {
+            Ok(ScanVerdict::Rejected(format!("denied extension {}", extension)))
+        } else {
+            Ok(ScanVerdict::Clean)
+        }
+    }
+}
+
+/// sniffs the mime type from well known magic bytes, ignoring the content
+/// type declared by the client
+pub fn sniff_mime(content: &[u8]) -> Option<&'static str> {
+    let sig = |prefix: &[u8]| content.starts_with(prefix);
+    if sig(b"\x7fELF") {
+        Some("application/x-elf")
+    } else if sig(b"MZ") {
+        Some("application/x-msdownload")
+    } else if sig(b"%PDF") {
+        Some("application/pdf")
+    } else if sig(b"PK\x03\x04") {
+        Some("application/zip")
+    } else if sig(b"\x1f\x8b") {
+        Some("application/gzip")
+    } else if sig(b"\x89PNG") {
+        Some("image/png")
+    } else if sig(b"\xff\xd8\xff") {
+        Some("image/jpeg")
+    } else if sig(b"GIF8") {
+        Some("image/gif")
+    } else if sig(b"#!") {
+        Some("text/x-shellscript")
+    } else {
+        None
+    }
+}
+
+/// rejects uploads whose sniffed mime type is denied, whatever the client
+/// declared
+#[derive(Debug, Clone)]
+pub struct MimeSniffPolicy {
+    pub denied: HashSet<String>,
+}
+
+impl FileScanner for MimeSniffPolicy {
+    fn scan(&self, file: &UploadedFile) -> Result<ScanVerdict, String> {
+        match sniff_mime(&file.content) {
+            Some(mime) if self.denied.contains(mime) => Ok(ScanVerdict::Rejected(format!("denied mime type {}", mime))),
+            _ => Ok(ScanVerdict::Clean),
+        }
+    }
+}
+
+/// pipes uploads to an external command, following the AV scanner convention
+/// of exit code 0 for clean and 1 for infected
+#[derive(Debug, Clone)]
+pub struct CommandScanner {
+    pub program: String,
+    pub args: Vec<String>,
+}
+
+impl FileScanner for CommandScanner {
+    fn scan(&self, file: &UploadedFile) -> Result<ScanVerdict, String> {
+        let mut child = Command::new(&self.program)
+            .args(&self.args)
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped())
+            .spawn()
+            .map_err(|rr| format!("could not spawn {}: {}", self.program, rr))?;
+        if let Some(mut stdin) = child.stdin.take() {
+            // the command may exit without draining its input, this is not an error
+            let _ = stdin.write_all(&file.content);
+        }
+        let output = child
+            .wait_with_output()
+            .map_err(|rr| format!("could not wait for {}: {}", self.program, rr))?;
+        match output.status.code() {
+            Some(0) => Ok(ScanVerdict::Clean),
+            Some(1) => {
+                let stdout = String::from_utf8_lossy(&output.stdout);
+                let verdict = stdout.lines().next().unwrap_or("command verdict").to_string();
+                Ok(ScanVerdict::Rejected(verdict))
+            }
+            status => Err(format!(
+                "{} exited with {:?}: {}",
+                self.program,
+                status,
+                String::from_utf8_lossy(&output.stderr)
+            )),
+        }
+    }
+}
+
+/// submits uploads to an ICAP server in REQMOD mode: a 204 answer passes the
+/// upload, a 200 answer rejects it
+#[derive(Debug, Clone)]
+pub struct IcapScanner {
+    /// icap://host:port/service URL
+    pub url: String,
+}
+
+impl FileScanner for IcapScanner {
+    fn scan(&self, file: &UploadedFile) -> Result<ScanVerdict, String> {
+        let rest = self
+            .url
+            .strip_prefix("icap://")
+            .ok_or_else(|| format!("invalid ICAP url {}", self.url))?;
+        let (hostport, service) = match rest.find('/') {
+            Some(idx) => (&rest[..idx], &rest[idx..]),
+            None => (rest, "/"),
+        };
+        let mut stream = TcpStream::connect(hostport).map_err(|rr| format!("{}: {}", hostport, rr))?;
+        stream
+            .set_read_timeout(Some(Duration::from_secs(5)))
+            .map_err(|rr| rr.to_string())?;
+        let http = format!(
+            "POST /upload HTTP/1.1\r\nHost: {}\r\nContent-Length: {}\r\n\r\n",
+            hostport,
+            file.content.len()
+        );
+        let icap = format!(
+            "REQMOD icap://{}{} ICAP/1.0\r\nHost: {}\r\nAllow: 204\r\nEncapsulated: req-hdr=0, req-body={}\r\n\r\n",
+            hostport,
+            service,
+            hostport,
+            http.len()
+        );
+        let send = |stream: &mut TcpStream, data: &[u8]| stream.write_all(data).map_err(|rr| rr.to_string());
+        send(&mut stream, icap.as_bytes())?;
+        send(&mut stream, http.as_bytes())?;
+        send(&mut stream, format!("{:x}\r\n", file.content.len()).as_bytes())?;
+        send(&mut stream, &file.content)?;
+        send(&mut stream, b"\r\n0\r\n\r\n")?;
+        let mut response = Vec::new();
+        let _ = stream.read_to_end(&mut response);
+        let response = String::from_utf8_lossy(&response);
+        let status: u32 = response
+            .split_whitespace()
+            .nth(1)
+            .and_then(|code| code.parse().ok())
+            .ok_or_else(|| format!("unparsable ICAP answer: {:?}", response.lines().next()))?;
+        match status {
+            204 => Ok(ScanVerdict::Clean),
+            200 => {
+                let infection = response
+                    .lines()
+                    .find_map(|l| l.strip_prefix("X-Infection-Found:").or_else(|| l.strip_prefix("X-Violations-Found:")))
+                    .map(|v| v.trim().to_string())
+                    .unwrap_or_else(|| "icap status 200".to_string());
+                Ok(ScanVerdict::Rejected(infection))
+            }
+            _ => Err(format!("unexpected ICAP status {}", status)),
+        }
+    }
+}
+
+/// a built-in scanner, resolved from a raw configuration entry
+#[derive(Debug, Clone)]
+pub enum ScannerImpl {
+    Extension(ExtensionPolicy),
+    MimeSniff(MimeSniffPolicy),
+    Icap(IcapScanner),
+    Command(CommandScanner),
+}
+
+impl FileScanner for ScannerImpl {
+    fn scan(&self, file: &UploadedFile) -> Result<ScanVerdict, String> {
+        match self {
+            ScannerImpl::Extension(s) => s.scan(file),
+            ScannerImpl::MimeSniff(s) => s.scan(file),
+            ScannerImpl::Icap(s) => s.scan(file),
+            ScannerImpl::Command(s) => s.scan(file),
+        }
+    }
+}
+
+/// a configured upload scanner, with its rejection action resolved
+#[derive(Debug, Clone)]
+pub struct FileScanEntry {
+    pub id: String,
+    pub name: String,
+    pub scanner: ScannerImpl,
+    /// action applied when the scanner rejects an upload, rejections are
+    /// only logged when it is missing
+    pub action: Option<SimpleAction>,
+}
+
+impl FileScanEntry {
+    pub fn resolve(
+        logs: &mut Logs,
+        actions: &HashMap<String, SimpleAction>,
+        rawentries: Vec<RawFileScanner>,
+    ) -> Vec<FileScanEntry> {
+        let mut out = Vec::new();
+        for raw in rawentries {
+            if !raw.active {
+                continue;
+            }
+            let denied = || raw.denied.iter().map(|e| e.to_lowercase()).collect();
+            let scanner = match raw.kind {
+                RawFileScannerKind::Extension => ScannerImpl::Extension(ExtensionPolicy { denied: denied() }),
+                RawFileScannerKind::Mime => ScannerImpl::MimeSniff(MimeSniffPolicy { denied: denied() }),
+                RawFileScannerKind::Icap => match &raw.url {
+                    Some(url) => ScannerImpl::Icap(IcapScanner { url: url.clone() }),
+                    None => {
+                        logs.error(|| format!("file scanner {}: icap kind without url", raw.id));
+                        continue;
+                    }
+                },
+                RawFileScannerKind::Command => match raw.command.split_first() {
+                    Some((program, args)) => ScannerImpl::Command(CommandScanner {
+                        program: program.clone(),
+                        args: args.to_vec(),
+                    }),
+                    None => {
+                        logs.error(|| format!("file scanner {}: command kind without command", raw.id));
+                        continue;
+                    }
+                },
+            };
+            let action = match &raw.action {
+                None => None,
+                Some(aid) => match actions.get(aid) {
+                    Some(a) => Some(a.clone()),
+                    None => {
+                        logs.error(|| format!("Could not resolve action {} in file scanner {}", aid, raw.id));
+                        None
+                    }
+                },
+            };
+            out.push(FileScanEntry {
+                name: if raw.name.is_empty() { raw.id.clone() } else { raw.name },
+                id: raw.id,
+                scanner,
+                action,
+            });
+        }
+        out
+    }
+}
+
+/// runs the uploaded files through the configured scanners, returning the
+/// action and block reason for the first rejection carrying an action
+pub fn scan_failure(
+    logs: &mut Logs,
+    entries: &[FileScanEntry],
+    files: &[UploadedFile],
+) -> Option<(SimpleAction, BlockReason)> {
+    for entry in entries {
+        for file in files {
+            match entry.scanner.scan(file) {
+                Err(rr) => logs.error(|| format!("file scanner {} on {}: {}", entry.id, file.filename, rr)),
+                Ok(ScanVerdict::Clean) => (),
+                Ok(ScanVerdict::Rejected(reason)) => {
+                    logs.info(|| format!("file scanner {} rejected {}: {}", entry.id, file.filename, reason));
+                    if let Some(action) = &entry.action {
+                        let br = BlockReason::restricted(
+                            entry.id.clone(),
+                            entry.name.clone(),
+                            action.atype.to_raw(),
+                            Location::Body,
+                            file.filename.clone(),
+                            reason,
+                        );
+                        return Some((action.clone(), br));
+                    }
+                }
+            }
+        }
+    }
+    None
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn mkfile(filename: &str, content: &[u8]) -> UploadedFile {
+        UploadedFile {
+            field: "file".to_string(),
+            filename: filename.to_string(),
+            content_type: None,
+            content: content.to_vec(),
+        }
+    }
+
+    #[test]
+    fn files_are_extracted_from_multipart() {
+        let content = [
+            "--XX",
+            "Content-Disposition: form-data; name=\"comment\"",
+            "",
+            "hello",
+            "--XX",
+            "Content-Disposition: form-data; name=\"file\"; filename=\"evil.exe\"",
+            "Content-Type: application/octet-stream",
+            "",
+            "MZcontent",
+            "--XX--",
+            "",
+        ];
+        let files = extract_uploaded_files(
+            Some("multipart/form-data; boundary=XX"),
+            content.join("\r\n").as_bytes(),
+        );
+        assert_eq!(files.len(), 1);
+        assert_eq!(files[0].field, "file");
+        assert_eq!(files[0].filename, "evil.exe");
+        assert_eq!(files[0].content, b"MZcontent");
+    }
+
+    #[test]
+    fn extension_policy() {
+        let policy = ExtensionPolicy {
+            denied: std::iter::once("exe".to_string()).collect(),
+        };
+        assert_eq!(
+            policy.scan(&mkfile("evil.EXE", b"x")),
+            Ok(ScanVerdict::Rejected("denied extension exe".to_string()))
+        );
+        assert_eq!(policy.scan(&mkfile("report.pdf", b"x")), Ok(ScanVerdict::Clean));
+        // no extension at all
+        assert_eq!(policy.scan(&mkfile("exe", b"x")), Ok(ScanVerdict::Clean));
+    }
+
+    #[test]
+    fn mime_sniffing() {
+        assert_eq!(sniff_mime(b"\x7fELF\x02\x01"), Some("application/x-elf"));
+        assert_eq!(sniff_mime(b"#!/bin/sh\n"), Some("text/x-shellscript"));
+        assert_eq!(sniff_mime(b"plain text"), None);
+        let policy = MimeSniffPolicy {
+            denied: std::iter::once("application/x-elf".to_string()).collect(),
+        };
+        // the declared name does not matter, only the content
+        assert_eq!(
+            policy.scan(&mkfile("kitten.jpg", b"\x7fELF\x02\x01")),
+            Ok(ScanVerdict::Rejected("denied mime type application/x-elf".to_string()))
+        );
+    }
+
+    #[test]
+    fn command_scanner() {
+        let clean = CommandScanner {
+            program: "sh".to_string(),
+            args: vec!["-c".to_string(), "cat >/dev/null".to_string()],
+        };
+        assert_eq!(clean.scan(&mkfile("a", b"data")), Ok(ScanVerdict::Clean));
+        let infected = CommandScanner {
+            program: "sh".to_string(),
+            args: vec!["-c".to_string(), "cat >/dev/null; echo infected; exit 1".to_string()],
+        };
+        assert_eq!(
+            infected.scan(&mkfile("a", b"data")),
+            Ok(ScanVerdict::Rejected("infected".to_string()))
+        );
+    }
+
+    #[test]
+    fn inactive_and_invalid_entries_are_dropped() {
+        let mut logs = Logs::default();
+        let raws = vec![
+            RawFileScanner {
+                id: "inactive".to_string(),
+                name: String::new(),
+                active: false,
+                kind: RawFileScannerKind::Extension,
+                denied: vec!["exe".to_string()],
+                url: None,
+                command: Vec::new(),
+                action: None,
+            },
+            RawFileScanner {
+                id: "nourl".to_string(),
+                name: String::new(),
+                active: true,
+                kind: RawFileScannerKind::Icap,
+                denied: Vec::new(),
+                url: None,
+                command: Vec::new(),
+                action: None,
+            },
+        ];
+        assert!(FileScanEntry::resolve(&mut logs, &HashMap::new(), raws).is_empty());
+    }
+}
@@ -1,30 +1,45 @@
-use redis::aio::ConnectionManager;
-
 use crate::interface::stats::{BStageFlow, BStageMapped, StatsCollect};
 use crate::Logs;
 
-use crate::config::flow::{FlowElement, FlowMap, SequenceKey};
-use crate::config::matchers::RequestSelector;
+use crate::config::flow::{FirstSeen, FlowElement, FlowMap, SequenceKey};
+use crate::counterstore::{CounterQuery, CounterStore};
 use crate::interface::{Location, Tags};
-use crate::redis::REDIS_KEY_PREFIX;
+use crate::redis::{jittered_ttl, mask_user_value, REDIS_KEY_PREFIX};
 use crate::utils::{check_selector_cond, select_string, RequestInfo};
 
 fn session_sequence_key(ri: &RequestInfo) -> SequenceKey {
     SequenceKey(ri.rinfo.meta.method.to_string() + &ri.rinfo.host + &ri.rinfo.qinfo.qpath)
 }
 
-fn build_redis_key(
+fn hashed_key(
     reqinfo: &RequestInfo,
     tags: &Tags,
-    key: &[RequestSelector],
-    entry_id: &str,
-    entry_name: &str,
+    id: &str,
+    name: &str,
+    selectors: &[crate::config::matchers::RequestSelector],
+    key_prefix: Option<&str>,
 ) -> Option<String> {
-    let mut tohash = entry_id.to_string() + entry_name;
-    for kpart in key.iter() {
-        tohash += &select_string(reqinfo, kpart, Some(tags))?;
+    let mut tohash = id.to_string() + name;
+    for kpart in selectors.iter() {
+        tohash += &mask_user_value(&select_string(reqinfo, kpart, Some(tags))?);
     }
-    Some(format!("{}{:X}", *REDIS_KEY_PREFIX, md5::compute(tohash)))
+    Some(format!(
+        "{}{}{:X}",
+        *REDIS_KEY_PREFIX,
+        key_prefix.unwrap_or(""),
+        md5::compute(tohash)
+    ))
+}
+
+fn build_redis_key(reqinfo: &RequestInfo, tags: &Tags, elem: &FlowElement) -> Option<String> {
+    hashed_key(
+        reqinfo,
+        tags,
+        &elem.id,
+        &elem.name,
+        &elem.key,
+        elem.key_prefix.as_deref(),
+    )
 }
 
 fn flow_match(reqinfo: &RequestInfo, tags: &Tags, elem: &FlowElement) -> bool {
@@ -50,6 +65,8 @@ pub enum FlowResultType {
     NonLast,
     LastOk,
     LastBlock,
+    /// first seen check, true when the key was never seen in the window
+    FirstSeen(bool),
 }
 
 #[derive(Clone)]
@@ -57,7 +74,10 @@ pub struct FlowCheck {
     pub redis_key: String,
     pub step: u32,
     pub timeframe: u64,
+    pub ttl_jitter: u64,
     pub is_last: bool,
+    /// first seen checks only tag new keys, and never block
+    pub first_seen: bool,
     pub id: String,
     pub name: String,
     pub tags: Vec<String>,
@@ -74,13 +94,15 @@ pub fn flow_info(logs: &mut Logs, flows: &FlowMap, reqinfo: &RequestInfo, tags:
                     continue;
                 }
                 logs.debug(|| format!("Testing flow control {} (step {})", elem.name, elem.step));
-                match build_redis_key(reqinfo, tags, &elem.key, &elem.id, &elem.name) {
+                match build_redis_key(reqinfo, tags, elem) {
                     Some(redis_key) => {
                         out.push(FlowCheck {
                             redis_key,
                             step: elem.step,
                             timeframe: elem.timeframe,
+                            ttl_jitter: elem.ttl_jitter,
                             is_last: elem.is_last,
+                            first_seen: false,
                             id: elem.id.clone(),
                             name: elem.name.clone(),
                             tags: elem.tags.clone(),
@@ -94,41 +116,83 @@ pub fn flow_info(logs: &mut Logs, flows: &FlowMap, reqinfo: &RequestInfo, tags:
     }
 }
 
-pub async fn flow_resolve_query<I: Iterator<Item = Option<i64>>>(
-    redis: &mut ConnectionManager,
+/// builds the checks for the first seen entries, which reuse the flow
+/// machinery with a single, never blocking step
+pub fn first_seen_info(logs: &mut Logs, entries: &[FirstSeen], reqinfo: &RequestInfo, tags: &Tags) -> Vec<FlowCheck> {
+    let mut out = Vec::new();
+    for elem in entries {
+        if elem.exclude.iter().any(|e| tags.contains(e)) {
+            continue;
+        }
+        if !(elem.include.is_empty() || elem.include.iter().any(|e| tags.contains(e))) {
+            continue;
+        }
+        logs.debug(|| format!("Testing first seen entry {}", elem.name));
+        match hashed_key(reqinfo, tags, &elem.id, &elem.name, &elem.key, elem.key_prefix.as_deref()) {
+            Some(redis_key) => out.push(FlowCheck {
+                redis_key,
+                step: 0,
+                timeframe: elem.timeframe,
+                ttl_jitter: elem.ttl_jitter,
+                is_last: false,
+                first_seen: true,
+                id: elem.id.clone(),
+                name: elem.name.clone(),
+                tags: elem.tags.clone(),
+            }),
+            None => logs.warning(|| format!("Could not fetch key in first seen entry {}", elem.name)),
+        }
+    }
+    out
+}
+
+/// records a visit for the checked key, refreshing the TTL when needed
+async fn record_visit(store: &mut dyn CounterStore, check: &FlowCheck) -> anyhow::Result<()> {
+    store
+        .run(vec![CounterQuery::Incr {
+            key: check.redis_key.clone(),
+            ttl: jittered_ttl(check.timeframe, check.ttl_jitter),
+        }])
+        .await?;
+    Ok(())
+}
+
+/// translates the flow checks into counter store queries, flow steps are
+/// stored as plain counters (one per step key)
+pub fn flow_queries(checks: &[FlowCheck]) -> Vec<CounterQuery> {
+    checks
+        .iter()
+        .map(|check| CounterQuery::Read {
+            key: check.redis_key.clone(),
+        })
+        .collect()
+}
+
+pub async fn flow_resolve_query<I: Iterator<Item = i64>>(
+    store: &mut dyn CounterStore,
     iter: &mut I,
     checks: Vec<FlowCheck>,
 ) -> anyhow::Result<Vec<FlowResult>> {
     let mut out = Vec::new();
     for check in checks {
-        let listlen = match iter.next() {
+        let visits = match iter.next() {
             None => anyhow::bail!("Empty iterator when checking {}", check.name),
-            Some(l) => l.unwrap_or(0) as usize,
+            Some(l) => l as usize,
         };
-        let tp = if check.is_last {
-            if check.step as usize == listlen {
+        let tp = if check.first_seen {
+            if visits == 0 {
+                record_visit(store, &check).await?;
+            }
+            FlowResultType::FirstSeen(visits == 0)
+        } else if check.is_last {
+            if check.step as usize == visits {
                 FlowResultType::LastOk
             } else {
                 FlowResultType::LastBlock
             }
         } else {
-            if check.step as usize == listlen {
-                let (_, mexpire): ((), Option<i64>) = redis::pipe()
-                    .cmd("LPUSH")
-                    .arg(&check.redis_key)
-                    .arg("foo")
-                    .cmd("TTL")
-                    .arg(&check.redis_key)
-                    .query_async(redis)
-                    .await?;
-                let expire = mexpire.unwrap_or(-1);
-                if expire < 0 {
-                    redis::cmd("EXPIRE")
-                        .arg(&check.redis_key)
-                        .arg(check.timeframe)
-                        .query_async(redis)
-                        .await?;
-                }
+            if check.step as usize == visits {
+                record_visit(store, &check).await?;
             }
             // never block if not the last step!
             FlowResultType::NonLast
@@ -143,12 +207,6 @@ pub async fn flow_resolve_query<I: Iterator<Item = Option<i64>>>(
     Ok(out)
 }
 
-pub fn flow_build_query(pipe: &mut redis::Pipeline, checks: &[FlowCheck]) {
-    for check in checks {
-        pipe.cmd("LLEN").arg(&check.redis_key);
-    }
-}
-
 pub fn flow_process(
     stats: StatsCollect<BStageMapped>,
     flow_total: usize,
@@ -164,6 +222,13 @@ pub fn flow_process(
                     tags.insert(tag, Location::Request);
                 }
             }
+            FlowResultType::FirstSeen(new) => {
+                if new {
+                    for tag in &result.tags {
+                        tags.insert(tag, Location::Request);
+                    }
+                }
+            }
             FlowResultType::LastBlock => (),
             FlowResultType::NonLast => (),
         }
@@ -99,28 +99,135 @@ mod imported {
     }
 }
 
-pub struct DummyGrasshopper {}
+pub mod testing {
+    //! configurable Grasshopper stubs, shared by the bindings and the tests
+    //! so that they do not grow diverging dummy implementations
+    use super::*;
+    use std::collections::VecDeque;
+    use std::sync::Mutex;
+
+    /// a stub Grasshopper where each call returns its scripted result
+    #[derive(Debug, Clone)]
+    pub struct TestGrasshopper {
+        pub humanity: Result<PrecisionLevel, String>,
+        pub challenge: Result<GHResponse, String>,
+        pub verify: Result<String, String>,
+        pub app_sig: Result<GHResponse, String>,
+        pub bio_report: Result<GHResponse, String>,
+    }
+
+    impl TestGrasshopper {
+        /// all calls fail, use this when grasshopper can't be used
+        pub fn unavailable() -> Self {
+            Self::failing("not implemented")
+        }
+
+        /// all calls fail with the given reason, for failure injection
+        pub fn failing(reason: &str) -> Self {
+            TestGrasshopper {
+                humanity: Err(reason.to_string()),
+                challenge: Err(reason.to_string()),
+                verify: Err(reason.to_string()),
+                app_sig: Err(reason.to_string()),
+                bio_report: Err(reason.to_string()),
+            }
+        }
 
-// use this when grasshopper can't be used
-impl Grasshopper for DummyGrasshopper {
-    fn should_provide_app_sig(&self, _headers: HashMap<&str, &str>) -> Result<GHResponse, String> {
-        Err("not implemented".into())
+        /// a fixed precision level, where challenge verification succeeds
+        /// unless the level is invalid
+        pub fn with_humanity(level: PrecisionLevel) -> Self {
+            TestGrasshopper {
+                humanity: Ok(level),
+                challenge: Ok(GHResponse::invalid()),
+                verify: if level == PrecisionLevel::Invalid {
+                    Err("Bad".to_string())
+                } else {
+                    Ok("OK".to_string())
+                },
+                app_sig: Ok(GHResponse::invalid()),
+                bio_report: Err("not implemented".to_string()),
+            }
+        }
     }
 
-    fn verify_challenge(&self, _headers: HashMap<&str, &str>) -> Result<String, String> {
-        Err("not implemented".into())
+    impl Grasshopper for TestGrasshopper {
+        fn is_human(&self, _input: GHQuery) -> Result<PrecisionLevel, String> {
+            self.humanity.clone()
+        }
+
+        fn init_challenge(&self, _input: GHQuery, _mode: GHMode) -> Result<GHResponse, String> {
+            self.challenge.clone()
+        }
+
+        fn verify_challenge(&self, _headers: HashMap<&str, &str>) -> Result<String, String> {
+            self.verify.clone()
+        }
+
+        fn should_provide_app_sig(&self, _headers: HashMap<&str, &str>) -> Result<GHResponse, String> {
+            self.app_sig.clone()
+        }
+
+        fn handle_bio_report(&self, _input: GHQuery, _precision_level: PrecisionLevel) -> Result<GHResponse, String> {
+            self.bio_report.clone()
+        }
     }
 
-    fn init_challenge(&self, _input: GHQuery, _mode: GHMode) -> Result<GHResponse, String> {
-        Err("not implemented".into())
+    /// a scripted sequence of stubs for integration tests: all calls
+    /// delegate to the current stage, and advance moves to the next one, so
+    /// a scenario like "invalid, then challenge passed, then human" can be
+    /// expressed as three stages
+    pub struct ScenarioGrasshopper {
+        stages: Mutex<VecDeque<TestGrasshopper>>,
     }
 
-    fn is_human(&self, _input: GHQuery) -> Result<PrecisionLevel, String> {
-        Err("not implemented".into())
+    impl ScenarioGrasshopper {
+        pub fn new(stages: Vec<TestGrasshopper>) -> Self {
+            ScenarioGrasshopper {
+                stages: Mutex::new(stages.into()),
+            }
+        }
+
+        /// moves to the next stage, the last stage is kept forever
+        pub fn advance(&self) {
+            if let Ok(mut stages) = self.stages.lock() {
+                if stages.len() > 1 {
+                    stages.pop_front();
+                }
+            }
+        }
+
+        fn with_current<F, R>(&self, f: F) -> R
+        where
+            F: FnOnce(&TestGrasshopper) -> R,
+        {
+            let fallback = TestGrasshopper::unavailable();
+            match self.stages.lock() {
+                Ok(stages) => f(stages.front().unwrap_or(&fallback)),
+                Err(_) => f(&fallback),
+            }
+        }
     }
 
-    fn handle_bio_report(&self, _input: GHQuery, _precision_leve: PrecisionLevel) -> Result<GHResponse, String> {
-        Err("not implemented".into())
+    impl Grasshopper for ScenarioGrasshopper {
+        fn is_human(&self, input: GHQuery) -> Result<PrecisionLevel, String> {
+            self.with_current(|gh| gh.is_human(input))
+        }
+
+        fn init_challenge(&self, input: GHQuery, mode: GHMode) -> Result<GHResponse, String> {
+            self.with_current(|gh| gh.init_challenge(input, mode))
+        }
+
+        fn verify_challenge(&self, headers: HashMap<&str, &str>) -> Result<String, String> {
+            self.with_current(|gh| gh.verify_challenge(headers))
+        }
+
+        fn should_provide_app_sig(&self, headers: HashMap<&str, &str>) -> Result<GHResponse, String> {
+            self.with_current(|gh| gh.should_provide_app_sig(headers))
+        }
+
+        fn handle_bio_report(&self, input: GHQuery, precision_level: PrecisionLevel) -> Result<GHResponse, String> {
+            self.with_current(|gh| gh.handle_bio_report(input, precision_level))
+        }
     }
 }
 
@@ -424,3 +531,41 @@ pub fn handle_bio_reports<GH: Grasshopper>(
         reasons,
     ))
 }
+
+#[cfg(test)]
+mod tests {
+    use super::testing::{ScenarioGrasshopper, TestGrasshopper};
+    use super::*;
+
+    fn query() -> GHQuery<'static> {
+        GHQuery {
+            headers: HashMap::new(),
+            cookies: HashMap::new(),
+            ip: "1.2.3.4",
+            protocol: "https",
+        }
+    }
+
+    #[test]
+    fn humanity_scripts_challenge_verification() {
+        let human = TestGrasshopper::with_humanity(PrecisionLevel::Interactive);
+        assert_eq!(human.is_human(query()), Ok(PrecisionLevel::Interactive));
+        assert_eq!(human.verify_challenge(HashMap::new()), Ok("OK".to_string()));
+        let bot = TestGrasshopper::with_humanity(PrecisionLevel::Invalid);
+        assert!(bot.verify_challenge(HashMap::new()).is_err());
+    }
+
+    #[test]
+    fn scenario_advances_through_stages() {
+        let gh = ScenarioGrasshopper::new(vec![
+            TestGrasshopper::with_humanity(PrecisionLevel::Invalid),
+            TestGrasshopper::with_humanity(PrecisionLevel::Active),
+        ]);
+        assert_eq!(gh.is_human(query()), Ok(PrecisionLevel::Invalid));
+        gh.advance();
+        assert_eq!(gh.is_human(query()), Ok(PrecisionLevel::Active));
+        // the last stage is kept forever
+        gh.advance();
+        assert_eq!(gh.is_human(query()), Ok(PrecisionLevel::Active));
+    }
+}
@@ -17,9 +17,11 @@ use crate::{
         contentfilter::ContentFilterRules,
         contentfilter::{ContentFilterProfile, SectionIdx},
         custom::Site,
-        flow::FlowMap,
+        flow::{FirstSeen, FlowMap},
         globalfilter::GlobalFilterSection,
         hostmap::SecurityPolicy,
+        raw::BodyTooLarge,
+        stickytags::StickyTag,
         virtualtags::VirtualTags,
         Config,
     },
@@ -32,7 +34,7 @@ use crate::{
     securitypolicy::match_securitypolicy,
     servergroup::match_servergroup,
     tagging::tag_request,
-    utils::{map_request, RawRequest, RequestMeta},
+    utils::{header_order_fingerprint, map_request, BodyDecodingResult, RawRequest, RequestMeta, HEADERS_FP_ATTR},
 };
 
 pub enum IPInfo {
@@ -45,15 +47,46 @@ pub struct IData {
     pub logs: Logs,
     meta: RequestMeta,
     headers: HashMap<String, String>,
+    /// header names in wire order, Some as long as all headers arrived through
+    /// individual add_header calls, None once a map was ingested
+    header_order: Option<Vec<String>>,
     secpol: Arc<SecurityPolicy>,
     sergroup: Arc<Site>,
-    body: Option<Vec<u8>>,
+    body: Option<BodyData>,
     ipinfo: IPInfo,
+    /// true once body bytes were discarded because the profile is in
+    /// truncate mode and the limit was reached
+    body_truncated: bool,
     stats: StatsCollect<BStageSecpol>,
     container_name: Option<String>,
     plugins: HashMap<String, String>,
 }
 
+/// the request body, either buffered for parsing at finalization, or
+/// consumed on the fly by a streaming parser
+enum BodyData {
+    Raw(Vec<u8>),
+    Multipart(MultipartStream),
+}
+
+impl BodyData {
+    /// total number of body bytes seen so far
+    fn size(&self) -> usize {
+        match self {
+            BodyData::Raw(b) => b.len(),
+            BodyData::Multipart(s) => s.total,
+        }
+    }
+
+    /// the buffered body, when it was not consumed by a streaming parser
+    fn raw(&self) -> Option<&[u8]> {
+        match self {
+            BodyData::Raw(b) => Some(b),
+            BodyData::Multipart(_) => None,
+        }
+    }
+}
+
 impl IData {
     fn ip(&self) -> String {
         match &self.ipinfo {
@@ -90,7 +123,7 @@ pub fn inspect_init(
     let mut logs = Logs::new(loglevel);
     let mr = match_securitypolicy(
         meta.authority.as_deref().unwrap_or("localhost"),
-        &meta.path,
+        meta.normalized_path(),
         config,
         &mut logs,
         selected_secpol,
@@ -106,10 +139,12 @@ pub fn inspect_init(
                 logs,
                 meta,
                 headers: HashMap::new(),
+                header_order: Some(Vec::new()),
                 secpol,
                 sergroup: server_group,
                 body: None,
                 ipinfo,
+                body_truncated: false,
                 stats,
                 container_name: config.container_name.clone(),
                 plugins,
@@ -118,6 +153,17 @@ pub fn inspect_init(
     }
 }
 
+/// injects the header order fingerprint into the request meta, when the
+/// headers arrived in wire order
+fn fingerprint_meta(mut meta: RequestMeta, header_order: &Option<Vec<String>>) -> RequestMeta {
+    if let Some(order) = header_order.as_ref().filter(|o| !o.is_empty()) {
+        meta.extra
+            .entry(HEADERS_FP_ATTR.to_string())
+            .or_insert_with(|| header_order_fingerprint(order.iter()));
+    }
+    meta
+}
+
 /// called when the content filter policy is violated
 /// no tags are returned though!
 fn early_block(idata: IData, action: Action, br: BlockReason) -> (Logs, AnalyzeResult) {
@@ -125,11 +171,13 @@ fn early_block(idata: IData, action: Action, br: BlockReason) -> (Logs, AnalyzeR
     let mut logs = idata.logs;
     let secpolicy = idata.secpol;
     let sergroup = idata.sergroup;
+    let meta = fingerprint_meta(idata.meta, &idata.header_order);
+    let body = idata.body;
     let rawrequest = RawRequest {
         ipstr,
         headers: idata.headers,
-        meta: idata.meta,
-        mbody: idata.body.as_deref(),
+        meta,
+        mbody: body.as_ref().and_then(|b| b.raw()),
     };
     let reqinfo = map_request(
         &mut logs,
@@ -140,13 +188,18 @@ fn early_block(idata: IData, action: Action, br: BlockReason) -> (Logs, AnalyzeR
         Some(idata.start),
         idata.plugins,
     );
+    let mut decision = Decision::action(action, vec![br]);
+    if reqinfo.rinfo.secpolicy.shadow {
+        decision.shadow();
+    }
     (
         logs,
         AnalyzeResult {
-            decision: Decision::action(action, vec![br]),
+            decision,
             tags: Tags::new(&VirtualTags::default()),
             rinfo: reqinfo,
             stats: idata.stats.early_exit(),
+            deferred_limits: Vec::new(),
         },
     )
 }
@@ -156,6 +209,24 @@ fn early_block(idata: IData, action: Action, br: BlockReason) -> (Logs, AnalyzeR
 /// other properties are not checked at this point (restrict for example), this early check purely exists as an anti DOS measure
 pub fn add_headers(idata: IData, new_headers: HashMap<String, String>) -> Result<IData, (Logs, AnalyzeResult)> {
     let mut dt = idata;
+    // map iteration order is not wire order, so the header order fingerprint
+    // can not be computed for this stream
+    dt.header_order = None;
+    // pre-parse guard on the raw path, so that adversarially huge query
+    // strings are rejected before being flattened
+    if dt.secpol.content_filter_active {
+        if let Some(br) = dt.secpol.content_filter_profile.uri_restriction(dt.meta.normalized_path()) {
+            let action = Action {
+                atype: ActionType::Block,
+                block_mode: true,
+                status: 403,
+                headers: None,
+                content: "Access denied".to_string(),
+                extra_tags: None,
+            };
+            return Err(early_block(dt, action, br));
+        }
+    }
     for (k, v) in new_headers {
         dt = add_header(dt, k, v)?;
     }
@@ -196,7 +267,7 @@ pub fn add_header(idata: IData, key: String, value: String) -> Result<IData, (Lo
         if kl == "content-length" {
             if let Ok(content_length) = value.parse::<usize>() {
                 let max_size = dt.secpol.content_filter_profile.max_body_size;
-                if content_length > max_size {
+                if content_length > max_size && dt.secpol.content_filter_profile.body_too_large == BodyTooLarge::Block {
                     let (a, br) = body_too_large(&dt.secpol.content_filter_profile, content_length, max_size);
                     return Err(early_block(dt, a, br));
                 }
@@ -214,10 +285,18 @@ pub fn add_header(idata: IData, key: String, value: String) -> Result<IData, (Lo
             );
             return Err(early_block(dt, cf_block(), br));
         }
+        if let Some(order) = dt.header_order.as_mut() {
+            order.push(kl.clone());
+        }
         dt.headers.insert(kl, value);
     } else {
-        dt.headers.insert(key.to_lowercase(), value);
+        let kl = key.to_lowercase();
+        if let Some(order) = dt.header_order.as_mut() {
+            order.push(kl.clone());
+        }
+        dt.headers.insert(kl, value);
     }
+    dt.stats = dt.stats.headers_done();
     Ok(dt)
 }
 
@@ -241,6 +320,246 @@ fn body_too_large(profile: &ContentFilterProfile, actual: usize, expected: usize
     )
 }
 
+/// information about the matched security policy, available right after the
+/// header phase, so that embedders can decide whether the body must be
+/// forwarded at all
+pub struct MatchedPolicy {
+    pub policy_id: String,
+    pub policy_name: String,
+    pub entry_id: String,
+    pub entry_name: String,
+    /// when false, the body is ignored by the analysis and does not need to be buffered
+    pub body_needed: bool,
+    /// maximum body size enforced by the content filter, when it is active
+    pub max_body_size: Option<usize>,
+}
+
+/// queries which security policy and entry matched, and whether the body is needed
+pub fn matched_policy(idata: &IData) -> MatchedPolicy {
+    let secpol = &idata.secpol;
+    MatchedPolicy {
+        policy_id: secpol.policy.id.clone(),
+        policy_name: secpol.policy.name.clone(),
+        entry_id: secpol.entry.id.clone(),
+        entry_name: secpol.entry.name.clone(),
+        body_needed: !secpol.content_filter_profile.ignore_body,
+        max_body_size: if secpol.content_filter_active {
+            Some(secpol.content_filter_profile.max_body_size)
+        } else {
+            None
+        },
+    }
+}
+
+/// how many more body bytes will be accepted before the too-large action triggers,
+/// so that streaming callers can stop forwarding chunks early
+pub fn body_budget(idata: &IData) -> usize {
+    if idata.secpol.content_filter_profile.ignore_body || !idata.secpol.content_filter_active {
+        return usize::MAX;
+    }
+    let cur_body_size = idata.body.as_ref().map(|b| b.size()).unwrap_or(0);
+    idata
+        .secpol
+        .content_filter_profile
+        .max_body_size
+        .saturating_sub(cur_body_size)
+}
+
+/// effective body limits of the matched security policy, available right after
+/// the header phase, so that embedders can size their body buffers instead of
+/// hardcoding values
+pub struct BodyLimits {
+    /// when true, the body is ignored by the analysis and does not need to be buffered
+    pub ignore_body: bool,
+    /// maximum body size enforced by the content filter, usize::MAX when unlimited
+    pub max_size: usize,
+    /// maximum nesting depth of parsed body arguments, usize::MAX when unlimited
+    pub max_depth: usize,
+}
+
+/// queries the body limits of the matched security policy
+pub fn body_limits(idata: &IData) -> BodyLimits {
+    let profile = &idata.secpol.content_filter_profile;
+    if profile.ignore_body || !idata.secpol.content_filter_active {
+        return BodyLimits {
+            ignore_body: profile.ignore_body,
+            max_size: usize::MAX,
+            max_depth: usize::MAX,
+        };
+    }
+    BodyLimits {
+        ignore_body: false,
+        max_size: profile.max_body_size,
+        max_depth: profile.max_body_depth,
+    }
+}
+
+#[derive(PartialEq, Eq)]
+enum MultipartState {
+    /// before the first boundary
+    Preamble,
+    /// inside the headers of a part
+    PartHeaders,
+    /// inside the content of a part
+    PartContent,
+    /// after the terminal boundary
+    Epilogue,
+}
+
+/// streaming multipart/form-data parser
+///
+/// chunks are parsed as they arrive, keeping only the current part (capped
+/// at the per-part limit) and a carry buffer bounded by the boundary size,
+/// so that large uploads are scanned with constant memory
+struct MultipartStream {
+    /// the boundary marker, as it appears in the body ("--" + the header boundary)
+    boundary: Vec<u8>,
+    /// bytes that could not be consumed yet, bounded by the boundary size
+    /// outside of the header state
+    buffer: Vec<u8>,
+    state: MultipartState,
+    /// finished parts, values truncated at the per-part limit
+    parts: Vec<(String, String)>,
+    name: String,
+    value: Vec<u8>,
+    /// actual size of the current part, including truncated bytes
+    size: usize,
+    /// per-part size limit, from the args section of the content filter profile
+    max_part_size: usize,
+    /// total number of body bytes seen
+    total: usize,
+}
+
+fn find_subslice(haystack: &[u8], needle: &[u8]) -> Option<usize> {
+    haystack.windows(needle.len()).position(|w| w == needle)
+}
+
+/// extracts the field name from the headers of a part
+fn part_name(headers: &str) -> Option<String> {
+    let start = headers.find("name=\"")? + 6;
+    let end = headers[start..].find('"')?;
+    Some(headers[start..start + end].to_string())
+}
+
+impl MultipartStream {
+    fn new(boundary: &str, max_part_size: usize) -> Self {
+        let mut full = b"--".to_vec();
+        full.extend_from_slice(boundary.as_bytes());
+        MultipartStream {
+            boundary: full,
+            buffer: Vec::new(),
+            state: MultipartState::Preamble,
+            parts: Vec::new(),
+            name: String::new(),
+            value: Vec::new(),
+            size: 0,
+            max_part_size,
+            total: 0,
+        }
+    }
+
+    /// consumes a body chunk, failing with the part name and size when a part
+    /// exceeds the per-part limit
+    fn feed(&mut self, chunk: &[u8]) -> Result<(), (String, usize)> {
+        self.total += chunk.len();
+        self.buffer.extend_from_slice(chunk);
+        loop {
+            match self.state {
+                MultipartState::Epilogue => {
+                    self.buffer.clear();
+                    return Ok(());
+                }
+                MultipartState::Preamble => match find_subslice(&self.buffer, &self.boundary) {
+                    None => {
+                        let keep = (self.boundary.len() - 1).min(self.buffer.len());
+                        let dropped = self.buffer.len() - keep;
+                        self.buffer.drain(..dropped);
+                        return Ok(());
+                    }
+                    Some(pos) => {
+                        self.buffer.drain(..pos + self.boundary.len());
+                        self.state = MultipartState::PartHeaders;
+                    }
+                },
+                MultipartState::PartHeaders => {
+                    if self.buffer.starts_with(b"--") {
+                        self.state = MultipartState::Epilogue;
+                        continue;
+                    }
+                    match find_subslice(&self.buffer, b"\r\n\r\n") {
+                        // wait for the end of the part headers, their size is
+                        // already bounded by the header phase limits
+                        None => return Ok(()),
+                        Some(pos) => {
+                            let headers = String::from_utf8_lossy(&self.buffer[..pos]).to_string();
+                            self.name = part_name(&headers).unwrap_or_default();
+                            self.value.clear();
+                            self.size = 0;
+                            self.buffer.drain(..pos + 4);
+                            self.state = MultipartState::PartContent;
+                        }
+                    }
+                }
+                MultipartState::PartContent => {
+                    let mut delimiter = b"\r\n".to_vec();
+                    delimiter.extend_from_slice(&self.boundary);
+                    match find_subslice(&self.buffer, &delimiter) {
+                        None => {
+                            // everything but a potential delimiter prefix at
+                            // the tail is part content
+                            if self.buffer.len() > delimiter.len() {
+                                let content_len = self.buffer.len() - delimiter.len();
+                                let content: Vec<u8> = self.buffer.drain(..content_len).collect();
+                                self.push_content(&content);
+                            }
+                            return self.check_part_size();
+                        }
+                        Some(pos) => {
+                            let content: Vec<u8> = self.buffer.drain(..pos).collect();
+                            self.push_content(&content);
+                            self.buffer.drain(..delimiter.len());
+                            self.check_part_size()?;
+                            self.finish_part();
+                            self.state = MultipartState::PartHeaders;
+                        }
+                    }
+                }
+            }
+        }
+    }
+
+    fn push_content(&mut self, data: &[u8]) {
+        self.size += data.len();
+        let room = self.max_part_size.saturating_sub(self.value.len());
+        self.value.extend_from_slice(&data[..data.len().min(room)]);
+    }
+
+    fn check_part_size(&self) -> Result<(), (String, usize)> {
+        if self.size > self.max_part_size {
+            Err((self.name.clone(), self.size))
+        } else {
+            Ok(())
+        }
+    }
+
+    fn finish_part(&mut self) {
+        let name = std::mem::take(&mut self.name);
+        let value = String::from_utf8_lossy(&self.value).to_string();
+        self.parts.push((name, value));
+        self.value.clear();
+        self.size = 0;
+    }
+
+    /// all the parsed parts, including the one still in progress for
+    /// truncated bodies
+    fn into_parts(mut self) -> Vec<(String, String)> {
+        if self.state == MultipartState::PartContent {
+            self.finish_part();
+        }
+        self.parts
+    }
+}
+
 pub fn add_body(idata: IData, new_body: &[u8]) -> Result<IData, (Logs, AnalyzeResult)> {
     let mut dt = idata;
 
@@ -249,18 +568,72 @@ pub fn add_body(idata: IData, new_body: &[u8]) -> Result<IData, (Logs, AnalyzeRe
         return Ok(dt);
     }
 
-    let cur_body_size = dt.body.as_ref().map(|v| v.len()).unwrap_or(0);
+    // multipart bodies of filtered requests are parsed on the fly, so that
+    // large uploads do not need to be buffered in full
+    if dt.body.is_none() && dt.secpol.content_filter_active {
+        if let Some(boundary) = dt
+            .headers
+            .get("content-type")
+            .and_then(|ct| ct.strip_prefix("multipart/form-data; boundary="))
+        {
+            dt.body = Some(BodyData::Multipart(MultipartStream::new(
+                boundary,
+                dt.secpol.content_filter_profile.sections.args.max_length,
+            )));
+        }
+    }
+
+    let cur_body_size = dt.body.as_ref().map(|b| b.size()).unwrap_or(0);
     let new_size = cur_body_size + new_body.len();
     let max_size = dt.secpol.content_filter_profile.max_body_size;
+    let mut new_body = new_body;
     if dt.secpol.content_filter_active && new_size > max_size {
-        let (a, br) = body_too_large(&dt.secpol.content_filter_profile, new_size, max_size);
-        return Err(early_block(dt, a, br));
+        match dt.secpol.content_filter_profile.body_too_large {
+            BodyTooLarge::Block => {
+                let (a, br) = body_too_large(&dt.secpol.content_filter_profile, new_size, max_size);
+                return Err(early_block(dt, a, br));
+            }
+            // keep the prefix that fits the limit, the rest is dropped and
+            // the request will be tagged at finalization
+            BodyTooLarge::Truncate => {
+                new_body = &new_body[..max_size.saturating_sub(cur_body_size)];
+                dt.body_truncated = true;
+            }
+        }
     }
 
+    let mut part_too_large = None;
     match dt.body.as_mut() {
-        None => dt.body = Some(new_body.to_vec()),
-        Some(b) => b.extend(new_body),
+        None => dt.body = Some(BodyData::Raw(new_body.to_vec())),
+        Some(BodyData::Raw(b)) => b.extend(new_body),
+        Some(BodyData::Multipart(stream)) => {
+            if let Err(overflow) = stream.feed(new_body) {
+                part_too_large = Some(overflow);
+            }
+        }
     }
+    if let Some((name, size)) = part_too_large {
+        let profile = &dt.secpol.content_filter_profile;
+        let br = BlockReason::entry_too_large(
+            profile.id.clone(),
+            profile.name.clone(),
+            profile.action.atype.to_raw(),
+            SectionIdx::Args,
+            &name,
+            size,
+            profile.sections.args.max_length,
+        );
+        let action = Action {
+            atype: ActionType::Block,
+            block_mode: true,
+            status: 403,
+            headers: None,
+            content: "Access denied".to_string(),
+            extra_tags: None,
+        };
+        return Err(early_block(dt, action, br));
+    }
+    dt.stats = dt.stats.body_done();
     Ok(dt)
 }
 
@@ -269,6 +642,8 @@ pub async fn finalize<GH: Grasshopper>(
     mgh: Option<&GH>,
     globalfilters: &[GlobalFilterSection],
     flows: &FlowMap,
+    first_seen: &[FirstSeen],
+    sticky_tags: &[StickyTag],
     mcfrules: Option<&HashMap<String, ContentFilterRules>>,
     vtags: VirtualTags,
 ) -> (AnalyzeResult, Logs) {
@@ -276,16 +651,18 @@ pub async fn finalize<GH: Grasshopper>(
     let mut logs = idata.logs;
     let secpolicy = idata.secpol;
     let sergroup = idata.sergroup;
+    let meta = fingerprint_meta(idata.meta, &idata.header_order);
+    let body = idata.body;
     let rawrequest = RawRequest {
         ipstr,
         headers: idata.headers,
-        meta: idata.meta,
-        mbody: idata.body.as_deref(),
+        meta,
+        mbody: body.as_ref().and_then(|b| b.raw()),
     };
     let cfrules = mcfrules
         .map(|cfrules| CfRulesArg::Get(cfrules.get(&secpolicy.content_filter_profile.id)))
         .unwrap_or(CfRulesArg::Global);
-    let reqinfo = map_request(
+    let mut reqinfo = map_request(
         &mut logs,
         secpolicy.clone(),
         sergroup.clone(),
@@ -294,6 +671,14 @@ pub async fn finalize<GH: Grasshopper>(
         Some(idata.start),
         idata.plugins,
     );
+    // streamed multipart parts are surfaced as body arguments, like their
+    // buffered counterpart
+    if let Some(BodyData::Multipart(stream)) = body {
+        for (name, value) in stream.into_parts() {
+            reqinfo.rinfo.qinfo.args.add(name, Location::Body, value);
+        }
+        reqinfo.rinfo.qinfo.body_decoding = BodyDecodingResult::ProperlyDecoded;
+    }
 
     let precision_level = if let Some(gh) = mgh {
         challenge_verified(gh, &reqinfo, &mut logs)
@@ -304,8 +689,11 @@ pub async fn finalize<GH: Grasshopper>(
     let (mut tags, globalfilter_dec, stats) =
         tag_request(idata.stats, precision_level, globalfilters, &reqinfo, &vtags);
     tags.insert("all", Location::Request);
+    if idata.body_truncated {
+        tags.insert("body-truncated", Location::Body);
+    }
 
-    let dec = analyze(
+    let mut dec = analyze(
         &mut logs,
         mgh,
         APhase0 {
@@ -315,10 +703,13 @@ pub async fn finalize<GH: Grasshopper>(
             precision_level,
             globalfilter_dec,
             flows: flows.clone(),
+            first_seen: first_seen.to_vec(),
+            sticky_tags: sticky_tags.to_vec(),
         },
         cfrules,
     )
     .await;
+    dec.stats.finalize_done();
     (dec, logs)
 }
 
@@ -358,11 +749,24 @@ mod test {
                     content_filter_profile: cf,
                     session: Vec::new(),
                     session_ids: Vec::new(),
+                    session_hash: Default::default(),
+                    session_seed: None,
+                    session_keep_raw: false,
+                    jwt_keys: Vec::new(),
+                    tag_injection: None,
+                    log_profile: None,
+                    api_key: None,
+                    shadow: false,
                     limits: Vec::new(),
+                    allowlist: None,
+                    features: HashMap::new(),
+                    origin_protection: None,
                 })),
             }),
             container_name: None,
             flows: HashMap::new(),
+            first_seen: Vec::new(),
+            sticky_tags: Vec::new(),
             content_filter_profiles: HashMap::new(),
             logs: Logs::default(),
             virtual_tags: Arc::new(HashMap::new()),
@@ -372,6 +776,11 @@ mod test {
             inactive_limits: HashSet::new(),
             acls: HashMap::new(),
             servergroups_map: HashMap::new(),
+            healthcheck: crate::config::healthcheck::HealthCheckAllowlist::default(),
+            embargo: crate::config::compliance::EmbargoPolicy::default(),
+            plugin_pipeline: Vec::new(),
+            mirrors: Vec::new(),
+            file_scanners: Vec::new(),
         }
     }
 
@@ -388,6 +797,9 @@ mod test {
                 method: "GET".to_string(),
                 protocol: None,
                 path: "/path/to/somewhere".to_string(),
+                client_cert_subject: None,
+                client_cert_san: None,
+                client_cert_fingerprint: None,
                 extra: HashMap::default(),
                 requestid: None,
             },
@@ -463,6 +875,27 @@ mod test {
         assert!(idata.is_err())
     }
 
+    #[test]
+    fn uri_too_long() {
+        let mut cf = ContentFilterProfile::default_from_seed("seed");
+        cf.max_uri_length = 10;
+        let cfg = empty_config(cf);
+        let idata = mk_idata(&cfg);
+        // the path used by mk_idata is longer than 10 bytes
+        let idata = add_headers(idata, HashMap::new());
+        assert!(idata.is_err())
+    }
+
+    #[test]
+    fn uri_not_too_long() {
+        let mut cf = ContentFilterProfile::default_from_seed("seed");
+        cf.max_uri_length = 1024;
+        let cfg = empty_config(cf);
+        let idata = mk_idata(&cfg);
+        let idata = add_headers(idata, HashMap::new());
+        assert!(idata.is_ok())
+    }
+
     #[test]
     fn body_too_large_cl() {
         let mut cf = ContentFilterProfile::default_from_seed("seed");
@@ -493,4 +926,157 @@ mod test {
             ),
         }
     }
+
+    #[test]
+    fn body_truncate_mode() {
+        let mut cf = ContentFilterProfile::default_from_seed("seed");
+        cf.max_body_size = 100;
+        cf.body_too_large = BodyTooLarge::Truncate;
+        let cfg = empty_config(cf);
+        let idata = mk_idata(&cfg);
+        // the content-length precheck does not block in truncate mode
+        let idata = add_headers(idata, hashmap(&[("content-length", "150"), ("k4", "v4"), ("k5", "v5")])).unwrap();
+        let mut chunk: Vec<u8> = Vec::new();
+        chunk.resize(80, 66);
+        let idata = add_body(idata, &chunk).unwrap();
+        // the second chunk crosses the limit: only the fitting prefix is kept
+        let idata = add_body(idata, &chunk).unwrap();
+        assert_eq!(body_budget(&idata), 0);
+        assert!(idata.body_truncated);
+    }
+
+    #[test]
+    fn matched_policy_body_needed() {
+        let mut cf = ContentFilterProfile::default_from_seed("seed");
+        cf.max_body_size = 100;
+        let cfg = empty_config(cf);
+        let idata = mk_idata(&cfg);
+        let policy = matched_policy(&idata);
+        assert_eq!(policy.policy_id, "__default__");
+        assert_eq!(policy.entry_id, "default");
+        assert!(policy.body_needed);
+        assert_eq!(policy.max_body_size, Some(100));
+    }
+
+    #[test]
+    fn matched_policy_body_ignored() {
+        let mut cf = ContentFilterProfile::default_from_seed("seed");
+        cf.ignore_body = true;
+        let cfg = empty_config(cf);
+        let idata = mk_idata(&cfg);
+        let policy = matched_policy(&idata);
+        assert!(!policy.body_needed);
+    }
+
+    #[test]
+    fn body_budget_tracking() {
+        let mut cf = ContentFilterProfile::default_from_seed("seed");
+        cf.max_body_size = 100;
+        let cfg = empty_config(cf);
+        let idata = mk_idata(&cfg);
+        let idata = add_headers(idata, HashMap::new()).unwrap();
+        assert_eq!(body_budget(&idata), 100);
+        let idata = add_body(idata, &[4, 5, 6, 8]).unwrap();
+        assert_eq!(body_budget(&idata), 96);
+    }
+
+    #[test]
+    fn body_limits_reporting() {
+        let mut cf = ContentFilterProfile::default_from_seed("seed");
+        cf.max_body_size = 100;
+        cf.max_body_depth = 7;
+        let cfg = empty_config(cf);
+        let idata = mk_idata(&cfg);
+        let limits = body_limits(&idata);
+        assert!(!limits.ignore_body);
+        assert_eq!(limits.max_size, 100);
+        assert_eq!(limits.max_depth, 7);
+    }
+
+    #[test]
+    fn body_limits_ignored_body() {
+        let mut cf = ContentFilterProfile::default_from_seed("seed");
+        cf.max_body_size = 100;
+        cf.ignore_body = true;
+        let cfg = empty_config(cf);
+        let idata = mk_idata(&cfg);
+        let limits = body_limits(&idata);
+        assert!(limits.ignore_body);
+        assert_eq!(limits.max_size, usize::MAX);
+    }
+
+    fn multipart_body() -> Vec<u8> {
+        [
+            "--b1\r\n",
+            "Content-Disposition: form-data; name=\"foo\"\r\n",
+            "\r\n",
+            "bar\r\n",
+            "--b1\r\n",
+            "Content-Disposition: form-data; name=\"baz\"\r\n",
+            "\r\n",
+            "qux\r\n",
+            "--b1--\r\n",
+        ]
+        .concat()
+        .into_bytes()
+    }
+
+    #[test]
+    fn multipart_stream_chunked() {
+        // the parts must be recovered whatever the chunking
+        for chunk_size in [1, 3, 7, 1024] {
+            let mut stream = MultipartStream::new("b1", 1024);
+            for chunk in multipart_body().chunks(chunk_size) {
+                stream.feed(chunk).unwrap();
+            }
+            assert_eq!(
+                stream.into_parts(),
+                vec![
+                    ("foo".to_string(), "bar".to_string()),
+                    ("baz".to_string(), "qux".to_string())
+                ]
+            );
+        }
+    }
+
+    #[test]
+    fn multipart_stream_bounded_memory() {
+        let mut stream = MultipartStream::new("b1", 16);
+        stream
+            .feed(b"--b1\r\nContent-Disposition: form-data; name=\"file\"\r\n\r\n")
+            .unwrap();
+        let (name, size) = stream.feed(&[66; 1024]).unwrap_err();
+        assert_eq!(name, "file");
+        assert!(size > 16);
+        // the stored value is capped at the per-part limit
+        assert!(stream.value.len() <= 16);
+    }
+
+    #[test]
+    fn multipart_streamed_within_limits() {
+        let mut cf = ContentFilterProfile::default_from_seed("seed");
+        cf.sections.args.max_length = 8;
+        let cfg = empty_config(cf);
+        let idata = mk_idata(&cfg);
+        let mut idata = add_headers(idata, hashmap(&[("content-type", "multipart/form-data; boundary=b1")])).unwrap();
+        for chunk in multipart_body().chunks(5) {
+            idata = add_body(idata, chunk).unwrap();
+        }
+        match idata.body.as_ref() {
+            Some(BodyData::Multipart(stream)) => assert_eq!(stream.parts.len(), 2),
+            _ => panic!("expected a multipart stream"),
+        }
+    }
+
+    #[test]
+    fn multipart_part_too_large() {
+        let mut cf = ContentFilterProfile::default_from_seed("seed");
+        cf.sections.args.max_length = 8;
+        let cfg = empty_config(cf);
+        let idata = mk_idata(&cfg);
+        let idata = add_headers(idata, hashmap(&[("content-type", "multipart/form-data; boundary=b1")])).unwrap();
+        let idata = add_body(idata, b"--b1\r\nContent-Disposition: form-data; name=\"foo\"\r\n\r\n").unwrap();
+        let idata = add_body(idata, b"this part is way too large");
+        assert!(idata.is_err())
+    }
 }
@@ -6,33 +6,198 @@ use serde::Serialize;
 use serde_json::Value;
 use std::collections::{btree_map::Entry, BTreeMap, HashMap};
 use std::hash::Hash;
+use std::sync::atomic::{AtomicU64, Ordering};
 
+use crate::config::contentfilter::MaskingSeed;
 use crate::config::raw::RawActionType;
-use crate::utils::RequestInfo;
+use crate::utils::{masker, RequestInfo};
 
 use super::{Decision, Location, Tags};
 
+/// the aggregation map is sharded to reduce mutex contention at high concurrency
+const AGGREGATION_SHARDS: usize = 16;
+
 lazy_static! {
-    static ref AGGREGATED: Mutex<HashMap<AggregationKey, BTreeMap<i64, AggregatedCounters>>> =
-        Mutex::new(HashMap::new());
-    static ref SAMPLES_KEPT: i64 = std::env::var("AGGREGATED_SAMPLES")
-        .ok()
-        .and_then(|s| s.parse().ok())
-        .unwrap_or(2);
-    static ref SAMPLE_DURATION: i64 = std::env::var("SAMPLE_DURATION")
-        .ok()
-        .and_then(|s| s.parse().ok())
-        .unwrap_or(60);
-    static ref TOP_AMOUNT: usize = std::env::var("AGGREGATED_TOP")
-        .ok()
-        .and_then(|s| s.parse().ok())
-        .unwrap_or(25);
-    static ref HYPERLOGLOG_SIZE: usize = std::env::var("AGGREGATED_HLL_SIZE")
-        .ok()
-        .and_then(|s| s.parse().ok())
-        .unwrap_or(8);
+    static ref AGGREGATED: Vec<Mutex<HashMap<AggregationKey, BTreeMap<i64, AggregatedCounters>>>> =
+        (0..AGGREGATION_SHARDS).map(|_| Mutex::new(HashMap::new())).collect();
+    static ref AGGREGATOR_CONFIG: std::sync::RwLock<AggregatorConfig> =
+        std::sync::RwLock::new(AggregatorConfig::default());
     static ref PLANET_NAME: String = std::env::var("CF_PLANET_NAME").ok().unwrap_or_default();
     static ref EMPTY_AGGREGATED_DATA: AggregatedCounters = AggregatedCounters::default();
+    /// per branch request counters, used to decide which branches keep their
+    /// own series when the branch cap is reached
+    static ref BRANCH_TRAFFIC: std::sync::Mutex<HashMap<String, u64>> = std::sync::Mutex::new(HashMap::new());
+    /// timestamp of the last counter reset; the aggregated counters only live
+    /// in memory, so this is the process start
+    static ref COUNTERS_RESET_AT: chrono::DateTime<Utc> = Utc::now();
+}
+
+/// number of counter saturations since the last reset; a non zero value means
+/// some aggregated numbers are floors instead of exact counts
+static COUNTER_OVERFLOWS: AtomicU64 = AtomicU64::new(0);
+
+/// adds to a counter, saturating instead of wrapping or panicking on overflow,
+/// and recording the event so that saturated series can be identified
+fn sat_add(counter: &mut usize, amount: usize) {
+    *counter = match counter.checked_add(amount) {
+        Some(n) => n,
+        None => {
+            COUNTER_OVERFLOWS.fetch_add(1, Ordering::Relaxed);
+            usize::MAX
+        }
+    };
+}
+
+fn sat_inc(counter: &mut usize) {
+    sat_add(counter, 1);
+}
+
+fn sat_inc_u64(counter: &mut u64) {
+    *counter = match counter.checked_add(1) {
+        Some(n) => n,
+        None => {
+            COUNTER_OVERFLOWS.fetch_add(1, Ordering::Relaxed);
+            u64::MAX
+        }
+    };
+}
+
+fn sat_add_i64(counter: &mut i64, amount: i64) {
+    *counter = match counter.checked_add(amount) {
+        Some(n) => n,
+        None => {
+            COUNTER_OVERFLOWS.fetch_add(1, Ordering::Relaxed);
+            if amount >= 0 {
+                i64::MAX
+            } else {
+                i64::MIN
+            }
+        }
+    };
+}
+
+/// tunables for the aggregation engine, reloadable from the `telemetry` config section
+#[derive(Debug, Clone)]
+pub struct AggregatorConfig {
+    pub samples_kept: i64,
+    pub sample_duration: i64,
+    pub top_amount: usize,
+    pub hyperloglog_size: usize,
+    /// emit zero-valued samples for security policy entries without traffic
+    pub heartbeat: bool,
+    /// maximum number of distinct branches tracked, 0 for no cap
+    pub max_branches: usize,
+    /// per branch overrides for `samples_kept`
+    pub branch_samples_kept: HashMap<String, i64>,
+}
+
+impl Default for AggregatorConfig {
+    /// environment variables are kept as a fallback for deployments without a telemetry section
+    fn default() -> Self {
+        fn from_env<T: std::str::FromStr>(var: &str, def: T) -> T {
+            std::env::var(var).ok().and_then(|s| s.parse().ok()).unwrap_or(def)
+        }
+        AggregatorConfig {
+            samples_kept: from_env("AGGREGATED_SAMPLES", 2),
+            sample_duration: from_env("SAMPLE_DURATION", 60),
+            top_amount: from_env("AGGREGATED_TOP", 25),
+            hyperloglog_size: from_env("AGGREGATED_HLL_SIZE", 8),
+            heartbeat: from_env("AGGREGATED_HEARTBEAT", false),
+            max_branches: from_env("AGGREGATED_MAX_BRANCHES", 0),
+            branch_samples_kept: HashMap::new(),
+        }
+    }
+}
+
+impl AggregatorConfig {
+    pub fn from_raw(raw: &crate::config::raw::RawTelemetry) -> Self {
+        let defaults = AggregatorConfig::default();
+        AggregatorConfig {
+            samples_kept: raw.samples_kept.unwrap_or(defaults.samples_kept),
+            sample_duration: raw.sample_duration.unwrap_or(defaults.sample_duration),
+            top_amount: raw.top_amount.unwrap_or(defaults.top_amount),
+            hyperloglog_size: raw.hyperloglog_size.unwrap_or(defaults.hyperloglog_size),
+            heartbeat: raw.heartbeat.unwrap_or(defaults.heartbeat),
+            max_branches: raw.max_branches.unwrap_or(defaults.max_branches),
+            branch_samples_kept: raw.branch_samples_kept.clone().unwrap_or(defaults.branch_samples_kept),
+        }
+    }
+}
+
+/// swaps the active aggregator configuration, called on configuration reloads
+pub fn set_aggregator_config(cfg: AggregatorConfig) {
+    if let Ok(mut w) = AGGREGATOR_CONFIG.write() {
+        *w = cfg;
+    }
+}
+
+fn samples_kept() -> i64 {
+    AGGREGATOR_CONFIG.read().map(|c| c.samples_kept).unwrap_or(2)
+}
+
+fn sample_duration() -> i64 {
+    AGGREGATOR_CONFIG.read().map(|c| c.sample_duration).unwrap_or(60)
+}
+
+fn top_amount() -> usize {
+    AGGREGATOR_CONFIG.read().map(|c| c.top_amount).unwrap_or(25)
+}
+
+fn hyperloglog_size() -> usize {
+    AGGREGATOR_CONFIG.read().map(|c| c.hyperloglog_size).unwrap_or(8)
+}
+
+fn heartbeat() -> bool {
+    AGGREGATOR_CONFIG.read().map(|c| c.heartbeat).unwrap_or(false)
+}
+
+fn max_branches() -> usize {
+    AGGREGATOR_CONFIG.read().map(|c| c.max_branches).unwrap_or(0)
+}
+
+/// number of samples kept for a given branch, falling back to the global
+/// setting when no override is configured
+fn branch_samples_kept(branch: &str) -> i64 {
+    AGGREGATOR_CONFIG
+        .read()
+        .map(|c| c.branch_samples_kept.get(branch).copied().unwrap_or(c.samples_kept))
+        .unwrap_or(2)
+}
+
+/// folds less active branches into the `other` pseudo branch once the number
+/// of distinct branches exceeds the configured cap, so that aggregator memory
+/// stays bounded in multi-branch CI environments
+fn fold_branch(branch: &str) -> String {
+    let cap = max_branches();
+    if cap == 0 || branch == "-" {
+        return branch.to_string();
+    }
+    let mut traffic = match BRANCH_TRAFFIC.lock() {
+        Ok(t) => t,
+        Err(_) => return branch.to_string(),
+    };
+    let count = {
+        let cnt = traffic.entry(branch.to_string()).or_insert(0);
+        sat_inc_u64(cnt);
+        *cnt
+    };
+    if traffic.len() <= cap {
+        return branch.to_string();
+    }
+    // only the `cap` most active branches keep their own series
+    let mut counts: Vec<u64> = traffic.values().copied().collect();
+    counts.sort_unstable_by(|a, b| b.cmp(a));
+    let cutoff = counts[cap - 1];
+    // the counter map itself must not grow without bounds, drop the least
+    // active branches once it gets much larger than the cap
+    if traffic.len() > cap.saturating_mul(8) {
+        traffic.retain(|_, cnt| *cnt >= cutoff);
+    }
+    if count >= cutoff {
+        branch.to_string()
+    } else {
+        "other".to_string()
+    }
 }
 
 #[derive(Debug, Default)]
@@ -148,6 +313,12 @@ struct AggregatedCounters {
     requests_triggered_cf_report: usize,
     requests_triggered_restriction_active: usize,
     requests_triggered_restriction_report: usize,
+    requests_triggered_allowlist_active: usize,
+    requests_triggered_allowlist_report: usize,
+    requests_triggered_compliance_active: usize,
+    requests_triggered_compliance_report: usize,
+    requests_triggered_external_active: usize,
+    requests_triggered_external_report: usize,
     requests_triggered_acl_active: usize,
     requests_triggered_acl_report: usize,
     requests_triggered_ratelimit_active: usize,
@@ -168,12 +339,15 @@ struct AggregatedCounters {
     bot: usize,
     human: usize,
     challenge: usize,
+    /// requests that a shadow mode policy would have blocked
+    would_block: usize,
 
     // per request
     /// Processing time in microseconds
     processing_time: IntegerMetric,
     ip: Metric<String>,
     session: Metric<String>,
+    api_key: Metric<String>,
     uri: Metric<String>,
     user_agent: Metric<String>,
     country: Metric<String>,
@@ -214,7 +388,7 @@ struct TopN<N> {
 impl<N: Eq + Ord> Default for TopN<N> {
     fn default() -> Self {
         Self {
-            k: *TOP_AMOUNT * 2,
+            k: top_amount() * 2,
             counters: Default::default(),
         }
     }
@@ -227,7 +401,7 @@ impl<N: Ord> TopN<N> {
 
         match self.counters.entry(n) {
             Entry::Occupied(mut item) => {
-                *item.get_mut() += 1;
+                sat_inc(item.get_mut());
                 counted = true;
             }
             Entry::Vacant(slot) => {
@@ -240,7 +414,7 @@ impl<N: Ord> TopN<N> {
 
         if !counted {
             self.counters.retain(|_, v| {
-                *v -= 1;
+                *v = v.saturating_sub(1);
                 *v != 0
             });
         }
@@ -260,7 +434,7 @@ impl<N: Eq + Serialize> Serialize for TopN<N> {
             .collect::<Vec<_>>();
         v.sort_by(|a, b| b.value.cmp(&a.value));
 
-        serializer.collect_seq(v.iter().take(*TOP_AMOUNT))
+        serializer.collect_seq(v.iter().take(top_amount()))
     }
 }
 
@@ -276,13 +450,13 @@ impl<N: Eq + std::hash::Hash + std::fmt::Display> Bag<N> {
 
     fn insert(&mut self, n: N, amount: usize) {
         let entry = self.inner.entry(n).or_default();
-        *entry += amount;
+        sat_add(entry, amount);
     }
 
     fn sorted_to_value(v: Vec<(String, usize)>) -> Value {
         Value::Array(
             v.into_iter()
-                .take(*TOP_AMOUNT)
+                .take(top_amount())
                 .map(|(k, v)| {
                     let mut mp = serde_json::Map::new();
                     mp.insert("key".into(), Value::String(k));
@@ -325,11 +499,11 @@ struct Metric<T: Eq + Clone + std::hash::Hash> {
 impl<T: Ord + Clone + std::hash::Hash> Default for Metric<T> {
     fn default() -> Self {
         Self {
-            unique: HyperLogLog::new(*HYPERLOGLOG_SIZE),
+            unique: HyperLogLog::new(hyperloglog_size()),
             unique_b: Arp {
-                pass: HyperLogLog::new(*HYPERLOGLOG_SIZE),
-                active: HyperLogLog::new(*HYPERLOGLOG_SIZE),
-                report: HyperLogLog::new(*HYPERLOGLOG_SIZE),
+                pass: HyperLogLog::new(hyperloglog_size()),
+                active: HyperLogLog::new(hyperloglog_size()),
+                report: HyperLogLog::new(hyperloglog_size()),
             },
             top: Default::default(),
         }
@@ -387,7 +561,7 @@ impl<N: Eq + std::hash::Hash, B: Eq + std::hash::Hash> UniqueTopNBy<N, B> {
         let entry = self
             .inner
             .entry(n)
-            .or_insert_with(|| HyperLogLog::new(*HYPERLOGLOG_SIZE));
+            .or_insert_with(|| HyperLogLog::new(hyperloglog_size()));
         entry.add(by);
     }
 }
@@ -406,7 +580,7 @@ impl<N: Ord + std::hash::Hash + Serialize, B: Eq + std::hash::Hash> Serialize fo
             })
             .collect::<Vec<_>>();
         content.sort_by(|a, b| b.value.cmp(&a.value));
-        serializer.collect_seq(content.into_iter().take(*TOP_AMOUNT))
+        serializer.collect_seq(content.into_iter().take(top_amount()))
     }
 }
 
@@ -431,10 +605,10 @@ impl Default for IntegerMetric {
 
 impl IntegerMetric {
     fn increment(&mut self, sample: i64) {
-        self.n_sample += 1;
+        sat_inc_u64(&mut self.n_sample);
         self.min = self.min.min(sample);
         self.max = self.max.max(sample);
-        self.total += sample;
+        sat_add_i64(&mut self.total, sample);
     }
 
     fn average(&self) -> f64 {
@@ -510,7 +684,7 @@ impl AggregatedCounters {
         tags: &Tags,
         bytes_sent: Option<usize>,
     ) {
-        self.hits += 1;
+        sat_inc(&mut self.hits);
 
         let mut blocked = false;
         let mut skipped = false;
@@ -526,7 +700,7 @@ impl AggregatedCounters {
                     false
                 }
                 RawActionType::Monitor => false,
-                RawActionType::Custom | RawActionType::Challenge | RawActionType::Ichallenge => {
+                RawActionType::Custom | RawActionType::Ban | RawActionType::Challenge | RawActionType::Ichallenge => {
                     blocked = true;
                     true
                 }
@@ -534,48 +708,48 @@ impl AggregatedCounters {
             match &r.initiator {
                 GlobalFilter => {
                     if this_blocked {
-                        self.requests_triggered_globalfilter_active += 1;
+                        sat_inc(&mut self.requests_triggered_globalfilter_active);
                     } else {
-                        self.requests_triggered_globalfilter_report += 1;
+                        sat_inc(&mut self.requests_triggered_globalfilter_report);
                     }
                 }
                 Acl { tags: _, stage } => {
                     if this_blocked {
                         acl_blocked = true;
-                        self.requests_triggered_acl_active += 1;
+                        sat_inc(&mut self.requests_triggered_acl_active);
                         if stage == &crate::interface::AclStage::DenyBot {
-                            self.challenge += 1;
+                            sat_inc(&mut self.challenge);
                         }
                     } else {
                         acl_report = true;
-                        self.requests_triggered_acl_report += 1;
+                        sat_inc(&mut self.requests_triggered_acl_report);
                     }
                 }
                 Phase01Fail(_) => (),
                 Phase02 => {
                     if this_blocked {
-                        self.requests_triggered_acl_active += 1;
+                        sat_inc(&mut self.requests_triggered_acl_active);
                     } else {
-                        self.requests_triggered_acl_report += 1;
+                        sat_inc(&mut self.requests_triggered_acl_report);
                     }
-                    self.challenge += 1;
+                    sat_inc(&mut self.challenge);
                 }
                 Limit { threshold: _ } => {
                     if this_blocked {
-                        self.requests_triggered_ratelimit_active += 1;
+                        sat_inc(&mut self.requests_triggered_ratelimit_active);
                     } else {
-                        self.requests_triggered_ratelimit_report += 1;
+                        sat_inc(&mut self.requests_triggered_ratelimit_report);
                     }
                 }
 
                 ContentFilter { ruleid, risk_level } => {
                     let cursor = if this_blocked {
                         cf_blocked = true;
-                        self.requests_triggered_cf_active += 1;
+                        sat_inc(&mut self.requests_triggered_cf_active);
                         ArpCursor::Active
                     } else {
                         cf_report = true;
-                        self.requests_triggered_cf_report += 1;
+                        sat_inc(&mut self.requests_triggered_cf_report);
                         ArpCursor::Report
                     };
                     self.ruleid.get_mut(cursor).inc(ruleid.clone());
@@ -583,9 +757,30 @@ impl AggregatedCounters {
                 }
                 Restriction { .. } => {
                     if this_blocked {
-                        self.requests_triggered_restriction_active += 1;
+                        sat_inc(&mut self.requests_triggered_restriction_active);
                     } else {
-                        self.requests_triggered_restriction_report += 1;
+                        sat_inc(&mut self.requests_triggered_restriction_report);
+                    }
+                }
+                Allowlist { .. } => {
+                    if this_blocked {
+                        sat_inc(&mut self.requests_triggered_allowlist_active);
+                    } else {
+                        sat_inc(&mut self.requests_triggered_allowlist_report);
+                    }
+                }
+                Compliance { .. } => {
+                    if this_blocked {
+                        sat_inc(&mut self.requests_triggered_compliance_active);
+                    } else {
+                        sat_inc(&mut self.requests_triggered_compliance_report);
+                    }
+                }
+                External { .. } => {
+                    if this_blocked {
+                        sat_inc(&mut self.requests_triggered_external_active);
+                    } else {
+                        sat_inc(&mut self.requests_triggered_external_report);
                     }
                 }
             }
@@ -596,34 +791,40 @@ impl AggregatedCounters {
                     self.location.get_mut(ArpCursor::Report)
                 };
                 match loc {
-                    Location::Body => aggloc.body += 1,
-                    Location::Attributes => aggloc.attrs += 1,
-                    Location::Uri => aggloc.uri += 1,
-                    Location::Headers => aggloc.headers += 1,
+                    Location::Body => sat_inc(&mut aggloc.body),
+                    Location::Attributes => sat_inc(&mut aggloc.attrs),
+                    Location::Uri => sat_inc(&mut aggloc.uri),
+                    Location::Headers => sat_inc(&mut aggloc.headers),
                     Location::UriArgumentValue(_, _)
                     | Location::RefererArgumentValue(_, _)
                     | Location::BodyArgumentValue(_, _)
                     | Location::BodyArgument(_)
                     | Location::RefererArgument(_)
-                    | Location::UriArgument(_) => aggloc.args += 1,
+                    | Location::UriArgument(_) => sat_inc(&mut aggloc.args),
                     Location::Request => (),
-                    Location::Ip => aggloc.attrs += 1,
-                    Location::Pathpart(_) | Location::PathpartValue(_, _) => aggloc.uri += 1,
+                    Location::Ip => sat_inc(&mut aggloc.attrs),
+                    Location::Pathpart(_) | Location::PathpartValue(_, _) => sat_inc(&mut aggloc.uri),
                     Location::Header(_)
                     | Location::HeaderValue(_, _)
                     | Location::RefererPath
                     | Location::RefererPathpart(_)
-                    | Location::RefererPathpartValue(_, _) => aggloc.headers += 1,
-                    Location::Cookies | Location::Cookie(_) | Location::CookieValue(_, _) => aggloc.headers += 1,
-                    Location::Plugins | Location::Plugin(_) | Location::PluginValue(_, _) => aggloc.plugins += 1,
+                    | Location::RefererPathpartValue(_, _) => sat_inc(&mut aggloc.headers),
+                    Location::Cookies | Location::Cookie(_) | Location::CookieValue(_, _) => sat_inc(&mut aggloc.headers),
+                    Location::Plugins | Location::Plugin(_) | Location::PluginValue(_, _) => sat_inc(&mut aggloc.plugins),
                 }
             }
         }
-        blocked &= !skipped;
+        // shadow mode: the reasons keep their blocking action, but the
+        // request went through; count it as reported, not blocked
+        let shadowed = dec.would_block;
+        if shadowed {
+            sat_inc(&mut self.would_block);
+        }
+        blocked &= !skipped && !shadowed;
         acl_report |= acl_blocked & !skipped;
-        acl_blocked &= !skipped;
+        acl_blocked &= !skipped && !shadowed;
         cf_report |= cf_blocked & !skipped;
-        cf_blocked &= !skipped;
+        cf_blocked &= !skipped && !shadowed;
 
         let acl_cursor = if acl_blocked {
             ArpCursor::Active
@@ -654,7 +855,7 @@ impl AggregatedCounters {
         self.cfid
             .get_mut(cf_cursor)
             .inc(rinfo.rinfo.secpolicy.content_filter_profile.id.to_string());
-        *self.requests.get_mut(cursor) += 1;
+        sat_inc(self.requests.get_mut(cursor));
         self.authority.get_mut(cursor).inc(rinfo.rinfo.host.to_string());
         let top_tags = self.top_tags.get_mut(cursor);
 
@@ -662,10 +863,10 @@ impl AggregatedCounters {
         for tag in tags.tags.keys() {
             match tag.as_str() {
                 "all" => (),
-                "bot" => self.bot += 1,
+                "bot" => sat_inc(&mut self.bot),
                 "human" => {
                     human = true;
-                    self.human += 1
+                    sat_inc(&mut self.human)
                 }
                 tg => match tg.split_once(':') {
                     None => top_tags.inc(tg.to_string()),
@@ -693,9 +894,23 @@ impl AggregatedCounters {
             self.processing_time.increment(processing_time)
         }
 
-        self.ip.inc(&rinfo.rinfo.geoip.ipstr, cursor);
+        // aggregated exports follow the same privacy rules as request logs:
+        // uri argument values are masked, and IPs hashed with the masking seed
+        let profile = &rinfo.rinfo.secpolicy.content_filter_profile;
+        let (ipstr, uri) = if profile.mask_aggregates {
+            (
+                masker(&profile.masking_seed, &rinfo.rinfo.geoip.ipstr),
+                mask_uri_args(&profile.masking_seed, &rinfo.rinfo.qinfo.uri),
+            )
+        } else {
+            (rinfo.rinfo.geoip.ipstr.clone(), rinfo.rinfo.qinfo.uri.clone())
+        };
+        self.ip.inc(&ipstr, cursor);
         self.session.inc(&rinfo.session, cursor);
-        self.uri.inc(&rinfo.rinfo.qinfo.uri, cursor);
+        if let Some(api_key) = &rinfo.api_key {
+            self.api_key.inc(api_key, cursor);
+        }
+        self.uri.inc(&uri, cursor);
         if let Some(user_agent) = &rinfo.headers.get("user-agent") {
             self.user_agent.inc(user_agent, cursor);
         }
@@ -721,12 +936,34 @@ impl AggregatedCounters {
         self.cookies_amount.inc(rinfo.cookies.len());
         self.headers_amount.inc(rinfo.headers.len());
 
-        self.ip_per_uri
-            .add(rinfo.rinfo.geoip.ipstr.clone(), &rinfo.rinfo.qinfo.uri);
-        self.uri_per_ip
-            .add(rinfo.rinfo.qinfo.uri.clone(), &rinfo.rinfo.geoip.ipstr);
-        self.session_per_uri.add(rinfo.session.clone(), &rinfo.rinfo.qinfo.uri);
-        self.uri_per_session.add(rinfo.rinfo.qinfo.uri.clone(), &rinfo.session);
+        self.ip_per_uri.add(ipstr.clone(), &uri);
+        self.uri_per_ip.add(uri.clone(), &ipstr);
+        self.session_per_uri.add(rinfo.session.clone(), &uri);
+        self.uri_per_session.add(uri, &rinfo.session);
+    }
+}
+
+/// seconds since the counters were last reset
+fn uptime_secs() -> i64 {
+    Utc::now().signed_duration_since(*COUNTERS_RESET_AT).num_seconds().max(0)
+}
+
+/// masks the query argument values of an uri with the policy masking seed,
+/// keeping the path and the argument names intact
+fn mask_uri_args(seed: &MaskingSeed, uri: &str) -> String {
+    match uri.split_once('?') {
+        None => uri.to_string(),
+        Some((path, query)) => {
+            let masked = query
+                .split('&')
+                .map(|kv| match kv.split_once('=') {
+                    None => kv.to_string(),
+                    Some((k, v)) => format!("{}={}", k, masker(seed, v)),
+                })
+                .collect::<Vec<_>>()
+                .join("&");
+            format!("{}?{}", path, masked)
+        }
     }
 }
 
@@ -749,6 +986,10 @@ fn serialize_counters(e: &AggregatedCounters) -> Value {
     content.insert("bot".into(), Value::Number(serde_json::Number::from(e.bot)));
     content.insert("human".into(), Value::Number(serde_json::Number::from(e.human)));
     content.insert("challenge".into(), Value::Number(serde_json::Number::from(e.challenge)));
+    content.insert(
+        "would_block".into(),
+        Value::Number(serde_json::Number::from(e.would_block)),
+    );
 
     e.location.serialize(&mut content, "section_");
     e.ruleid.serialize(&mut content, "top_ruleid_");
@@ -779,6 +1020,30 @@ fn serialize_counters(e: &AggregatedCounters) -> Value {
         "requests_triggered_restriction_report".into(),
         Value::Number(serde_json::Number::from(e.requests_triggered_restriction_report)),
     );
+    content.insert(
+        "requests_triggered_allowlist_active".into(),
+        Value::Number(serde_json::Number::from(e.requests_triggered_allowlist_active)),
+    );
+    content.insert(
+        "requests_triggered_allowlist_report".into(),
+        Value::Number(serde_json::Number::from(e.requests_triggered_allowlist_report)),
+    );
+    content.insert(
+        "requests_triggered_compliance_active".into(),
+        Value::Number(serde_json::Number::from(e.requests_triggered_compliance_active)),
+    );
+    content.insert(
+        "requests_triggered_compliance_report".into(),
+        Value::Number(serde_json::Number::from(e.requests_triggered_compliance_report)),
+    );
+    content.insert(
+        "requests_triggered_external_active".into(),
+        Value::Number(serde_json::Number::from(e.requests_triggered_external_active)),
+    );
+    content.insert(
+        "requests_triggered_external_report".into(),
+        Value::Number(serde_json::Number::from(e.requests_triggered_external_report)),
+    );
     content.insert(
         "requests_triggered_cf_active".into(),
         Value::Number(serde_json::Number::from(e.requests_triggered_cf_active)),
@@ -808,6 +1073,7 @@ fn serialize_counters(e: &AggregatedCounters) -> Value {
     content.insert("bytes_sent".into(), e.bytes_sent.to_json());
     e.ip.serialize_map("ip", &mut content);
     e.session.serialize_map("session", &mut content);
+    e.api_key.serialize_map("api_key", &mut content);
     e.uri.serialize_map("uri", &mut content);
     e.user_agent.serialize_map("user_agent", &mut content);
     e.country.serialize_map("country", &mut content);
@@ -846,9 +1112,8 @@ fn serialize_counters(e: &AggregatedCounters) -> Value {
 }
 
 fn serialize_entry(sample: i64, hdr: &AggregationKey, counters: &AggregatedCounters) -> Value {
-    let naive_dt =
-        chrono::NaiveDateTime::from_timestamp_opt(sample * *SAMPLE_DURATION, 0).unwrap_or(chrono::NaiveDateTime::MIN);
-    let timestamp: chrono::DateTime<chrono::Utc> = chrono::DateTime::from_utc(naive_dt, chrono::Utc);
+    let timestamp = chrono::DateTime::<chrono::Utc>::from_timestamp(sample * sample_duration(), 0)
+        .unwrap_or(chrono::DateTime::<chrono::Utc>::MIN_UTC);
     let mut content = serde_json::Map::new();
 
     content.insert(
@@ -866,44 +1131,94 @@ fn serialize_entry(sample: i64, hdr: &AggregationKey, counters: &AggregatedCount
     content.insert("secpolentryid".into(), Value::String(hdr.secpolentryid.clone()));
     content.insert("branch".into(), Value::String(hdr.branch.clone()));
     content.insert("planet_name".into(), Value::String(PLANET_NAME.clone()));
+    // counters only live in memory: exposing when they last started from zero
+    // lets consumers tell a restart apart from a genuine drop in traffic
+    content.insert(
+        "counters_reset_at".into(),
+        serde_json::to_value(*COUNTERS_RESET_AT).unwrap_or_else(|_| Value::String("??".into())),
+    );
+    content.insert("uptime_secs".into(), Value::Number(serde_json::Number::from(uptime_secs())));
+    content.insert(
+        "counter_overflows".into(),
+        Value::Number(serde_json::Number::from(COUNTER_OVERFLOWS.load(Ordering::Relaxed))),
+    );
     content.insert("counters".into(), serialize_counters(counters));
     Value::Object(content)
 }
 
 fn prune_old_values<A>(amp: &mut HashMap<AggregationKey, BTreeMap<i64, A>>, cursample: i64) {
-    for (_, mp) in amp.iter_mut() {
+    for (hdr, mp) in amp.iter_mut() {
+        let kept = branch_samples_kept(&hdr.branch);
         #[allow(clippy::needless_collect)]
         let keys: Vec<i64> = mp.keys().copied().collect();
         for k in keys.into_iter() {
-            if k <= cursample - *SAMPLES_KEPT {
+            if k <= cursample - kept {
                 mp.remove(&k);
             }
         }
     }
 }
 
+/// selects the shard holding a given key, a key always maps to the same shard
+/// so that merging the shards is a plain concatenation
+fn shard_of(key: &AggregationKey) -> usize {
+    use std::collections::hash_map::DefaultHasher;
+    use std::hash::Hasher;
+    let mut hasher = DefaultHasher::new();
+    key.hash(&mut hasher);
+    hasher.finish() as usize % AGGREGATION_SHARDS
+}
+
 /// displays the Nth samples of aggregated data
 pub async fn aggregated_values() -> String {
-    let mut guard = AGGREGATED.lock().await;
     let timestamp = chrono::Utc::now().timestamp();
-    let cursample = timestamp / *SAMPLE_DURATION;
-    // first, prune excess data
-    prune_old_values(&mut guard, cursample);
-    let timerange = || 1 + cursample - *SAMPLES_KEPT..=cursample;
-
-    let entries: Vec<Value> = guard
-        .iter()
-        .flat_map(|(hdr, v)| {
-            let range = if !v.is_empty() {
-                timerange().collect()
+    let cursample = timestamp / sample_duration();
+    let timerange = || 1 + cursample - samples_kept()..=cursample;
+
+    let mut entries: Vec<Value> = Vec::new();
+    let mut seen: std::collections::HashSet<(String, String)> = std::collections::HashSet::new();
+    for shard in AGGREGATED.iter() {
+        let mut guard = shard.lock().await;
+        // first, prune excess data
+        prune_old_values(&mut guard, cursample);
+        if heartbeat() {
+            seen.extend(guard.keys().map(|hdr| (hdr.secpolid.clone(), hdr.secpolentryid.clone())));
+        }
+        entries.extend(guard.iter().flat_map(|(hdr, v)| {
+            let range: Vec<i64> = if !v.is_empty() {
+                (1 + cursample - branch_samples_kept(&hdr.branch)..=cursample).collect()
             } else {
                 Vec::new()
             };
             range
                 .into_iter()
                 .map(move |secs| serialize_entry(secs, hdr, v.get(&secs).unwrap_or(&EMPTY_AGGREGATED_DATA)))
-        })
-        .collect();
+        }));
+    }
+    if heartbeat() {
+        // emit zero-valued samples for configured policy entries that received
+        // no traffic, so that dashboards do not mistake them for missing data
+        if let Ok(cfg) = crate::config::CONFIGS.config.read() {
+            let proxy = cfg.container_name.clone();
+            for hostmap in cfg.securitypolicies_map.values() {
+                for secpol in hostmap.entries.iter().map(|m| &m.inner).chain(hostmap.default.iter()) {
+                    let polid = secpol.policy.id.clone();
+                    let entryid = secpol.entry.id.clone();
+                    if !seen.insert((polid.clone(), entryid.clone())) {
+                        continue;
+                    }
+                    let hdr = AggregationKey {
+                        proxy: proxy.clone(),
+                        secpolid: polid,
+                        secpolentryid: entryid,
+                        branch: "-".to_string(),
+                    };
+                    entries
+                        .extend(timerange().map(|secs| serialize_entry(secs, &hdr, &AggregatedCounters::default())));
+                }
+            }
+        }
+    }
     let entries = if entries.is_empty() {
         let proxy = crate::config::CONFIGS
             .config
@@ -946,7 +1261,7 @@ pub async fn aggregate(
     bytes_sent: Option<usize>,
 ) {
     let seconds = rinfo.timestamp.timestamp();
-    let sample = seconds / *SAMPLE_DURATION;
+    let sample = seconds / sample_duration();
     let branch_tag = tags
         .inner()
         .keys()
@@ -957,9 +1272,9 @@ pub async fn aggregate(
         proxy: rinfo.rinfo.container_name.clone(),
         secpolid: rinfo.rinfo.secpolicy.policy.id.to_string(),
         secpolentryid: rinfo.rinfo.secpolicy.entry.id.to_string(),
-        branch: branch_tag.to_string(),
+        branch: fold_branch(branch_tag),
     };
-    let mut guard = AGGREGATED.lock().await;
+    let mut guard = AGGREGATED[shard_of(&key)].lock().await;
     prune_old_values(&mut guard, sample);
     let entry_hdrs = guard.entry(key).or_default();
     let entry = entry_hdrs.entry(sample).or_default();
@@ -18,7 +18,7 @@ pub enum AclStage {
     Deny,
 }
 
-#[derive(Debug, Clone, Hash, PartialEq, Eq)]
+#[derive(Debug, Clone, PartialEq)]
 pub enum Initiator {
     GlobalFilter,
     Acl {
@@ -37,6 +37,25 @@ pub enum Initiator {
         actual: String,
         expected: String,
     },
+    /// positive security allowlist, tpe is the dimension that was not allowed
+    Allowlist {
+        tpe: &'static str,
+        value: String,
+    },
+    /// embargo compliance rules, tpe is the matched dimension (country or
+    /// region) and version identifies the rule set that produced the block
+    Compliance {
+        tpe: &'static str,
+        value: String,
+        version: String,
+    },
+    /// third-party or experimental stages: kind identifies the subsystem
+    /// (for example "schema_validation") and details carries its structured
+    /// report
+    External {
+        kind: String,
+        details: Value,
+    },
 
     // TODO, these two are not serialized for now
     Phase01Fail(String),
@@ -54,6 +73,9 @@ impl std::fmt::Display for Initiator {
             Phase01Fail(r) => write!(f, "grasshopper phase 1 error: {}", r),
             Phase02 => write!(f, "grasshopper phase 2"),
             Restriction { tpe, actual, expected } => write!(f, "restricted {}[{}/{}]", tpe, actual, expected),
+            Allowlist { tpe, value } => write!(f, "not allowlisted {}[{}]", tpe, value),
+            Compliance { tpe, value, version } => write!(f, "embargo {}[{}] rules={}", tpe, value, version),
+            External { kind, .. } => write!(f, "external stage {}", kind),
         }
     }
 }
@@ -66,6 +88,9 @@ pub enum InitiatorKind {
     GlobalFilter,
     ContentFilter,
     Restriction,
+    Allowlist,
+    Compliance,
+    External,
 }
 
 impl Initiator {
@@ -79,6 +104,59 @@ impl Initiator {
             Initiator::Phase01Fail(_) => None,
             Initiator::Phase02 => None,
             Initiator::Restriction { .. } => Some(Restriction),
+            Initiator::Allowlist { .. } => Some(Allowlist),
+            Initiator::Compliance { .. } => Some(Compliance),
+            Initiator::External { .. } => Some(External),
+        }
+    }
+
+    /// stable numeric code identifying the kind of trigger, for programmatic
+    /// handling by clients. These values are part of the external API and must
+    /// never be reassigned, only new ones can be added.
+    pub fn reason_code(&self) -> u32 {
+        match self {
+            Initiator::GlobalFilter => 1000,
+            Initiator::Acl { stage, .. } => match stage {
+                AclStage::Allow => 2000,
+                AclStage::Bypass => 2001,
+                AclStage::AllowBot => 2002,
+                AclStage::DenyBot => 2003,
+                AclStage::Deny => 2004,
+                AclStage::EnforceDeny => 2005,
+            },
+            Initiator::Limit { .. } => 3000,
+            Initiator::ContentFilter { ruleid, .. } => {
+                if ruleid.starts_with("sqli:") {
+                    4001
+                } else if ruleid == "xss" {
+                    4002
+                } else {
+                    4000
+                }
+            }
+            Initiator::Restriction { tpe, .. } => match *tpe {
+                "restricted" => 5000,
+                "too many" => 5001,
+                "too large" => 5002,
+                "too deep" => 5003,
+                "missing body" => 5004,
+                "malformed body" => 5005,
+                _ => 5999,
+            },
+            Initiator::Allowlist { tpe, .. } => match *tpe {
+                "method" => 7001,
+                "path" => 7002,
+                "content-type" => 7003,
+                _ => 7000,
+            },
+            Initiator::Compliance { tpe, .. } => match *tpe {
+                "country" => 8001,
+                "region" => 8002,
+                _ => 8000,
+            },
+            Initiator::Phase01Fail(_) => 6001,
+            Initiator::Phase02 => 6002,
+            Initiator::External { .. } => 9000,
         }
     }
 
@@ -104,6 +182,21 @@ impl Initiator {
                 map.serialize_entry("actual", actual)?;
                 map.serialize_entry("expected", expected)?;
             }
+            Initiator::Allowlist { tpe, value } => {
+                map.serialize_entry("type", tpe)?;
+                map.serialize_entry("value", value)?;
+            }
+            Initiator::Compliance { tpe, value, version } => {
+                map.serialize_entry("type", tpe)?;
+                map.serialize_entry("value", value)?;
+                map.serialize_entry("rules_version", version)?;
+            }
+            Initiator::External { kind, details } => {
+                map.serialize_entry("type", kind)?;
+                if details != &Value::Null {
+                    map.serialize_entry("details", details)?;
+                }
+            }
 
             // not serialized
             Initiator::Phase01Fail(r) => {
@@ -118,7 +211,7 @@ impl Initiator {
     }
 }
 
-#[derive(Debug, Clone, PartialEq, Eq)]
+#[derive(Debug, Clone, PartialEq)]
 pub struct BlockReason {
     pub id: String,
     pub name: String,
@@ -162,6 +255,13 @@ impl BlockReason {
     pub fn block_reason_desc(reasons: &[Self]) -> Option<String> {
         reasons.iter().find(|r| r.action.is_final()).map(|r| r.to_string())
     }
+    //get the machine readable code of the blocking reason for this request
+    pub fn block_reason_code(reasons: &[Self]) -> Option<u32> {
+        reasons
+            .iter()
+            .find(|r| r.action.is_final())
+            .map(|r| r.initiator.reason_code())
+    }
     //get the list of all the monitor reasons for this request
     pub fn monitor_reason_desc(reasons: &[Self]) -> Option<Vec<String>> {
         let matching_reasons: Vec<String> = reasons
@@ -195,6 +295,12 @@ impl BlockReason {
         BlockReason::nodetails(id, name, Initiator::Limit { threshold }, action)
     }
 
+    /// a reason reported by a third-party or experimental stage, see
+    /// [`Initiator::External`]
+    pub fn external(id: String, name: String, kind: String, details: Value, action: RawActionType) -> Self {
+        BlockReason::nodetails(id, name, Initiator::External { kind, details }, action)
+    }
+
     pub fn phase01_unknown(reason: &str) -> Self {
         BlockReason::nodetails(
             "phase01".to_string(),
@@ -255,6 +361,28 @@ impl BlockReason {
             extra: Value::Null,
         }
     }
+    pub fn graphql_budget(
+        id: String,
+        name: String,
+        action: RawActionType,
+        counter: &'static str,
+        actual: String,
+        expected: String,
+    ) -> Self {
+        BlockReason {
+            id,
+            name,
+            initiator: Initiator::Restriction {
+                tpe: counter,
+                actual,
+                expected,
+            },
+            location: Location::Body,
+            action,
+            extra_locations: Vec::new(),
+            extra: Value::Null,
+        }
+    }
     pub fn body_missing(id: String, name: String, action: RawActionType) -> Self {
         BlockReason {
             id,
@@ -364,6 +492,20 @@ impl BlockReason {
             extra: Value::Null,
         }
     }
+    pub fn allowlist(id: String, name: String, action: RawActionType, tpe: &'static str, value: String) -> Self {
+        BlockReason::nodetails(id, name, Initiator::Allowlist { tpe, value }, action)
+    }
+    pub fn compliance(action: RawActionType, tpe: &'static str, value: String, version: String) -> Self {
+        BlockReason {
+            id: "embargo".to_string(),
+            name: "embargo".to_string(),
+            initiator: Initiator::Compliance { tpe, value, version },
+            location: Location::Ip,
+            action,
+            extra_locations: Vec::new(),
+            extra: Value::Null,
+        }
+    }
     pub fn restricted(
         id: String,
         name: String,
@@ -386,6 +528,24 @@ impl BlockReason {
             extra: Value::Null,
         }
     }
+    /// automated ban: the client was recorded by a ban action and is
+    /// rejected until the ban expires
+    pub fn banned(action: RawActionType) -> Self {
+        BlockReason {
+            id: "banlist".to_string(),
+            name: "automated ban".to_string(),
+            initiator: Initiator::Restriction {
+                tpe: "ban",
+                actual: "banned".to_string(),
+                expected: "not banned".to_string(),
+            },
+            location: Location::Ip,
+            action,
+            extra_locations: Vec::new(),
+            extra: Value::Null,
+        }
+    }
+
     pub fn acl(id: String, name: String, tags: Tags, stage: AclStage) -> Self {
         let mut tagv = Vec::new();
         let mut locations = HashSet::new();
@@ -411,6 +571,33 @@ impl BlockReason {
         }
     }
 
+    /// canonical form for the logs: duplicate reasons (same initiator,
+    /// location and action) are folded into a single entry carrying a count,
+    /// and entries are sorted so that the serialized order does not depend on
+    /// the evaluation order of the stages
+    pub fn canonicalize<'t>(reasons: &[&'t Self]) -> Vec<CountedBlockReason<'t>> {
+        let mut out: Vec<CountedBlockReason<'t>> = Vec::new();
+        for reason in reasons {
+            match out.iter_mut().find(|c| {
+                c.reason.initiator == reason.initiator
+                    && c.reason.location == reason.location
+                    && c.reason.action == reason.action
+            }) {
+                Some(c) => c.count += 1,
+                None => out.push(CountedBlockReason { reason, count: 1 }),
+            }
+        }
+        out.sort_by_key(|c| {
+            (
+                c.reason.initiator.reason_code(),
+                c.reason.id.clone(),
+                c.reason.name.clone(),
+                c.reason.location.to_string(),
+            )
+        });
+        out
+    }
+
     pub fn regroup<'t>(reasons: &'t [Self]) -> HashMap<InitiatorKind, Vec<&'t Self>> {
         let mut out: HashMap<InitiatorKind, Vec<&'t Self>> = HashMap::new();
 
@@ -431,12 +618,32 @@ impl BlockReason {
         self.initiator.serialize_in_map::<S>(map)?;
         self.location.serialize_with_parent::<S>(map)?;
         map.serialize_entry("action", &self.action)?;
+        map.serialize_entry("reason_code", &self.initiator.reason_code())?;
         map.serialize_entry("trigger_id", &self.id)?;
         map.serialize_entry("trigger_name", &self.name)?;
         Ok(())
     }
 }
 
+/// a deduplicated block reason together with the number of times it was
+/// reported, see [`BlockReason::canonicalize`]
+pub struct CountedBlockReason<'t> {
+    pub reason: &'t BlockReason,
+    pub count: usize,
+}
+
+impl<'t> Serialize for CountedBlockReason<'t> {
+    fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
+    where
+        S: serde::Serializer,
+    {
+        let mut map: <S as serde::Serializer>::SerializeMap = serializer.serialize_map(None)?;
+        self.reason.serialize_in_map::<S>(&mut map)?;
+        map.serialize_entry("count", &self.count)?;
+        map.end()
+    }
+}
+
 pub struct LegacyBlockReason<'t>(&'t BlockReason);
 
 impl<'t> Serialize for LegacyBlockReason<'t> {
@@ -0,0 +1,170 @@
+/* Elasticsearch / OpenSearch bulk export
+
+   When ELASTICSEARCH_ENDPOINT is set (host:port of the cluster), the JSON
+   records produced by `jsonlog` are buffered and flushed to the `_bulk`
+   API as ndjson, into one index per day (ELASTICSEARCH_INDEX_PREFIX,
+   default curiefense-access, giving curiefense-access-YYYY.MM.DD), so
+   that index lifecycle policies can expire old days.
+
+   Records are queued on a bounded channel (ELASTICSEARCH_QUEUE) and
+   flushed from a detached task, either when ELASTICSEARCH_BATCH records
+   are buffered or after ELASTICSEARCH_FLUSH_MS; delivery reuses the
+   `outbound` retry and circuit breaking policy. When the queue is full,
+   records are dropped and counted instead of blocking the request path.
+
+   Setting ELASTICSEARCH_EXPORT_ONLY makes the exporter the only delivery
+   path: `jsonlog` then returns an empty record to the proxy instead of
+   the log blob.
+*/
+
+use lazy_static::lazy_static;
+use std::sync::atomic::{AtomicU64, Ordering};
+
+use crate::outbound::{with_backoff, CircuitBreaker, OutboundConfig};
+
+lazy_static! {
+    static ref ENDPOINT: Option<String> = std::env::var("ELASTICSEARCH_ENDPOINT").ok().filter(|s| !s.is_empty());
+    static ref INDEX_PREFIX: String =
+        std::env::var("ELASTICSEARCH_INDEX_PREFIX").unwrap_or_else(|_| "curiefense-access".to_string());
+    /// maximal number of records waiting for delivery before new ones are dropped
+    static ref QUEUE_SIZE: usize = std::env::var("ELASTICSEARCH_QUEUE")
+        .ok()
+        .and_then(|s| s.parse().ok())
+        .filter(|&n| n > 0)
+        .unwrap_or(4096);
+    /// number of records per bulk request
+    static ref BATCH_SIZE: usize = std::env::var("ELASTICSEARCH_BATCH")
+        .ok()
+        .and_then(|s| s.parse().ok())
+        .filter(|&n| n > 0)
+        .unwrap_or(128);
+    /// maximal milliseconds a buffered record waits before a flush
+    static ref FLUSH_MS: u64 = std::env::var("ELASTICSEARCH_FLUSH_MS")
+        .ok()
+        .and_then(|s| s.parse().ok())
+        .filter(|&n| n > 0)
+        .unwrap_or(2000);
+    /// when set, the exporter replaces the log blob returned to the proxy
+    static ref EXPORT_ONLY: bool = std::env::var("ELASTICSEARCH_EXPORT_ONLY")
+        .map(|s| s.parse().unwrap_or(false))
+        .unwrap_or(false);
+    static ref OUTBOUND: OutboundConfig = OutboundConfig::default();
+    static ref BREAKER: CircuitBreaker = CircuitBreaker::new(&OUTBOUND);
+    static ref SENDER: Option<async_std::channel::Sender<Vec<u8>>> =
+        ENDPOINT.as_ref().map(|endpoint| start_worker(endpoint.clone()));
+}
+
+/// records dropped because the delivery queue was full
+static DROPPED: AtomicU64 = AtomicU64::new(0);
+
+pub fn enabled() -> bool {
+    ENDPOINT.is_some()
+}
+
+/// when true, the exporter is the only delivery path and the log blob is
+/// not returned to the proxy
+pub fn exclusive() -> bool {
+    enabled() && *EXPORT_ONLY
+}
+
+/// amount of records dropped because of backpressure
+pub fn dropped() -> u64 {
+    DROPPED.load(Ordering::Relaxed)
+}
+
+/// queues a serialized log record for bulk delivery, dropping it when the
+/// exporter is disabled or the queue is full
+pub fn ship(record: &[u8]) {
+    if let Some(sender) = &*SENDER {
+        if sender.try_send(record.to_vec()).is_err() {
+            DROPPED.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+}
+
+/// the ndjson body of one bulk request, indexing every record into the
+/// current daily index
+fn bulk_body(records: &[Vec<u8>]) -> Vec<u8> {
+    let index = format!("{}-{}", &*INDEX_PREFIX, chrono::Utc::now().format("%Y.%m.%d"));
+    let action = format!("{{\"index\":{{\"_index\":\"{}\"}}}}\n", index);
+    let mut body = Vec::new();
+    for record in records {
+        body.extend_from_slice(action.as_bytes());
+        body.extend_from_slice(record);
+        body.push(b'\n');
+    }
+    body
+}
+
+fn start_worker(endpoint: String) -> async_std::channel::Sender<Vec<u8>> {
+    let (sender, receiver) = async_std::channel::bounded(*QUEUE_SIZE);
+    async_std::task::spawn(worker(endpoint, receiver));
+    sender
+}
+
+/// drains the queue into bulk requests, flushing on batch size or age;
+/// failed batches are dropped after the `outbound` retries, so that a dead
+/// cluster can not make the queue back up into the request path
+async fn worker(endpoint: String, receiver: async_std::channel::Receiver<Vec<u8>>) {
+    let flush_after = std::time::Duration::from_millis(*FLUSH_MS);
+    loop {
+        let first = match receiver.recv().await {
+            Ok(r) => r,
+            Err(_) => return,
+        };
+        let mut batch = vec![first];
+        let deadline = std::time::Instant::now() + flush_after;
+        while batch.len() < *BATCH_SIZE {
+            let left = match deadline.checked_duration_since(std::time::Instant::now()) {
+                Some(d) => d,
+                None => break,
+            };
+            match async_std::future::timeout(left, receiver.recv()).await {
+                Ok(Ok(r)) => batch.push(r),
+                _ => break,
+            }
+        }
+        let body = bulk_body(&batch);
+        let _ = with_backoff(&OUTBOUND, &BREAKER, |_| post_bulk(&endpoint, &body)).await;
+    }
+}
+
+/// posts one bulk request, returning an error description
+async fn post_bulk(endpoint: &str, body: &[u8]) -> Result<(), String> {
+    use async_std::io::prelude::{ReadExt, WriteExt};
+    use async_std::net::TcpStream;
+
+    let mut stream = TcpStream::connect(endpoint).await.map_err(|rr| rr.to_string())?;
+    let request = format!(
+        "POST /_bulk HTTP/1.1\r\nHost: {}\r\nContent-Type: application/x-ndjson\r\nContent-Length: {}\r\nConnection: close\r\n\r\n",
+        endpoint,
+        body.len()
+    );
+    stream.write_all(request.as_bytes()).await.map_err(|rr| rr.to_string())?;
+    stream.write_all(body).await.map_err(|rr| rr.to_string())?;
+    let mut answer = [0u8; 32];
+    let n = stream.read(&mut answer).await.map_err(|rr| rr.to_string())?;
+    let status = std::str::from_utf8(&answer[..n]).unwrap_or_default();
+    match status.split(' ').nth(1) {
+        Some(code) if code.starts_with('2') => Ok(()),
+        _ => Err(format!("cluster answered {}", status.lines().next().unwrap_or("?"))),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn bulk_body_format() {
+        let body = bulk_body(&[b"{\"a\":1}".to_vec(), b"{\"b\":2}".to_vec()]);
+        let text = String::from_utf8(body).unwrap();
+        let lines: Vec<&str> = text.lines().collect();
+        assert_eq!(lines.len(), 4);
+        assert!(lines[0].starts_with("{\"index\":{\"_index\":\"curiefense-access-"));
+        assert_eq!(lines[1], "{\"a\":1}");
+        assert_eq!(lines[0], lines[2]);
+        assert_eq!(lines[3], "{\"b\":2}");
+        assert!(text.ends_with('\n'));
+    }
+}
@@ -0,0 +1,224 @@
+/* log shipping sink
+
+   When LOG_SINK_ENDPOINT is set, every JSON record produced by `jsonlog`
+   is also forwarded to a collector, so that log delivery does not depend
+   on the embedding proxy. Supported endpoint schemes:
+
+     udp://host:port           raw JSON, one record per datagram
+     tcp://host:port           raw JSON, newline delimited
+     syslog+udp://host:port    RFC 5424 over UDP
+     syslog+tcp://host:port    RFC 5424 over TCP, octet-counted framing
+
+   Records are queued on a bounded channel and shipped in batches from a
+   detached task: when the queue is full, records are dropped and counted
+   instead of blocking the request path. LOG_SINK_QUEUE sets the queue
+   size, LOG_SINK_BATCH the maximal number of records per flush.
+*/
+
+use lazy_static::lazy_static;
+use std::sync::atomic::{AtomicU64, Ordering};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum SinkFormat {
+    /// the JSON record is sent as-is
+    Raw,
+    /// the JSON record is wrapped in an RFC 5424 syslog message
+    Syslog,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum SinkTransport {
+    Udp,
+    Tcp,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct SinkConfig {
+    format: SinkFormat,
+    transport: SinkTransport,
+    addr: String,
+}
+
+/// parses an endpoint of the form `scheme://host:port`
+fn parse_endpoint(raw: &str) -> Option<SinkConfig> {
+    let (scheme, addr) = raw.split_once("://")?;
+    if addr.is_empty() {
+        return None;
+    }
+    let (format, transport) = match scheme {
+        "udp" => (SinkFormat::Raw, SinkTransport::Udp),
+        "tcp" => (SinkFormat::Raw, SinkTransport::Tcp),
+        "syslog+udp" => (SinkFormat::Syslog, SinkTransport::Udp),
+        "syslog+tcp" => (SinkFormat::Syslog, SinkTransport::Tcp),
+        _ => return None,
+    };
+    Some(SinkConfig {
+        format,
+        transport,
+        addr: addr.to_string(),
+    })
+}
+
+lazy_static! {
+    static ref CONFIG: Option<SinkConfig> = std::env::var("LOG_SINK_ENDPOINT")
+        .ok()
+        .as_deref()
+        .and_then(parse_endpoint);
+    /// maximal number of records waiting for delivery before new ones are dropped
+    static ref QUEUE_SIZE: usize = std::env::var("LOG_SINK_QUEUE")
+        .ok()
+        .and_then(|s| s.parse().ok())
+        .filter(|&n| n > 0)
+        .unwrap_or(1024);
+    /// maximal number of records shipped per flush
+    static ref BATCH_SIZE: usize = std::env::var("LOG_SINK_BATCH")
+        .ok()
+        .and_then(|s| s.parse().ok())
+        .filter(|&n| n > 0)
+        .unwrap_or(16);
+    /// hostname advertised in the syslog header
+    static ref HOSTNAME: String = std::env::var("HOSTNAME").unwrap_or_else(|_| "-".to_string());
+    static ref SENDER: Option<async_std::channel::Sender<Vec<u8>>> =
+        CONFIG.as_ref().map(|cfg| start_worker(cfg.clone()));
+}
+
+/// records dropped because the delivery queue was full, and delivery errors
+static DROPPED: AtomicU64 = AtomicU64::new(0);
+static ERRORS: AtomicU64 = AtomicU64::new(0);
+
+pub fn enabled() -> bool {
+    CONFIG.is_some()
+}
+
+/// amount of records dropped because of backpressure
+pub fn dropped() -> u64 {
+    DROPPED.load(Ordering::Relaxed)
+}
+
+/// amount of failed delivery attempts
+pub fn delivery_errors() -> u64 {
+    ERRORS.load(Ordering::Relaxed)
+}
+
+/// queues a serialized log record for delivery, dropping it when the sink
+/// is disabled or the queue is full
+pub fn ship(record: &[u8]) {
+    if let Some(sender) = &*SENDER {
+        if sender.try_send(record.to_vec()).is_err() {
+            DROPPED.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+}
+
+/// RFC 5424 wrapping of a record, as facility local0 / severity
+/// informational, with the JSON record as the message
+fn syslog_frame(record: &[u8]) -> Vec<u8> {
+    let header = format!(
+        "<134>1 {} {} curiefense - - - ",
+        chrono::Utc::now().to_rfc3339_opts(chrono::SecondsFormat::Millis, true),
+        &*HOSTNAME
+    );
+    let mut out = header.into_bytes();
+    out.extend_from_slice(record);
+    out
+}
+
+fn start_worker(cfg: SinkConfig) -> async_std::channel::Sender<Vec<u8>> {
+    let (sender, receiver) = async_std::channel::bounded(*QUEUE_SIZE);
+    async_std::task::spawn(worker(cfg, receiver));
+    sender
+}
+
+/// drains the queue, shipping records in batches; delivery errors are
+/// counted and the batch is dropped, so that a dead collector can not
+/// make the queue back up into the request path
+async fn worker(cfg: SinkConfig, receiver: async_std::channel::Receiver<Vec<u8>>) {
+    loop {
+        let first = match receiver.recv().await {
+            Ok(r) => r,
+            Err(_) => return,
+        };
+        let mut batch = vec![first];
+        while batch.len() < *BATCH_SIZE {
+            match receiver.try_recv() {
+                Ok(r) => batch.push(r),
+                Err(_) => break,
+            }
+        }
+        if send_batch(&cfg, &batch).await.is_err() {
+            ERRORS.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+}
+
+async fn send_batch(cfg: &SinkConfig, batch: &[Vec<u8>]) -> Result<(), std::io::Error> {
+    match cfg.transport {
+        SinkTransport::Udp => {
+            let socket = async_std::net::UdpSocket::bind("0.0.0.0:0").await?;
+            for record in batch {
+                let frame = match cfg.format {
+                    SinkFormat::Raw => record.clone(),
+                    SinkFormat::Syslog => syslog_frame(record),
+                };
+                socket.send_to(&frame, &cfg.addr).await?;
+            }
+        }
+        SinkTransport::Tcp => {
+            use async_std::io::prelude::WriteExt;
+            let mut stream = async_std::net::TcpStream::connect(&cfg.addr).await?;
+            for record in batch {
+                match cfg.format {
+                    SinkFormat::Raw => {
+                        // newline delimited JSON
+                        stream.write_all(record).await?;
+                        stream.write_all(b"\n").await?;
+                    }
+                    SinkFormat::Syslog => {
+                        // RFC 6587 octet-counted framing
+                        let msg = syslog_frame(record);
+                        stream.write_all(format!("{} ", msg.len()).as_bytes()).await?;
+                        stream.write_all(&msg).await?;
+                    }
+                }
+            }
+            stream.flush().await?;
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn endpoint_parsing() {
+        assert_eq!(
+            parse_endpoint("udp://collector:5514"),
+            Some(SinkConfig {
+                format: SinkFormat::Raw,
+                transport: SinkTransport::Udp,
+                addr: "collector:5514".to_string(),
+            })
+        );
+        assert_eq!(
+            parse_endpoint("syslog+tcp://10.0.0.1:601"),
+            Some(SinkConfig {
+                format: SinkFormat::Syslog,
+                transport: SinkTransport::Tcp,
+                addr: "10.0.0.1:601".to_string(),
+            })
+        );
+        assert_eq!(parse_endpoint("http://collector:80"), None);
+        assert_eq!(parse_endpoint("udp://"), None);
+        assert_eq!(parse_endpoint("collector:5514"), None);
+    }
+
+    #[test]
+    fn syslog_framing() {
+        let frame = syslog_frame(b"{\"a\":1}");
+        let msg = String::from_utf8(frame).unwrap();
+        assert!(msg.starts_with("<134>1 "));
+        assert!(msg.ends_with(" curiefense - - - {\"a\":1}"));
+    }
+}
@@ -0,0 +1,126 @@
+/* per tenant log volume safeguard
+
+   A single security policy entry under attack can flood the logging
+   pipeline. When LOG_VOLUME_PER_MINUTE is set, each entry may only emit
+   that many full log records per minute: the first dropped request is
+   replaced by a summary record, further ones are dropped with a counter.
+   Aggregation is skipped for dropped requests as well, so that the
+   per-entry sample collections stay bounded too.
+*/
+
+use lazy_static::lazy_static;
+use std::collections::HashMap;
+use std::sync::Mutex;
+
+use crate::utils::RequestInfo;
+
+lazy_static! {
+    /// maximum number of full log records a security policy entry can emit
+    /// per minute, 0 disables the cap
+    pub static ref LOG_VOLUME_PER_MINUTE: u64 = std::env::var("LOG_VOLUME_PER_MINUTE")
+        .ok()
+        .and_then(|s| s.parse().ok())
+        .unwrap_or(0);
+    static ref WINDOWS: Mutex<HashMap<String, Window>> = Mutex::new(HashMap::new());
+}
+
+/// stale windows are evicted once the map exceeds this many entries
+const WINDOWS_CLEANUP_THRESHOLD: usize = 8192;
+
+struct Window {
+    minute: i64,
+    sent: u64,
+    dropped: u64,
+}
+
+pub enum LogVolumeVerdict {
+    Allowed,
+    /// first drop of the window, a summary record replaces the log entry
+    Summarize,
+    Dropped,
+}
+
+fn check_at(entry_id: &str, minute: i64, cap: u64) -> LogVolumeVerdict {
+    if cap == 0 {
+        return LogVolumeVerdict::Allowed;
+    }
+    let mut windows = match WINDOWS.lock() {
+        Ok(w) => w,
+        // failing open: a poisoned lock should not stop logging
+        Err(_) => return LogVolumeVerdict::Allowed,
+    };
+    if windows.len() > WINDOWS_CLEANUP_THRESHOLD {
+        windows.retain(|_, w| w.minute == minute);
+    }
+    let w = windows.entry(entry_id.to_string()).or_insert(Window {
+        minute,
+        sent: 0,
+        dropped: 0,
+    });
+    if w.minute != minute {
+        w.minute = minute;
+        w.sent = 0;
+        w.dropped = 0;
+    }
+    if w.sent < cap {
+        w.sent += 1;
+        LogVolumeVerdict::Allowed
+    } else {
+        w.dropped += 1;
+        if w.dropped == 1 {
+            LogVolumeVerdict::Summarize
+        } else {
+            LogVolumeVerdict::Dropped
+        }
+    }
+}
+
+/// checks whether this security policy entry may still emit a full log record
+/// in the current minute
+pub fn check(entry_id: &str, now: &chrono::DateTime<chrono::Utc>) -> LogVolumeVerdict {
+    check_at(entry_id, now.timestamp() / 60, *LOG_VOLUME_PER_MINUTE)
+}
+
+/// the summary record emitted in place of the first dropped log entry
+pub fn summary_record(rinfo: &RequestInfo, now: &chrono::DateTime<chrono::Utc>) -> Vec<u8> {
+    let entry = serde_json::json!({
+        "timestamp": now,
+        "secpolid": rinfo.rinfo.secpolicy.policy.id,
+        "secpolentryid": rinfo.rinfo.secpolicy.entry.id,
+        "log_volume_exceeded": *LOG_VOLUME_PER_MINUTE,
+    });
+    serde_json::to_vec(&entry).unwrap_or_else(|_| b"null".to_vec())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn volume_capped_with_summary() {
+        for i in 0..3 {
+            assert!(
+                matches!(check_at("volume-capped", 1, 3), LogVolumeVerdict::Allowed),
+                "request {} should pass",
+                i
+            );
+        }
+        assert!(matches!(check_at("volume-capped", 1, 3), LogVolumeVerdict::Summarize));
+        assert!(matches!(check_at("volume-capped", 1, 3), LogVolumeVerdict::Dropped));
+    }
+
+    #[test]
+    fn window_resets_every_minute() {
+        assert!(matches!(check_at("volume-window", 1, 1), LogVolumeVerdict::Allowed));
+        assert!(matches!(check_at("volume-window", 1, 1), LogVolumeVerdict::Summarize));
+        assert!(matches!(check_at("volume-window", 2, 1), LogVolumeVerdict::Allowed));
+        assert!(matches!(check_at("volume-window", 2, 1), LogVolumeVerdict::Summarize));
+    }
+
+    #[test]
+    fn zero_cap_is_unlimited() {
+        for _ in 0..100 {
+            assert!(matches!(check_at("volume-unlimited", 1, 0), LogVolumeVerdict::Allowed));
+        }
+    }
+}
@@ -0,0 +1,377 @@
+/* Prometheus metrics exporter
+
+   The aggregator samples are rich but only reachable through the
+   CFAGGREGATED log line. This module keeps a small set of always cheap
+   counters in Prometheus shape: hits and blocks per security policy entry,
+   triggers per initiator, hits per rule id (bounded by CF_METRICS_MAX_RULES,
+   with request id exemplars to pivot into the log store), response status
+   classes and a processing time histogram. `render` returns the text
+   exposition format, and
+   `spawn_exporter` starts a minimal HTTP listener answering GET /metrics,
+   for embedders that do not have their own HTTP surface.
+
+   Recording only happens once the exporter is started (or CF_METRICS is
+   set), so that embedders that do not scrape pay nothing.
+*/
+
+use lazy_static::lazy_static;
+use std::collections::BTreeMap;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Mutex;
+
+use crate::config::raw::RawActionType;
+use crate::utils::RequestInfo;
+
+use super::{Decision, Initiator, Stats};
+
+/// histogram bucket upper bounds, in microseconds
+const BUCKETS_MICROS: [u64; 10] = [500, 1000, 2500, 5000, 10000, 25000, 50000, 100000, 250000, 1000000];
+
+lazy_static! {
+    static ref METRICS: Mutex<MetricsState> = Mutex::new(MetricsState::default());
+    static ref CF_METRICS: bool = std::env::var("CF_METRICS")
+        .map(|s| !s.is_empty() && s != "0")
+        .unwrap_or(false);
+    /// cardinality bound on the per-rule-id counters, extra rule ids are
+    /// lumped into the "_other" bucket
+    static ref CF_METRICS_MAX_RULES: usize = std::env::var("CF_METRICS_MAX_RULES")
+        .ok()
+        .and_then(|s| s.parse().ok())
+        .unwrap_or(200);
+}
+
+static ENABLED: AtomicBool = AtomicBool::new(false);
+
+pub fn enabled() -> bool {
+    ENABLED.load(Ordering::Relaxed) || *CF_METRICS
+}
+
+/// enables recording without starting the exporter, for embedders that call
+/// `render` themselves
+pub fn enable() {
+    ENABLED.store(true, Ordering::Relaxed);
+}
+
+#[derive(Default)]
+struct EntryCounters {
+    hits: u64,
+    blocked: u64,
+}
+
+#[derive(Default)]
+struct Histogram {
+    buckets: [u64; BUCKETS_MICROS.len()],
+    sum: u64,
+    count: u64,
+}
+
+impl Histogram {
+    fn observe(&mut self, micros: u64) {
+        for (i, le) in BUCKETS_MICROS.iter().enumerate() {
+            if micros <= *le {
+                self.buckets[i] += 1;
+            }
+        }
+        self.sum += micros;
+        self.count += 1;
+    }
+}
+
+#[derive(Default)]
+struct RuleCounter {
+    hits: u64,
+    /// request id of the last hit, exported as an exemplar so that a spike
+    /// can be traced back to concrete requests in the log store
+    exemplar: Option<String>,
+}
+
+#[derive(Default)]
+struct MetricsState {
+    // BTreeMaps so that the exposition output is stable
+    entries: BTreeMap<(String, String), EntryCounters>,
+    triggers: BTreeMap<(&'static str, &'static str), u64>,
+    rules: BTreeMap<String, RuleCounter>,
+    /// hits on rule ids beyond the cardinality bound
+    rules_overflow: u64,
+    status_classes: BTreeMap<String, u64>,
+    processing: Histogram,
+}
+
+/// the initiator label, following the aggregator naming
+fn initiator_label(initiator: &Initiator) -> Option<&'static str> {
+    Some(match initiator {
+        Initiator::GlobalFilter => "global_filter",
+        Initiator::Acl { .. } | Initiator::Phase02 => "acl",
+        Initiator::Phase01Fail(_) => return None,
+        Initiator::Limit { .. } => "rate_limit",
+        Initiator::ContentFilter { .. } => "content_filter",
+        Initiator::Restriction { .. } => "restriction",
+        Initiator::Allowlist { .. } => "allowlist",
+        Initiator::Compliance { .. } => "compliance",
+        Initiator::External { .. } => "external",
+    })
+}
+
+/// records one inspection, called from the logging path
+pub fn record(dec: &Decision, rcode: Option<u32>, rinfo: &RequestInfo, stats: &Stats) {
+    if !enabled() {
+        return;
+    }
+    let mut state = match METRICS.lock() {
+        Ok(s) => s,
+        Err(_) => return,
+    };
+    let entry = state
+        .entries
+        .entry((
+            rinfo.rinfo.secpolicy.policy.id.to_string(),
+            rinfo.rinfo.secpolicy.entry.id.to_string(),
+        ))
+        .or_default();
+    entry.hits += 1;
+    if dec.blocked() {
+        entry.blocked += 1;
+    }
+    for r in &dec.reasons {
+        let mode = match r.action {
+            RawActionType::Skip | RawActionType::Monitor => "report",
+            RawActionType::Custom | RawActionType::Ban | RawActionType::Challenge | RawActionType::Ichallenge => {
+                "active"
+            }
+        };
+        if let Some(initiator) = initiator_label(&r.initiator) {
+            *state.triggers.entry((initiator, mode)).or_default() += 1;
+        }
+        if state.rules.contains_key(&r.id) || state.rules.len() < *CF_METRICS_MAX_RULES {
+            let counter = state.rules.entry(r.id.clone()).or_default();
+            counter.hits += 1;
+            if let Some(rid) = &rinfo.rinfo.meta.requestid {
+                counter.exemplar = Some(rid.clone());
+            }
+        } else {
+            state.rules_overflow += 1;
+        }
+    }
+    if let Some(code) = rcode {
+        *state.status_classes.entry(format!("{}xx", code / 100)).or_default() += 1;
+    }
+    state.processing.observe(stats.timing.max_value());
+}
+
+fn escape_label(value: &str) -> String {
+    value.replace('\\', "\\\\").replace('"', "\\\"").replace('\n', "\\n")
+}
+
+/// writes the HELP/TYPE preamble of a metric family; OpenMetrics wants the
+/// family name without the _total suffix
+fn family(out: &mut String, name: &str, help: &str, tpe: &str, openmetrics: bool) {
+    let name = if openmetrics {
+        name.trim_end_matches("_total")
+    } else {
+        name
+    };
+    out.push_str(&format!("# HELP {} {}\n# TYPE {} {}\n", name, help, name, tpe));
+}
+
+/// renders the counters in the Prometheus text exposition format
+pub fn render() -> String {
+    render_fmt(false)
+}
+
+/// renders the counters in the OpenMetrics format, with exemplars on the
+/// per-rule counters
+pub fn render_openmetrics() -> String {
+    render_fmt(true)
+}
+
+fn render_fmt(openmetrics: bool) -> String {
+    let state = match METRICS.lock() {
+        Ok(s) => s,
+        Err(_) => return String::new(),
+    };
+    let mut out = String::new();
+    family(
+        &mut out,
+        "curiefense_hits_total",
+        "Inspected requests per security policy entry",
+        "counter",
+        openmetrics,
+    );
+    for ((secpolid, secpolentryid), counters) in &state.entries {
+        out.push_str(&format!(
+            "curiefense_hits_total{{secpolid=\"{}\",secpolentryid=\"{}\"}} {}\n",
+            escape_label(secpolid),
+            escape_label(secpolentryid),
+            counters.hits
+        ));
+    }
+    family(
+        &mut out,
+        "curiefense_blocked_total",
+        "Blocked requests per security policy entry",
+        "counter",
+        openmetrics,
+    );
+    for ((secpolid, secpolentryid), counters) in &state.entries {
+        out.push_str(&format!(
+            "curiefense_blocked_total{{secpolid=\"{}\",secpolentryid=\"{}\"}} {}\n",
+            escape_label(secpolid),
+            escape_label(secpolentryid),
+            counters.blocked
+        ));
+    }
+    family(
+        &mut out,
+        "curiefense_triggers_total",
+        "Triggered rules per initiator",
+        "counter",
+        openmetrics,
+    );
+    for ((initiator, mode), count) in &state.triggers {
+        out.push_str(&format!(
+            "curiefense_triggers_total{{initiator=\"{}\",mode=\"{}\"}} {}\n",
+            initiator, mode, count
+        ));
+    }
+    family(
+        &mut out,
+        "curiefense_rule_hits_total",
+        "Triggered rules per rule id",
+        "counter",
+        openmetrics,
+    );
+    for (ruleid, counter) in &state.rules {
+        out.push_str(&format!(
+            "curiefense_rule_hits_total{{ruleid=\"{}\"}} {}",
+            escape_label(ruleid),
+            counter.hits
+        ));
+        // exemplars only exist in the OpenMetrics format
+        if openmetrics {
+            if let Some(rid) = &counter.exemplar {
+                out.push_str(&format!(" # {{request_id=\"{}\"}} 1", escape_label(rid)));
+            }
+        }
+        out.push('\n');
+    }
+    if state.rules_overflow > 0 {
+        out.push_str(&format!(
+            "curiefense_rule_hits_total{{ruleid=\"_other\"}} {}\n",
+            state.rules_overflow
+        ));
+    }
+    family(
+        &mut out,
+        "curiefense_status_class_total",
+        "Upstream response status classes",
+        "counter",
+        openmetrics,
+    );
+    for (class, count) in &state.status_classes {
+        out.push_str(&format!(
+            "curiefense_status_class_total{{class=\"{}\"}} {}\n",
+            class, count
+        ));
+    }
+    family(
+        &mut out,
+        "curiefense_processing_microseconds",
+        "Request analysis time",
+        "histogram",
+        openmetrics,
+    );
+    for (i, le) in BUCKETS_MICROS.iter().enumerate() {
+        out.push_str(&format!(
+            "curiefense_processing_microseconds_bucket{{le=\"{}\"}} {}\n",
+            le, state.processing.buckets[i]
+        ));
+    }
+    out.push_str(&format!(
+        "curiefense_processing_microseconds_bucket{{le=\"+Inf\"}} {}\n",
+        state.processing.count
+    ));
+    out.push_str(&format!(
+        "curiefense_processing_microseconds_sum {}\n",
+        state.processing.sum
+    ));
+    out.push_str(&format!(
+        "curiefense_processing_microseconds_count {}\n",
+        state.processing.count
+    ));
+    if openmetrics {
+        out.push_str("# EOF\n");
+    }
+    out
+}
+
+/// starts the scrape endpoint on a detached task, answering GET /metrics;
+/// recording is enabled as a side effect
+pub fn spawn_exporter(listen: String) {
+    enable();
+    async_std::task::spawn(async move {
+        use async_std::io::prelude::{ReadExt, WriteExt};
+        use async_std::net::TcpListener;
+
+        let listener = match TcpListener::bind(&listen).await {
+            Ok(l) => l,
+            Err(rr) => {
+                eprintln!("Could not bind the metrics endpoint on {}: {}", listen, rr);
+                return;
+            }
+        };
+        loop {
+            let (mut socket, _) = match listener.accept().await {
+                Ok(s) => s,
+                Err(_) => continue,
+            };
+            let mut buf = [0u8; 512];
+            let n = socket.read(&mut buf).await.unwrap_or(0);
+            let request = String::from_utf8_lossy(&buf[..n]);
+            let answer = if request.starts_with("GET /metrics") {
+                // scrapers announcing OpenMetrics support get exemplars
+                let openmetrics = request.to_ascii_lowercase().contains("openmetrics");
+                let (body, ctype) = if openmetrics {
+                    (
+                        render_openmetrics(),
+                        "application/openmetrics-text; version=1.0.0; charset=utf-8",
+                    )
+                } else {
+                    (render(), "text/plain; version=0.0.4")
+                };
+                format!(
+                    "HTTP/1.1 200 OK\r\ncontent-type: {}\r\ncontent-length: {}\r\n\r\n{}",
+                    ctype,
+                    body.len(),
+                    body
+                )
+            } else {
+                "HTTP/1.1 404 Not Found\r\ncontent-length: 0\r\n\r\n".to_string()
+            };
+            let _ = socket.write_all(answer.as_bytes()).await;
+        }
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn histogram_buckets_are_cumulative() {
+        let mut h = Histogram::default();
+        h.observe(400);
+        h.observe(800);
+        h.observe(2_000_000);
+        assert_eq!(h.buckets[0], 1); // le 500
+        assert_eq!(h.buckets[1], 2); // le 1000
+        assert_eq!(h.buckets[BUCKETS_MICROS.len() - 1], 2); // le 1000000
+        assert_eq!(h.count, 3);
+        assert_eq!(h.sum, 2_001_200);
+    }
+
+    #[test]
+    fn labels_are_escaped() {
+        assert_eq!(escape_label("plain"), "plain");
+        assert_eq!(escape_label("a\"b\\c"), "a\\\"b\\\\c");
+    }
+}
@@ -1,11 +1,13 @@
 use crate::config::hostmap::SecurityPolicy;
 /// this file contains all the data type that are used when interfacing with a proxy
 use crate::config::matchers::RequestSelector;
+use crate::config::hostmap::SessionHash;
 use crate::config::raw::{RawAction, RawActionType};
 use crate::grasshopper::{challenge_phase01, GHMode, Grasshopper, PrecisionLevel};
+use crate::limit::LimitCheck;
 use crate::logs::Logs;
 use crate::utils::json::NameValue;
-use crate::utils::templating::{parse_request_template, RequestTemplate, TVar, TemplatePart};
+use crate::utils::templating::{parse_request_template, BlockPageVar, RequestTemplate, TVar, TemplatePart};
 use crate::utils::{selector, GeoIp, RequestInfo, Selected};
 use chrono::{DateTime, Duration, DurationRound};
 use md5;
@@ -19,6 +21,10 @@ pub use self::tagging::*;
 
 pub mod aggregator;
 pub mod block_reasons;
+pub mod esexport;
+pub mod logsink;
+pub mod logvolume;
+pub mod metrics;
 pub mod stats;
 pub mod tagging;
 
@@ -41,7 +47,11 @@ pub enum SimpleDecision {
 /// In all cases, block reasons are always merged.
 ///
 /// Priorities of actions are: Skip > Block > Monitor > None
-pub fn merge_decisions(d1: Decision, d2: Decision) -> Decision {
+pub fn merge_decisions(mut d1: Decision, mut d2: Decision) -> Decision {
+    // the trace is kept in stage order, independently of which decision wins
+    let mut trace = std::mem::take(&mut d1.trace);
+    trace.append(&mut d2.trace);
+    let would_block = d1.would_block || d2.would_block;
     // Choose which decision to keep, and which decision to throw away
     let (mut kept, thrown) = {
         match (&d1.maction, &d2.maction) {
@@ -71,6 +81,9 @@ pub fn merge_decisions(d1: Decision, d2: Decision) -> Decision {
     }
 
     kept.reasons.extend(thrown.reasons);
+    kept.annotations.extend(thrown.annotations);
+    kept.trace = trace;
+    kept.would_block = would_block;
 
     kept
 }
@@ -108,12 +121,39 @@ pub struct AnalyzeResult {
     pub tags: Tags,
     pub rinfo: RequestInfo,
     pub stats: Stats,
+    /// deferred limits, whose counters are only incremented once the embedder
+    /// reports the upstream status (see limit_report_status)
+    pub deferred_limits: Vec<LimitCheck>,
 }
 
 #[derive(Debug, Clone)]
 pub struct Decision {
     pub maction: Option<Action>,
     pub reasons: Vec<BlockReason>,
+    /// structured annotations attached by the analysis stages, serialized in
+    /// the `annotations` log field (tags only carry flat strings)
+    pub annotations: HashMap<String, serde_json::Value>,
+    /// ordered per-stage decision trace, only collected when
+    /// CF_DECISION_TRACE is set and serialized in the `decision_trace` log
+    /// field (see trace_stage)
+    pub trace: Vec<DecisionTraceEntry>,
+    /// set when the policy is in shadow mode and a blocking action was
+    /// downgraded to monitor; the block reasons keep their original action
+    /// so that the log shows what would have happened
+    pub would_block: bool,
+}
+
+/// one step of the decision trace: the verdict of a single analysis stage
+/// and the cumulated outcome after the priority comparison of
+/// merge_decisions, so that operators can audit how the final action was
+/// reached; stages that did not produce a verdict are absent
+#[derive(Debug, Clone, Serialize)]
+pub struct DecisionTraceEntry {
+    pub stage: &'static str,
+    /// the verdict of this stage alone
+    pub verdict: &'static str,
+    /// the cumulated verdict after merging
+    pub outcome: &'static str,
 }
 
 impl Decision {
@@ -129,17 +169,89 @@ impl Decision {
                 extra_locations: Vec::new(),
                 extra: serde_json::Value::Null,
             }],
+            annotations: HashMap::new(),
+            trace: Vec::new(),
+            would_block: false,
         }
     }
 
     pub fn pass(reasons: Vec<BlockReason>) -> Self {
-        Decision { maction: None, reasons }
+        Decision {
+            maction: None,
+            reasons,
+            annotations: HashMap::new(),
+            trace: Vec::new(),
+            would_block: false,
+        }
     }
 
     pub fn action(action: Action, reasons: Vec<BlockReason>) -> Self {
         Decision {
             maction: Some(action),
             reasons,
+            annotations: HashMap::new(),
+            trace: Vec::new(),
+            would_block: false,
+        }
+    }
+
+    /// the verdict label of this decision, for the decision trace
+    pub fn verdict(&self) -> &'static str {
+        match &self.maction {
+            None => "pass",
+            Some(a) => match a.atype {
+                ActionType::Skip => "skip",
+                ActionType::Monitor => "monitor",
+                ActionType::Block => "block",
+            },
+        }
+    }
+
+    /// records one step of the decision trace: the verdict of `stage` and
+    /// the cumulated outcome after merging, when CF_DECISION_TRACE is set
+    pub fn trace_stage(&mut self, stage: &'static str, verdict: &'static str) {
+        if *DECISION_TRACE {
+            self.trace.push(DecisionTraceEntry {
+                stage,
+                verdict,
+                outcome: self.verdict(),
+            });
+        }
+    }
+
+    /// shadow mode downgrade: a blocking action is replaced by a monitor
+    /// action; the block reasons are kept as they are, so that the log
+    /// still shows what would have happened
+    pub fn shadow(&mut self) {
+        if let Some(action) = &mut self.maction {
+            if action.atype.is_blocking() {
+                action.atype = ActionType::Monitor;
+                action.block_mode = false;
+                action.status = 200;
+                self.would_block = true;
+            }
+        }
+    }
+
+    /// attaches a structured annotation, unserializable values are dropped
+    pub fn annotate<V: Serialize>(&mut self, key: &str, value: V) {
+        if let Ok(v) = serde_json::to_value(value) {
+            self.annotations.insert(key.to_string(), v);
+        }
+    }
+
+    pub fn with_annotation<V: Serialize>(mut self, key: &str, value: V) -> Self {
+        self.annotate(key, value);
+        self
+    }
+
+    /// json rendering of the annotations, None when there are none, used by
+    /// the FFI and Lua accessors
+    pub fn annotations_json(&self) -> Option<String> {
+        if self.annotations.is_empty() {
+            None
+        } else {
+            serde_json::to_string(&self.annotations).ok()
         }
     }
 
@@ -149,6 +261,11 @@ impl Decision {
     }
 
     pub fn blocked(&self) -> bool {
+        // in shadow mode the reasons keep their blocking action, but the
+        // request was not actually denied
+        if self.would_block {
+            return false;
+        }
         for r in &self.reasons {
             if !(matches!(r.action, RawActionType::Monitor) || matches!(r.action, RawActionType::Skip)) {
                 return true;
@@ -164,13 +281,36 @@ impl Decision {
     }
 
     pub fn response_json(&self) -> String {
+        self.response_json_with_tags(None)
+    }
+
+    /// serializes the decision for embedders, with an optional tags summary.
+    /// The schema version is selected with the CF_RESPONSE_JSON_VERSION
+    /// environment variable: v1 is the legacy unversioned format, v2 adds the
+    /// version marker, reason codes, tags and header mutation fields.
+    pub fn response_json_with_tags(&self, tags: Option<&Tags>) -> String {
         let action_desc = if self.is_blocking() { "custom_response" } else { "pass" };
         let response =
             serde_json::to_value(&self.maction).unwrap_or_else(|rr| serde_json::Value::String(rr.to_string()));
-        let j = serde_json::json!({
-            "action": action_desc,
-            "response": response,
-        });
+        let j = if *RESPONSE_JSON_VERSION < 2 {
+            serde_json::json!({
+                "action": action_desc,
+                "response": response,
+            })
+        } else {
+            let reason_codes: Vec<u32> = self.reasons.iter().map(|r| r.initiator.reason_code()).collect();
+            let headers = self.maction.as_ref().and_then(|a| a.headers.as_ref());
+            let redirect = headers.and_then(|h| h.get("location").or_else(|| h.get("Location")));
+            serde_json::json!({
+                "version": 2,
+                "action": action_desc,
+                "response": response,
+                "reason_codes": reason_codes,
+                "tags": tags.map(|t| t.as_hash_ref().keys().cloned().collect::<Vec<_>>()),
+                "redirect": redirect,
+                "headers": headers,
+            })
+        };
         serde_json::to_string(&j).unwrap_or_else(|_| "{}".to_string())
     }
 
@@ -215,16 +355,59 @@ pub async fn jsonlog(
     } else {
         rcode.or_else(|| proxy_status)
     };
-    match mrinfo {
+    let (record, now) = match mrinfo {
         Some(rinfo) => {
-            aggregator::aggregate(dec, status_code, rinfo, tags, bytes_sent).await;
-            match jsonlog_rinfo(dec, rinfo, status_code, tags, stats, logs, proxy, &now) {
-                Err(_) => (b"null".to_vec(), now),
-                Ok(y) => (y, now),
+            // feed the origin protection tracker with the upstream outcome
+            let timed_out = proxy.get("timeout").map(|t| t == "true").unwrap_or(false);
+            crate::originprotection::report_upstream(&rinfo.rinfo.secpolicy, proxy_status, timed_out);
+            // trace export is independent from the logging pipeline and its
+            // volume cap
+            if crate::telemetry::enabled() {
+                crate::telemetry::report(dec, rinfo, stats, &now);
+            }
+            // same for scrape counters, which must cover every request
+            metrics::record(dec, status_code, rinfo, stats);
+            // a noisy tenant can not flood the logging pipeline: once its
+            // per-minute volume cap is reached, records are dropped after a
+            // summary entry, and aggregation sampling is skipped as well
+            match logvolume::check(&rinfo.rinfo.secpolicy.entry.id, &now) {
+                logvolume::LogVolumeVerdict::Allowed => {
+                    aggregator::aggregate(dec, status_code, rinfo, tags, bytes_sent).await;
+                    match jsonlog_rinfo(dec, rinfo, status_code, tags, stats, logs, proxy, &now) {
+                        Err(_) => (b"null".to_vec(), now),
+                        Ok(y) => (y, now),
+                    }
+                }
+                logvolume::LogVolumeVerdict::Summarize => (logvolume::summary_record(rinfo, &now), now),
+                logvolume::LogVolumeVerdict::Dropped => (b"null".to_vec(), now),
             }
         }
         None => (b"null".to_vec(), now),
+    };
+    // independent delivery path: when a log sink is configured, records are
+    // shipped directly instead of relying on the proxy to forward them
+    if record != b"null" {
+        logsink::ship(&record);
+        esexport::ship(&record);
+        #[cfg(feature = "kafka-export")]
+        if let Some(rinfo) = mrinfo {
+            crate::log_export::publish_log(&rinfo.rinfo.secpolicy.policy.id, &record);
+        }
+        // the bulk exporter can be the only delivery path, in which case the
+        // proxy does not get the log blob back
+        if esexport::exclusive() {
+            return (b"null".to_vec(), now);
+        }
     }
+    (record, now)
+}
+
+/// stable form of the session data in the logs: the session hash and the
+/// algorithm that produced it; the raw session value is never part of it
+#[derive(Debug, Clone, Serialize)]
+pub struct SessionLog<'a> {
+    pub hash: &'a str,
+    pub algorithm: SessionHash,
 }
 
 #[allow(clippy::too_many_arguments)]
@@ -245,11 +428,36 @@ pub fn jsonlog_rinfo(
         None
     };
     let greasons = BlockReason::regroup(&dec.reasons);
-    let get_trigger = |k: &InitiatorKind| -> &[&BlockReason] { greasons.get(k).map(|v| v.as_slice()).unwrap_or(&[]) };
+    // duplicates are folded and entries sorted, so that identical requests
+    // always produce the same trigger lists
+    let get_trigger = |k: &InitiatorKind| -> Vec<CountedBlockReason> {
+        BlockReason::canonicalize(greasons.get(k).map(|v| v.as_slice()).unwrap_or(&[]))
+    };
 
     //monitor reason(s) is for the list of reasons for monitor action
     let monitor_reason_desc = BlockReason::monitor_reason_desc(&dec.reasons);
 
+    // per-policy log shaping: request content fields can be omitted or their
+    // values masked, identity and decision fields are always emitted
+    let log_profile = rinfo.rinfo.secpolicy.log_profile.as_ref();
+    let keeps = |field: &str| log_profile.map_or(true, |p| p.keeps(field));
+
+    /// request fields with their values masked, keeping the names, used when
+    /// a policy redacts request data from its logs
+    struct RedactedField<'t>(&'t crate::requestfields::RequestField);
+    impl<'t> Serialize for RedactedField<'t> {
+        fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
+        where
+            S: Serializer,
+        {
+            let mut mp = serializer.serialize_map(None)?;
+            for (name, _) in self.0.iter() {
+                mp.serialize_entry(name, "REDACTED")?;
+            }
+            mp.end()
+        }
+    }
+
     let mut outbuffer = Vec::<u8>::new();
     let mut ser = serde_json::Serializer::new(&mut outbuffer);
     let mut map_ser = ser.serialize_map(None)?;
@@ -258,7 +466,13 @@ pub fn jsonlog_rinfo(
         "timestamp_min",
         &now.duration_trunc(chrono::Duration::minutes(1)).unwrap(),
     )?;
-    map_ser.serialize_entry("curiesession", &rinfo.session)?;
+    map_ser.serialize_entry(
+        "curiesession",
+        &SessionLog {
+            hash: &rinfo.session,
+            algorithm: rinfo.rinfo.secpolicy.session_hash,
+        },
+    )?;
     //pulled up params from proxy map
     if let Some(val) = proxy.get("bytes_sent") {
         let bytes_sent = val.parse::<i32>().unwrap_or_default();
@@ -305,9 +519,15 @@ pub fn jsonlog_rinfo(
         }
     }
 
-    map_ser.serialize_entry("host", &rinfo.headers.get("host"))?;
-    map_ser.serialize_entry("user_agent", &rinfo.headers.get("user-agent"))?;
-    map_ser.serialize_entry("referer", &rinfo.headers.get("referer"))?;
+    if keeps("host") {
+        map_ser.serialize_entry("host", &rinfo.headers.get("host"))?;
+    }
+    if keeps("user_agent") {
+        map_ser.serialize_entry("user_agent", &rinfo.headers.get("user-agent"))?;
+    }
+    if keeps("referer") {
+        map_ser.serialize_entry("referer", &rinfo.headers.get("referer"))?;
+    }
     map_ser.serialize_entry("hostname", &rinfo.rinfo.container_name)?;
     map_ser.serialize_entry("protocol", &rinfo.headers.get("x-forwarded-proto"))?;
     map_ser.serialize_entry("port", &rinfo.headers.get("x-forwarded-port"))?;
@@ -357,32 +577,68 @@ pub fn jsonlog_rinfo(
     map_ser.serialize_entry("bot", &has_bot)?;
 
     map_ser.serialize_entry("curiesession_ids", &NameValue::new(&rinfo.session_ids))?;
+    // already hashed at extraction time, the raw key value never reaches here
+    map_ser.serialize_entry("api_key", &rinfo.api_key)?;
     let request_id = proxy.get("request_id").or(rinfo.rinfo.meta.requestid.as_ref());
     map_ser.serialize_entry("request_id", &request_id)?;
-    map_ser.serialize_entry("arguments", &rinfo.rinfo.qinfo.args)?;
+    if keeps("arguments") {
+        if log_profile.map_or(false, |p| p.redact_arguments) {
+            map_ser.serialize_entry("arguments", &RedactedField(&rinfo.rinfo.qinfo.args))?;
+        } else if *LOG_ARGS_PROVENANCE {
+            map_ser.serialize_entry("arguments", &rinfo.rinfo.qinfo.args.with_provenance())?;
+        } else {
+            map_ser.serialize_entry("arguments", &rinfo.rinfo.qinfo.args)?;
+        }
+    }
     map_ser.serialize_entry("path", &rinfo.rinfo.qinfo.qpath)?;
-    map_ser.serialize_entry("path_parts", &rinfo.rinfo.qinfo.path_as_map)?;
+    if keeps("path_parts") {
+        map_ser.serialize_entry("path_parts", &rinfo.rinfo.qinfo.path_as_map)?;
+    }
     map_ser.serialize_entry("authority", &rinfo.rinfo.host)?;
-    map_ser.serialize_entry("cookies", &rinfo.cookies)?;
-    map_ser.serialize_entry("headers", &rinfo.headers)?;
-    if !rinfo.plugins.is_empty() {
+    if keeps("cookies") {
+        if log_profile.map_or(false, |p| p.redact_cookies) {
+            map_ser.serialize_entry("cookies", &RedactedField(&rinfo.cookies))?;
+        } else {
+            map_ser.serialize_entry("cookies", &rinfo.cookies)?;
+        }
+    }
+    if keeps("headers") {
+        if log_profile.map_or(false, |p| p.redact_headers) {
+            map_ser.serialize_entry("headers", &RedactedField(&rinfo.headers))?;
+        } else {
+            map_ser.serialize_entry("headers", &rinfo.headers)?;
+        }
+    }
+    if !rinfo.plugins.is_empty() && keeps("plugins") {
         map_ser.serialize_entry("plugins", &rinfo.plugins)?;
     }
-    map_ser.serialize_entry("query", &rinfo.rinfo.qinfo.query)?;
+    if keeps("query") {
+        map_ser.serialize_entry("query", &rinfo.rinfo.qinfo.query)?;
+    }
     map_ser.serialize_entry("ip", &rinfo.rinfo.geoip.ip)?;
     map_ser.serialize_entry("method", &rinfo.rinfo.meta.method)?;
     map_ser.serialize_entry("response_code", &rcode)?;
 
-    map_ser.serialize_entry("logs", logs)?;
+    if keeps("logs") {
+        map_ser.serialize_entry("logs", logs)?;
+    }
     map_ser.serialize_entry("processing_stage", &stats.processing_stage)?;
-
-    map_ser.serialize_entry("acl_triggers", get_trigger(&InitiatorKind::Acl))?;
-    map_ser.serialize_entry("rl_triggers", get_trigger(&InitiatorKind::RateLimit))?;
-    map_ser.serialize_entry("gf_triggers", get_trigger(&InitiatorKind::GlobalFilter))?;
-    map_ser.serialize_entry("cf_triggers", get_trigger(&InitiatorKind::ContentFilter))?;
-    map_ser.serialize_entry("cf_restrict_triggers", get_trigger(&InitiatorKind::Restriction))?;
+    map_ser.serialize_entry("stage_overruns", &stats.overruns)?;
+
+    map_ser.serialize_entry("acl_triggers", &get_trigger(&InitiatorKind::Acl))?;
+    map_ser.serialize_entry("rl_triggers", &get_trigger(&InitiatorKind::RateLimit))?;
+    map_ser.serialize_entry("gf_triggers", &get_trigger(&InitiatorKind::GlobalFilter))?;
+    map_ser.serialize_entry("cf_triggers", &get_trigger(&InitiatorKind::ContentFilter))?;
+    map_ser.serialize_entry("cf_restrict_triggers", &get_trigger(&InitiatorKind::Restriction))?;
+    map_ser.serialize_entry("external_triggers", &get_trigger(&InitiatorKind::External))?;
     map_ser.serialize_entry("reason", &block_reason_desc)?;
     map_ser.serialize_entry("monitor_reasons", &monitor_reason_desc)?;
+    if !dec.annotations.is_empty() {
+        map_ser.serialize_entry("annotations", &dec.annotations)?;
+    }
+    if !dec.trace.is_empty() {
+        map_ser.serialize_entry("decision_trace", &dec.trace)?;
+    }
 
     let branch_tag = tags.inner().keys().filter_map(|t| t.strip_prefix("branch:")).next();
     map_ser.serialize_entry("branch", &branch_tag)?;
@@ -426,7 +682,7 @@ pub fn jsonlog_rinfo(
     let blocked = dec.blocked();
     let mut filtered_tags = tags.clone();
     if !blocked && filtered_tags.contains("action:content-filter-block") {
-        filtered_tags.tags.remove("action:content-filter-block");
+        filtered_tags.remove("action:content-filter-block");
     }
 
     map_ser.serialize_entry(
@@ -507,14 +763,16 @@ pub fn jsonlog_rinfo(
             sq.end()
         }
     }
-    map_ser.serialize_entry(
-        "proxy",
-        &LogProxy {
-            p: &proxy,
-            geo: &rinfo.rinfo.geoip,
-            n: &rinfo.rinfo.container_name,
-        },
-    )?;
+    if keeps("proxy") {
+        map_ser.serialize_entry(
+            "proxy",
+            &LogProxy {
+                p: &proxy,
+                geo: &rinfo.rinfo.geoip,
+                n: &rinfo.rinfo.container_name,
+            },
+        )?;
+    }
 
     struct SecurityConfig<'t>(&'t Stats, &'t SecurityPolicy);
     impl<'t> Serialize for SecurityConfig<'t> {
@@ -526,11 +784,13 @@ pub fn jsonlog_rinfo(
             mp.serialize_entry("revision", &self.0.revision)?;
             mp.serialize_entry("acl_active", &self.0.secpol.acl_enabled)?;
             mp.serialize_entry("cf_active", &self.0.secpol.content_filter_enabled)?;
+            mp.serialize_entry("allowlist_active", &self.0.secpol.allowlist_enabled)?;
             mp.serialize_entry("cf_rules", &self.0.content_filter_total)?;
             mp.serialize_entry("rl_rules", &self.0.secpol.limit_amount)?;
             mp.serialize_entry("gf_rules", &self.0.secpol.globalfilters_amount)?;
             mp.serialize_entry("secpolid", &self.1.policy.id)?;
             mp.serialize_entry("secpolentryid", &self.1.entry.id)?;
+            mp.serialize_entry("features", &self.1.features)?;
             mp.end()
         }
     }
@@ -553,6 +813,7 @@ pub fn jsonlog_rinfo(
             let rate_limit = stats_counter(InitiatorKind::RateLimit);
             let content_filters = stats_counter(InitiatorKind::ContentFilter);
             let restriction = stats_counter(InitiatorKind::Restriction);
+            let external = stats_counter(InitiatorKind::External);
 
             let mut mp = serializer.serialize_map(None)?;
             mp.serialize_entry("acl", &acl)?;
@@ -560,6 +821,7 @@ pub fn jsonlog_rinfo(
             mp.serialize_entry("rl", &rate_limit)?;
             mp.serialize_entry("cf", &content_filters)?;
             mp.serialize_entry("cf_restrict", &restriction)?;
+            mp.serialize_entry("external", &external)?;
             mp.end()
         }
     }
@@ -569,6 +831,9 @@ pub fn jsonlog_rinfo(
     if !(has_challenge || has_ichallenge) {
         map_ser.serialize_entry("blocked", &blocked)?;
     }
+    if dec.would_block {
+        map_ser.serialize_entry("would_block", &true)?;
+    }
 
     struct EmptyMap;
     impl Serialize for EmptyMap {
@@ -580,7 +845,9 @@ pub fn jsonlog_rinfo(
             mp.end()
         }
     }
-    map_ser.serialize_entry("profiling", &stats.timing)?;
+    if keeps("profiling") {
+        map_ser.serialize_entry("profiling", &stats.timing)?;
+    }
 
     map_ser.serialize_entry("rbz_latency", &stats.timing.max_value())?;
 
@@ -588,6 +855,42 @@ pub fn jsonlog_rinfo(
     Ok(outbuffer)
 }
 
+lazy_static::lazy_static! {
+    /// zstd level used when compressing request logs, 0 disables compression
+    static ref LOG_COMPRESSION_LEVEL: i32 = std::env::var("CF_LOG_COMPRESSION_LEVEL")
+        .ok()
+        .and_then(|s| s.parse().ok())
+        .unwrap_or(0);
+    /// when set, the logged arguments include the provenance of each value
+    static ref LOG_ARGS_PROVENANCE: bool = std::env::var("CF_LOG_ARGS_PROVENANCE")
+        .map(|s| s.parse().unwrap_or(false))
+        .unwrap_or(false);
+    /// when set, the json log carries the ordered per-stage decision trace
+    static ref DECISION_TRACE: bool = std::env::var("CF_DECISION_TRACE")
+        .map(|s| s.parse().unwrap_or(false))
+        .unwrap_or(false);
+    /// when set, blocking responses carry the machine readable reason code in the x-cf-reason header
+    static ref REASON_HEADER: bool = std::env::var("CF_REASON_HEADER")
+        .map(|s| s.parse().unwrap_or(false))
+        .unwrap_or(false);
+    /// schema version of Decision::response_json, defaults to the legacy v1
+    /// format expected by existing Lua consumers
+    static ref RESPONSE_JSON_VERSION: u32 = std::env::var("CF_RESPONSE_JSON_VERSION")
+        .ok()
+        .and_then(|s| s.parse().ok())
+        .unwrap_or(1);
+}
+
+/// the configured zstd level for log compression, 0 meaning disabled
+pub fn log_compression_level() -> i32 {
+    *LOG_COMPRESSION_LEVEL
+}
+
+/// compresses a serialized log entry into a zstd frame
+pub fn compress_log(raw: &[u8], level: i32) -> std::io::Result<Vec<u8>> {
+    zstd::stream::encode_all(raw, level)
+}
+
 //parse and split multiple values into a vector
 fn parse_values<T: std::str::FromStr>(val: &str) -> Vec<T> {
     val.split(',')
@@ -624,8 +927,18 @@ pub struct Action {
 pub enum SimpleActionT {
     Skip,
     Monitor,
-    Custom { content: String },
-    Challenge { ch_level: GHMode },
+    Custom {
+        content: String,
+    },
+    Challenge {
+        ch_level: GHMode,
+    },
+    /// blocks like a custom action, and records the client in the counter
+    /// store so that its next requests are rejected for `duration` seconds
+    Ban {
+        content: String,
+        duration: u64,
+    },
 }
 
 impl SimpleActionT {
@@ -633,6 +946,7 @@ impl SimpleActionT {
         use SimpleActionT::*;
         match self {
             Custom { content: _ } => 8,
+            Ban { .. } => 8,
             Challenge { ch_level: _ } => 6,
             Monitor => 1,
             Skip => 9,
@@ -643,6 +957,7 @@ impl SimpleActionT {
         use SimpleActionT::*;
         match self {
             Custom { content: _ } => 8,
+            Ban { .. } => 8,
             Challenge { .. } => 6,
             Monitor => 1,
             // skip action should be ignored when using with rate limit
@@ -659,6 +974,7 @@ impl SimpleActionT {
             SimpleActionT::Skip => RawActionType::Skip,
             SimpleActionT::Monitor => RawActionType::Monitor,
             SimpleActionT::Custom { .. } => RawActionType::Custom,
+            SimpleActionT::Ban { .. } => RawActionType::Ban,
             SimpleActionT::Challenge { ch_level } => {
                 if ch_level == &GHMode::Active {
                     RawActionType::Challenge
@@ -676,6 +992,8 @@ pub struct SimpleAction {
     pub atype: SimpleActionT,
     pub headers: Option<HashMap<String, RequestTemplate>>,
     pub status: u32,
+    /// overrides the status code when the request carries one of these tags, first match wins
+    pub status_by_tag: Vec<(String, u32)>,
     pub extra_tags: Option<HashSet<String>>,
 }
 
@@ -685,6 +1003,7 @@ impl Default for SimpleAction {
             atype: SimpleActionT::default(),
             headers: None,
             status: 503,
+            status_by_tag: Vec::new(),
             extra_tags: None,
         }
     }
@@ -740,10 +1059,14 @@ impl std::default::Default for Action {
 }
 
 impl SimpleAction {
-    pub fn resolve_actions(logs: &mut Logs, rawactions: Vec<RawAction>) -> HashMap<String, Self> {
+    pub fn resolve_actions(
+        logs: &mut Logs,
+        templates: &HashMap<String, String>,
+        rawactions: Vec<RawAction>,
+    ) -> HashMap<String, Self> {
         let mut out = HashMap::new();
         for raction in rawactions {
-            match Self::resolve(&raction) {
+            match Self::resolve(&raction, templates) {
                 Ok((id, action)) => {
                     out.insert(id, action);
                 }
@@ -753,13 +1076,20 @@ impl SimpleAction {
         out
     }
 
-    fn resolve(rawaction: &RawAction) -> anyhow::Result<(String, SimpleAction)> {
+    fn resolve(rawaction: &RawAction, templates: &HashMap<String, String>) -> anyhow::Result<(String, SimpleAction)> {
         let id = rawaction.id.clone();
         let atype = match rawaction.type_ {
             RawActionType::Skip => SimpleActionT::Skip,
             RawActionType::Monitor => SimpleActionT::Monitor,
             RawActionType::Custom => SimpleActionT::Custom {
-                content: rawaction.params.content.clone().unwrap_or_default(),
+                // template files take precedence over the inline content
+                content: match rawaction.params.template.as_deref() {
+                    Some(tid) => templates
+                        .get(tid)
+                        .cloned()
+                        .ok_or_else(|| anyhow::anyhow!("unknown template id {}", tid))?,
+                    None => rawaction.params.content.clone().unwrap_or_default(),
+                },
             },
             RawActionType::Challenge => SimpleActionT::Challenge {
                 ch_level: GHMode::Active,
@@ -767,8 +1097,26 @@ impl SimpleAction {
             RawActionType::Ichallenge => SimpleActionT::Challenge {
                 ch_level: GHMode::Interactive,
             },
+            RawActionType::Ban => SimpleActionT::Ban {
+                content: match rawaction.params.template.as_deref() {
+                    Some(tid) => templates
+                        .get(tid)
+                        .cloned()
+                        .ok_or_else(|| anyhow::anyhow!("unknown template id {}", tid))?,
+                    None => rawaction.params.content.clone().unwrap_or_default(),
+                },
+                duration: rawaction.params.duration.unwrap_or(3600),
+            },
         };
         let status = rawaction.params.status.unwrap_or(503);
+        // sorted so that the override picked when several tags match is deterministic
+        let mut status_by_tag: Vec<(String, u32)> = rawaction
+            .params
+            .status_by_tag
+            .iter()
+            .map(|(t, s)| (t.clone(), *s))
+            .collect();
+        status_by_tag.sort();
         let headers = rawaction.params.headers.as_ref().map(|hm| {
             hm.iter()
                 .map(|(k, v)| (k.to_string(), parse_request_template(v)))
@@ -785,6 +1133,7 @@ impl SimpleAction {
             SimpleAction {
                 atype,
                 status,
+                status_by_tag,
                 headers,
                 extra_tags,
             },
@@ -803,9 +1152,13 @@ impl SimpleAction {
         let mut reason = reason;
         action.block_mode = action.atype.is_blocking();
         action.status = self.status;
+        if let Some((_, status)) = self.status_by_tag.iter().find(|(t, _)| tags.contains(t)) {
+            action.status = *status;
+        }
+        let bctx = BlockPageContext::build(rinfo, &reason);
         action.headers = self.headers.as_ref().map(|hm| {
             hm.iter()
-                .map(|(k, v)| (k.to_string(), render_template(rinfo, tags, v)))
+                .map(|(k, v)| (k.to_string(), render_template(rinfo, tags, Some(&bctx), v)))
                 .collect()
         });
         match &self.atype {
@@ -813,7 +1166,16 @@ impl SimpleAction {
             SimpleActionT::Monitor => action.atype = ActionType::Monitor,
             SimpleActionT::Custom { content } => {
                 action.atype = ActionType::Block;
-                action.content = content.clone();
+                action.content = render_template(rinfo, tags, Some(&bctx), &parse_request_template(content));
+            }
+            SimpleActionT::Ban { content, duration } => {
+                action.atype = ActionType::Block;
+                action.content = render_template(rinfo, tags, Some(&bctx), &parse_request_template(content));
+                // the offending client is recorded off the request path, its
+                // next requests are rejected before the pipeline runs
+                if !rinfo.rinfo.secpolicy.shadow {
+                    crate::banlist::record(&rinfo.rinfo.geoip.ipstr, &rinfo.session, *duration);
+                }
             }
             SimpleActionT::Challenge { ch_level } => {
                 let is_human = match ch_level {
@@ -835,6 +1197,14 @@ impl SimpleAction {
             action.status = 200;
             action.block_mode = false;
         }
+        if *REASON_HEADER && action.block_mode {
+            if let Some(code) = BlockReason::block_reason_code(&reason) {
+                action
+                    .headers
+                    .get_or_insert_with(HashMap::new)
+                    .insert("x-cf-reason".to_string(), code.to_string());
+            }
+        }
         Ok(Decision::action(action, reason))
     }
 
@@ -851,12 +1221,9 @@ impl SimpleAction {
             tags.insert(t, Location::Request);
         }
         if self.atype == SimpleActionT::Skip {
-            return Decision {
-                maction: None,
-                reasons: reason,
-            };
+            return Decision::pass(reason);
         }
-        match self.build_decision(rinfo, tags, precision_level, reason) {
+        let mut decision = match self.build_decision(rinfo, tags, precision_level, reason) {
             Err(nreason) => match mgh {
                 //if None-must be one of the challenge actions
                 Some(gh) => {
@@ -870,7 +1237,11 @@ impl SimpleAction {
                 _ => Decision::action(Action::default(), nreason),
             },
             Ok(a) => a,
+        };
+        if rinfo.rinfo.secpolicy.shadow {
+            decision.shadow();
         }
+        decision
     }
 
     pub fn is_blocking(&self) -> bool {
@@ -878,7 +1249,33 @@ impl SimpleAction {
     }
 }
 
-fn render_template(rinfo: &RequestInfo, tags: &Tags, template: &[TemplatePart<TVar>]) -> String {
+/// typed context for block page templating, the fields are exposed to
+/// `Action.content` and header templates as ${blockpage.<field>} variables
+pub struct BlockPageContext<'t> {
+    pub request_id: Option<&'t str>,
+    pub timestamp: DateTime<chrono::Utc>,
+    pub reason_code: Option<u32>,
+    /// taken from the `support_contact` entry of the policy features map
+    pub support_contact: Option<&'t str>,
+}
+
+impl<'t> BlockPageContext<'t> {
+    fn build(rinfo: &'t RequestInfo, reason: &[BlockReason]) -> Self {
+        BlockPageContext {
+            request_id: rinfo.rinfo.meta.requestid.as_deref(),
+            timestamp: rinfo.timestamp,
+            reason_code: BlockReason::block_reason_code(reason),
+            support_contact: rinfo.rinfo.secpolicy.features.get("support_contact").map(String::as_str),
+        }
+    }
+}
+
+fn render_template(
+    rinfo: &RequestInfo,
+    tags: &Tags,
+    bctx: Option<&BlockPageContext>,
+    template: &[TemplatePart<TVar>],
+) -> String {
     let mut out = String::new();
     for p in template {
         match p {
@@ -889,6 +1286,17 @@ fn render_template(rinfo: &RequestInfo, tags: &Tags, template: &[TemplatePart<TV
             TemplatePart::Var(TVar::Tag(tagname)) => {
                 out.push_str(if tags.contains(tagname) { "true" } else { "false" })
             }
+            TemplatePart::Var(TVar::BlockPage(v)) => match bctx {
+                None => out.push_str("nil"),
+                Some(ctx) => match v {
+                    BlockPageVar::RequestId => out.push_str(ctx.request_id.unwrap_or("-")),
+                    BlockPageVar::Timestamp => out.push_str(&ctx.timestamp.to_rfc3339()),
+                    BlockPageVar::ReasonCode => {
+                        out.push_str(&ctx.reason_code.map(|c| c.to_string()).unwrap_or_else(|| "0".into()))
+                    }
+                    BlockPageVar::SupportContact => out.push_str(ctx.support_contact.unwrap_or("-")),
+                },
+            },
             TemplatePart::Var(TVar::Selector(sel)) => match selector(rinfo, sel, Some(tags)) {
                 None => out.push_str("nil"),
                 Some(Selected::OStr(s)) => out.push_str(&s),
@@ -900,16 +1308,74 @@ fn render_template(rinfo: &RequestInfo, tags: &Tags, template: &[TemplatePart<TV
     out
 }
 
+/// renders the block page of an action against a sample request, used by the
+/// `render-blockpage` subcommand to preview templates
+pub fn render_blockpage_preview(action: &SimpleAction, rinfo: &RequestInfo, tags: &Tags) -> String {
+    let bctx = BlockPageContext::build(rinfo, &[]);
+    match &action.atype {
+        SimpleActionT::Custom { content } => {
+            render_template(rinfo, tags, Some(&bctx), &parse_request_template(content))
+        }
+        _ => Action::default().content,
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
 
+    #[test]
+    fn action_template_resolution() {
+        let mut logs = Logs::default();
+        let templates = HashMap::from([(
+            "blockpage".to_string(),
+            "<html>${blockpage.request_id}</html>".to_string(),
+        )]);
+        let raw: RawAction = serde_json::from_str(
+            r#"{"id": "tpl-action", "type": "custom", "params": {"status": 403, "template": "blockpage"}}"#,
+        )
+        .unwrap();
+        let actions = SimpleAction::resolve_actions(&mut logs, &templates, vec![raw]);
+        match &actions.get("tpl-action").unwrap().atype {
+            SimpleActionT::Custom { content } => assert_eq!(content, "<html>${blockpage.request_id}</html>"),
+            a => panic!("unexpected action type {:?}", a),
+        }
+        // actions referencing an unknown template are rejected
+        let raw: RawAction =
+            serde_json::from_str(r#"{"id": "bad", "type": "custom", "params": {"template": "missing"}}"#).unwrap();
+        let actions = SimpleAction::resolve_actions(&mut logs, &templates, vec![raw]);
+        assert!(actions.get("bad").is_none());
+    }
+
+    #[test]
+    fn test_merge_keeps_trace_order() {
+        let mut d1 = Decision::pass(Vec::new());
+        d1.trace.push(DecisionTraceEntry {
+            stage: "globalfilter",
+            verdict: "pass",
+            outcome: "pass",
+        });
+        let mut d2 = Decision::action(Action::default(), Vec::new());
+        d2.trace.push(DecisionTraceEntry {
+            stage: "acl",
+            verdict: "block",
+            outcome: "block",
+        });
+        let merged = merge_decisions(d1, d2);
+        let stages: Vec<&str> = merged.trace.iter().map(|e| e.stage).collect();
+        assert_eq!(stages, vec!["globalfilter", "acl"]);
+        assert_eq!(merged.verdict(), "block");
+    }
+
     #[test]
     fn test_blocked_no_reasons() {
         let default_action = Some(Action::default());
         let dec = Decision {
             maction: default_action,
             reasons: vec![],
+            annotations: HashMap::new(),
+            trace: Vec::new(),
+            would_block: false,
         };
         assert_eq!(dec.blocked(), false);
     }
@@ -929,6 +1395,9 @@ mod tests {
         let dec = Decision {
             maction: default_action,
             reasons,
+            annotations: HashMap::new(),
+            trace: Vec::new(),
+            would_block: false,
         };
         assert_eq!(dec.blocked(), false);
     }
@@ -944,7 +1413,65 @@ mod tests {
         let dec = Decision {
             maction: default_action,
             reasons,
+            annotations: HashMap::new(),
+            trace: Vec::new(),
+            would_block: false,
         };
         assert_eq!(dec.blocked(), true);
     }
+
+    #[test]
+    fn test_shadow_downgrades_block() {
+        let mut dec = Decision {
+            maction: Some(Action::default()),
+            reasons: vec![BlockReason::phase02()],
+            annotations: HashMap::new(),
+            trace: Vec::new(),
+            would_block: false,
+        };
+        dec.shadow();
+        let action = dec.maction.as_ref().unwrap();
+        assert_eq!(action.atype, ActionType::Monitor);
+        assert!(!action.block_mode);
+        assert!(dec.would_block);
+        // the reason keeps its blocking action, but the request went through
+        assert_eq!(dec.reasons[0].action, RawActionType::Custom);
+        assert_eq!(dec.blocked(), false);
+    }
+
+    #[test]
+    fn test_block_reason_code() {
+        let reasons = vec![
+            BlockReason::limit("01".to_string(), "monitor".to_string(), 23, RawActionType::Monitor),
+            BlockReason::phase02(),
+        ];
+        assert_eq!(BlockReason::block_reason_code(&reasons), Some(6002));
+        let monitor_only = vec![BlockReason::limit(
+            "01".to_string(),
+            "monitor".to_string(),
+            23,
+            RawActionType::Monitor,
+        )];
+        assert_eq!(BlockReason::block_reason_code(&monitor_only), None);
+    }
+
+    #[test]
+    fn test_canonicalize_block_reasons() {
+        let reasons = vec![
+            BlockReason::limit("02".to_string(), "limit-02".to_string(), 42, RawActionType::Monitor),
+            BlockReason::limit("01".to_string(), "limit-01".to_string(), 23, RawActionType::Monitor),
+            BlockReason::limit("01".to_string(), "limit-01".to_string(), 23, RawActionType::Monitor),
+        ];
+        let refs: Vec<&BlockReason> = reasons.iter().collect();
+        let canon = BlockReason::canonicalize(&refs);
+        // duplicates are folded into a single counted entry, and the output
+        // is sorted independently from the input order
+        assert_eq!(canon.len(), 2);
+        assert_eq!(canon[0].reason.id, "01");
+        assert_eq!(canon[0].count, 2);
+        assert_eq!(canon[1].reason.id, "02");
+        assert_eq!(canon[1].count, 1);
+        let serialized = serde_json::to_value(&canon[0]).unwrap();
+        assert_eq!(serialized["count"], serde_json::json!(2));
+    }
 }
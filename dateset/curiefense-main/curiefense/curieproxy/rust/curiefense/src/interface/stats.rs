@@ -1,8 +1,52 @@
+use lazy_static::lazy_static;
 use serde::{ser::SerializeSeq, Serialize};
-use std::{marker::PhantomData, time::Instant};
+use std::{
+    convert::TryFrom,
+    marker::PhantomData,
+    time::{Duration, Instant},
+};
 
 use crate::{config::hostmap::SecurityPolicy, utils::json::BigTableKV};
 
+/// per stage time budgets, exceeded budgets record an overrun in the logs and,
+/// when `enforce` is set, skip the remaining work for the stage
+pub struct StageBudgets {
+    pub tagging: Option<Duration>,
+    pub flow: Option<Duration>,
+    pub limit: Option<Duration>,
+    pub content_filter: Option<Duration>,
+    pub enforce: bool,
+}
+
+lazy_static! {
+    pub static ref STAGE_BUDGETS: StageBudgets = {
+        fn budget(var: &str) -> Option<Duration> {
+            std::env::var(var)
+                .ok()
+                .and_then(|s| s.parse().ok())
+                .filter(|&ms| ms > 0)
+                .map(Duration::from_millis)
+        }
+        StageBudgets {
+            // CF_GF_BUDGET_MS is kept as a fallback, global filter evaluation
+            // dominates the tagging stage
+            tagging: budget("CF_STAGE_BUDGET_TAGGING_MS").or_else(|| budget("CF_GF_BUDGET_MS")),
+            flow: budget("CF_STAGE_BUDGET_FLOW_MS"),
+            limit: budget("CF_STAGE_BUDGET_LIMIT_MS"),
+            content_filter: budget("CF_STAGE_BUDGET_CF_MS"),
+            enforce: std::env::var("CF_STAGE_BUDGET_ENFORCE")
+                .map(|s| s.parse().unwrap_or(false))
+                .unwrap_or(false),
+        }
+    };
+}
+
+/// elapsed time in microseconds, clamped instead of truncated when the u128
+/// value does not fit in the serialized u64
+fn elapsed_micros(start: Instant) -> u64 {
+    u64::try_from(start.elapsed().as_micros()).unwrap_or(u64::MAX)
+}
+
 #[derive(Default, Debug, Clone)]
 pub struct TimingInfo {
     secpol: Option<u64>,
@@ -11,6 +55,10 @@ pub struct TimingInfo {
     limit: Option<u64>,
     acl: Option<u64>,
     content_filter: Option<u64>,
+    // streaming (incremental) phases, absent for one-shot requests
+    headers: Option<u64>,
+    body: Option<u64>,
+    finalize: Option<u64>,
 }
 
 impl Serialize for TimingInfo {
@@ -43,11 +91,39 @@ impl Serialize for TimingInfo {
             name: "content_filter",
             value: &self.content_filter,
         })?;
+        mp.serialize_element(&BigTableKV {
+            name: "headers",
+            value: &self.headers,
+        })?;
+        mp.serialize_element(&BigTableKV {
+            name: "body",
+            value: &self.body,
+        })?;
+        mp.serialize_element(&BigTableKV {
+            name: "finalize",
+            value: &self.finalize,
+        })?;
         mp.end()
     }
 }
 
 impl TimingInfo {
+    /// cumulative stage stamps in processing order, used by the telemetry
+    /// exporter to derive per stage spans
+    pub fn stages(&self) -> Vec<(&'static str, u64)> {
+        [
+            ("secpol", self.secpol),
+            ("tagging", self.mapping),
+            ("flow", self.flow),
+            ("limit", self.limit),
+            ("acl", self.acl),
+            ("content_filter", self.content_filter),
+        ]
+        .iter()
+        .filter_map(|(name, stamp)| stamp.map(|s| (*name, s)))
+        .collect()
+    }
+
     pub fn max_value(&self) -> u64 {
         let mut max_value: u64 = 0;
         if let Some(value) = self.secpol {
@@ -68,6 +144,15 @@ impl TimingInfo {
         if let Some(value) = self.content_filter {
             max_value = value.max(max_value);
         }
+        if let Some(value) = self.headers {
+            max_value = value.max(max_value);
+        }
+        if let Some(value) = self.body {
+            max_value = value.max(max_value);
+        }
+        if let Some(value) = self.finalize {
+            max_value = value.max(max_value);
+        }
         max_value
     }
 }
@@ -88,6 +173,7 @@ pub struct SecpolStats {
     // stage secpol
     pub acl_enabled: bool,
     pub content_filter_enabled: bool,
+    pub allowlist_enabled: bool,
     pub limit_amount: usize,
     pub globalfilters_amount: usize,
 }
@@ -97,6 +183,7 @@ impl SecpolStats {
         SecpolStats {
             acl_enabled: policy.acl_active,
             content_filter_enabled: policy.content_filter_active,
+            allowlist_enabled: policy.allowlist.is_some(),
             limit_amount: policy.limits.len(),
             globalfilters_amount,
         }
@@ -131,6 +218,9 @@ pub struct Stats {
     content_filter_active: usize,
 
     pub timing: TimingInfo,
+
+    /// names of the stages that exceeded their time budget
+    pub overruns: Vec<&'static str>,
 }
 
 impl Stats {
@@ -156,8 +246,14 @@ impl Stats {
             content_filter_triggered: 0,
             content_filter_active: 0,
             timing: TimingInfo::default(),
+            overruns: Vec::new(),
         }
     }
+
+    /// stamps the end of the streamed finalize phase
+    pub fn finalize_done(&mut self) {
+        self.timing.finalize = Some(elapsed_micros(self.start));
+    }
 }
 
 // the builder uses a phantom data structure to make sure we did not forget to update the stats from a previous stage
@@ -167,6 +263,21 @@ pub struct StatsCollect<A> {
     phantom: PhantomData<A>,
 }
 
+impl<A> StatsCollect<A> {
+    /// time spent in the current stage, computed from the cumulative timings
+    pub fn stage_elapsed(&self) -> Duration {
+        self.stats
+            .start
+            .elapsed()
+            .saturating_sub(Duration::from_micros(self.stats.timing.max_value()))
+    }
+
+    /// records a stage time budget overrun
+    pub fn overrun(&mut self, stage: &'static str) {
+        self.stats.overruns.push(stage);
+    }
+}
+
 impl StatsCollect<BStageInit> {
     pub fn new(start: Instant, revision: String) -> Self {
         StatsCollect {
@@ -179,7 +290,7 @@ impl StatsCollect<BStageInit> {
         let mut stats = self.stats;
         stats.processing_stage = 1;
         stats.secpol = secpol;
-        stats.timing.secpol = Some(stats.start.elapsed().as_micros() as u64);
+        stats.timing.secpol = Some(elapsed_micros(stats.start));
         StatsCollect {
             stats,
             phantom: PhantomData,
@@ -189,7 +300,7 @@ impl StatsCollect<BStageInit> {
     pub fn content_filter_only(self) -> StatsCollect<BStageAcl> {
         let mut stats = self.stats;
         stats.processing_stage = 5;
-        stats.timing.acl = Some(stats.start.elapsed().as_micros() as u64);
+        stats.timing.acl = Some(elapsed_micros(stats.start));
         StatsCollect {
             stats,
             phantom: PhantomData,
@@ -203,7 +314,7 @@ impl StatsCollect<BStageSecpol> {
         stats.processing_stage = 2;
         stats.globalfilters_total = globalfilters_total;
         stats.globalfilters_active = globalfilters_active;
-        stats.timing.mapping = Some(stats.start.elapsed().as_micros() as u64);
+        stats.timing.mapping = Some(elapsed_micros(stats.start));
         StatsCollect {
             stats,
             phantom: PhantomData,
@@ -213,6 +324,28 @@ impl StatsCollect<BStageSecpol> {
     pub fn early_exit(self) -> Stats {
         self.stats
     }
+
+    /// stamps the end of the streamed header phase, later stamps overwrite
+    /// earlier ones so that the last header marks the end of the phase
+    pub fn headers_done(self) -> Self {
+        let mut stats = self.stats;
+        stats.timing.headers = Some(elapsed_micros(stats.start));
+        StatsCollect {
+            stats,
+            phantom: PhantomData,
+        }
+    }
+
+    /// stamps the end of the streamed body accumulation, overwritten when the
+    /// body arrives in several chunks
+    pub fn body_done(self) -> Self {
+        let mut stats = self.stats;
+        stats.timing.body = Some(elapsed_micros(stats.start));
+        StatsCollect {
+            stats,
+            phantom: PhantomData,
+        }
+    }
 }
 
 impl StatsCollect<BStageMapped> {
@@ -234,7 +367,7 @@ impl StatsCollect<BStageMapped> {
         stats.processing_stage = 3;
         stats.flow_total = flow_total;
         stats.flow_active = flow_active;
-        stats.timing.flow = Some(stats.start.elapsed().as_micros() as u64);
+        stats.timing.flow = Some(elapsed_micros(stats.start));
         StatsCollect {
             stats,
             phantom: PhantomData,
@@ -261,7 +394,7 @@ impl StatsCollect<BStageFlow> {
         stats.processing_stage = 4;
         stats.limit_total = limit_total;
         stats.limit_active = limit_active;
-        stats.timing.limit = Some(stats.start.elapsed().as_micros() as u64);
+        stats.timing.limit = Some(elapsed_micros(stats.start));
         StatsCollect {
             stats,
             phantom: PhantomData,
@@ -278,7 +411,7 @@ impl StatsCollect<BStageLimit> {
         let mut stats = self.stats;
         stats.processing_stage = 5;
         stats.acl_active = acl_active;
-        stats.timing.acl = Some(stats.start.elapsed().as_micros() as u64);
+        stats.timing.acl = Some(elapsed_micros(stats.start));
         StatsCollect {
             stats,
             phantom: PhantomData,
@@ -304,7 +437,7 @@ impl StatsCollect<BStageAcl> {
         let mut stats = self.stats;
         stats.processing_stage = 6;
         stats.content_filter_total = total;
-        stats.timing.content_filter = Some(stats.start.elapsed().as_micros() as u64);
+        stats.timing.content_filter = Some(elapsed_micros(stats.start));
         StatsCollect {
             stats,
             phantom: PhantomData,
@@ -317,7 +450,7 @@ impl StatsCollect<BStageAcl> {
         stats.content_filter_total = total;
         stats.content_filter_active = active;
         stats.content_filter_triggered = triggered;
-        stats.timing.content_filter = Some(stats.start.elapsed().as_micros() as u64);
+        stats.timing.content_filter = Some(elapsed_micros(stats.start));
         StatsCollect {
             stats,
             phantom: PhantomData,
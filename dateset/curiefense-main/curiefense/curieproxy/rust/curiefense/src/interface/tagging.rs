@@ -2,7 +2,7 @@ use crate::config::contentfilter::SectionIdx;
 use crate::config::virtualtags::VirtualTags;
 use serde::ser::{SerializeMap, SerializeSeq};
 use serde::Serialize;
-use std::collections::{HashMap, HashSet};
+use std::collections::{BTreeSet, HashMap, HashSet};
 
 #[derive(Debug, Clone, Hash, PartialEq, Eq)]
 pub enum Location {
@@ -152,6 +152,34 @@ impl Location {
             SectionIdx::Plugins => Location::Plugin(name.to_string()),
         }
     }
+    /// the raw value carried by this location, when it refers to a single entry occurrence
+    pub fn get_value(&self) -> Option<&str> {
+        use Location::*;
+        match self {
+            PathpartValue(_, v)
+            | UriArgumentValue(_, v)
+            | RefererArgumentValue(_, v)
+            | RefererPathpartValue(_, v)
+            | BodyArgumentValue(_, v)
+            | HeaderValue(_, v)
+            | CookieValue(_, v)
+            | PluginValue(_, v) => Some(v.as_str()),
+            _ => None,
+        }
+    }
+    /// the source of an entry occurrence and its original key, for provenance logging
+    pub fn get_provenance(&self) -> Option<(&'static str, &str)> {
+        use Location::*;
+        match self {
+            UriArgument(n) | UriArgumentValue(n, _) => Some(("uri", n)),
+            BodyArgument(n) | BodyArgumentValue(n, _) => Some(("body", n)),
+            RefererArgument(n) | RefererArgumentValue(n, _) => Some(("referer", n)),
+            Header(n) | HeaderValue(n, _) => Some(("headers", n)),
+            Cookie(n) | CookieValue(n, _) => Some(("cookies", n)),
+            Plugin(n) | PluginValue(n, _) => Some(("plugins", n)),
+            _ => None,
+        }
+    }
     pub fn from_section(idx: SectionIdx) -> Self {
         match idx {
             SectionIdx::Headers => Location::Headers,
@@ -264,6 +292,12 @@ pub fn all_parents(locs: HashSet<Location>, mode: ParentMode) -> HashSet<Locatio
     out
 }
 
+/// whether two plain tag sets share an element, iterating over the smaller one
+pub fn sets_intersect(a: &HashSet<String>, b: &HashSet<String>) -> bool {
+    let (small, large) = if a.len() <= b.len() { (a, b) } else { (b, a) };
+    small.iter().any(|t| large.contains(t))
+}
+
 /// a newtype representing tags, to make sure they are tagified when inserted
 #[derive(Debug, Clone)]
 pub struct Tags {
@@ -295,7 +329,10 @@ impl Serialize for Tags {
     where
         S: serde::Serializer,
     {
-        serializer.collect_seq(self.tags.keys())
+        // sorted, so that log lines for identical requests are identical
+        let mut keys: Vec<&String> = self.tags.keys().collect();
+        keys.sort_unstable();
+        serializer.collect_seq(keys)
     }
 }
 
@@ -427,6 +464,40 @@ impl Tags {
         other.iter().any(|t| self.tags.contains_key(t))
     }
 
+    /// tags present in both sets, with the locations from both sides merged
+    pub fn intersection(&self, other: &Self) -> Self {
+        let mut tags = HashMap::new();
+        for (k, locs) in &self.tags {
+            if let Some(olocs) = other.tags.get(k) {
+                let mut merged = locs.clone();
+                merged.extend(olocs.iter().cloned());
+                tags.insert(k.clone(), merged);
+            }
+        }
+        Tags {
+            tags,
+            vtags: self.vtags.clone(),
+        }
+    }
+
+    /// tags present in this set but not in the other, locations preserved
+    pub fn difference(&self, other: &Self) -> Self {
+        Tags {
+            tags: self
+                .tags
+                .iter()
+                .filter(|(k, _)| !other.tags.contains_key(*k))
+                .map(|(k, v)| (k.clone(), v.clone()))
+                .collect(),
+            vtags: self.vtags.clone(),
+        }
+    }
+
+    /// removes a single tag, the virtual tags it may have implied are kept
+    pub fn remove(&mut self, value: &str) {
+        self.tags.remove(&tagify(value));
+    }
+
     pub fn merge(&mut self, other: Self) {
         for (k, v) in other.tags.into_iter() {
             let e = self.tags.entry(k).or_default();
@@ -449,15 +520,17 @@ impl Tags {
         I: Iterator<Item = &'t str>,
         Q: Iterator<Item = (&'t str, String)>,
     {
-        let mut sq = serializer.serialize_seq(None)?;
-        for t in self.tags.keys() {
-            sq.serialize_element(t)?;
-        }
+        // merged into an ordered set, so that the output is deterministic
+        let mut all: BTreeSet<String> = self.tags.keys().cloned().collect();
         for t in extra {
-            sq.serialize_element(&tagify(t))?;
+            all.insert(tagify(t));
         }
         for (k, v) in extra_qualified {
-            sq.serialize_element(&Self::qualified(k, &v))?;
+            all.insert(Self::qualified(k, &v));
+        }
+        let mut sq = serializer.serialize_seq(Some(all.len()))?;
+        for t in &all {
+            sq.serialize_element(t)?;
         }
         sq.end()
     }
@@ -550,6 +623,65 @@ mod test {
         assert_eq!(tags.selector(), "tag1*tag2*vtag1");
     }
 
+    #[test]
+    fn intersection_merges_locations() {
+        let a = Tags::from_slice(
+            &[
+                ("both".to_string(), Location::Ip),
+                ("only-a".to_string(), Location::Request),
+            ],
+            VirtualTags::default(),
+        );
+        let b = Tags::from_slice(
+            &[
+                ("both".to_string(), Location::Uri),
+                ("only-b".to_string(), Location::Request),
+            ],
+            VirtualTags::default(),
+        );
+        let inter = a.intersection(&b);
+        assert_eq!(inter.selector(), "both");
+        let locs = inter.get("both").unwrap();
+        assert!(locs.contains(&Location::Ip));
+        assert!(locs.contains(&Location::Uri));
+    }
+
+    #[test]
+    fn difference_preserves_locations() {
+        let a = Tags::from_slice(
+            &[
+                ("both".to_string(), Location::Ip),
+                ("only-a".to_string(), Location::Uri),
+            ],
+            VirtualTags::default(),
+        );
+        let b = Tags::from_slice(&[("both".to_string(), Location::Request)], VirtualTags::default());
+        let diff = a.difference(&b);
+        assert_eq!(diff.selector(), "only-a");
+        assert!(diff.get("only-a").unwrap().contains(&Location::Uri));
+    }
+
+    #[test]
+    fn serialization_is_sorted() {
+        let tags = Tags::from_slice(
+            &[
+                ("zzz".to_string(), Location::Request),
+                ("aaa".to_string(), Location::Request),
+                ("mmm".to_string(), Location::Request),
+            ],
+            VirtualTags::default(),
+        );
+        assert_eq!(serde_json::to_string(&tags).unwrap(), r#"["aaa","mmm","zzz"]"#);
+    }
+
+    #[test]
+    fn sets_intersection() {
+        let mk = |elems: &[&str]| elems.iter().map(|s| s.to_string()).collect::<HashSet<_>>();
+        assert!(sets_intersect(&mk(&["a", "b"]), &mk(&["b", "c"])));
+        assert!(!sets_intersect(&mk(&["a", "b"]), &mk(&["c", "d"])));
+        assert!(!sets_intersect(&mk(&[]), &mk(&["c"])));
+    }
+
     #[test]
     fn location_no_overlap() {
         use Location::*;
@@ -0,0 +1,160 @@
+/// JWT inspection
+///
+/// Bearer tokens from the authorization header are parsed so that their
+/// claims can be used as selectors (limits, global filters, session ids).
+/// By default the signature is not checked; when the security policy
+/// configures verification keys, a verification result is computed (HS256
+/// only, as no asymmetric crypto dependency is available).
+use std::collections::HashMap;
+
+use serde_json::Value;
+use sha2::{Digest, Sha256};
+
+use crate::utils::decoders::base64dec_all;
+
+#[derive(Debug, Clone)]
+pub struct JwtInfo {
+    /// top level claims, scalar values as strings, compound values in their
+    /// json form
+    pub claims: HashMap<String, String>,
+    /// signature algorithm announced in the token header
+    pub algorithm: Option<String>,
+    /// None when no keys are configured, otherwise the verification result
+    pub verified: Option<bool>,
+}
+
+/// extracts the token part of a bearer authorization header
+pub fn bearer_token(authorization: &str) -> Option<&str> {
+    let (scheme, token) = authorization.split_once(' ')?;
+    if scheme.eq_ignore_ascii_case("bearer") {
+        Some(token.trim())
+    } else {
+        None
+    }
+}
+
+fn claim_string(v: &Value) -> String {
+    match v {
+        Value::String(s) => s.clone(),
+        other => other.to_string(),
+    }
+}
+
+/// parses a JWT, returning None when the token is not structurally a JWT
+pub fn parse_jwt(token: &str, keys: &[String]) -> Option<JwtInfo> {
+    let mut parts = token.split('.');
+    let header_b64 = parts.next()?;
+    let payload_b64 = parts.next()?;
+    let signature_b64 = parts.next()?;
+    if parts.next().is_some() {
+        return None;
+    }
+    let header: Value = serde_json::from_slice(&base64dec_all(header_b64).ok()?).ok()?;
+    let payload: Value = serde_json::from_slice(&base64dec_all(payload_b64).ok()?).ok()?;
+    let claims = payload
+        .as_object()?
+        .iter()
+        .map(|(k, v)| (k.clone(), claim_string(v)))
+        .collect();
+    let algorithm = header.get("alg").and_then(|v| v.as_str()).map(|s| s.to_string());
+    let verified = if keys.is_empty() {
+        None
+    } else {
+        let signature = base64dec_all(signature_b64).unwrap_or_default();
+        let signed = &token[..header_b64.len() + 1 + payload_b64.len()];
+        Some(match algorithm.as_deref() {
+            Some("HS256") => keys
+                .iter()
+                .any(|k| ct_eq(&hmac_sha256(k.as_bytes(), signed.as_bytes()), &signature)),
+            // other algorithms can not be checked without extra crypto dependencies
+            _ => false,
+        })
+    };
+    Some(JwtInfo {
+        claims,
+        algorithm,
+        verified,
+    })
+}
+
+/// constant-time equality for MAC verification, so that the comparison does
+/// not leak how many leading bytes matched (the length is not secret)
+pub(crate) fn ct_eq(a: &[u8], b: &[u8]) -> bool {
+    if a.len() != b.len() {
+        return false;
+    }
+    a.iter().zip(b.iter()).fold(0u8, |acc, (x, y)| acc | (x ^ y)) == 0
+}
+
+/// HMAC-SHA256, built on the sha2 crate as no hmac dependency is available
+pub(crate) fn hmac_sha256(key: &[u8], message: &[u8]) -> Vec<u8> {
+    const BLOCK_SIZE: usize = 64;
+    let mut k = if key.len() > BLOCK_SIZE {
+        Sha256::digest(key).to_vec()
+    } else {
+        key.to_vec()
+    };
+    k.resize(BLOCK_SIZE, 0);
+    let mut h = Sha256::new();
+    h.update(k.iter().map(|b| b ^ 0x36).collect::<Vec<u8>>());
+    h.update(message);
+    let inner = h.finalize();
+    let mut h = Sha256::new();
+    h.update(k.iter().map(|b| b ^ 0x5c).collect::<Vec<u8>>());
+    h.update(inner);
+    h.finalize().to_vec()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // { "alg": "HS256", "typ": "JWT" } . { "sub": "1234567890", "name": "John Doe", "admin": true }
+    // signed with the key "secret"
+    const TOKEN: &str = "eyJhbGciOiJIUzI1NiIsInR5cCI6IkpXVCJ9.\
+         eyJzdWIiOiIxMjM0NTY3ODkwIiwibmFtZSI6IkpvaG4gRG9lIiwiYWRtaW4iOnRydWV9.\
+         TJVA95OrM7E2cBab30RMHrHDcEfxjoYZgeFONFh7HgQ";
+
+    #[test]
+    fn bearer_extraction() {
+        assert_eq!(bearer_token("Bearer abc.def.ghi"), Some("abc.def.ghi"));
+        assert_eq!(bearer_token("bearer abc.def.ghi"), Some("abc.def.ghi"));
+        assert_eq!(bearer_token("Basic dXNlcjpwYXNz"), None);
+        assert_eq!(bearer_token("abc.def.ghi"), None);
+    }
+
+    #[test]
+    fn claims_without_verification() {
+        let jwt = parse_jwt(TOKEN, &[]).unwrap();
+        assert_eq!(jwt.algorithm.as_deref(), Some("HS256"));
+        assert_eq!(jwt.verified, None);
+        assert_eq!(jwt.claims.get("sub").map(|s| s.as_str()), Some("1234567890"));
+        assert_eq!(jwt.claims.get("name").map(|s| s.as_str()), Some("John Doe"));
+        assert_eq!(jwt.claims.get("admin").map(|s| s.as_str()), Some("true"));
+    }
+
+    #[test]
+    fn hs256_verification() {
+        let good = parse_jwt(TOKEN, &["secret".to_string()]).unwrap();
+        assert_eq!(good.verified, Some(true));
+        let bad = parse_jwt(TOKEN, &["other".to_string()]).unwrap();
+        assert_eq!(bad.verified, Some(false));
+        let multi = parse_jwt(TOKEN, &["other".to_string(), "secret".to_string()]).unwrap();
+        assert_eq!(multi.verified, Some(true));
+    }
+
+    #[test]
+    fn ct_eq_compares_all_bytes() {
+        assert!(ct_eq(b"abcd", b"abcd"));
+        assert!(!ct_eq(b"abcd", b"abce"));
+        assert!(!ct_eq(b"abcd", b"xbcd"));
+        assert!(!ct_eq(b"abcd", b"abc"));
+    }
+
+    #[test]
+    fn not_a_jwt() {
+        assert!(parse_jwt("not a token", &[]).is_none());
+        assert!(parse_jwt("a.b", &[]).is_none());
+        assert!(parse_jwt("a.b.c.d", &[]).is_none());
+    }
+}
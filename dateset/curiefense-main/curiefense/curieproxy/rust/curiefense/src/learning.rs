@@ -0,0 +1,206 @@
+/// offline analysis of monitor mode data, suggesting content filter exclusions
+///
+/// This consumes request logs (as produced by jsonlog, one JSON document per
+/// line) and aggregates the content filter triggers that did not block. The
+/// resulting suggestions are expressed in the configuration JSON format
+/// (`RawContentFilterEntryMatch`), so that they can be pasted into the
+/// matching section of a content filter profile after review.
+use serde::Serialize;
+use serde_json::Value;
+use std::collections::{HashMap, HashSet};
+
+use crate::config::contentfilter::SectionIdx;
+use crate::config::raw::RawContentFilterEntryMatch;
+
+#[derive(Debug, PartialEq, Eq, Hash)]
+struct TriggerKey {
+    section: SectionIdx,
+    name: String,
+    ruleid: String,
+}
+
+#[derive(Debug, Default)]
+struct TriggerData {
+    hits: usize,
+    paths: HashSet<String>,
+}
+
+/// aggregated monitor mode triggers, feed it log lines and extract suggestions
+#[derive(Debug, Default)]
+pub struct LearningData {
+    counters: HashMap<TriggerKey, TriggerData>,
+}
+
+/// a suggested exclusion, with enough context for a human to review it
+#[derive(Debug, Serialize)]
+pub struct ExclusionSuggestion {
+    pub section: SectionIdx,
+    /// amount of requests that triggered the rule on this parameter
+    pub hits: usize,
+    /// paths on which the triggers were observed
+    pub paths: Vec<String>,
+    /// the suggested entry, in the configuration format
+    pub entry: RawContentFilterEntryMatch,
+}
+
+/// maps the "section" field of a serialized location to the corresponding
+/// content filter profile section
+fn section_from_log(section: &str, name: &str) -> Option<(SectionIdx, String)> {
+    match section {
+        "headers" => Some((SectionIdx::Headers, name.to_string())),
+        "cookies" => Some((SectionIdx::Cookies, name.to_string())),
+        "body" => Some((SectionIdx::Args, name.to_string())),
+        "uri" => Some((SectionIdx::Args, name.to_string())),
+        "plugins" => Some((SectionIdx::Plugins, name.to_string())),
+        _ => None,
+    }
+}
+
+impl LearningData {
+    /// registers a single log entry, ignoring anything that is not a non
+    /// blocking content filter trigger
+    pub fn add_log_line(&mut self, line: &str) -> serde_json::Result<()> {
+        let doc: Value = serde_json::from_str(line)?;
+        let path = doc.get("path").and_then(|v| v.as_str()).unwrap_or("/");
+        let triggers = match doc.get("cf_triggers").and_then(|v| v.as_array()) {
+            None => return Ok(()),
+            Some(ts) => ts,
+        };
+        for trigger in triggers {
+            let get_str = |k: &str| trigger.get(k).and_then(|v| v.as_str());
+            // blocking triggers are not candidates for exclusions: those would
+            // have been acted upon already
+            if get_str("action") != Some("monitor") {
+                continue;
+            }
+            let ruleid = match get_str("ruleid") {
+                None => continue,
+                Some(r) => r.to_string(),
+            };
+            let raw_name = match trigger.get("name") {
+                Some(Value::String(s)) => s.clone(),
+                Some(Value::Number(n)) => n.to_string(),
+                _ => match trigger.get("part") {
+                    Some(Value::Number(n)) => n.to_string(),
+                    _ => continue,
+                },
+            };
+            if let Some((section, name)) = get_str("section").and_then(|s| section_from_log(s, &raw_name)) {
+                let entry = self
+                    .counters
+                    .entry(TriggerKey { section, name, ruleid })
+                    .or_default();
+                entry.hits += 1;
+                entry.paths.insert(path.to_string());
+            }
+        }
+        Ok(())
+    }
+
+    /// computes the suggestions for all parameters with at least min_hits
+    /// triggers, most triggered first
+    pub fn suggestions(&self, min_hits: usize) -> Vec<ExclusionSuggestion> {
+        let mut out: Vec<ExclusionSuggestion> = self
+            .counters
+            .iter()
+            .filter(|(_, d)| d.hits >= min_hits)
+            .map(|(k, d)| {
+                let mut paths: Vec<String> = d.paths.iter().cloned().collect();
+                paths.sort();
+                ExclusionSuggestion {
+                    section: k.section,
+                    hits: d.hits,
+                    paths,
+                    entry: RawContentFilterEntryMatch {
+                        key: k.name.clone(),
+                        reg: None,
+                        restrict: false,
+                        mask: None,
+                        exclusions: vec![format!("cf-rule-id:{}", k.ruleid)],
+                    },
+                }
+            })
+            .collect();
+        out.sort_by(|a, b| b.hits.cmp(&a.hits).then_with(|| a.entry.key.cmp(&b.entry.key)));
+        out
+    }
+}
+
+/// helper for the command line tool: analyzes a whole log file content
+pub fn suggest_exclusions(log_content: &str, min_hits: usize) -> Vec<ExclusionSuggestion> {
+    let mut data = LearningData::default();
+    for line in log_content.lines().filter(|l| !l.trim().is_empty()) {
+        // unparseable lines are skipped, logs can be truncated
+        let _ = data.add_log_line(line);
+    }
+    data.suggestions(min_hits)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn log_line(path: &str, triggers: Value) -> String {
+        serde_json::json!({
+            "path": path,
+            "cf_triggers": triggers,
+        })
+        .to_string()
+    }
+
+    #[test]
+    fn test_suggest_from_monitor_triggers() {
+        let trigger = serde_json::json!([{
+            "ruleid": "100042",
+            "risk_level": 3,
+            "section": "uri",
+            "name": "search",
+            "action": "monitor",
+            "trigger_id": "cfid",
+            "trigger_name": "content filter"
+        }]);
+        let content = [
+            log_line("/search", trigger.clone()),
+            log_line("/find", trigger.clone()),
+            log_line("/search", trigger),
+        ]
+        .join("\n");
+        let suggestions = suggest_exclusions(&content, 2);
+        assert_eq!(suggestions.len(), 1);
+        let s = &suggestions[0];
+        assert_eq!(s.section, SectionIdx::Args);
+        assert_eq!(s.hits, 3);
+        assert_eq!(s.paths, vec!["/find".to_string(), "/search".to_string()]);
+        assert_eq!(s.entry.key, "search");
+        assert_eq!(s.entry.exclusions, vec!["cf-rule-id:100042".to_string()]);
+    }
+
+    #[test]
+    fn test_blocking_triggers_are_ignored() {
+        let content = log_line(
+            "/",
+            serde_json::json!([{
+                "ruleid": "100042",
+                "section": "uri",
+                "name": "search",
+                "action": "custom"
+            }]),
+        );
+        assert!(suggest_exclusions(&content, 1).is_empty());
+    }
+
+    #[test]
+    fn test_min_hits_threshold() {
+        let content = log_line(
+            "/",
+            serde_json::json!([{
+                "ruleid": "100042",
+                "section": "headers",
+                "name": "user-agent",
+                "action": "monitor"
+            }]),
+        );
+        assert!(suggest_exclusions(&content, 2).is_empty());
+        assert_eq!(suggest_exclusions(&content, 1).len(), 1);
+    }
+}
@@ -1,23 +1,46 @@
 pub mod acl;
 pub mod analyze;
+pub mod banlist;
 pub mod body;
+pub mod compliance;
 pub mod config;
+pub mod configtest;
 pub mod contentfilter;
+pub mod counterstore;
+pub mod explain;
+pub mod filescan;
 pub mod flow;
 pub mod geo;
 pub mod grasshopper;
 pub mod incremental;
 pub mod interface;
 pub mod ipinfo;
+pub mod jwt;
+pub mod learning;
 pub mod limit;
+pub mod limit_memory;
+#[cfg(feature = "kafka-export")]
+pub mod log_export;
 pub mod logs;
+pub mod originprotection;
+pub mod outbound;
+pub mod pluginpipeline;
+pub mod preadmit;
 pub mod redis;
 pub mod requestfields;
+pub mod sampling;
 pub mod securitypolicy;
 pub mod servergroup;
 pub mod simple_executor;
+pub mod stickytags;
 pub mod tagging;
+pub mod telemetry;
+#[cfg(feature = "testing")]
+pub mod testing;
+pub mod timewindow;
 pub mod utils;
+#[cfg(feature = "wasm-plugins")]
+pub mod wasmplugins;
 
 use std::collections::HashMap;
 use std::sync::Arc;
@@ -37,6 +60,7 @@ use utils::{map_request, RawRequest, RequestInfo};
 
 use crate::config::custom::Site;
 use crate::config::hostmap::SecurityPolicy;
+use crate::config::raw::BodyTooLarge;
 use crate::interface::SimpleAction;
 //todo should receive sdk configuration from config/raw.rs struct, and pass it to gg
 fn challenge_verified<GH: Grasshopper>(gh: &GH, reqinfo: &RequestInfo, logs: &mut Logs) -> PrecisionLevel {
@@ -111,7 +135,8 @@ pub fn inspect_generic_request_map_init<GH: Grasshopper>(
     #[allow(clippy::large_enum_variant)]
     enum RequestMappingResult<A> {
         NoSecurityPolicy,
-        BodyTooLarge((SimpleAction, BlockReason), RequestInfo),
+        HealthCheck,
+        EarlyBlock((SimpleAction, BlockReason), RequestInfo),
         Res(A),
     }
 
@@ -119,56 +144,98 @@ pub fn inspect_generic_request_map_init<GH: Grasshopper>(
     // there is a lot of copying taking place, to minimize the lock time
     // this decision should be backed with benchmarks
 
-    let ((mut ntags, globalfilter_dec, stats), flows, reqinfo, precision_level) =
+    let ((mut ntags, globalfilter_dec, stats), (flows, first_seen, sticky_tags), reqinfo, precision_level) =
         match with_config(logs, |slogs, cfg| {
-            let mmapinfo = match_securitypolicy(&raw.get_host(), &raw.meta.path, cfg, slogs, selected_secpol);
+            // known health checkers short circuit to pass, before any body parsing
+            if let Ok(ip) = raw.ipstr.parse() {
+                if cfg
+                    .healthcheck
+                    .matches(&ip, raw.headers.get("user-agent").map(|s| s.as_str()))
+                {
+                    return RequestMappingResult::HealthCheck;
+                }
+            }
+            let mmapinfo = match_securitypolicy(&raw.get_host(), raw.meta.normalized_path(), cfg, slogs, selected_secpol);
             let server_group = match_servergroup(cfg, slogs, selected_sergrp);
             match mmapinfo {
                 Some(secpolicy) => {
                     // this part is where we use the configuration as much as possible, while we have a lock on it
 
                     // check if the body is too large
-                    // if the body is too large, we store the "too large" action for later use, and set the max depth to 0
-                    let body_too_large = if let Some(body) = raw.mbody {
-                        if body.len() > secpolicy.content_filter_profile.max_body_size
-                            && !secpolicy.content_filter_profile.ignore_body
-                        {
-                            Some((
+                    // if the body is too large, we either store the "too large" action for later
+                    // use, or truncate the body to the limit and keep inspecting the prefix
+                    let max_body_size = secpolicy.content_filter_profile.max_body_size;
+                    let oversized = raw.mbody.map_or(false, |body| body.len() > max_body_size)
+                        && !secpolicy.content_filter_profile.ignore_body;
+                    let truncate =
+                        oversized && secpolicy.content_filter_profile.body_too_large == BodyTooLarge::Truncate;
+                    let body_too_large = if oversized && !truncate {
+                        raw.mbody.map(|body| {
+                            (
                                 secpolicy.content_filter_profile.action.clone(),
                                 BlockReason::body_too_large(
                                     secpolicy.content_filter_profile.id.clone(),
                                     secpolicy.content_filter_profile.name.clone(),
                                     secpolicy.content_filter_profile.action.atype.to_raw(),
                                     body.len(),
-                                    secpolicy.content_filter_profile.max_body_size,
+                                    max_body_size,
                                 ),
-                            ))
-                        } else {
-                            None
-                        }
+                            )
+                        })
                     } else {
                         None
                     };
 
+                    // fast pre-parse guard on the raw path, checked before the query
+                    // string is flattened by map_request
+                    let uri_restricted = secpolicy
+                        .content_filter_profile
+                        .uri_restriction(raw.meta.normalized_path())
+                        .map(|br| (secpolicy.content_filter_profile.action.clone(), br));
+
                     let stats = StatsCollect::new(slogs.start, cfg.revision.clone())
                         .secpol(SecpolStats::build(&secpolicy, cfg.globalfilters.len()));
 
+                    // in truncate mode the first max_body_size bytes are still inspected
+                    let traw;
+                    let raw = if truncate {
+                        traw = RawRequest {
+                            ipstr: raw.ipstr.clone(),
+                            headers: raw.headers.clone(),
+                            meta: raw.meta.clone(),
+                            mbody: raw.mbody.map(|body| &body[..max_body_size]),
+                        };
+                        &traw
+                    } else {
+                        &raw
+                    };
+
                     // if the max depth is equal to 0, the body will not be parsed
                     let reqinfo = map_request(
                         slogs,
                         secpolicy,
                         server_group,
                         cfg.container_name.clone(),
-                        &raw,
+                        raw,
                         Some(start),
                         plugins.clone(),
                     );
 
+                    // compliance stage: embargoed origins are blocked before any
+                    // security processing, leaving an audit trail entry
+                    if let Some(action) = compliance::embargo_check(slogs, &cfg.embargo, &reqinfo) {
+                        return RequestMappingResult::EarlyBlock(action, reqinfo);
+                    }
                     if let Some(action) = body_too_large {
-                        return RequestMappingResult::BodyTooLarge(action, reqinfo);
+                        return RequestMappingResult::EarlyBlock(action, reqinfo);
+                    }
+                    if let Some(action) = uri_restricted {
+                        return RequestMappingResult::EarlyBlock(action, reqinfo);
                     }
 
                     let nflows = cfg.flows.clone();
+                    let nfirst_seen = cfg.first_seen.clone();
+                    let nsticky_tags = cfg.sticky_tags.clone();
 
                     // without grasshopper, default to being not human
                     let precision_level = if let Some(gh) = mgh {
@@ -177,14 +244,23 @@ pub fn inspect_generic_request_map_init<GH: Grasshopper>(
                         PrecisionLevel::Invalid
                     };
 
-                    let ntags = tag_request(stats, precision_level, &cfg.globalfilters, &reqinfo, &cfg.virtual_tags);
-                    RequestMappingResult::Res((ntags, nflows, reqinfo, precision_level))
+                    let (mut rtags, globalfilter_dec, stats) =
+                        tag_request(stats, precision_level, &cfg.globalfilters, &reqinfo, &cfg.virtual_tags);
+                    if truncate {
+                        rtags.insert("body-truncated", Location::Body);
+                    }
+                    RequestMappingResult::Res((
+                        (rtags, globalfilter_dec, stats),
+                        (nflows, nfirst_seen, nsticky_tags),
+                        reqinfo,
+                        precision_level,
+                    ))
                 }
                 None => RequestMappingResult::NoSecurityPolicy,
             }
         }) {
             Some(RequestMappingResult::Res(x)) => x,
-            Some(RequestMappingResult::BodyTooLarge((action, br), rinfo)) => {
+            Some(RequestMappingResult::EarlyBlock((action, br), rinfo)) => {
                 let mut tags = tags;
                 let decision = action.to_decision(logs, PrecisionLevel::Invalid, mgh, &rinfo, &mut tags, vec![br]);
                 return Err(AnalyzeResult {
@@ -192,6 +268,31 @@ pub fn inspect_generic_request_map_init<GH: Grasshopper>(
                     tags,
                     rinfo,
                     stats: Stats::new(logs.start, "unknown".into()),
+                    deferred_limits: Vec::new(),
+                });
+            }
+            Some(RequestMappingResult::HealthCheck) => {
+                logs.debug("Health check allowlist matched");
+                let mut secpol = SecurityPolicy::default();
+                secpol.content_filter_profile.ignore_body = true;
+                let server_group = Site::default();
+                let rinfo = map_request(
+                    logs,
+                    Arc::new(secpol),
+                    Arc::new(server_group),
+                    None,
+                    &raw,
+                    Some(start),
+                    plugins,
+                );
+                let mut tags = tags;
+                tags.insert("healthcheck", Location::Request);
+                return Err(AnalyzeResult {
+                    decision: Decision::pass(Vec::new()),
+                    tags,
+                    rinfo,
+                    stats: Stats::new(logs.start, "unknown".into()),
+                    deferred_limits: Vec::new(),
                 });
             }
             Some(RequestMappingResult::NoSecurityPolicy) => {
@@ -213,6 +314,7 @@ pub fn inspect_generic_request_map_init<GH: Grasshopper>(
                     tags,
                     rinfo,
                     stats: Stats::new(logs.start, "unknown".into()),
+                    deferred_limits: Vec::new(),
                 });
             }
             None => {
@@ -234,11 +336,29 @@ pub fn inspect_generic_request_map_init<GH: Grasshopper>(
                     tags,
                     rinfo,
                     stats: Stats::new(logs.start, "unknown".into()),
+                    deferred_limits: Vec::new(),
                 });
             }
         };
     ntags.extend(tags);
 
+    // automated bans: clients recorded by a ban action are rejected before
+    // the analysis pipeline runs
+    if banlist::is_banned(&reqinfo.rinfo.geoip.ipstr, &reqinfo.session) {
+        let mut tags = ntags;
+        tags.insert("banned", Location::Ip);
+        let action = SimpleAction::default();
+        let br = BlockReason::banned(action.atype.to_raw());
+        let decision = action.to_decision(logs, precision_level, mgh, &reqinfo, &mut tags, vec![br]);
+        return Err(AnalyzeResult {
+            decision,
+            tags,
+            rinfo: reqinfo,
+            stats: Stats::new(logs.start, "unknown".into()),
+            deferred_limits: Vec::new(),
+        });
+    }
+
     Ok(APhase0 {
         stats,
         itags: ntags,
@@ -246,6 +366,8 @@ pub fn inspect_generic_request_map_init<GH: Grasshopper>(
         precision_level,
         globalfilter_dec,
         flows,
+        first_seen,
+        sticky_tags,
     })
 }
 
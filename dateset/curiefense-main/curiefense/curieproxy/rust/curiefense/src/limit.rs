@@ -1,19 +1,33 @@
+use crate::counterstore::{counter_store, CounterQuery};
 use crate::interface::stats::{BStageFlow, BStageLimit, StatsCollect};
 use crate::logs::Logs;
-use crate::redis::REDIS_KEY_PREFIX;
-use redis::aio::ConnectionManager;
+use crate::redis::{jittered_ttl, mask_user_value, REDIS_KEY_PREFIX};
 
 use crate::config::limit::Limit;
+use crate::config::limit::LimitCondition;
 use crate::config::limit::LimitThreshold;
+use crate::config::raw::Relation;
 use crate::interface::{stronger_decision, BlockReason, Location, SimpleDecision, Tags};
-use crate::utils::{select_string, RequestInfo};
+use crate::timewindow::{bucket_start, seconds_to_rollover, TimeWindow};
+use crate::utils::{check_selector_cond, select_string, RequestInfo};
 
 fn build_key(reqinfo: &RequestInfo, tags: &Tags, limit: &Limit) -> Option<String> {
     let mut key = limit.id.clone();
     for kpart in limit.key.iter().map(|r| select_string(reqinfo, r, Some(tags))) {
-        key += &kpart?;
+        key += &mask_user_value(&kpart?);
     }
-    Some(format!("{}{:X}", *REDIS_KEY_PREFIX, md5::compute(key)))
+    Some(format!(
+        "{}{}{:X}",
+        *REDIS_KEY_PREFIX,
+        limit.key_prefix.as_deref().unwrap_or(""),
+        md5::compute(key)
+    ))
+}
+
+/// renders the redis key of a limit for a given request, used by the
+/// configuration linter to preview keys from a sample request
+pub fn example_key(reqinfo: &RequestInfo, tags: &Tags, limit: &Limit) -> Option<String> {
+    build_key(reqinfo, tags, limit)
 }
 
 #[allow(clippy::too_many_arguments)]
@@ -36,18 +50,38 @@ fn limit_pure_react(tags: &mut Tags, limit: &Limit, threshold: &LimitThreshold)
     )
 }
 
-fn limit_match(tags: &Tags, elem: &Limit) -> bool {
+fn condition_match(reqinfo: &RequestInfo, tags: &Tags, cond: &LimitCondition) -> bool {
+    match cond {
+        LimitCondition::Rel(Relation::And, entries) => entries.iter().all(|e| condition_match(reqinfo, tags, e)),
+        LimitCondition::Rel(Relation::Or, entries) => entries.iter().any(|e| condition_match(reqinfo, tags, e)),
+        LimitCondition::Entry(negated, conds) => {
+            conds.iter().all(|c| check_selector_cond(reqinfo, tags, c)) ^ negated
+        }
+    }
+}
+
+fn limit_match(reqinfo: &RequestInfo, tags: &Tags, elem: &Limit) -> bool {
     if elem.exclude.iter().any(|e| tags.contains(e)) {
         return false;
     }
     if !(elem.include.is_empty() || elem.include.iter().any(|e| tags.contains(e))) {
         return false;
     }
+    if let Some(cond) = &elem.condition {
+        if !condition_match(reqinfo, tags, cond) {
+            return false;
+        }
+    }
     true
 }
 
+/// whether a limit applies to this request at all, for the explain output
+pub fn limit_applies(reqinfo: &RequestInfo, tags: &Tags, limit: &Limit) -> bool {
+    limit_match(reqinfo, tags, limit)
+}
+
 /// an item that needs to be checked in redis
-#[derive(Clone)]
+#[derive(Clone, Debug)]
 pub struct LimitCheck {
     pub key: String,
     pub pairwith: Option<String>,
@@ -58,13 +92,33 @@ impl LimitCheck {
     pub fn zero_limits(&self) -> bool {
         self.limit.thresholds.iter().all(|t| t.limit == 0)
     }
+
+    /// deferred limits are not incremented at request time, but when the
+    /// embedder reports a matching upstream status
+    pub fn deferred(&self) -> bool {
+        !self.limit.count_status.is_empty()
+    }
+
+    /// counter lifetime: the jittered timeframe for rolling windows, the
+    /// time left until the boundary for calendar windows (jitter would
+    /// defeat the alignment)
+    pub fn ttl(&self) -> u64 {
+        if self.limit.window == TimeWindow::Rolling {
+            jittered_ttl(self.limit.timeframe, self.limit.ttl_jitter)
+        } else {
+            let now = chrono::Utc::now().timestamp() as u64;
+            seconds_to_rollover(self.limit.window, self.limit.window_offset, now, self.limit.timeframe)
+        }
+    }
 }
 
 /// generate information that needs to be checked in redis for limit checks
 pub fn limit_info(logs: &mut Logs, reqinfo: &RequestInfo, limits: &[Limit], tags: &Tags) -> Vec<LimitCheck> {
     let mut out = Vec::new();
+    // while origin protection is engaged, limit thresholds are lowered
+    let divisor = crate::originprotection::limit_divisor(&reqinfo.rinfo.secpolicy);
     for limit in limits {
-        if !limit_match(tags, limit) {
+        if !limit_match(reqinfo, tags, limit) {
             continue;
         }
         let key = match build_key(reqinfo, tags, limit) {
@@ -73,6 +127,16 @@ pub fn limit_info(logs: &mut Logs, reqinfo: &RequestInfo, limits: &[Limit], tags
             None => continue,
             Some(k) => k,
         };
+        // calendar windows mix the bucket start into the key, so that a new
+        // counter starts on every boundary
+        let key = match bucket_start(
+            limit.window,
+            limit.window_offset,
+            chrono::Utc::now().timestamp() as u64,
+        ) {
+            None => key,
+            Some(bucket) => format!("{}@{}", key, bucket),
+        };
         let pairwith = match &limit.pairwith {
             None => None,
             Some(sel) => match select_string(reqinfo, sel, Some(tags)) {
@@ -81,76 +145,75 @@ pub fn limit_info(logs: &mut Logs, reqinfo: &RequestInfo, limits: &[Limit], tags
             },
         };
         logs.debug(|| format!("checking limit[{}/{:?}] {:?}", key, pairwith, limit));
-        out.push(LimitCheck {
-            key,
-            pairwith,
-            limit: limit.clone(),
-        })
+        let mut limit = limit.clone();
+        if divisor > 1 {
+            for threshold in &mut limit.thresholds {
+                threshold.limit /= divisor;
+            }
+        }
+        out.push(LimitCheck { key, pairwith, limit })
     }
     out
 }
 
 #[derive(Clone)]
 pub struct LimitResult {
-    pub limit: Limit,
+    pub check: LimitCheck,
     pub curcount: i64,
 }
 
-pub fn limit_build_query(pipe: &mut redis::Pipeline, checks: &[LimitCheck]) {
+/// translates the limit checks into counter store queries, zero limit checks
+/// are skipped as they always trigger
+pub fn limit_queries(checks: &[LimitCheck]) -> Vec<CounterQuery> {
+    let mut out = Vec::new();
     for check in checks {
-        let key = &check.key;
-        if !check.zero_limits() {
-            match &check.pairwith {
-                None => {
-                    pipe.cmd("INCR").arg(key).cmd("TTL").arg(key);
-                }
-                Some(pv) => {
-                    pipe.cmd("SADD")
-                        .arg(key)
-                        .arg(pv)
-                        .ignore()
-                        .cmd("SCARD")
-                        .arg(key)
-                        .cmd("TTL")
-                        .arg(key);
-                }
-            };
+        if check.zero_limits() {
+            continue;
+        }
+        let ttl = check.ttl();
+        if check.deferred() {
+            // deferred limits are only read at request time, incrementation
+            // happens when the upstream status is reported
+            out.push(match &check.pairwith {
+                None => CounterQuery::Read { key: check.key.clone() },
+                Some(_) => CounterQuery::MemberCount { key: check.key.clone() },
+            });
+        } else {
+            out.push(match &check.pairwith {
+                None => CounterQuery::Incr {
+                    key: check.key.clone(),
+                    ttl,
+                },
+                Some(pv) => CounterQuery::AddMember {
+                    key: check.key.clone(),
+                    member: mask_user_value(pv),
+                    ttl,
+                },
+            });
         }
     }
+    out
 }
 
-pub async fn limit_resolve_query<I: Iterator<Item = Option<i64>>>(
+/// matches the counter store answers back to their checks, the iterator must
+/// come from running the queries built by limit_queries
+pub fn limit_resolve<I: Iterator<Item = i64>>(
     logs: &mut Logs,
-    redis: &mut ConnectionManager,
     iter: &mut I,
     checks: Vec<LimitCheck>,
 ) -> anyhow::Result<Vec<LimitResult>> {
     let mut out = Vec::new();
-    let mut pipe = redis::pipe();
-
     for check in checks {
-        let (curcount, expire) = if check.zero_limits() {
-            (1, 0)
+        let curcount = if check.zero_limits() {
+            1
         } else {
-            let curcount = match iter.next() {
+            match iter.next() {
                 None => anyhow::bail!("Empty iterator when getting curcount for {:?}", check.limit),
-                Some(r) => r.unwrap_or(0),
-            };
-            let expire = match iter.next() {
-                None => anyhow::bail!("Empty iterator when getting expire for {:?}", check.limit),
-                Some(r) => r.unwrap_or(-1),
-            };
-            (curcount, expire)
+                Some(r) => r,
+            }
         };
-        logs.debug(|| format!("limit {} curcount={} expire={}", check.limit.id, curcount, expire));
-        if expire < 0 {
-            pipe.cmd("EXPIRE").arg(&check.key).arg(check.limit.timeframe);
-        }
-        pipe.query_async(redis).await?;
-        out.push(LimitResult {
-            limit: check.limit,
-            curcount,
-        })
+        logs.debug(|| format!("limit {} curcount={}", check.limit.id, curcount));
+        out.push(LimitResult { check, curcount })
     }
     Ok(out)
 }
@@ -165,11 +228,11 @@ pub fn limit_process(
     let mut out = SimpleDecision::Pass;
     for result in results {
         if result.curcount > 0 {
-            for threshold in &result.limit.thresholds {
+            for threshold in &result.check.limit.thresholds {
                 // Only one action with highest limit larger than current
                 // counter will be applied, all the rest will be skipped.
                 if result.curcount > threshold.limit as i64 {
-                    out = stronger_decision(out, limit_pure_react(tags, &result.limit, threshold));
+                    out = stronger_decision(out, limit_pure_react(tags, &result.check.limit, threshold));
                 }
             }
         }
@@ -177,3 +240,77 @@ pub fn limit_process(
 
     (out, stats.limit(nlimits, results.len()))
 }
+
+/// remaining quota for calendar window limits, surfaced through the decision
+/// annotations so that embedders can emit X-RateLimit style response headers
+pub fn limit_quota(results: &[LimitResult]) -> Option<serde_json::Value> {
+    let now = chrono::Utc::now().timestamp() as u64;
+    let quotas: Vec<serde_json::Value> = results
+        .iter()
+        .filter(|r| r.check.limit.window != TimeWindow::Rolling)
+        .map(|r| {
+            let limit = r.check.limit.thresholds.iter().map(|t| t.limit).max().unwrap_or(0);
+            serde_json::json!({
+                "id": &r.check.limit.id,
+                "name": &r.check.limit.name,
+                "limit": limit,
+                "remaining": limit.saturating_sub(r.curcount.max(0) as u64),
+                "reset": seconds_to_rollover(
+                    r.check.limit.window,
+                    r.check.limit.window_offset,
+                    now,
+                    r.check.limit.timeframe
+                ),
+            })
+        })
+        .collect();
+    if quotas.is_empty() {
+        None
+    } else {
+        Some(serde_json::Value::Array(quotas))
+    }
+}
+
+/// increments the counters of deferred limits once the embedder knows the
+/// upstream status, so that only matching responses (such as failed logins)
+/// are counted
+pub async fn limit_report_status(logs: &mut Logs, checks: &[LimitCheck], status: u32) {
+    if *crate::limit_memory::MEMORY_LIMITS {
+        crate::limit_memory::limit_report_status(logs, checks, status);
+        return;
+    }
+    let mut queries = Vec::new();
+    for check in checks.iter().filter(|c| c.limit.count_status.contains(&status)) {
+        logs.debug(|| format!("limit {} counted status {}", check.limit.id, status));
+        let ttl = check.ttl();
+        queries.push(match &check.pairwith {
+            None => CounterQuery::Incr {
+                key: check.key.clone(),
+                ttl,
+            },
+            Some(pv) => CounterQuery::AddMember {
+                key: check.key.clone(),
+                member: mask_user_value(pv),
+                ttl,
+            },
+        });
+    }
+    if queries.is_empty() {
+        return;
+    }
+    let mut store = match counter_store().await {
+        Ok(s) => s,
+        Err(rr) => {
+            logs.error(|| format!("Could not connect to the counter store {}", rr));
+            return;
+        }
+    };
+    if let Err(rr) = store.run(queries).await {
+        logs.error(|| format!("{}", rr));
+    }
+}
+
+/// blocking version of limit_report_status
+pub fn limit_report_status_block(logs: &mut Logs, checks: &[LimitCheck], status: u32) {
+    async_std::task::block_on(limit_report_status(logs, checks, status))
+}
@@ -0,0 +1,207 @@
+/* in-process limiter backend
+
+   Single instance deployments can run without a redis container by setting
+   LIMIT_BACKEND=memory. Counters are then kept in a process local map with
+   the same semantics as the redis backend: fixed windows with a jittered
+   TTL, set cardinality for paired limits, and read-only checks for deferred
+   limits. Expired entries are evicted periodically, whenever the map is
+   touched and the last sweep is old enough.
+
+   Note that the counters are local to the process, so this backend is not
+   suitable when several instances serve the same traffic.
+*/
+
+use lazy_static::lazy_static;
+use std::collections::{HashMap, HashSet};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use crate::limit::{LimitCheck, LimitResult};
+use crate::logs::Logs;
+use crate::redis::mask_user_value;
+
+/// how often expired entries are swept from the map
+const EVICTION_PERIOD: Duration = Duration::from_secs(60);
+
+struct MemoryEntry {
+    expires: Instant,
+    count: i64,
+    /// masked pair values, for limits with a pairwith selector
+    pairs: HashSet<String>,
+}
+
+struct MemoryLimiter {
+    entries: HashMap<String, MemoryEntry>,
+    last_eviction: Instant,
+}
+
+lazy_static! {
+    /// when set, limits are checked against the in-process map instead of redis
+    pub static ref MEMORY_LIMITS: bool = std::env::var("LIMIT_BACKEND")
+        .map(|s| s == "memory")
+        .unwrap_or(false);
+    static ref LIMITER: Mutex<MemoryLimiter> = Mutex::new(MemoryLimiter {
+        entries: HashMap::new(),
+        last_eviction: Instant::now(),
+    });
+}
+
+fn evict_expired(limiter: &mut MemoryLimiter) {
+    let now = Instant::now();
+    if now.duration_since(limiter.last_eviction) < EVICTION_PERIOD {
+        return;
+    }
+    limiter.entries.retain(|_, e| e.expires > now);
+    limiter.last_eviction = now;
+}
+
+/// returns the live entry for a key, resetting it when its window expired
+fn entry<'t>(limiter: &'t mut MemoryLimiter, check: &LimitCheck) -> &'t mut MemoryEntry {
+    let now = Instant::now();
+    let fresh = || MemoryEntry {
+        expires: now + Duration::from_secs(check.ttl()),
+        count: 0,
+        pairs: HashSet::new(),
+    };
+    let e = limiter.entries.entry(check.key.clone()).or_insert_with(fresh);
+    if e.expires <= now {
+        *e = fresh();
+    }
+    e
+}
+
+/// checks the limits against the in-process map, mirroring the counter store
+/// queries built by limit_queries
+pub fn limit_check(logs: &mut Logs, checks: Vec<LimitCheck>) -> Vec<LimitResult> {
+    let mut limiter = match LIMITER.lock() {
+        Ok(l) => l,
+        Err(rr) => {
+            logs.error(|| format!("Could not lock the in-process limiter: {}", rr));
+            return Vec::new();
+        }
+    };
+    evict_expired(&mut limiter);
+    let mut out = Vec::new();
+    for check in checks {
+        let curcount = if check.zero_limits() {
+            1
+        } else {
+            let e = entry(&mut limiter, &check);
+            if check.deferred() {
+                // deferred limits are only read at request time, incrementation
+                // happens when the upstream status is reported
+                match &check.pairwith {
+                    None => e.count,
+                    Some(_) => e.pairs.len() as i64,
+                }
+            } else {
+                match &check.pairwith {
+                    None => {
+                        e.count += 1;
+                        e.count
+                    }
+                    Some(pv) => {
+                        e.pairs.insert(mask_user_value(pv));
+                        e.pairs.len() as i64
+                    }
+                }
+            }
+        };
+        logs.debug(|| format!("limit {} curcount={} (memory)", check.limit.id, curcount));
+        out.push(LimitResult { check, curcount });
+    }
+    out
+}
+
+/// increments the counters of deferred limits once the upstream status is
+/// known, mirroring limit_report_status
+pub fn limit_report_status(logs: &mut Logs, checks: &[LimitCheck], status: u32) {
+    let todo: Vec<&LimitCheck> = checks
+        .iter()
+        .filter(|c| c.limit.count_status.contains(&status))
+        .collect();
+    if todo.is_empty() {
+        return;
+    }
+    let mut limiter = match LIMITER.lock() {
+        Ok(l) => l,
+        Err(rr) => {
+            logs.error(|| format!("Could not lock the in-process limiter: {}", rr));
+            return;
+        }
+    };
+    evict_expired(&mut limiter);
+    for check in todo {
+        let e = entry(&mut limiter, check);
+        match &check.pairwith {
+            None => e.count += 1,
+            Some(pv) => {
+                e.pairs.insert(mask_user_value(pv));
+            }
+        }
+        logs.debug(|| format!("limit {} counted status {} (memory)", check.limit.id, status));
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::config::limit::{Limit, LimitThreshold};
+    use crate::interface::SimpleAction;
+    use crate::timewindow::TimeWindow;
+
+    fn mklimit(id: &str, deferred: bool) -> Limit {
+        Limit {
+            id: id.to_string(),
+            name: id.to_string(),
+            timeframe: 60,
+            thresholds: vec![LimitThreshold {
+                limit: 2,
+                action: SimpleAction::default(),
+            }],
+            exclude: HashSet::new(),
+            include: HashSet::new(),
+            condition: None,
+            pairwith: None,
+            key: Vec::new(),
+            key_prefix: None,
+            tags: Vec::new(),
+            count_status: if deferred { vec![403] } else { Vec::new() },
+            ttl_jitter: 0,
+            window: TimeWindow::Rolling,
+            window_offset: 0,
+        }
+    }
+
+    fn mkcheck(key: &str, limit: Limit) -> LimitCheck {
+        LimitCheck {
+            key: key.to_string(),
+            pairwith: None,
+            limit,
+        }
+    }
+
+    #[test]
+    fn counting_increments() {
+        let mut logs = Logs::default();
+        let limit = mklimit("memtest-count", false);
+        for expected in 1..4 {
+            let results = limit_check(&mut logs, vec![mkcheck("memtest-count", limit.clone())]);
+            assert_eq!(results.len(), 1);
+            assert_eq!(results[0].curcount, expected);
+        }
+    }
+
+    #[test]
+    fn deferred_reads_only() {
+        let mut logs = Logs::default();
+        let limit = mklimit("memtest-deferred", true);
+        let check = mkcheck("memtest-deferred", limit);
+        let results = limit_check(&mut logs, vec![check.clone()]);
+        assert_eq!(results[0].curcount, 0);
+        limit_report_status(&mut logs, &[check.clone()], 403);
+        limit_report_status(&mut logs, &[check.clone()], 200);
+        let results = limit_check(&mut logs, vec![check]);
+        assert_eq!(results[0].curcount, 1);
+    }
+}
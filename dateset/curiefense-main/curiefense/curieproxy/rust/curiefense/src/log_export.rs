@@ -0,0 +1,111 @@
+/* Kafka export of access logs and aggregates
+
+   Built with the kafka-export feature, this module publishes the JSON
+   records produced by `jsonlog` and periodic `aggregated_values` snapshots
+   to Kafka, so that SIEM ingestion does not need a log shipping sidecar.
+
+   KAFKA_BROKERS (bootstrap servers) enables the export. Access logs go to
+   KAFKA_LOGS_TOPIC (default curiefense-access-logs) and aggregate entries
+   to KAFKA_AGGREGATES_TOPIC (default curiefense-aggregates), every
+   KAFKA_AGGREGATES_PERIOD seconds (default 60, 0 disables the snapshots).
+   Messages are keyed by secpolid, so that records for one policy land on
+   one partition and stay ordered.
+
+   Delivery is best effort: sends happen on detached tasks and errors are
+   counted, never propagated to the request path.
+*/
+
+use lazy_static::lazy_static;
+use rdkafka::config::ClientConfig;
+use rdkafka::producer::{FutureProducer, FutureRecord};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::Duration;
+
+lazy_static! {
+    static ref LOGS_TOPIC: String =
+        std::env::var("KAFKA_LOGS_TOPIC").unwrap_or_else(|_| "curiefense-access-logs".to_string());
+    static ref AGGREGATES_TOPIC: String =
+        std::env::var("KAFKA_AGGREGATES_TOPIC").unwrap_or_else(|_| "curiefense-aggregates".to_string());
+    /// seconds between aggregate snapshots, 0 disables them
+    static ref AGGREGATES_PERIOD: u64 = std::env::var("KAFKA_AGGREGATES_PERIOD")
+        .ok()
+        .and_then(|s| s.parse().ok())
+        .unwrap_or(60);
+    static ref PRODUCER: Option<FutureProducer> = init_producer();
+}
+
+/// builds the producer when KAFKA_BROKERS is set, starting the aggregates
+/// snapshot task alongside it
+fn init_producer() -> Option<FutureProducer> {
+    let brokers = std::env::var("KAFKA_BROKERS").ok().filter(|s| !s.is_empty())?;
+    match ClientConfig::new().set("bootstrap.servers", &brokers).create() {
+        Ok(producer) => {
+            if *AGGREGATES_PERIOD > 0 {
+                async_std::task::spawn(export_aggregates());
+            }
+            Some(producer)
+        }
+        Err(_) => None,
+    }
+}
+
+/// failed publish attempts, for diagnostics
+static ERRORS: AtomicU64 = AtomicU64::new(0);
+
+pub fn enabled() -> bool {
+    PRODUCER.is_some()
+}
+
+/// amount of failed publish attempts
+pub fn publish_errors() -> u64 {
+    ERRORS.load(Ordering::Relaxed)
+}
+
+async fn send(producer: &FutureProducer, topic: &str, key: &str, payload: &[u8]) {
+    let record = FutureRecord::to(topic).key(key).payload(payload);
+    if producer.send(record, Duration::from_secs(0)).await.is_err() {
+        ERRORS.fetch_add(1, Ordering::Relaxed);
+    }
+}
+
+/// publishes one access log record, keyed by the security policy id; the
+/// delivery happens on a detached task so that the request path is not
+/// delayed
+pub fn publish_log(secpolid: &str, record: &[u8]) {
+    let producer = match &*PRODUCER {
+        None => return,
+        Some(p) => p.clone(),
+    };
+    let key = secpolid.to_string();
+    let payload = record.to_vec();
+    async_std::task::spawn(async move {
+        send(&producer, &LOGS_TOPIC, &key, &payload).await;
+    });
+}
+
+/// periodically publishes the aggregated samples, one message per entry so
+/// that each one can be keyed by its secpolid
+async fn export_aggregates() {
+    loop {
+        async_std::task::sleep(Duration::from_secs(*AGGREGATES_PERIOD)).await;
+        let producer = match &*PRODUCER {
+            None => return,
+            Some(p) => p.clone(),
+        };
+        let raw = crate::interface::aggregator::aggregated_values().await;
+        let entries: Vec<serde_json::Value> = match serde_json::from_str(&raw) {
+            Ok(e) => e,
+            Err(_) => continue,
+        };
+        for entry in entries {
+            let key = entry
+                .get("secpolid")
+                .and_then(|v| v.as_str())
+                .unwrap_or("-")
+                .to_string();
+            if let Ok(payload) = serde_json::to_vec(&entry) {
+                send(&producer, &AGGREGATES_TOPIC, &key, &payload).await;
+            }
+        }
+    }
+}
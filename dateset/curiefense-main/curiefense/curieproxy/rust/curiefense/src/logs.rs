@@ -1,6 +1,56 @@
+use lazy_static::lazy_static;
+use regex::Regex;
 use serde::Serialize;
 use std::time::Instant;
 
+lazy_static! {
+    /// comma separated list of sensitive field names that are redacted from
+    /// log messages before they are stored
+    static ref REDACTED_FIELDS: Vec<String> = std::env::var("LOGS_REDACTED_FIELDS")
+        .unwrap_or_else(|_| "authorization,proxy-authorization,cookie,set-cookie".to_string())
+        .split(',')
+        .map(|s| s.trim().to_lowercase())
+        .filter(|s| !s.is_empty())
+        .collect();
+    /// set LOGS_REDACT=false to keep raw values, for isolated test environments
+    static ref LOGS_REDACT: bool = std::env::var("LOGS_REDACT")
+        .map(|s| s.parse().unwrap_or(true))
+        .unwrap_or(true);
+    static ref REDACT_RE: Option<Regex> = build_redacter(&REDACTED_FIELDS);
+}
+
+fn build_redacter(fields: &[String]) -> Option<Regex> {
+    if fields.is_empty() {
+        return None;
+    }
+    let alternatives = fields.iter().map(|f| regex::escape(f)).collect::<Vec<_>>().join("|");
+    // matches "field: value" and "field=value" interpolations, where the value
+    // runs until the next delimiter
+    Regex::new(&format!(r#"(?i)\b({})\b\s*[:=]\s*[^\s,;"']+"#, alternatives)).ok()
+}
+
+/// masks the values of sensitive fields, so that debug messages that
+/// interpolate raw header values do not leak credentials
+fn redact_sensitive(message: String) -> String {
+    match REDACT_RE.as_ref() {
+        None => message,
+        Some(re) => re.replace_all(&message, "$1: [REDACTED]").into_owned(),
+    }
+}
+
+/// masks the values of sensitive keys in structured fields, only the top
+/// level of the object is inspected
+fn redact_fields(mut fields: serde_json::Value, redacted: &[String]) -> serde_json::Value {
+    if let Some(map) = fields.as_object_mut() {
+        for (k, v) in map.iter_mut() {
+            if redacted.contains(&k.to_lowercase()) {
+                *v = serde_json::Value::String("[REDACTED]".to_string());
+            }
+        }
+    }
+    fields
+}
+
 #[derive(Debug, Clone)]
 pub struct Logs {
     pub level: LogLevel,
@@ -13,6 +63,9 @@ pub struct Log {
     pub elapsed_micros: u64,
     pub level: LogLevel,
     pub message: String,
+    /// optional structured fields, serialized under the record in the json logs
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub fields: Option<serde_json::Value>,
 }
 
 #[derive(Debug, Clone, Serialize, PartialEq, Eq, PartialOrd, Ord, Copy)]
@@ -53,7 +106,11 @@ impl std::str::FromStr for LogLevel {
 
 impl std::fmt::Display for Log {
     fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
-        write!(f, "{} {}µs {}", self.level.short(), self.elapsed_micros, self.message)
+        write!(f, "{} {}µs {}", self.level.short(), self.elapsed_micros, self.message)?;
+        match &self.fields {
+            None => Ok(()),
+            Some(fields) => write!(f, " {}", fields),
+        }
     }
 }
 
@@ -98,13 +155,32 @@ impl Logs {
     }
 
     pub fn log<S: CheapString>(&mut self, level: LogLevel, message: S) {
+        self.push(level, message, None)
+    }
+
+    /// logs a message with structured fields attached, for consumers that
+    /// analyze the json logs instead of the formatted strings
+    pub fn log_fields<S: CheapString>(&mut self, level: LogLevel, message: S, fields: serde_json::Value) {
+        self.push(level, message, Some(fields))
+    }
+
+    fn push<S: CheapString>(&mut self, level: LogLevel, message: S, fields: Option<serde_json::Value>) {
         if level < self.level {
             return;
         }
+        let (message, fields) = if *LOGS_REDACT {
+            (
+                redact_sensitive(message.c_to_string()),
+                fields.map(|f| redact_fields(f, &REDACTED_FIELDS)),
+            )
+        } else {
+            (message.c_to_string(), fields)
+        };
         self.logs.push(Log {
             elapsed_micros: self.start.elapsed().as_micros() as u64,
-            message: message.c_to_string(),
+            message,
             level,
+            fields,
         })
     }
 
@@ -121,6 +197,19 @@ impl Logs {
         self.log(LogLevel::Error, message);
     }
 
+    pub fn debug_fields<S: CheapString>(&mut self, message: S, fields: serde_json::Value) {
+        self.log_fields(LogLevel::Debug, message, fields);
+    }
+    pub fn info_fields<S: CheapString>(&mut self, message: S, fields: serde_json::Value) {
+        self.log_fields(LogLevel::Info, message, fields);
+    }
+    pub fn warning_fields<S: CheapString>(&mut self, message: S, fields: serde_json::Value) {
+        self.log_fields(LogLevel::Warning, message, fields);
+    }
+    pub fn error_fields<S: CheapString>(&mut self, message: S, fields: serde_json::Value) {
+        self.log_fields(LogLevel::Error, message, fields);
+    }
+
     pub fn to_stringvec(&self) -> Vec<String> {
         self.logs.iter().map(|l| l.to_string()).collect()
     }
@@ -139,6 +228,70 @@ impl Serialize for Logs {
     where
         S: serde::Serializer,
     {
-        serializer.collect_seq(self.logs.iter().map(|l| l.to_string()))
+        // records are serialized as structures, so that the fields of
+        // log_fields entries survive into the json logs
+        serializer.collect_seq(self.logs.iter())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn redacter() -> Regex {
+        build_redacter(&["authorization".to_string(), "cookie".to_string()]).unwrap()
+    }
+
+    fn redact(msg: &str) -> String {
+        redacter().replace_all(msg, "$1: [REDACTED]").into_owned()
+    }
+
+    #[test]
+    fn redacts_authorization() {
+        assert_eq!(
+            redact("inspecting header authorization: Bearer secret-token"),
+            "inspecting header authorization: [REDACTED]"
+        );
+    }
+
+    #[test]
+    fn redacts_cookie_equal_sign() {
+        assert_eq!(
+            redact("Cookie=sessionid12345; path matched"),
+            "Cookie: [REDACTED]; path matched"
+        );
+    }
+
+    #[test]
+    fn keeps_other_fields() {
+        let msg = "content-type: application/json matched";
+        assert_eq!(redact(msg), msg);
+    }
+
+    #[test]
+    fn does_not_redact_substrings() {
+        let msg = "x-authorization-hint: none";
+        assert_eq!(redact(msg), msg);
+    }
+
+    #[test]
+    fn fields_are_serialized_under_the_record() {
+        let mut logs = Logs::default();
+        logs.info("plain message");
+        logs.info_fields("structured message", serde_json::json!({ "count": 3 }));
+        let serialized = logs.to_json();
+        assert!(serialized[0].get("fields").is_none());
+        assert_eq!(serialized[1]["fields"]["count"], 3);
+        assert_eq!(serialized[1]["message"], "structured message");
+    }
+
+    #[test]
+    fn sensitive_field_values_are_redacted() {
+        let redacted = redact_fields(
+            serde_json::json!({ "authorization": "Bearer secret", "count": 1 }),
+            &["authorization".to_string()],
+        );
+        assert_eq!(redacted["authorization"], "[REDACTED]");
+        assert_eq!(redacted["count"], 1);
     }
 }
@@ -0,0 +1,114 @@
+/// automatic origin protection
+///
+/// Tracks upstream failures (5xx statuses and timeouts, reported through the
+/// proxy feedback map) per security policy entry. When consecutive failures
+/// reach the configured threshold, the entry enters protection mode for a
+/// cooldown period: matching requests are tagged origin-protection, rate
+/// limit thresholds are divided by the configured divisor, and tag driven
+/// rules (such as challenging unauthenticated traffic) can key off the tag.
+use lazy_static::lazy_static;
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use crate::config::hostmap::SecurityPolicy;
+use crate::outbound::CircuitBreaker;
+
+lazy_static! {
+    /// one breaker per security policy entry, created on first use
+    static ref BREAKERS: Mutex<HashMap<String, Arc<CircuitBreaker>>> = Mutex::new(HashMap::new());
+}
+
+fn breaker_for(secpolicy: &SecurityPolicy) -> Option<Arc<CircuitBreaker>> {
+    let settings = secpolicy.origin_protection.as_ref()?;
+    let key = format!("{}:{}", secpolicy.policy.id, secpolicy.entry.id);
+    let mut breakers = BREAKERS.lock().ok()?;
+    Some(
+        breakers
+            .entry(key)
+            .or_insert_with(|| {
+                Arc::new(CircuitBreaker::with_settings(
+                    settings.threshold,
+                    Duration::from_secs(settings.cooldown),
+                ))
+            })
+            .clone(),
+    )
+}
+
+/// reports the upstream outcome of a request, as seen in the proxy feedback map
+pub fn report_upstream(secpolicy: &SecurityPolicy, status: Option<u32>, timed_out: bool) {
+    if let Some(breaker) = breaker_for(secpolicy) {
+        if timed_out || matches!(status, Some(s) if s >= 500) {
+            breaker.report_failure();
+        } else if status.is_some() {
+            breaker.report_success();
+        }
+    }
+}
+
+/// true when the entry is currently in protection mode
+pub fn engaged(secpolicy: &SecurityPolicy) -> bool {
+    breaker_for(secpolicy).map(|b| b.is_open()).unwrap_or(false)
+}
+
+/// divisor to apply to rate limit thresholds, 1 when protection is not engaged
+pub fn limit_divisor(secpolicy: &SecurityPolicy) -> u64 {
+    match &secpolicy.origin_protection {
+        Some(settings) if engaged(secpolicy) => settings.limit_divisor,
+        _ => 1,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::hostmap::{OriginProtection, PolicyId};
+
+    fn mkpolicy(id: &str) -> SecurityPolicy {
+        SecurityPolicy {
+            policy: PolicyId {
+                id: id.to_string(),
+                name: "policy name".to_string(),
+            },
+            origin_protection: Some(OriginProtection {
+                threshold: 3,
+                cooldown: 60,
+                limit_divisor: 4,
+            }),
+            ..SecurityPolicy::default()
+        }
+    }
+
+    #[test]
+    fn protection_engages_and_recovers() {
+        let secpol = mkpolicy("op-test-engage");
+        assert!(!engaged(&secpol));
+        for _ in 0..3 {
+            report_upstream(&secpol, Some(503), false);
+        }
+        assert!(engaged(&secpol));
+        assert_eq!(limit_divisor(&secpol), 4);
+        report_upstream(&secpol, Some(200), false);
+        assert!(!engaged(&secpol));
+        assert_eq!(limit_divisor(&secpol), 1);
+    }
+
+    #[test]
+    fn timeouts_count_as_failures() {
+        let secpol = mkpolicy("op-test-timeout");
+        for _ in 0..3 {
+            report_upstream(&secpol, None, true);
+        }
+        assert!(engaged(&secpol));
+    }
+
+    #[test]
+    fn disabled_without_settings() {
+        let secpol = SecurityPolicy::default();
+        for _ in 0..10 {
+            report_upstream(&secpol, Some(503), false);
+        }
+        assert!(!engaged(&secpol));
+    }
+}
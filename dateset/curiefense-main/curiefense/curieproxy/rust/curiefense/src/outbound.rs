@@ -0,0 +1,345 @@
+/* shared connection management for outbound calls
+
+   Grasshopper HTTP calls, webhooks, remote configuration fetches and log
+   exporters all talk to external services, and each used to implement its
+   own ad-hoc connection handling. This module centralizes the policy side
+   of those calls: pooled reusable connections, timeouts, retries with
+   jittered exponential backoff, and a circuit breaker that stops hammering
+   an endpoint that keeps failing.
+*/
+
+use std::future::Future;
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use std::sync::Mutex;
+use std::time::Duration;
+
+use rand::Rng;
+
+/// tuning knobs for outbound calls, shared by all integrations
+#[derive(Clone, Debug)]
+pub struct OutboundConfig {
+    /// timeout for establishing a new connection
+    pub connect_timeout: Duration,
+    /// timeout for a single request attempt
+    pub request_timeout: Duration,
+    /// how many times a failed call is retried before giving up
+    pub max_retries: usize,
+    /// base delay for the exponential backoff
+    pub base_backoff: Duration,
+    /// upper bound for the backoff delay
+    pub max_backoff: Duration,
+    /// consecutive failures before the circuit breaker opens
+    pub breaker_threshold: usize,
+    /// how long an open breaker rejects calls before allowing a probe
+    pub breaker_cooldown: Duration,
+    /// maximum amount of idle connections kept in the pool
+    pub max_idle: usize,
+}
+
+impl Default for OutboundConfig {
+    fn default() -> Self {
+        fn from_env(var: &str, def: u64) -> u64 {
+            std::env::var(var).ok().and_then(|s| s.parse().ok()).unwrap_or(def)
+        }
+        OutboundConfig {
+            connect_timeout: Duration::from_millis(from_env("OUTBOUND_CONNECT_TIMEOUT_MS", 2000)),
+            request_timeout: Duration::from_millis(from_env("OUTBOUND_REQUEST_TIMEOUT_MS", 5000)),
+            max_retries: from_env("OUTBOUND_MAX_RETRIES", 2) as usize,
+            base_backoff: Duration::from_millis(from_env("OUTBOUND_BASE_BACKOFF_MS", 100)),
+            max_backoff: Duration::from_millis(from_env("OUTBOUND_MAX_BACKOFF_MS", 5000)),
+            breaker_threshold: from_env("OUTBOUND_BREAKER_THRESHOLD", 5) as usize,
+            breaker_cooldown: Duration::from_millis(from_env("OUTBOUND_BREAKER_COOLDOWN_MS", 30000)),
+            max_idle: from_env("OUTBOUND_MAX_IDLE", 4) as usize,
+        }
+    }
+}
+
+impl OutboundConfig {
+    /// backoff delay before the Nth retry, exponential with full jitter
+    pub fn backoff_delay(&self, attempt: usize) -> Duration {
+        let exp = self
+            .base_backoff
+            .saturating_mul(1u32.checked_shl(attempt as u32).unwrap_or(u32::MAX))
+            .min(self.max_backoff);
+        let jittered = rand::thread_rng().gen_range(0..=exp.as_millis() as u64);
+        Duration::from_millis(jittered)
+    }
+}
+
+/// why an outbound call ultimately failed
+#[derive(Debug)]
+pub enum OutboundError<E> {
+    /// the circuit breaker is open, the call was not attempted
+    BreakerOpen,
+    /// the last attempt did not complete within the request timeout
+    TimedOut,
+    /// the last attempt failed with the integration's own error
+    Inner(E),
+}
+
+impl<E: std::fmt::Display> std::fmt::Display for OutboundError<E> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            OutboundError::BreakerOpen => write!(f, "circuit breaker open"),
+            OutboundError::TimedOut => write!(f, "request timed out"),
+            OutboundError::Inner(e) => e.fmt(f),
+        }
+    }
+}
+
+/// a circuit breaker, opening after a configured amount of consecutive failures
+///
+/// all state is atomic so that the breaker can be shared between tasks
+/// without locking
+#[derive(Debug)]
+pub struct CircuitBreaker {
+    threshold: usize,
+    cooldown: Duration,
+    failures: AtomicUsize,
+    /// monotonic deadline in milliseconds until which the breaker is open
+    open_until: AtomicU64,
+}
+
+fn now_ms() -> u64 {
+    use std::time::{SystemTime, UNIX_EPOCH};
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_millis() as u64)
+        .unwrap_or(0)
+}
+
+impl CircuitBreaker {
+    pub fn new(config: &OutboundConfig) -> Self {
+        Self::with_settings(config.breaker_threshold, config.breaker_cooldown)
+    }
+
+    /// a breaker with explicit settings, for uses outside of outbound calls
+    pub fn with_settings(threshold: usize, cooldown: Duration) -> Self {
+        CircuitBreaker {
+            threshold,
+            cooldown,
+            failures: AtomicUsize::new(0),
+            open_until: AtomicU64::new(0),
+        }
+    }
+
+    /// true when calls should be rejected without being attempted
+    pub fn is_open(&self) -> bool {
+        self.open_until.load(Ordering::Relaxed) > now_ms()
+    }
+
+    pub fn report_success(&self) {
+        self.failures.store(0, Ordering::Relaxed);
+        self.open_until.store(0, Ordering::Relaxed);
+    }
+
+    pub fn report_failure(&self) {
+        let failures = self.failures.fetch_add(1, Ordering::Relaxed) + 1;
+        if failures >= self.threshold {
+            self.open_until
+                .store(now_ms() + self.cooldown.as_millis() as u64, Ordering::Relaxed);
+            // leave a single failure so that one cooldown probe failure reopens immediately
+            self.failures.store(self.threshold - 1, Ordering::Relaxed);
+        }
+    }
+}
+
+/// a pool of idle reusable connections
+///
+/// the pool never creates connections itself, callers check in the
+/// connections they established once they are done with them
+#[derive(Debug)]
+pub struct Pool<T> {
+    idle: Mutex<Vec<T>>,
+    max_idle: usize,
+}
+
+impl<T> Pool<T> {
+    pub fn new(config: &OutboundConfig) -> Self {
+        Pool {
+            idle: Mutex::new(Vec::new()),
+            max_idle: config.max_idle,
+        }
+    }
+
+    /// takes an idle connection out of the pool, if any
+    pub fn checkout(&self) -> Option<T> {
+        self.idle.lock().ok().and_then(|mut v| v.pop())
+    }
+
+    /// returns a healthy connection to the pool, dropping it when full
+    pub fn checkin(&self, conn: T) {
+        if let Ok(mut v) = self.idle.lock() {
+            if v.len() < self.max_idle {
+                v.push(conn);
+            }
+        }
+    }
+}
+
+/// runs an outbound call with timeout, retries with jittered backoff, and
+/// circuit breaking
+///
+/// the operation is handed the attempt number, starting at 0, so that
+/// integrations can log retries
+pub async fn with_backoff<F, Fut, A, E>(
+    config: &OutboundConfig,
+    breaker: &CircuitBreaker,
+    op: F,
+) -> Result<A, OutboundError<E>>
+where
+    F: Fn(usize) -> Fut,
+    Fut: Future<Output = Result<A, E>>,
+{
+    if breaker.is_open() {
+        return Err(OutboundError::BreakerOpen);
+    }
+    let mut attempt = 0;
+    loop {
+        let outcome = match async_std::future::timeout(config.request_timeout, op(attempt)).await {
+            Err(_) => Err(OutboundError::TimedOut),
+            Ok(Err(e)) => Err(OutboundError::Inner(e)),
+            Ok(Ok(a)) => Ok(a),
+        };
+        match outcome {
+            Ok(a) => {
+                breaker.report_success();
+                return Ok(a);
+            }
+            Err(e) => {
+                breaker.report_failure();
+                if attempt >= config.max_retries || breaker.is_open() {
+                    return Err(e);
+                }
+                async_std::task::sleep(config.backoff_delay(attempt)).await;
+                attempt += 1;
+            }
+        }
+    }
+}
+
+/// a pooled client for connection oriented integrations
+///
+/// the connection type is generic, integrations provide a connector and an
+/// operation; connections are reused on success and dropped on failure
+#[derive(Debug)]
+pub struct PooledClient<T> {
+    pub config: OutboundConfig,
+    pub breaker: CircuitBreaker,
+    pool: Pool<T>,
+}
+
+impl<T> PooledClient<T> {
+    pub fn new(config: OutboundConfig) -> Self {
+        let breaker = CircuitBreaker::new(&config);
+        let pool = Pool::new(&config);
+        PooledClient { config, breaker, pool }
+    }
+
+    /// runs an operation on a pooled connection, establishing one when the
+    /// pool is empty, with the configured retry and circuit breaking policy
+    ///
+    /// the operation returns the connection alongside its result so that it
+    /// can be checked back in for reuse
+    pub async fn request<Conn, ConnFut, F, Fut, A, E>(&self, connect: Conn, op: F) -> Result<A, OutboundError<E>>
+    where
+        Conn: Fn() -> ConnFut,
+        ConnFut: Future<Output = Result<T, E>>,
+        F: Fn(T) -> Fut,
+        Fut: Future<Output = Result<(T, A), E>>,
+    {
+        with_backoff(&self.config, &self.breaker, |_| async {
+            let conn = match self.pool.checkout() {
+                Some(c) => c,
+                None => match async_std::future::timeout(self.config.connect_timeout, connect()).await {
+                    Err(_) => return Err(None),
+                    Ok(r) => r.map_err(Some)?,
+                },
+            };
+            // a failed operation drops the connection instead of pooling it
+            let (conn, result) = op(conn).await.map_err(Some)?;
+            self.pool.checkin(conn);
+            Ok(result)
+        })
+        .await
+        .map_err(|e| match e {
+            OutboundError::BreakerOpen => OutboundError::BreakerOpen,
+            OutboundError::TimedOut | OutboundError::Inner(None) => OutboundError::TimedOut,
+            OutboundError::Inner(Some(e)) => OutboundError::Inner(e),
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::atomic::AtomicUsize;
+
+    fn quick_config() -> OutboundConfig {
+        OutboundConfig {
+            connect_timeout: Duration::from_millis(100),
+            request_timeout: Duration::from_millis(100),
+            max_retries: 2,
+            base_backoff: Duration::from_millis(1),
+            max_backoff: Duration::from_millis(2),
+            breaker_threshold: 3,
+            breaker_cooldown: Duration::from_millis(10000),
+            max_idle: 2,
+        }
+    }
+
+    #[test]
+    fn backoff_is_bounded() {
+        let config = quick_config();
+        for attempt in 0..64 {
+            assert!(config.backoff_delay(attempt) <= config.max_backoff);
+        }
+    }
+
+    #[test]
+    fn retries_until_success() {
+        let config = quick_config();
+        let breaker = CircuitBreaker::new(&config);
+        let calls = AtomicUsize::new(0);
+        let r: Result<usize, OutboundError<&str>> = async_std::task::block_on(with_backoff(&config, &breaker, |_| {
+            let calls = &calls;
+            async move {
+                if calls.fetch_add(1, Ordering::Relaxed) < 2 {
+                    Err("transient")
+                } else {
+                    Ok(42)
+                }
+            }
+        }));
+        assert_eq!(r.unwrap(), 42);
+        assert_eq!(calls.load(Ordering::Relaxed), 3);
+    }
+
+    #[test]
+    fn breaker_opens_and_rejects() {
+        let config = quick_config();
+        let breaker = CircuitBreaker::new(&config);
+        for _ in 0..config.breaker_threshold {
+            breaker.report_failure();
+        }
+        assert!(breaker.is_open());
+        let r: Result<usize, OutboundError<&str>> =
+            async_std::task::block_on(with_backoff(&config, &breaker, |_| async { Ok(1) }));
+        assert!(matches!(r, Err(OutboundError::BreakerOpen)));
+        breaker.report_success();
+        assert!(!breaker.is_open());
+    }
+
+    #[test]
+    fn pool_reuses_connections() {
+        let config = quick_config();
+        let pool: Pool<usize> = Pool::new(&config);
+        assert_eq!(pool.checkout(), None);
+        pool.checkin(1);
+        pool.checkin(2);
+        pool.checkin(3); // dropped, max_idle is 2
+        assert_eq!(pool.checkout(), Some(2));
+        assert_eq!(pool.checkout(), Some(1));
+        assert_eq!(pool.checkout(), None);
+    }
+}
@@ -0,0 +1,239 @@
+/* embedder plugin pipeline
+
+   The plugins map attached to a request is an opaque set of key/values
+   supplied by the embedder (callout based enrichment, or the embedded WASM
+   runtime when built with the wasm-plugins feature, see the wasmplugins
+   module). This module gives that map a contract: the configuration declares
+   an ordered list of plugins, whether each one is required, its time budget
+   and the action applied when a required plugin did not deliver.
+
+   By convention a plugin reports its values under "<id>" or "<id>:<field>"
+   keys; the "<id>:error" key marks a failed execution and "<id>:duration_us"
+   carries the execution time that is checked against the budget.
+*/
+
+use serde::Serialize;
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+
+use crate::config::raw::RawPluginStep;
+use crate::interface::{BlockReason, Location, SimpleAction};
+use crate::logs::Logs;
+use crate::utils::RequestInfo;
+
+/// one stage of the plugin pipeline, with its failure action resolved
+#[derive(Debug, Clone)]
+pub struct PluginStep {
+    pub id: String,
+    pub name: String,
+    /// when true, a missing, failed or overrunning plugin triggers the failure action
+    pub required: bool,
+    /// time budget in microseconds, checked against the reported duration
+    pub timeout_us: Option<u64>,
+    /// action applied when a required plugin did not deliver, also used when
+    /// an embedded WASM module hints at blocking
+    pub failure_action: Option<SimpleAction>,
+    /// path of a WASM module executed by the embedded runtime
+    pub module: Option<PathBuf>,
+    /// fuel budget for a single execution of the WASM module
+    pub fuel: Option<u64>,
+}
+
+impl PluginStep {
+    pub fn resolve(
+        logs: &mut Logs,
+        actions: &HashMap<String, SimpleAction>,
+        basepath: &Path,
+        rawsteps: Vec<RawPluginStep>,
+    ) -> Vec<PluginStep> {
+        let wasmdir = basepath.join("wasm");
+        let mut out = Vec::new();
+        for raw in rawsteps {
+            let failure_action = match &raw.failure_action {
+                None => None,
+                Some(aid) => match actions.get(aid) {
+                    Some(a) => Some(a.clone()),
+                    None => {
+                        logs.error(|| format!("Could not resolve action {} in plugin step {}", aid, raw.id));
+                        None
+                    }
+                },
+            };
+            out.push(PluginStep {
+                name: if raw.name.is_empty() { raw.id.clone() } else { raw.name },
+                id: raw.id,
+                required: raw.required,
+                timeout_us: raw.timeout_us,
+                failure_action,
+                module: raw.module.map(|m| wasmdir.join(m)),
+                fuel: raw.fuel,
+            });
+        }
+        out
+    }
+}
+
+/// outcome of a single pipeline stage
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum PluginStatus {
+    Ok,
+    /// the plugin did not report anything
+    Missing,
+    /// the plugin reported an error
+    Failed,
+    /// the plugin reported a duration above its time budget
+    Overrun,
+}
+
+impl std::fmt::Display for PluginStatus {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            PluginStatus::Ok => "ok".fmt(f),
+            PluginStatus::Missing => "missing".fmt(f),
+            PluginStatus::Failed => "failed".fmt(f),
+            PluginStatus::Overrun => "overrun".fmt(f),
+        }
+    }
+}
+
+/// per plugin statistics, exposed through the decision annotations
+#[derive(Debug, Clone, Serialize)]
+pub struct PluginReport {
+    pub id: String,
+    pub status: PluginStatus,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub duration_us: Option<u64>,
+}
+
+/// checks the plugin outputs of a request against the configured pipeline,
+/// in pipeline order
+pub fn check_pipeline(reqinfo: &RequestInfo, steps: &[PluginStep]) -> Vec<PluginReport> {
+    steps
+        .iter()
+        .map(|step| {
+            let prefix = format!("{}:", step.id);
+            let duration_us = reqinfo
+                .plugins
+                .get_str(&format!("{}:duration_us", step.id))
+                .and_then(|s| s.parse().ok());
+            let present = reqinfo.plugins.get_str(&step.id).is_some()
+                || reqinfo.plugins.iter().any(|(k, _)| k.starts_with(&prefix));
+            let status = if !present {
+                PluginStatus::Missing
+            } else if reqinfo.plugins.get_str(&format!("{}:error", step.id)).is_some() {
+                PluginStatus::Failed
+            } else if matches!((step.timeout_us, duration_us), (Some(budget), Some(spent)) if spent > budget) {
+                PluginStatus::Overrun
+            } else {
+                PluginStatus::Ok
+            };
+            PluginReport {
+                id: step.id.clone(),
+                status,
+                duration_us,
+            }
+        })
+        .collect()
+}
+
+/// the failure action of the first required stage that did not deliver
+pub fn pipeline_failure(steps: &[PluginStep], reports: &[PluginReport]) -> Option<(SimpleAction, BlockReason)> {
+    for (step, report) in steps.iter().zip(reports) {
+        if step.required && report.status != PluginStatus::Ok {
+            if let Some(action) = &step.failure_action {
+                let br = BlockReason::restricted(
+                    step.id.clone(),
+                    step.name.clone(),
+                    action.atype.to_raw(),
+                    Location::Plugin(step.id.clone()),
+                    report.status.to_string(),
+                    "plugin report".to_string(),
+                );
+                return Some((action.clone(), br));
+            }
+        }
+    }
+    None
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::custom::Site;
+    use crate::config::hostmap::SecurityPolicy;
+    use crate::utils::{map_request, RawRequest, RequestMeta};
+    use std::sync::Arc;
+
+    fn mkstep(id: &str, required: bool, timeout_us: Option<u64>, failing: bool) -> PluginStep {
+        PluginStep {
+            id: id.to_string(),
+            name: id.to_string(),
+            required,
+            timeout_us,
+            failure_action: if failing { Some(SimpleAction::default()) } else { None },
+            module: None,
+            fuel: None,
+        }
+    }
+
+    fn mkreqinfo(plugins: &[(&str, &str)]) -> RequestInfo {
+        let mut logs = Logs::default();
+        let raw = RawRequest {
+            ipstr: "1.2.3.4".to_string(),
+            headers: HashMap::new(),
+            meta: RequestMeta {
+                authority: Some("example.com".to_string()),
+                method: "GET".to_string(),
+                path: "/".to_string(),
+                client_cert_subject: None,
+                client_cert_san: None,
+                client_cert_fingerprint: None,
+                extra: HashMap::new(),
+                requestid: None,
+                protocol: None,
+            },
+            mbody: None,
+        };
+        map_request(
+            &mut logs,
+            Arc::new(SecurityPolicy::default()),
+            Arc::new(Site::default()),
+            None,
+            &raw,
+            None,
+            plugins.iter().map(|(k, v)| (k.to_string(), v.to_string())).collect(),
+        )
+    }
+
+    #[test]
+    fn statuses_are_detected() {
+        let steps = vec![
+            mkstep("geo", true, None, true),
+            mkstep("scorer", false, Some(1000), false),
+            mkstep("absent", false, None, false),
+        ];
+        let reqinfo = mkreqinfo(&[
+            ("geo:country", "FR"),
+            ("scorer:score", "0.7"),
+            ("scorer:duration_us", "2500"),
+        ]);
+        let reports = check_pipeline(&reqinfo, &steps);
+        assert_eq!(reports[0].status, PluginStatus::Ok);
+        assert_eq!(reports[1].status, PluginStatus::Overrun);
+        assert_eq!(reports[1].duration_us, Some(2500));
+        assert_eq!(reports[2].status, PluginStatus::Missing);
+        // the only failed stages are optional, so the pipeline passes
+        assert!(pipeline_failure(&steps, &reports).is_none());
+    }
+
+    #[test]
+    fn required_failure_triggers_action() {
+        let steps = vec![mkstep("geo", true, None, true)];
+        let reqinfo = mkreqinfo(&[("geo:error", "upstream timeout")]);
+        let reports = check_pipeline(&reqinfo, &steps);
+        assert_eq!(reports[0].status, PluginStatus::Failed);
+        let (_, br) = pipeline_failure(&steps, &reports).expect("should have failed");
+        assert_eq!(br.id, "geo");
+    }
+}
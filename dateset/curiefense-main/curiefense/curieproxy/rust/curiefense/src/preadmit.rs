@@ -0,0 +1,199 @@
+/* accept-time pre-admission checks
+
+   Embedders can reject obviously banned clients before allocating any
+   request processing resources: `pre_admit` only consults the global
+   filter rules that are decidable from the client address alone (ip,
+   network and range entries with a blocking action) and a local
+   concurrency cap, without parsing the request.
+
+   The optional policy hint lets rules restricted to one security policy
+   (`securitypolicyid` conditions) participate; rules needing anything
+   else from the request never match here and are evaluated as usual
+   during full analysis, so pre-admission can only reject early, never
+   admit a request that the full analysis would block.
+
+   PREADMIT_MAX_PER_IP caps the amount of admitted, unreleased connections
+   per client address (0, the default, disables the cap); embedders must
+   call `pre_admit_release` when an admitted connection closes.
+*/
+
+use lazy_static::lazy_static;
+use std::collections::HashMap;
+use std::net::IpAddr;
+use std::sync::Mutex;
+
+use crate::config::globalfilter::{GlobalFilterEntryE, GlobalFilterRule, GlobalFilterSection};
+use crate::config::raw::Relation;
+use crate::interface::SimpleActionT;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PreAdmitVerdict {
+    /// nothing known against this client, run the full analysis
+    Allow,
+    /// the client address matches a blocking, address-only global filter
+    Banned,
+    /// the per address concurrency cap is reached
+    OverCapacity,
+}
+
+lazy_static! {
+    /// maximal amount of admitted, unreleased connections per client
+    /// address, 0 disables the cap
+    static ref MAX_PER_IP: usize = std::env::var("PREADMIT_MAX_PER_IP")
+        .ok()
+        .and_then(|s| s.parse().ok())
+        .unwrap_or(0);
+    static ref INFLIGHT: Mutex<HashMap<IpAddr, usize>> = Mutex::new(HashMap::new());
+}
+
+/// does this entry ban the address, using only accept-time knowledge
+fn entry_bans(entry: &GlobalFilterEntryE, ip: &IpAddr, hint: Option<&str>) -> bool {
+    match entry {
+        GlobalFilterEntryE::Ip(i) => i == ip,
+        GlobalFilterEntryE::Network(net) => net.contains(ip),
+        GlobalFilterEntryE::Range4(range) => match ip {
+            IpAddr::V4(ip4) => range.contains(ip4),
+            IpAddr::V6(_) => false,
+        },
+        GlobalFilterEntryE::Range6(range) => match ip {
+            IpAddr::V4(_) => false,
+            IpAddr::V6(ip6) => range.contains(ip6),
+        },
+        GlobalFilterEntryE::SecurityPolicyId(id) => hint == Some(id.as_str()),
+        // anything else needs the parsed request, so it can not ban here
+        _ => false,
+    }
+}
+
+/// conservative evaluation of a rule from the client address alone: a
+/// conjunction only matches when every conjunct is decidable at accept
+/// time, and negations never match, so this can only under-approximate
+/// the full evaluation
+fn rule_bans(rule: &GlobalFilterRule, ip: &IpAddr, hint: Option<&str>) -> bool {
+    match rule {
+        GlobalFilterRule::Entry(e) => !e.negated && entry_bans(&e.entry, ip, hint),
+        GlobalFilterRule::Rel(rel) => match rel.relation {
+            Relation::Or => rel.entries.iter().any(|r| rule_bans(r, ip, hint)),
+            Relation::And => !rel.entries.is_empty() && rel.entries.iter().all(|r| rule_bans(r, ip, hint)),
+        },
+    }
+}
+
+fn section_bans(section: &GlobalFilterSection, ip: &IpAddr, hint: Option<&str>) -> bool {
+    match &section.action {
+        Some(action) if !matches!(action.atype, SimpleActionT::Monitor | SimpleActionT::Skip) => {
+            rule_bans(&section.rule, ip, hint)
+        }
+        // tag-only and monitoring sections never reject at accept time
+        _ => false,
+    }
+}
+
+/// checks a client address against the quick-ban rules and the local
+/// concurrency cap; unparsable addresses are admitted, as rejecting them
+/// here would only hide the problem from the full analysis
+pub fn pre_admit(ipstr: &str, policy_hint: Option<&str>) -> PreAdmitVerdict {
+    let ip: IpAddr = match ipstr.parse() {
+        Ok(i) => i,
+        Err(_) => return PreAdmitVerdict::Allow,
+    };
+    if let Ok(cfg) = crate::config::CONFIGS.config.read() {
+        if cfg.globalfilters.iter().any(|s| section_bans(s, &ip, policy_hint)) {
+            return PreAdmitVerdict::Banned;
+        }
+    }
+    let cap = *MAX_PER_IP;
+    if cap > 0 {
+        if let Ok(mut inflight) = INFLIGHT.lock() {
+            let count = inflight.entry(ip).or_insert(0);
+            if *count >= cap {
+                return PreAdmitVerdict::OverCapacity;
+            }
+            *count += 1;
+        }
+    }
+    PreAdmitVerdict::Allow
+}
+
+/// releases one admitted connection, the counterpart of a `pre_admit` that
+/// returned `Allow` when the cap is enabled
+pub fn pre_admit_release(ipstr: &str) {
+    if *MAX_PER_IP == 0 {
+        return;
+    }
+    let ip: IpAddr = match ipstr.parse() {
+        Ok(i) => i,
+        Err(_) => return,
+    };
+    if let Ok(mut inflight) = INFLIGHT.lock() {
+        if let Some(count) = inflight.get_mut(&ip) {
+            *count = count.saturating_sub(1);
+            if *count == 0 {
+                inflight.remove(&ip);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::interface::SimpleAction;
+
+    fn ban_section(rule: GlobalFilterRule) -> GlobalFilterSection {
+        GlobalFilterSection {
+            id: "quickban".to_string(),
+            name: "quick ban".to_string(),
+            tags: Default::default(),
+            rule,
+            action: Some(SimpleAction::default()),
+        }
+    }
+
+    fn ip_entry(ip: &str) -> GlobalFilterRule {
+        GlobalFilterRule::Entry(crate::config::globalfilter::GlobalFilterEntry {
+            negated: false,
+            entry: GlobalFilterEntryE::Ip(ip.parse().unwrap()),
+        })
+    }
+
+    #[test]
+    fn ip_only_rules() {
+        let section = ban_section(ip_entry("10.0.0.1"));
+        assert!(section_bans(&section, &"10.0.0.1".parse().unwrap(), None));
+        assert!(!section_bans(&section, &"10.0.0.2".parse().unwrap(), None));
+    }
+
+    #[test]
+    fn policy_scoped_rules() {
+        let rule = GlobalFilterRule::Rel(crate::config::globalfilter::GlobalFilterRelation {
+            relation: Relation::And,
+            entries: vec![
+                ip_entry("10.0.0.1"),
+                GlobalFilterRule::Entry(crate::config::globalfilter::GlobalFilterEntry {
+                    negated: false,
+                    entry: GlobalFilterEntryE::SecurityPolicyId("polA".to_string()),
+                }),
+            ],
+        });
+        let section = ban_section(rule);
+        let ip = "10.0.0.1".parse().unwrap();
+        assert!(section_bans(&section, &ip, Some("polA")));
+        // without the hint the conjunction is not decidable, so it fails open
+        assert!(!section_bans(&section, &ip, None));
+        assert!(!section_bans(&section, &ip, Some("polB")));
+    }
+
+    #[test]
+    fn request_dependent_rules_fail_open() {
+        let rule = GlobalFilterRule::Entry(crate::config::globalfilter::GlobalFilterEntry {
+            negated: false,
+            entry: GlobalFilterEntryE::Method(crate::config::globalfilter::SingleEntry {
+                exact: "POST".to_string(),
+                re: None,
+            }),
+        });
+        let section = ban_section(rule);
+        assert!(!section_bans(&section, &"10.0.0.1".parse().unwrap(), None));
+    }
+}
@@ -1,5 +1,6 @@
 use lazy_static::lazy_static;
 use redis::{ConnectionAddr, ConnectionInfo, RedisConnectionInfo};
+use std::hash::Hasher;
 
 lazy_static! {
     static ref RPOOL: anyhow::Result<redis::aio::ConnectionManager> = async_std::task::block_on(build_pool());
@@ -9,6 +10,33 @@ lazy_static! {
             prefix
         })
         .unwrap_or_default();
+    /// when set, user derived key components are stored raw in redis instead of
+    /// being hashed, which helps debugging
+    pub static ref REDIS_RAW_KEYS: bool = std::env::var("REDIS_RAW_KEYS")
+        .map(|s| s.parse().unwrap_or(false))
+        .unwrap_or(false);
+    /// siphash key for masking user derived values, derived from the
+    /// REDIS_KEY_MASKING_SEED environment variable
+    static ref MASKING_KEY: (u64, u64) = {
+        let seed = std::env::var("REDIS_KEY_MASKING_SEED").unwrap_or_default();
+        let digest = md5::compute(seed.as_bytes());
+        let mut k0 = [0u8; 8];
+        let mut k1 = [0u8; 8];
+        k0.copy_from_slice(&digest[0..8]);
+        k1.copy_from_slice(&digest[8..16]);
+        (u64::from_le_bytes(k0), u64::from_le_bytes(k1))
+    };
+}
+
+/// masks a user derived key component with a keyed SipHash, so that PII such as
+/// IPs or session tokens never reaches redis, and the component size is bounded
+pub fn mask_user_value(value: &str) -> String {
+    if *REDIS_RAW_KEYS {
+        return value.to_string();
+    }
+    let mut hasher = siphasher::sip::SipHasher13::new_with_keys(MASKING_KEY.0, MASKING_KEY.1);
+    hasher.write(value.as_bytes());
+    format!("{:016x}", hasher.finish())
 }
 
 /// creates an async connection to a redis server
@@ -37,3 +65,15 @@ pub async fn redis_async_conn() -> anyhow::Result<redis::aio::ConnectionManager>
         Err(rr) => Err(anyhow::anyhow!("{}", rr)),
     }
 }
+
+/// computes the key TTL, randomly increased by up to `jitter_pct` percent of the
+/// timeframe, so that keys created in the same timeframe do not all expire at once
+pub fn jittered_ttl(timeframe: u64, jitter_pct: u64) -> u64 {
+    use rand::Rng;
+    let spread = timeframe * jitter_pct / 100;
+    if spread == 0 {
+        timeframe
+    } else {
+        timeframe + rand::thread_rng().gen_range(0..=spread)
+    }
+}
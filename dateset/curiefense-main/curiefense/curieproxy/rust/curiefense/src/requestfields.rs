@@ -1,4 +1,4 @@
-use crate::config::contentfilter::Transformation;
+use crate::config::contentfilter::{CollisionPolicy, MaskingSeed, Transformation};
 use crate::interface::Location;
 use crate::utils::decoders::DecodingResult;
 use crate::utils::json::BigTableKV;
@@ -7,27 +7,76 @@ use std::collections::HashSet;
 use std::collections::{hash_map, HashMap};
 
 /// a newtype for user supplied data that can collide
-/// more or less like a HashMap, but concatenates entries with a separator on insert
+/// more or less like a HashMap, where the collision policy decides how
+/// entries with the same name are combined on insert
 #[derive(Debug, Clone, PartialEq, Eq)]
 pub struct RequestField {
     pub decoding: Vec<Transformation>,
+    pub collision: CollisionPolicy,
     pub fields: HashMap<String, (String, HashSet<Location>)>,
 }
 
 impl RequestField {
     fn base_add(&mut self, key: String, ds: Location, value: String) {
-        self.fields
-            .entry(key)
-            .and_modify(|(v, pds)| {
-                v.push(' ');
-                v.push_str(&value);
-                pds.insert(ds.clone());
-            })
-            .or_insert({
+        // the locations always record each occurrence, whatever the policy,
+        // as pollution detection works on them
+        match &self.collision {
+            CollisionPolicy::Merge(separator) => {
+                let separator = separator.clone();
+                self.fields
+                    .entry(key)
+                    .and_modify(|(v, pds)| {
+                        v.push_str(&separator);
+                        v.push_str(&value);
+                        pds.insert(ds.clone());
+                    })
+                    .or_insert_with(|| {
+                        let mut hs = HashSet::new();
+                        hs.insert(ds);
+                        (value, hs)
+                    });
+            }
+            CollisionPolicy::KeepFirst => {
+                self.fields
+                    .entry(key)
+                    .and_modify(|(_, pds)| {
+                        pds.insert(ds.clone());
+                    })
+                    .or_insert_with(|| {
+                        let mut hs = HashSet::new();
+                        hs.insert(ds);
+                        (value, hs)
+                    });
+            }
+            CollisionPolicy::KeepLast => {
+                self.fields
+                    .entry(key)
+                    .and_modify(|(v, pds)| {
+                        *v = value.clone();
+                        pds.insert(ds.clone());
+                    })
+                    .or_insert_with(|| {
+                        let mut hs = HashSet::new();
+                        hs.insert(ds);
+                        (value, hs)
+                    });
+            }
+            CollisionPolicy::IndexSuffix => {
+                let target = if self.fields.contains_key(&key) {
+                    // the first free indexed name, so that each occurrence
+                    // keeps its own entry
+                    (1..)
+                        .map(|i| format!("{}:{}", key, i))
+                        .find(|k| !self.fields.contains_key(k))
+                        .unwrap()
+                } else {
+                    key
+                };
                 let mut hs = HashSet::new();
                 hs.insert(ds);
-                (value, hs)
-            });
+                self.fields.insert(target, (value, hs));
+            }
+        }
     }
 
     pub fn as_map(&self) -> HashMap<&str, &str> {
@@ -89,7 +138,7 @@ impl RequestField {
         }
     }
 
-    pub fn mask(&mut self, masking_seed: &[u8], key: &str) -> HashSet<Location> {
+    pub fn mask(&mut self, masking_seed: &MaskingSeed, key: &str) -> HashSet<Location> {
         self.fields
             .get_mut(key)
             .map(|(v, ds)| {
@@ -116,6 +165,38 @@ impl RequestField {
         self.fields.get(k).map(|(s, _)| s.as_str())
     }
 
+    /// the per occurrence values for an entry, as recorded in its locations
+    ///
+    /// the stored value concatenates all occurrences with a space, but the
+    /// locations keep the individual values, which is what the content filter
+    /// needs when inspecting polluted parameters
+    pub fn occurrences(&self, k: &str) -> Vec<&str> {
+        self.fields
+            .get(k)
+            .map(|(_, locs)| locs.iter().filter_map(Location::get_value).collect())
+            .unwrap_or_default()
+    }
+
+    /// names of the entries that were sent several times with differing values,
+    /// which is the telltale sign of HTTP parameter pollution
+    ///
+    /// identical repeated values are not reported, as locations are stored in a set
+    pub fn polluted(&self) -> impl Iterator<Item = &str> + '_ {
+        self.fields.iter().filter_map(|(k, (_, locs))| {
+            let values: HashSet<&str> = locs.iter().filter_map(Location::get_value).collect();
+            if values.len() > 1 {
+                Some(k.as_str())
+            } else {
+                None
+            }
+        })
+    }
+
+    /// a serialization wrapper that adds the provenance of each value to the entries
+    pub fn with_provenance(&self) -> RequestFieldProvenance<'_> {
+        RequestFieldProvenance(self)
+    }
+
     pub fn len(&self) -> usize {
         self.fields.len()
     }
@@ -129,8 +210,15 @@ impl RequestField {
     }
 
     pub fn new(decoding: &[Transformation]) -> Self {
+        RequestField::with_policy(decoding, CollisionPolicy::default())
+    }
+
+    /// a request field with an explicit collision policy; `new` keeps the
+    /// historical space-separated merge
+    pub fn with_policy(decoding: &[Transformation], collision: CollisionPolicy) -> Self {
         RequestField {
             decoding: decoding.to_vec(),
+            collision,
             fields: HashMap::default(),
         }
     }
@@ -158,6 +246,7 @@ impl RequestField {
     pub fn raw_create(decoding: &[Transformation], content: &[(&str, &Location, &str)]) -> Self {
         RequestField {
             decoding: decoding.to_vec(),
+            collision: CollisionPolicy::default(),
             fields: content
                 .iter()
                 .map(|(k, ds, v)| {
@@ -181,3 +270,128 @@ impl serde::Serialize for RequestField {
         }))
     }
 }
+
+/// serializes a request field in the big table KV format, with an extra
+/// `provenance` list describing where each value came from
+pub struct RequestFieldProvenance<'t>(&'t RequestField);
+
+#[derive(serde::Serialize)]
+struct ProvenanceEntry<'t> {
+    source: &'static str,
+    key: &'t str,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    value: Option<&'t str>,
+}
+
+#[derive(serde::Serialize)]
+struct EntryWithProvenance<'t> {
+    name: &'t str,
+    value: &'t str,
+    provenance: Vec<ProvenanceEntry<'t>>,
+}
+
+impl<'t> serde::Serialize for RequestFieldProvenance<'t> {
+    fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
+    where
+        S: serde::Serializer,
+    {
+        serializer.collect_seq(self.0.fields.iter().map(|(k, (v, locs))| EntryWithProvenance {
+            name: k.as_str(),
+            value: v.as_str(),
+            provenance: locs
+                .iter()
+                .filter_map(|loc| {
+                    loc.get_provenance().map(|(source, key)| ProvenanceEntry {
+                        source,
+                        key,
+                        value: loc.get_value(),
+                    })
+                })
+                .collect(),
+        }))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn polluted_args() {
+        let mut args = RequestField::new(&[]);
+        args.add(
+            "polluted".to_string(),
+            Location::UriArgumentValue("polluted".to_string(), "first".to_string()),
+            "first".to_string(),
+        );
+        args.add(
+            "polluted".to_string(),
+            Location::BodyArgumentValue("polluted".to_string(), "second".to_string()),
+            "second".to_string(),
+        );
+        args.add(
+            "repeated".to_string(),
+            Location::UriArgumentValue("repeated".to_string(), "same".to_string()),
+            "same".to_string(),
+        );
+        args.add(
+            "repeated".to_string(),
+            Location::UriArgumentValue("repeated".to_string(), "same".to_string()),
+            "same".to_string(),
+        );
+        args.add(
+            "single".to_string(),
+            Location::UriArgumentValue("single".to_string(), "value".to_string()),
+            "value".to_string(),
+        );
+        let polluted: Vec<&str> = args.polluted().collect();
+        assert_eq!(polluted, ["polluted"]);
+        assert_eq!(args.get_str("polluted"), Some("first second"));
+        let mut occurrences = args.occurrences("polluted");
+        occurrences.sort_unstable();
+        assert_eq!(occurrences, ["first", "second"]);
+    }
+
+    #[test]
+    fn collision_policies() {
+        let loc = |v: &str| Location::UriArgumentValue("a".to_string(), v.to_string());
+        let fill = |collision: CollisionPolicy| {
+            let mut args = RequestField::with_policy(&[], collision);
+            args.add("a".to_string(), loc("1"), "1".to_string());
+            args.add("a".to_string(), loc("2"), "2".to_string());
+            args
+        };
+        let merged = fill(CollisionPolicy::Merge(";".to_string()));
+        assert_eq!(merged.get_str("a"), Some("1;2"));
+        let first = fill(CollisionPolicy::KeepFirst);
+        assert_eq!(first.get_str("a"), Some("1"));
+        let last = fill(CollisionPolicy::KeepLast);
+        assert_eq!(last.get_str("a"), Some("2"));
+        let indexed = fill(CollisionPolicy::IndexSuffix);
+        assert_eq!(indexed.get_str("a"), Some("1"));
+        assert_eq!(indexed.get_str("a:1"), Some("2"));
+        // pollution detection works on the locations, so it still sees both
+        // values when only one is kept
+        let polluted: Vec<&str> = first.polluted().collect();
+        assert_eq!(polluted, ["a"]);
+    }
+
+    #[test]
+    fn provenance_serialization() {
+        let args = RequestField::singleton(
+            &[],
+            "arg".to_string(),
+            Location::BodyArgumentValue("arg".to_string(), "val".to_string()),
+            "val".to_string(),
+        );
+        let serialized = serde_json::to_value(args.with_provenance()).unwrap();
+        assert_eq!(
+            serialized,
+            serde_json::json!([{
+                "name": "arg",
+                "value": "val",
+                "provenance": [{"source": "body", "key": "arg", "value": "val"}]
+            }])
+        );
+    }
+}
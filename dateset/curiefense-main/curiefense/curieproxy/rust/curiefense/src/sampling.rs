@@ -0,0 +1,103 @@
+/// deterministic per-request sampling
+///
+/// Several features (log sampling, A/B configuration rollouts, debug
+/// capture) need to agree on which requests are sampled. This module
+/// computes a stable sample bucket from a request fingerprint and a
+/// configured salt, so that all features observe the same subset of the
+/// traffic, and a given request keeps its bucket across restarts.
+use lazy_static::lazy_static;
+use sha2::{Digest, Sha256};
+
+use crate::utils::RequestInfo;
+
+lazy_static! {
+    /// salt mixed into the fingerprint, change it to rotate the sampled population
+    static ref SAMPLING_SALT: String = std::env::var("SAMPLING_SALT").unwrap_or_default();
+    /// number of sample buckets, requests are spread uniformly over 0..SAMPLING_BUCKETS
+    static ref SAMPLING_BUCKETS: u32 = std::env::var("SAMPLING_BUCKETS")
+        .ok()
+        .and_then(|s| s.parse().ok())
+        .filter(|b| *b > 0)
+        .unwrap_or(100);
+}
+
+pub fn bucket_count() -> u32 {
+    *SAMPLING_BUCKETS
+}
+
+/// stable sample bucket for this request, in 0..bucket_count()
+pub fn sample_bucket(rinfo: &RequestInfo) -> u32 {
+    fingerprint_bucket(
+        &SAMPLING_SALT,
+        &[
+            &rinfo.rinfo.geoip.ipstr,
+            &rinfo.rinfo.meta.method,
+            &rinfo.rinfo.qinfo.uri,
+            &rinfo.session,
+        ],
+        *SAMPLING_BUCKETS,
+    )
+}
+
+/// hashes the salted fingerprint parts into a bucket, parts are
+/// null-separated so that moving a boundary changes the digest
+fn fingerprint_bucket(salt: &str, parts: &[&str], buckets: u32) -> u32 {
+    let mut hasher = Sha256::new();
+    hasher.update(salt.as_bytes());
+    for part in parts {
+        hasher.update([0]);
+        hasher.update(part.as_bytes());
+    }
+    let digest = hasher.finalize();
+    let mut prefix = [0u8; 8];
+    prefix.copy_from_slice(&digest[..8]);
+    (u64::from_be_bytes(prefix) % buckets as u64) as u32
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn bucket_deterministic() {
+        let a = fingerprint_bucket("salt", &["1.2.3.4", "GET", "/api"], 100);
+        let b = fingerprint_bucket("salt", &["1.2.3.4", "GET", "/api"], 100);
+        assert_eq!(a, b);
+    }
+
+    #[test]
+    fn bucket_salt_rotates_population() {
+        let mut moved = 0;
+        for i in 0..100 {
+            let ip = format!("10.0.0.{}", i);
+            let a = fingerprint_bucket("salt-a", &[&ip, "GET", "/"], 100);
+            let b = fingerprint_bucket("salt-b", &[&ip, "GET", "/"], 100);
+            if a != b {
+                moved += 1;
+            }
+        }
+        assert!(moved > 50, "only {} requests changed bucket", moved);
+    }
+
+    #[test]
+    fn bucket_boundaries_matter() {
+        // the separator keeps ["ab"] and ["a", "b"] from colliding for most inputs
+        let mut collisions = 0;
+        for i in 0..100 {
+            let s = format!("part{}", i);
+            let (left, right) = s.split_at(3);
+            if fingerprint_bucket("", &[&s], 1000) == fingerprint_bucket("", &[left, right], 1000) {
+                collisions += 1;
+            }
+        }
+        assert!(collisions < 10, "{} collisions", collisions);
+    }
+
+    #[test]
+    fn bucket_in_range() {
+        for i in 0..1000 {
+            let ip = format!("192.168.{}.{}", i / 256, i % 256);
+            assert!(fingerprint_bucket("s", &[&ip], 10) < 10);
+        }
+    }
+}
@@ -87,7 +87,7 @@ pub enum Progress<A> {
 }
 
 // TODO: deduplicate this code
-impl<A> Executor<TaskCB<A>> {
+impl<A: 'static> Executor<TaskCB<A>> {
     pub fn step(&self) -> Progress<A> {
         match self.ready_queue.try_recv() {
             Err(TryRecvError::Empty) => Progress::More,
@@ -108,7 +108,7 @@ impl<A> Executor<TaskCB<A>> {
     }
 }
 
-impl<A> Executor<Task<A>> {
+impl<A: 'static> Executor<Task<A>> {
     pub fn step(&self) -> Progress<A> {
         match self.ready_queue.try_recv() {
             Err(TryRecvError::Empty) => Progress::More,
@@ -129,7 +129,7 @@ impl<A> Executor<Task<A>> {
     }
 }
 
-pub fn block_on<A>(future: impl Future<Output = A> + 'static + Send) -> A {
+pub fn block_on<A: 'static>(future: impl Future<Output = A> + 'static + Send) -> A {
     let (executor, spawner) = new_executor_and_spawner();
     spawner.spawn(future);
     drop(spawner);
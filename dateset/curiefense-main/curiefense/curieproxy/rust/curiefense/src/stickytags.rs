@@ -0,0 +1,120 @@
+use redis::aio::ConnectionManager;
+
+use crate::config::stickytags::StickyTag;
+use crate::interface::{Location, Tags};
+use crate::logs::Logs;
+use crate::redis::{jittered_ttl, mask_user_value, REDIS_KEY_PREFIX};
+use crate::utils::{select_string, RequestInfo};
+
+fn build_key(reqinfo: &RequestInfo, tags: &Tags, entry: &StickyTag) -> Option<String> {
+    let mut tohash = entry.id.clone();
+    for kpart in entry.key.iter().map(|r| select_string(reqinfo, r, Some(tags))) {
+        tohash += &mask_user_value(&kpart?);
+    }
+    Some(format!(
+        "{}{}sticky{:X}",
+        *REDIS_KEY_PREFIX,
+        entry.key_prefix.as_deref().unwrap_or(""),
+        md5::compute(tohash)
+    ))
+}
+
+/// a sticky tag entry, with its resolved redis key
+#[derive(Clone, Debug)]
+pub struct StickyCheck {
+    pub redis_key: String,
+    pub entry: StickyTag,
+}
+
+/// resolves the redis keys of the sticky tag entries
+pub fn sticky_info(logs: &mut Logs, entries: &[StickyTag], reqinfo: &RequestInfo, tags: &Tags) -> Vec<StickyCheck> {
+    let mut out = Vec::new();
+    for entry in entries {
+        match build_key(reqinfo, tags, entry) {
+            Some(redis_key) => out.push(StickyCheck {
+                redis_key,
+                entry: entry.clone(),
+            }),
+            None => logs.warning(|| format!("Could not fetch key in sticky tag entry {}", entry.name)),
+        }
+    }
+    out
+}
+
+/// re-applies the tags that were remembered for this client
+pub async fn sticky_recall(logs: &mut Logs, redis: &mut ConnectionManager, checks: &[StickyCheck], tags: &mut Tags) {
+    if checks.is_empty() {
+        return;
+    }
+    let mut pipe = redis::pipe();
+    for check in checks {
+        pipe.cmd("SMEMBERS").arg(&check.redis_key);
+    }
+    let remembered: Vec<Vec<String>> = match pipe.query_async(redis).await {
+        Ok(r) => r,
+        Err(rr) => {
+            logs.error(|| format!("{}", rr));
+            return;
+        }
+    };
+    for (check, rtags) in checks.iter().zip(remembered) {
+        for rtag in rtags {
+            logs.debug(|| format!("sticky tag entry {} recalled tag {}", check.entry.id, rtag));
+            tags.insert(&rtag, Location::Request);
+        }
+    }
+}
+
+/// remembers the watched tags that were triggered by this request, refreshing
+/// the TTL of new keys
+pub async fn sticky_record(logs: &mut Logs, checks: &[StickyCheck], tags: &Tags) {
+    let todo: Vec<(&StickyCheck, Vec<&String>)> = checks
+        .iter()
+        .map(|c| (c, c.entry.tags.iter().filter(|t| tags.contains(t)).collect()))
+        .filter(|(_, triggered): &(_, Vec<&String>)| !triggered.is_empty())
+        .collect();
+    if todo.is_empty() {
+        return;
+    }
+    let mut redis = match crate::redis::redis_async_conn().await {
+        Ok(c) => c,
+        Err(rr) => {
+            logs.error(|| format!("Could not connect to the redis server {}", rr));
+            return;
+        }
+    };
+    let mut pipe = redis::pipe();
+    for (check, triggered) in &todo {
+        let mut cmd = pipe.cmd("SADD");
+        cmd = cmd.arg(&check.redis_key);
+        for tag in triggered {
+            cmd = cmd.arg(tag);
+        }
+        cmd.ignore();
+        pipe.cmd("TTL").arg(&check.redis_key);
+    }
+    let ttls: Vec<i64> = match pipe.query_async(&mut redis).await {
+        Ok(l) => l,
+        Err(rr) => {
+            logs.error(|| format!("{}", rr));
+            return;
+        }
+    };
+    let mut epipe = redis::pipe();
+    let mut has_expires = false;
+    for ((check, _), ttl) in todo.iter().zip(ttls) {
+        if ttl < 0 {
+            epipe
+                .cmd("EXPIRE")
+                .arg(&check.redis_key)
+                .arg(jittered_ttl(check.entry.timeframe, check.entry.ttl_jitter))
+                .ignore();
+            has_expires = true;
+        }
+    }
+    if has_expires {
+        if let Err(rr) = epipe.query_async::<_, ()>(&mut redis).await {
+            logs.error(|| format!("{}", rr));
+        }
+    }
+}
@@ -1,15 +1,18 @@
 use crate::config::globalfilter::{
-    GlobalFilterEntry, GlobalFilterEntryE, GlobalFilterRule, GlobalFilterSection, PairEntry, SingleEntry,
+    GlobalFilterEntry, GlobalFilterEntryE, GlobalFilterRule, GlobalFilterSection, PairEntry, SetEntry, SingleEntry,
+    SingleEntryType,
 };
+use crate::config::contentfilter::SectionIdx;
 use crate::config::raw::Relation;
 use crate::config::virtualtags::VirtualTags;
 use crate::grasshopper::PrecisionLevel;
-use crate::interface::stats::{BStageMapped, BStageSecpol, StatsCollect};
+use crate::interface::stats::{BStageMapped, BStageSecpol, StatsCollect, STAGE_BUDGETS};
 use crate::interface::{stronger_decision, BlockReason, Location, SimpleActionT, SimpleDecision, Tags};
 use crate::requestfields::RequestField;
 use crate::utils::RequestInfo;
 use std::collections::HashSet;
 use std::net::IpAddr;
+use std::time::Instant;
 
 struct MatchResult {
     matched: HashSet<Location>,
@@ -74,6 +77,20 @@ fn check_single(pr: &SingleEntry, s: &str, loc: Location) -> Option<HashSet<Loca
     }
 }
 
+fn check_set(se: &SetEntry, s: &str, loc: Location) -> Option<HashSet<Location>> {
+    if se.exact.iter().any(|e| e == s) || se.set.is_match(s) {
+        Some(std::iter::once(loc).collect())
+    } else {
+        None
+    }
+}
+
+/// re-evaluates a single global filter against an already analyzed request,
+/// for the explain output
+pub fn globalfilter_matched(rinfo: &RequestInfo, tags: &Tags, section: &GlobalFilterSection) -> bool {
+    check_rule(rinfo, tags, &section.rule).matching
+}
+
 fn check_entry(rinfo: &RequestInfo, tags: &Tags, sub: &GlobalFilterEntry) -> MatchResult {
     fn bool(loc: Location, b: bool) -> Option<HashSet<Location>> {
         if b {
@@ -159,6 +176,42 @@ fn check_entry(rinfo: &RequestInfo, tags: &Tags, sub: &GlobalFilterEntry) -> Mat
                 None
             }
         }
+        GlobalFilterEntryE::SingleSet(tp, se) => match tp {
+            SingleEntryType::Path => check_set(se, &rinfo.rinfo.qinfo.qpath, Location::Uri),
+            SingleEntryType::Query => rinfo
+                .rinfo
+                .qinfo
+                .query
+                .as_ref()
+                .and_then(|q| check_set(se, q, Location::Uri)),
+            SingleEntryType::Uri => check_set(se, &rinfo.rinfo.qinfo.uri, Location::Uri),
+            SingleEntryType::Country => rinfo
+                .rinfo
+                .geoip
+                .country_name
+                .as_ref()
+                .and_then(|ccty| check_set(se, ccty.to_lowercase().as_ref(), Location::Ip)),
+            SingleEntryType::Region => rinfo
+                .rinfo
+                .geoip
+                .region
+                .as_ref()
+                .and_then(|ccty| check_set(se, ccty.to_lowercase().as_ref(), Location::Ip)),
+            SingleEntryType::SubRegion => rinfo
+                .rinfo
+                .geoip
+                .subregion
+                .as_ref()
+                .and_then(|ccty| check_set(se, ccty.to_lowercase().as_ref(), Location::Ip)),
+            SingleEntryType::Method => check_set(se, &rinfo.rinfo.meta.method, Location::Request),
+            SingleEntryType::Company => rinfo
+                .rinfo
+                .geoip
+                .company
+                .as_ref()
+                .and_then(|ccmp| check_set(se, ccmp.as_str(), Location::Ip)),
+            SingleEntryType::Authority => check_set(se, &rinfo.rinfo.host, Location::Request),
+        },
         GlobalFilterEntryE::SecurityPolicyEntryId(id) => {
             if &rinfo.rinfo.secpolicy.entry.id == id {
                 Some(std::iter::once(Location::Request).collect())
@@ -180,7 +233,7 @@ fn check_entry(rinfo: &RequestInfo, tags: &Tags, sub: &GlobalFilterEntry) -> Mat
 }
 
 pub fn tag_request(
-    stats: StatsCollect<BStageSecpol>,
+    mut stats: StatsCollect<BStageSecpol>,
     precision_level: PrecisionLevel,
     globalfilters: &[GlobalFilterSection],
     rinfo: &RequestInfo,
@@ -212,6 +265,37 @@ pub fn tag_request(
     tags.insert_qualified("headers", &rinfo.headers.len().to_string(), Location::Headers);
     tags.insert_qualified("cookies", &rinfo.cookies.len().to_string(), Location::Cookies);
     tags.insert_qualified("args", &rinfo.rinfo.qinfo.args.len().to_string(), Location::Request);
+    // parameter pollution: tag arguments that were repeated with differing values,
+    // so that global filters can restrict or monitor them
+    for name in rinfo.rinfo.qinfo.args.polluted() {
+        tags.insert_qualified("hpp", name, Location::from_name(SectionIdx::Args, name));
+    }
+    // conflicting duplicates of critical headers commonly indicate smuggling or
+    // spoofing attempts, tag them so that global filters can restrict or monitor them
+    for name in rinfo
+        .headers
+        .polluted()
+        .filter(|n| ["host", "content-length", "authorization"].contains(n))
+    {
+        tags.insert_qualified("dup-header", name, Location::from_name(SectionIdx::Headers, name));
+    }
+    // quirk request targets, tagged so that global filters can restrict or monitor them
+    if rinfo.rinfo.meta.is_absolute_form() {
+        tags.insert("absolute-form-uri", Location::Uri);
+    }
+    if rinfo.rinfo.meta.is_http10() {
+        tags.insert("http10", Location::Request);
+    }
+    // the origin of this entry is failing, tag the request so that tag driven
+    // rules (challenges, global filters) can tighten enforcement
+    if crate::originprotection::engaged(&rinfo.rinfo.secpolicy) {
+        tags.insert("origin-protection", Location::Request);
+    }
+    // header order fingerprint, only present when the embedder provided the
+    // headers as an ordered list
+    if let Some(fp) = rinfo.rinfo.meta.extra.get(crate::utils::HEADERS_FP_ATTR) {
+        tags.insert_qualified("headersfp", fp, Location::Headers);
+    }
     tags.insert_qualified("host", &rinfo.rinfo.host, Location::Request);
     tags.insert_qualified("ip", &rinfo.rinfo.geoip.ipstr, Location::Ip);
     tags.insert_qualified(
@@ -293,9 +377,42 @@ pub fn tag_request(
         tags.insert(tag, Location::Request)
     }
 
+    // edge-computed tags carried in a signed header, verified before global
+    // filters run so that they can influence policy decisions; they are
+    // namespaced under "edge" so that internal tags can not be spoofed
+    if let Some(ti) = &rinfo.rinfo.secpolicy.tag_injection {
+        if let Some(value) = rinfo.headers.get(&ti.header) {
+            match ti.verified_tags(value, chrono::Utc::now().timestamp()) {
+                Some(injected) => {
+                    for tag in injected {
+                        tags.insert_qualified("edge", &tag, Location::Header(ti.header.clone()));
+                    }
+                }
+                None => tags.insert("edge-tags-invalid", Location::Header(ti.header.clone())),
+            }
+        }
+    }
+
     let mut matched = 0;
     let mut decision = SimpleDecision::Pass;
+    let gf_start = Instant::now();
     for psection in globalfilters {
+        if let Some(budget) = STAGE_BUDGETS.tagging {
+            if gf_start.elapsed() > budget {
+                // evaluation is incomplete, do not block on partial results
+                tags.insert("gf-timeout", Location::Request);
+                stats.overrun("tagging");
+                if let SimpleDecision::Action(a, brs) = &mut decision {
+                    a.atype = SimpleActionT::Monitor;
+                    // the reasons carry the original blocking action and
+                    // drive Decision::blocked(), so they are downgraded too
+                    for br in brs.iter_mut() {
+                        br.action.inactive();
+                    }
+                }
+                break;
+            }
+        }
         let mtch = check_rule(rinfo, &tags, &psection.rule);
         if mtch.matching {
             matched += 1;
@@ -327,6 +444,7 @@ mod tests {
     use super::*;
     use crate::config::custom::Site;
     use crate::config::globalfilter::optimize_ipranges;
+    use crate::config::globalfilter::optimize_regexsets;
     use crate::config::globalfilter::GlobalFilterRelation;
     use crate::config::hostmap::SecurityPolicy;
     use crate::logs::Logs;
@@ -565,6 +683,54 @@ mod tests {
         check_iprange(Relation::Or, &entries, &samples);
     }
 
+    fn mk_pathentries(lst: &[&str]) -> Vec<GlobalFilterRule> {
+        lst.iter()
+            .map(|e| {
+                GlobalFilterRule::Entry(GlobalFilterEntry {
+                    negated: false,
+                    entry: GlobalFilterEntryE::Path(single_re(e)),
+                })
+            })
+            .collect()
+    }
+
+    #[test]
+    fn regexset_merge_matches() {
+        let entries = mk_pathentries(&["^/nomatch$", ".*adminl%20e.*", "foobar"]);
+        let optimized = optimize_regexsets(Relation::Or, entries.clone());
+        assert_eq!(optimized.len(), 1);
+        let ri = mk_rinfo();
+        let tags = Tags::new(&VirtualTags::default());
+        for ntr in [entries, optimized] {
+            let rule = GlobalFilterRule::Rel(GlobalFilterRelation {
+                relation: Relation::Or,
+                entries: ntr,
+            });
+            assert!(check_rule(&ri, &tags, &rule).matching);
+        }
+    }
+
+    #[test]
+    fn regexset_merge_no_match() {
+        let entries = mk_pathentries(&["^/nomatch$", "foobar"]);
+        let optimized = optimize_regexsets(Relation::Or, entries);
+        assert_eq!(optimized.len(), 1);
+        let ri = mk_rinfo();
+        let tags = Tags::new(&VirtualTags::default());
+        let rule = GlobalFilterRule::Rel(GlobalFilterRelation {
+            relation: Relation::Or,
+            entries: optimized,
+        });
+        assert!(!check_rule(&ri, &tags, &rule).matching);
+    }
+
+    #[test]
+    fn regexset_and_untouched() {
+        let entries = mk_pathentries(&["^/nomatch$", "foobar"]);
+        let optimized = optimize_regexsets(Relation::And, entries);
+        assert_eq!(optimized.len(), 2);
+    }
+
     #[test]
     fn optimization_works() {
         let entries = mk_globalfilterentries(&["127.0.0.1/8", "192.168.0.1/24"]);
@@ -578,4 +744,31 @@ mod tests {
             GlobalFilterRule::Entry(_) => (),
         }
     }
+
+    #[test]
+    fn signed_tag_injection() {
+        use crate::config::hostmap::TagInjection;
+        let ti = TagInjection {
+            header: "x-edge-tags".to_string(),
+            key: "edgekey".to_string(),
+            max_age: 60,
+        };
+        // hmac-sha256("edgekey", "bot-score:99,cdn-verified|1700000000")
+        let good = "bot-score:99,cdn-verified|1700000000|\
+             75db301b61b3a29d196bf101fbf98ebfbb0fcf71f6ea70bc14a647e226f7248d";
+        let now = 1_700_000_030;
+        assert_eq!(
+            ti.verified_tags(good, now),
+            Some(vec!["bot-score:99".to_string(), "cdn-verified".to_string()])
+        );
+        // bad signature
+        assert_eq!(
+            ti.verified_tags("bot-score:99,cdn-verified|1700000000|deadbeef", now),
+            None
+        );
+        // stale timestamp
+        assert_eq!(ti.verified_tags(good, 1_700_001_000), None);
+        // not even the expected shape
+        assert_eq!(ti.verified_tags("bot-score:99", now), None);
+    }
 }
@@ -0,0 +1,174 @@
+/* OpenTelemetry trace export
+
+   When OTLP_TRACES_ENDPOINT is set (host:port of an OTLP/HTTP collector),
+   every inspection is exported as a span, with one child span per analysis
+   stage derived from the cumulative stamps in `Stats::timing`. The payload
+   follows the OTLP/HTTP JSON encoding and is posted to /v1/traces, so that
+   no protobuf machinery is needed; delivery reuses the `outbound` retry and
+   circuit breaking policy and happens on a detached task, off the request
+   path.
+
+   OTLP_SERVICE_NAME overrides the `service.name` resource attribute, and
+   OTLP_TRACES_SAMPLE (a ratio between 0 and 1) thins the export.
+*/
+
+use chrono::{DateTime, Utc};
+use lazy_static::lazy_static;
+use rand::Rng;
+
+use crate::interface::{Decision, Stats};
+use crate::outbound::{with_backoff, CircuitBreaker, OutboundConfig};
+use crate::utils::RequestInfo;
+
+lazy_static! {
+    static ref OTLP_TRACES_ENDPOINT: Option<String> = std::env::var("OTLP_TRACES_ENDPOINT").ok().filter(|s| !s.is_empty());
+    static ref OTLP_SERVICE_NAME: String =
+        std::env::var("OTLP_SERVICE_NAME").unwrap_or_else(|_| "curiefense".to_string());
+    static ref OTLP_TRACES_SAMPLE: f64 = std::env::var("OTLP_TRACES_SAMPLE")
+        .ok()
+        .and_then(|s| s.parse().ok())
+        .unwrap_or(1.0);
+    static ref OUTBOUND: OutboundConfig = OutboundConfig::default();
+    static ref BREAKER: CircuitBreaker = CircuitBreaker::new(&OUTBOUND);
+}
+
+pub fn enabled() -> bool {
+    OTLP_TRACES_ENDPOINT.is_some()
+}
+
+fn random_id(bytes: usize) -> String {
+    let mut rng = rand::thread_rng();
+    (0..bytes).map(|_| format!("{:02x}", rng.gen::<u8>())).collect()
+}
+
+fn attr_str(key: &str, value: &str) -> serde_json::Value {
+    serde_json::json!({ "key": key, "value": { "stringValue": value } })
+}
+
+fn attr_bool(key: &str, value: bool) -> serde_json::Value {
+    serde_json::json!({ "key": key, "value": { "boolValue": value } })
+}
+
+/// builds the OTLP/HTTP JSON payload for one inspection
+///
+/// the root span covers the whole analysis, child spans are reconstructed
+/// from the cumulative stage stamps: each stage starts where the previous
+/// one ended
+fn build_payload(dec: &Decision, rinfo: &RequestInfo, stats: &Stats, now: &DateTime<Utc>) -> serde_json::Value {
+    let trace_id = random_id(16);
+    let root_id = random_id(8);
+    // timestamp_nanos_opt only fails for dates far outside the representable
+    // range, fall back to 0 instead of panicking
+    let start_ns = rinfo.timestamp.timestamp_nanos_opt().unwrap_or(0) as u64;
+    let end_ns = std::cmp::max(
+        start_ns + stats.timing.max_value() * 1000,
+        now.timestamp_nanos_opt().unwrap_or(0) as u64,
+    );
+
+    let mut spans = Vec::new();
+    let mut previous = 0u64;
+    for (name, stamp) in stats.timing.stages() {
+        spans.push(serde_json::json!({
+            "traceId": trace_id,
+            "spanId": random_id(8),
+            "parentSpanId": root_id,
+            "name": name,
+            "kind": 1,
+            "startTimeUnixNano": (start_ns + previous * 1000).to_string(),
+            "endTimeUnixNano": (start_ns + stamp * 1000).to_string(),
+        }));
+        previous = stamp;
+    }
+
+    let mut attributes = vec![
+        attr_str("http.request.method", &rinfo.rinfo.meta.method),
+        attr_str("url.path", &rinfo.rinfo.qinfo.qpath),
+        attr_str("curiefense.secpolid", &rinfo.rinfo.secpolicy.policy.id),
+        attr_str("curiefense.secpolentryid", &rinfo.rinfo.secpolicy.entry.id),
+        attr_bool("curiefense.blocked", dec.blocked()),
+    ];
+    if let Some(rid) = &rinfo.rinfo.meta.requestid {
+        attributes.push(attr_str("http.request.id", rid));
+    }
+    spans.push(serde_json::json!({
+        "traceId": trace_id,
+        "spanId": root_id,
+        "name": "inspection",
+        "kind": 2,
+        "startTimeUnixNano": start_ns.to_string(),
+        "endTimeUnixNano": end_ns.to_string(),
+        "attributes": attributes,
+    }));
+
+    serde_json::json!({
+        "resourceSpans": [{
+            "resource": {
+                "attributes": [ attr_str("service.name", &OTLP_SERVICE_NAME) ]
+            },
+            "scopeSpans": [{
+                "scope": { "name": "curiefense" },
+                "spans": spans,
+            }]
+        }]
+    })
+}
+
+/// posts a payload to the collector, returning an error description
+async fn post_traces(endpoint: &str, body: &[u8]) -> Result<(), String> {
+    use async_std::io::prelude::{ReadExt, WriteExt};
+    use async_std::net::TcpStream;
+
+    let mut stream = TcpStream::connect(endpoint).await.map_err(|rr| rr.to_string())?;
+    let request = format!(
+        "POST /v1/traces HTTP/1.1\r\nHost: {}\r\nContent-Type: application/json\r\nContent-Length: {}\r\nConnection: close\r\n\r\n",
+        endpoint,
+        body.len()
+    );
+    stream.write_all(request.as_bytes()).await.map_err(|rr| rr.to_string())?;
+    stream.write_all(body).await.map_err(|rr| rr.to_string())?;
+    let mut answer = [0u8; 32];
+    let n = stream.read(&mut answer).await.map_err(|rr| rr.to_string())?;
+    let status = std::str::from_utf8(&answer[..n]).unwrap_or_default();
+    // of the form "HTTP/1.1 200 OK"
+    match status.split(' ').nth(1) {
+        Some(code) if code.starts_with('2') => Ok(()),
+        _ => Err(format!("collector answered {}", status.lines().next().unwrap_or("?"))),
+    }
+}
+
+/// exports the spans for one inspection, the delivery happens on a detached
+/// task so that the request path is not delayed
+///
+/// delivery errors are dropped: the circuit breaker already stops retry
+/// storms against a dead collector, and traces are best effort
+pub fn report(dec: &Decision, rinfo: &RequestInfo, stats: &Stats, now: &DateTime<Utc>) {
+    let endpoint = match &*OTLP_TRACES_ENDPOINT {
+        None => return,
+        Some(e) => e.clone(),
+    };
+    if *OTLP_TRACES_SAMPLE < 1.0 && rand::thread_rng().gen::<f64>() >= *OTLP_TRACES_SAMPLE {
+        return;
+    }
+    let payload = build_payload(dec, rinfo, stats, now);
+    let body = match serde_json::to_vec(&payload) {
+        Ok(b) => b,
+        Err(_) => return,
+    };
+    async_std::task::spawn(async move {
+        let _ = with_backoff(&OUTBOUND, &BREAKER, |_| post_traces(&endpoint, &body)).await;
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn random_ids_are_hex_of_expected_size() {
+        let tid = random_id(16);
+        let sid = random_id(8);
+        assert_eq!(tid.len(), 32);
+        assert_eq!(sid.len(), 16);
+        assert!(tid.chars().all(|c| c.is_ascii_hexdigit()));
+    }
+}
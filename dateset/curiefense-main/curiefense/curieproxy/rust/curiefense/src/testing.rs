@@ -0,0 +1,171 @@
+/// Golden decision test fixtures
+///
+/// A fixture is a JSON file describing a full scenario: a reference to a
+/// configuration snapshot, a request, and the expected outcome. The runner
+/// executes directories of such fixtures, so that regression cases for rule
+/// behavior can be contributed without writing Rust code.
+use std::collections::HashMap;
+use std::path::Path;
+
+use serde::{Deserialize, Serialize};
+
+use crate::config::reload_config;
+use crate::grasshopper::testing::TestGrasshopper;
+use crate::inspect_generic_request_map;
+use crate::logs::{LogLevel, Logs};
+use crate::utils::{RawRequest, RequestMeta};
+
+/// serializable counterpart of RawRequest
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct FixtureRequest {
+    pub ipstr: String,
+    #[serde(default)]
+    pub headers: HashMap<String, String>,
+    pub method: String,
+    pub path: String,
+    #[serde(default)]
+    pub authority: Option<String>,
+    #[serde(default)]
+    pub body: Option<String>,
+}
+
+/// expectations that are checked against the analysis outcome
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct FixtureExpectation {
+    #[serde(default)]
+    pub blocked: bool,
+    /// expected action status code, only checked when set
+    #[serde(default)]
+    pub status: Option<u32>,
+    /// tags that must be present
+    #[serde(default)]
+    pub tags: Vec<String>,
+    /// tags that must not be present
+    #[serde(default)]
+    pub absent_tags: Vec<String>,
+}
+
+/// a full scenario: configuration snapshot reference, request, expected outcome
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Fixture {
+    pub name: String,
+    /// path to the configuration snapshot, relative to the fixture file
+    pub config: String,
+    pub request: FixtureRequest,
+    pub expected: FixtureExpectation,
+}
+
+/// the outcome of running a single fixture, with one entry per failed expectation
+#[derive(Debug, Clone, Serialize)]
+pub struct FixtureResult {
+    pub name: String,
+    pub errors: Vec<String>,
+}
+
+impl FixtureResult {
+    pub fn passed(&self) -> bool {
+        self.errors.is_empty()
+    }
+}
+
+/// serializes a scenario, making it easy to generate fixtures from existing traffic
+pub fn save_fixture(fixture: &Fixture, path: &Path) -> anyhow::Result<()> {
+    let file = std::fs::File::create(path)?;
+    serde_json::to_writer_pretty(file, fixture)?;
+    Ok(())
+}
+
+/// runs a single fixture, `basedir` being the directory the config reference is relative to
+pub fn run_fixture(fixture: &Fixture, basedir: &Path) -> FixtureResult {
+    let configpath = basedir.join(&fixture.config);
+    reload_config(&configpath.to_string_lossy(), Vec::new());
+
+    let mut logs = Logs::new(LogLevel::Error);
+    let body_bytes = fixture.request.body.as_ref().map(|b| b.as_bytes().to_vec());
+    let raw = RawRequest {
+        ipstr: fixture.request.ipstr.clone(),
+        headers: fixture.request.headers.clone(),
+        meta: RequestMeta {
+            authority: fixture.request.authority.clone(),
+            method: fixture.request.method.clone(),
+            path: fixture.request.path.clone(),
+            requestid: None,
+            protocol: None,
+            client_cert_subject: None,
+            client_cert_san: None,
+            client_cert_fingerprint: None,
+            extra: HashMap::new(),
+        },
+        mbody: body_bytes.as_deref(),
+    };
+    let result = inspect_generic_request_map::<TestGrasshopper>(None, raw, &mut logs, None, None, HashMap::new());
+
+    let mut errors = Vec::new();
+    let expected = &fixture.expected;
+    let blocked = result.decision.blocked();
+    if blocked != expected.blocked {
+        errors.push(format!("expected blocked={}, got blocked={}", expected.blocked, blocked));
+    }
+    if let Some(expected_status) = expected.status {
+        match result.decision.maction.as_ref().map(|a| a.status) {
+            Some(status) if status == expected_status => (),
+            status => errors.push(format!("expected status={}, got {:?}", expected_status, status)),
+        }
+    }
+    for tag in &expected.tags {
+        if !result.tags.contains(tag) {
+            errors.push(format!("missing expected tag {}", tag));
+        }
+    }
+    for tag in &expected.absent_tags {
+        if result.tags.contains(tag) {
+            errors.push(format!("unexpected tag {}", tag));
+        }
+    }
+    FixtureResult {
+        name: fixture.name.clone(),
+        errors,
+    }
+}
+
+/// runs all *.json fixtures from a directory, in file name order
+pub fn run_fixture_dir(dir: &Path) -> anyhow::Result<Vec<FixtureResult>> {
+    let mut paths: Vec<_> = std::fs::read_dir(dir)?
+        .filter_map(|e| e.ok().map(|e| e.path()))
+        .filter(|p| p.extension().map(|x| x == "json").unwrap_or(false))
+        .collect();
+    paths.sort();
+
+    let mut out = Vec::new();
+    for path in paths {
+        let content = std::fs::read_to_string(&path)?;
+        match serde_json::from_str::<Fixture>(&content) {
+            Ok(fixture) => out.push(run_fixture(&fixture, dir)),
+            Err(rr) => out.push(FixtureResult {
+                name: path.to_string_lossy().to_string(),
+                errors: vec![format!("could not parse fixture: {}", rr)],
+            }),
+        }
+    }
+    Ok(out)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_fixture_parsing_defaults() {
+        let content = r#"{
+            "name": "basic",
+            "config": "config",
+            "request": { "ipstr": "1.2.3.4", "method": "GET", "path": "/" },
+            "expected": { "blocked": false }
+        }"#;
+        let fixture: Fixture = serde_json::from_str(content).unwrap();
+        assert_eq!(fixture.name, "basic");
+        assert!(fixture.request.headers.is_empty());
+        assert!(fixture.expected.status.is_none());
+        assert!(fixture.expected.tags.is_empty());
+    }
+}
@@ -0,0 +1,97 @@
+/* Unified time window helpers
+
+   Limits historically count over rolling windows: a counter is created on
+   the first hit and expires `timeframe` seconds later. Quota style rules
+   ("1000 requests per day per API key") want calendar aligned windows
+   instead, where every counter resets on the same clock boundary. This
+   module centralizes the window arithmetic: the bucket identifier mixed
+   into counter keys, and the number of seconds left until the current
+   window rolls over, both in a configurable UTC offset so that "per day"
+   can follow a local midnight.
+*/
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TimeWindow {
+    /// the window starts at the first hit, counters live for the timeframe
+    Rolling,
+    Minute,
+    Hour,
+    Day,
+}
+
+impl Default for TimeWindow {
+    fn default() -> Self {
+        TimeWindow::Rolling
+    }
+}
+
+impl TimeWindow {
+    /// window length in seconds, None for rolling windows
+    pub fn span(&self) -> Option<u64> {
+        match self {
+            TimeWindow::Rolling => None,
+            TimeWindow::Minute => Some(60),
+            TimeWindow::Hour => Some(3600),
+            TimeWindow::Day => Some(86400),
+        }
+    }
+
+    /// parses the raw configuration value, a missing entry means rolling
+    pub fn from_raw(raw: Option<&str>) -> anyhow::Result<Self> {
+        match raw {
+            None | Some("rolling") => Ok(TimeWindow::Rolling),
+            Some("minute") => Ok(TimeWindow::Minute),
+            Some("hour") => Ok(TimeWindow::Hour),
+            Some("day") => Ok(TimeWindow::Day),
+            Some(other) => Err(anyhow::anyhow!("unknown window type {}", other)),
+        }
+    }
+}
+
+/// start of the current calendar window as a unix timestamp, None for
+/// rolling windows; `offset_minutes` shifts the boundaries east of UTC
+pub fn bucket_start(window: TimeWindow, offset_minutes: i32, now: u64) -> Option<u64> {
+    let span = window.span()? as i64;
+    let offset = offset_minutes as i64 * 60;
+    let shifted = now as i64 + offset;
+    let start = shifted - shifted.rem_euclid(span);
+    Some((start - offset) as u64)
+}
+
+/// seconds until the current window ends, rolling windows last `timeframe`
+pub fn seconds_to_rollover(window: TimeWindow, offset_minutes: i32, now: u64, timeframe: u64) -> u64 {
+    match (bucket_start(window, offset_minutes, now), window.span()) {
+        (Some(start), Some(span)) => start + span - now,
+        _ => timeframe,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn minute_buckets_are_aligned() {
+        let now = 1_000_000_030; // 10s into a minute
+        assert_eq!(bucket_start(TimeWindow::Minute, 0, now), Some(1_000_000_020));
+        assert_eq!(seconds_to_rollover(TimeWindow::Minute, 0, now, 999), 50);
+    }
+
+    #[test]
+    fn rolling_windows_have_no_bucket() {
+        assert_eq!(bucket_start(TimeWindow::Rolling, 0, 12345), None);
+        assert_eq!(seconds_to_rollover(TimeWindow::Rolling, 0, 12345, 60), 60);
+    }
+
+    #[test]
+    fn offset_shifts_day_boundaries() {
+        // 2001-09-09 01:46:40 UTC
+        let now = 1_000_000_000;
+        let utc = bucket_start(TimeWindow::Day, 0, now).unwrap();
+        // at UTC+2, local midnight is two hours before UTC midnight
+        let east = bucket_start(TimeWindow::Day, 120, now).unwrap();
+        assert_eq!(utc - east, 7200);
+        // the window always contains the current instant
+        assert!(east <= now && now < east + 86400);
+    }
+}
@@ -102,7 +102,9 @@ fn urldecode_bytes(input: &[u8]) -> DecodingResult<Vec<u8>> {
     }
 }
 
-fn base64dec_all(input: &str) -> Result<Vec<u8>, &str> {
+/// decodes a base64 string (standard or url-safe alphabet, with or without
+/// padding) into raw bytes
+pub fn base64dec_all(input: &str) -> Result<Vec<u8>, &str> {
     const BAD_PADDING_MESSAGE: &str = "bad padding";
     if input.len() % 4 == 1 {
         return Err(BAD_PADDING_MESSAGE);
@@ -3,7 +3,7 @@ use ipnet::IpNet;
 use itertools::Itertools;
 use maxminddb::geoip2::country;
 use serde_json::json;
-use sha2::{Digest, Sha224};
+use sha2::{Digest, Sha224, Sha256, Sha512};
 use std::collections::HashMap;
 use std::net::IpAddr;
 use std::sync::Arc;
@@ -14,9 +14,9 @@ pub mod templating;
 pub mod url;
 
 use crate::body::parse_body;
-use crate::config::contentfilter::Transformation;
+use crate::config::contentfilter::{CollisionPolicy, GraphqlLimits, MaskingSeed, Transformation};
 use crate::config::custom::Site;
-use crate::config::hostmap::SecurityPolicy;
+use crate::config::hostmap::{SecurityPolicy, SessionHash};
 use crate::config::matchers::{RequestSelector, RequestSelectorCondition};
 use crate::config::raw::ContentType;
 use crate::config::virtualtags::VirtualTags;
@@ -50,9 +50,13 @@ pub fn cookie_map(cookies: &mut RequestField, cookie: &str) {
 /// * extract cookies
 ///
 /// Returns (headers, cookies)
-pub fn map_headers(dec: &[Transformation], rawheaders: &HashMap<String, String>) -> (RequestField, RequestField) {
-    let mut cookies = RequestField::new(dec);
-    let mut headers = RequestField::new(dec);
+pub fn map_headers(
+    dec: &[Transformation],
+    collision: &CollisionPolicy,
+    rawheaders: &HashMap<String, String>,
+) -> (RequestField, RequestField) {
+    let mut cookies = RequestField::with_policy(dec, collision.clone());
+    let mut headers = RequestField::with_policy(dec, collision.clone());
     for (k, v) in rawheaders {
         let lk = k.to_lowercase();
         if lk == "cookie" {
@@ -104,6 +108,13 @@ fn parse_query_params(rf: &mut RequestField, query: &str, mode: ParseUriMode) {
 pub enum BodyProblem {
     TooDeep,
     DecodingError(String, Option<String>),
+    /// a graphql complexity budget was exceeded, the counter names the
+    /// dimension (fields, aliases, batch or introspection)
+    GraphqlBudget {
+        counter: &'static str,
+        actual: String,
+        expected: String,
+    },
 }
 
 impl std::fmt::Display for BodyProblem {
@@ -114,6 +125,11 @@ impl std::fmt::Display for BodyProblem {
                 Some(e) => write!(f, "actual:{} expected:{}", actual, e),
                 None => actual.fmt(f),
             },
+            BodyProblem::GraphqlBudget {
+                counter,
+                actual,
+                expected,
+            } => write!(f, "{} actual:{} expected:{}", counter, actual, expected),
         }
     }
 }
@@ -130,11 +146,14 @@ fn parse_uri(
     path_as_map: &mut RequestField,
     path: &str,
     mode: ParseUriMode,
+    flatten_args: bool,
 ) -> (String, Option<String>) {
     let prefix = mode.prefix();
     let (qpath, query) = match path.splitn(2, '?').collect_tuple() {
         Some((qpath, query)) => {
-            parse_query_params(args, query, mode);
+            if flatten_args {
+                parse_query_params(args, query, mode);
+            }
             let nquery = "?".to_string() + query;
             (qpath.to_string(), Some(nquery))
         }
@@ -161,25 +180,33 @@ fn parse_uri(
 
 /// parses the request uri, storing the path and query parts (if possible)
 /// returns the hashmap of arguments
+#[allow(clippy::too_many_arguments)]
 fn map_args(
     logs: &mut Logs,
     dec: &[Transformation],
+    collision: &CollisionPolicy,
     path: &str,
+    flatten_uri_args: bool,
     mcontent_type: Option<&str>,
     accepted_types: &[ContentType],
     mbody: Option<&[u8]>,
     max_depth: usize,
     graphql_path: &str,
+    graphql_limits: &GraphqlLimits,
 ) -> QueryInfo {
     // this is necessary to do this in this convoluted way so at not to borrow attrs
     let uri = match urldecode_str(path) {
         DecodingResult::NoChange => path.to_string(),
         DecodingResult::Changed(nuri) => nuri,
     };
-    let mut args = RequestField::new(dec);
-    let mut path_as_map = RequestField::new(dec);
-    let (qpath, query) = parse_uri(&mut args, &mut path_as_map, path, ParseUriMode::Uri);
-    logs.debug("uri parsed");
+    let mut args = RequestField::with_policy(dec, collision.clone());
+    let mut path_as_map = RequestField::with_policy(dec, collision.clone());
+    let (qpath, query) = parse_uri(&mut args, &mut path_as_map, path, ParseUriMode::Uri, flatten_uri_args);
+    if flatten_uri_args {
+        logs.debug("uri parsed");
+    } else {
+        logs.debug("uri parsed, query arguments skipped by the pre-parse guard");
+    }
 
     let body_decoding = if let Some(body) = mbody {
         logs.debug("body parsing start");
@@ -190,6 +217,7 @@ fn map_args(
             mcontent_type,
             accepted_types,
             graphql_path,
+            graphql_limits,
             body,
         ) {
             // if the body could not be parsed, store it in an argument, as if it was text
@@ -209,6 +237,12 @@ fn map_args(
         BodyDecodingResult::NoBody
     };
 
+    // uploaded files are kept aside for the file scanning stage
+    let uploads = match mbody {
+        Some(body) => crate::filescan::extract_uploaded_files(mcontent_type, body),
+        None => Vec::new(),
+    };
+
     QueryInfo {
         qpath,
         query,
@@ -216,6 +250,7 @@ fn map_args(
         args,
         path_as_map,
         body_decoding,
+        uploads,
     }
 }
 
@@ -231,6 +266,9 @@ pub struct QueryInfo {
     pub args: RequestField,
     pub path_as_map: RequestField,
     pub body_decoding: BodyDecodingResult,
+    /// files extracted from a multipart/form-data body, checked by the
+    /// configured file scanners
+    pub uploads: Vec<crate::filescan::UploadedFile>,
 }
 
 #[derive(Debug, Clone)]
@@ -339,6 +377,12 @@ pub struct RequestMeta {
     pub path: String,
     pub requestid: Option<String>,
     pub protocol: Option<String>,
+    /// mTLS client certificate subject, forwarded by the proxy when available
+    pub client_cert_subject: Option<String>,
+    /// mTLS client certificate subject alternative names, forwarded by the proxy when available
+    pub client_cert_san: Option<String>,
+    /// mTLS client certificate fingerprint, forwarded by the proxy when available
+    pub client_cert_fingerprint: Option<String>,
     /// this field only exists for gradual Lua interop
     /// TODO: remove when complete
     pub extra: HashMap<String, String>,
@@ -352,6 +396,9 @@ impl RequestMeta {
         let protocol = mattrs.remove("protocol");
         let method = mattrs.remove("method").ok_or("missing method field")?;
         let path = mattrs.remove("path").ok_or("missing path field")?;
+        let client_cert_subject = mattrs.remove("client-cert-subject");
+        let client_cert_san = mattrs.remove("client-cert-san");
+        let client_cert_fingerprint = mattrs.remove("client-cert-fingerprint");
         Ok(RequestMeta {
             authority,
             method,
@@ -359,8 +406,42 @@ impl RequestMeta {
             extra: mattrs,
             requestid,
             protocol,
+            client_cert_subject,
+            client_cert_san,
+            client_cert_fingerprint,
         })
     }
+
+    /// true when the request target is in absolute form (`GET http://host/path`)
+    pub fn is_absolute_form(&self) -> bool {
+        let lpath = self.path.to_ascii_lowercase();
+        lpath.starts_with("http://") || lpath.starts_with("https://")
+    }
+
+    /// true for HTTP/1.0 quirk requests
+    pub fn is_http10(&self) -> bool {
+        self.protocol
+            .as_deref()
+            .map(|p| p.eq_ignore_ascii_case("HTTP/1.0"))
+            .unwrap_or(false)
+    }
+
+    /// the path with scheme and authority stripped for absolute-form request
+    /// targets, so that security policy matching and argument parsing are not
+    /// confused by the authority part
+    pub fn normalized_path(&self) -> &str {
+        if !self.is_absolute_form() {
+            return &self.path;
+        }
+        let after_scheme = match self.path.find("://") {
+            None => return &self.path,
+            Some(p) => p + 3,
+        };
+        match self.path[after_scheme..].find('/') {
+            None => "/",
+            Some(p) => &self.path[after_scheme + p..],
+        }
+    }
 }
 
 #[derive(Debug, Clone)]
@@ -382,6 +463,15 @@ pub struct RequestInfo {
     pub rinfo: RInfo,
     pub session: String,
     pub session_ids: HashMap<String, String>,
+    /// raw session value, only kept when the policy enables session_keep_raw
+    /// so that limit keys can use it; it must never be written to the logs
+    pub session_raw: Option<String>,
+    /// canonical api key identity, hashed like the session so the raw key
+    /// never reaches the logs; None when the policy defines no key sources
+    /// or the request carried no key
+    pub api_key: Option<String>,
+    /// parsed bearer token, when the request carried one
+    pub jwt: Option<crate::jwt::JwtInfo>,
     pub plugins: RequestField,
 }
 
@@ -406,6 +496,9 @@ impl RequestInfo {
             ("ip", Some(self.rinfo.geoip.ipstr)),
             ("authority", Some(self.rinfo.host)),
             ("method", Some(self.rinfo.meta.method)),
+            ("client_cert_subject", self.rinfo.meta.client_cert_subject),
+            ("client_cert_san", self.rinfo.meta.client_cert_san),
+            ("client_cert_fingerprint", self.rinfo.meta.client_cert_fingerprint),
         ]
         .iter()
         .map(|(k, v)| (k.to_string(), v.clone()))
@@ -665,6 +758,30 @@ pub fn find_geoip(logs: &mut Logs, ipstr: String) -> GeoIp {
     geoip
 }
 
+/// meta attribute carrying the header order fingerprint, exposed as the
+/// "headersfp" attribute selector and tag
+pub const HEADERS_FP_ATTR: &str = "headersfp";
+
+/// order based fingerprint of the header names
+///
+/// header order is a strong bot signal, but is lost once the headers are
+/// stored in a map, so the fingerprint is computed by embedders that receive
+/// the headers as an ordered list, and carried in the request meta under
+/// [`HEADERS_FP_ATTR`]
+pub fn header_order_fingerprint<I, S>(names: I) -> String
+where
+    I: IntoIterator<Item = S>,
+    S: AsRef<str>,
+{
+    let mut hasher = Sha224::new();
+    for name in names {
+        hasher.update(name.as_ref().to_ascii_lowercase().as_bytes());
+        hasher.update(b"\n");
+    }
+    let bytes = hasher.finalize();
+    format!("{:x}", bytes)[0..16].to_string()
+}
+
 pub struct RawRequest<'a> {
     pub ipstr: String,
     pub headers: HashMap<String, String>,
@@ -693,14 +810,25 @@ pub fn map_request(
     let host = raw.get_host();
 
     logs.debug("map_request starts");
-    let (headers, cookies) = map_headers(&secpolicy.content_filter_profile.decoding, &raw.headers);
+    let (headers, cookies) = map_headers(
+        &secpolicy.content_filter_profile.decoding,
+        &secpolicy.content_filter_profile.collision_policy,
+        &raw.headers,
+    );
     logs.debug("headers mapped");
     let geoip = find_geoip(logs, raw.ipstr.clone());
     logs.debug("geoip computed");
+    // fast pre-parse guard: adversarially huge query strings are not flattened
+    let uri_restricted = secpolicy
+        .content_filter_profile
+        .uri_restriction(raw.meta.normalized_path())
+        .is_some();
     let mut qinfo = map_args(
         logs,
         &secpolicy.content_filter_profile.decoding,
-        &raw.meta.path,
+        &secpolicy.content_filter_profile.collision_policy,
+        raw.meta.normalized_path(),
+        !uri_restricted,
         headers.get_str("content-type"),
         &secpolicy.content_filter_profile.content_type,
         if secpolicy.content_filter_profile.ignore_body {
@@ -710,6 +838,7 @@ pub fn map_request(
         },
         secpolicy.content_filter_profile.max_body_depth,
         &secpolicy.content_filter_profile.graphql_path,
+        &secpolicy.content_filter_profile.graphql,
     );
     if secpolicy.content_filter_profile.referer_as_uri {
         if let Some(rf) = headers.get("referer") {
@@ -718,6 +847,7 @@ pub fn map_request(
                 &mut qinfo.path_as_map,
                 url::drop_scheme(rf),
                 ParseUriMode::Referer,
+                true,
             );
         }
     }
@@ -733,12 +863,19 @@ pub fn map_request(
         container_name,
     };
 
-    let mut plugins_field = RequestField::new(&[]);
+    let mut plugins_field = RequestField::with_policy(&[], secpolicy.content_filter_profile.collision_policy.clone());
     for (k, v) in plugins {
         let l = Location::PluginValue(k.clone(), v.clone());
         plugins_field.add(k, l, v);
     }
 
+    // bearer tokens are parsed before the session is computed, so that
+    // session selectors can use jwt claims
+    let jwt = headers
+        .get_str("authorization")
+        .and_then(crate::jwt::bearer_token)
+        .and_then(|t| crate::jwt::parse_jwt(t, &secpolicy.jwt_keys));
+
     let dummy_reqinfo = RequestInfo {
         timestamp: ts.unwrap_or_else(Utc::now),
         cookies,
@@ -746,6 +883,9 @@ pub fn map_request(
         rinfo,
         session: String::new(),
         session_ids: HashMap::new(),
+        session_raw: None,
+        api_key: None,
+        jwt,
         plugins: plugins_field,
     };
 
@@ -759,13 +899,11 @@ pub fn map_request(
     .next()
     .unwrap_or_else(|| "???".to_string());
 
-    let session_string = |s: &str| {
-        let mut hasher = Sha224::new();
-        hasher.update(&secpolicy.content_filter_profile.masking_seed);
-        hasher.update(s.as_bytes());
-        let bytes = hasher.finalize();
-        format!("{:x}", bytes)
+    let seed: &[u8] = match &secpolicy.session_seed {
+        Some(s) => s.as_bytes(),
+        None => &secpolicy.content_filter_profile.masking_seed.seed,
     };
+    let session_string = |s: &str| session_hash_string(secpolicy.session_hash, seed, s.as_bytes());
 
     let session = session_string(&raw_session);
     let session_ids = secpolicy
@@ -773,6 +911,18 @@ pub fn map_request(
         .iter()
         .filter_map(|s| select_string(&dummy_reqinfo, s, None).map(|str| (s.to_string(), session_string(&str))))
         .collect();
+    let session_raw = if secpolicy.session_keep_raw {
+        Some(raw_session)
+    } else {
+        None
+    };
+    let api_key = secpolicy.api_key.as_ref().and_then(|ak| {
+        ak.sources
+            .iter()
+            .filter_map(|s| select_string(&dummy_reqinfo, s, None))
+            .next()
+            .map(|value| session_string(ak.strip(&value)))
+    });
 
     RequestInfo {
         timestamp: dummy_reqinfo.timestamp,
@@ -781,10 +931,28 @@ pub fn map_request(
         rinfo: dummy_reqinfo.rinfo,
         session,
         session_ids,
+        session_raw,
+        api_key,
+        jwt: dummy_reqinfo.jwt,
         plugins: dummy_reqinfo.plugins,
     }
 }
 
+/// hashes a session value with the algorithm selected by the policy
+pub fn session_hash_string(algorithm: SessionHash, seed: &[u8], value: &[u8]) -> String {
+    fn run<D: Digest>(seed: &[u8], value: &[u8]) -> String {
+        let mut hasher = D::new();
+        hasher.update(seed);
+        hasher.update(value);
+        hasher.finalize().iter().map(|b| format!("{:02x}", b)).collect()
+    }
+    match algorithm {
+        SessionHash::Sha224 => run::<Sha224>(seed, value),
+        SessionHash::Sha256 => run::<Sha256>(seed, value),
+        SessionHash::Sha512 => run::<Sha512>(seed, value),
+    }
+}
+
 pub enum Selected<'a> {
     OStr(String),    // owned
     Str(&'a String), // ref
@@ -816,7 +984,18 @@ pub fn selector<'a>(reqinfo: &'a RequestInfo, sel: &RequestSelector, tags: Optio
         RequestSelector::SecpolEntryId => Some(Selected::Str(&reqinfo.rinfo.secpolicy.entry.id)),
         RequestSelector::Region => reqinfo.rinfo.geoip.region.as_ref().map(Selected::Str),
         RequestSelector::SubRegion => reqinfo.rinfo.geoip.subregion.as_ref().map(Selected::Str),
-        RequestSelector::Session => Some(Selected::Str(&reqinfo.session)),
+        // the raw value is only kept when the policy opted into it, for limit keys
+        RequestSelector::Session => Some(Selected::Str(reqinfo.session_raw.as_ref().unwrap_or(&reqinfo.session))),
+        RequestSelector::ApiKey => reqinfo.api_key.as_ref().map(Selected::Str),
+        RequestSelector::JwtClaim(k) => reqinfo.jwt.as_ref().and_then(|j| j.claims.get(k)).map(Selected::Str),
+        RequestSelector::HeadersFingerprint => reqinfo.rinfo.meta.extra.get(HEADERS_FP_ATTR).map(Selected::Str),
+        RequestSelector::ClientCertSubject => reqinfo.rinfo.meta.client_cert_subject.as_ref().map(Selected::Str),
+        RequestSelector::ClientCertSan => reqinfo.rinfo.meta.client_cert_san.as_ref().map(Selected::Str),
+        RequestSelector::ClientCertFingerprint => {
+            reqinfo.rinfo.meta.client_cert_fingerprint.as_ref().map(Selected::Str)
+        }
+        RequestSelector::PolicyFeature(k) => reqinfo.rinfo.secpolicy.features.get(k).map(Selected::Str),
+        RequestSelector::SampleBucket => Some(Selected::U32(crate::sampling::sample_bucket(reqinfo))),
     }
 }
 
@@ -840,13 +1019,16 @@ pub fn check_selector_cond(reqinfo: &RequestInfo, tags: &Tags, sel: &RequestSele
     }
 }
 
-pub fn masker(seed: &[u8], value: &str) -> String {
+pub fn masker(seed: &MaskingSeed, value: &str) -> String {
     let mut hasher = Sha224::new();
-    hasher.update(seed);
+    hasher.update(&seed.seed);
     hasher.update(value.as_bytes());
     let bytes = hasher.finalize();
     let hash_str = format!("{:x}", bytes);
-    format!("MASKED{{{}}}", &hash_str[0..8])
+    match &seed.id {
+        None => format!("MASKED{{{}}}", &hash_str[0..8]),
+        Some(id) => format!("MASKED{{{}:{}}}", id, &hash_str[0..8]),
+    }
 }
 
 pub fn eat_errors<T: Default, R: std::fmt::Display>(logs: &mut Logs, rv: Result<T, R>) -> T {
@@ -863,18 +1045,37 @@ pub fn eat_errors<T: Default, R: std::fmt::Display>(logs: &mut Logs, rv: Result<
 mod tests {
     use super::*;
 
+    #[test]
+    fn header_order_fingerprint_case_insensitive() {
+        assert_eq!(
+            header_order_fingerprint(["Host", "User-Agent", "Accept"]),
+            header_order_fingerprint(["host", "user-agent", "accept"])
+        );
+    }
+
+    #[test]
+    fn header_order_fingerprint_order_sensitive() {
+        assert_ne!(
+            header_order_fingerprint(["host", "user-agent", "accept"]),
+            header_order_fingerprint(["user-agent", "host", "accept"])
+        );
+    }
+
     #[test]
     fn test_map_args_full() {
         let mut logs = Logs::default();
         let qinfo = map_args(
             &mut logs,
             &[Transformation::Base64Decode],
+            &CollisionPolicy::default(),
             "/a/b/%20c?xa%20=12&bbbb=12%28&cccc&b64=YXJndW1lbnQ%3D",
+            true,
             None,
             &[],
             None,
             500,
             "",
+            &GraphqlLimits::default(),
         );
 
         assert_eq!(qinfo.qpath, "/a/b/%20c");
@@ -920,10 +1121,51 @@ mod tests {
         assert_eq!(qinfo.args.fields, expected_args.fields);
     }
 
+    #[test]
+    fn test_normalized_path() {
+        let mk = |path: &str, protocol: Option<&str>| RequestMeta {
+            authority: None,
+            method: "GET".to_string(),
+            path: path.to_string(),
+            requestid: None,
+            protocol: protocol.map(|p| p.to_string()),
+            client_cert_subject: None,
+            client_cert_san: None,
+            client_cert_fingerprint: None,
+            extra: HashMap::new(),
+        };
+        let meta = mk("/a/b?x=1", None);
+        assert!(!meta.is_absolute_form());
+        assert!(!meta.is_http10());
+        assert_eq!(meta.normalized_path(), "/a/b?x=1");
+
+        let meta = mk("http://host.example.com/a/b?x=1", Some("HTTP/1.0"));
+        assert!(meta.is_absolute_form());
+        assert!(meta.is_http10());
+        assert_eq!(meta.normalized_path(), "/a/b?x=1");
+
+        let meta = mk("HTTPS://host.example.com", Some("HTTP/1.1"));
+        assert!(meta.is_absolute_form());
+        assert!(!meta.is_http10());
+        assert_eq!(meta.normalized_path(), "/");
+    }
+
     #[test]
     fn test_map_args_simple() {
         let mut logs = Logs::default();
-        let qinfo = map_args(&mut logs, &[], "/a/b", None, &[], None, 500, "");
+        let qinfo = map_args(
+            &mut logs,
+            &[],
+            &CollisionPolicy::default(),
+            "/a/b",
+            true,
+            None,
+            &[],
+            None,
+            500,
+            "",
+            &GraphqlLimits::default(),
+        );
 
         assert_eq!(qinfo.qpath, "/a/b");
         assert_eq!(qinfo.uri, "/a/b");
@@ -932,6 +1174,19 @@ mod tests {
         assert_eq!(qinfo.args, RequestField::new(&[]));
     }
 
+    #[test]
+    fn session_hash_algorithms() {
+        // the default algorithm must keep producing the legacy sha224 digests
+        let sha224 = session_hash_string(SessionHash::Sha224, b"seed", b"value");
+        let mut hasher = Sha224::new();
+        hasher.update(b"seed");
+        hasher.update(b"value");
+        assert_eq!(sha224, format!("{:x}", hasher.finalize()));
+        assert_eq!(sha224.len(), 56);
+        assert_eq!(session_hash_string(SessionHash::Sha256, b"seed", b"value").len(), 64);
+        assert_eq!(session_hash_string(SessionHash::Sha512, b"seed", b"value").len(), 128);
+    }
+
     #[test]
     fn referer_a() {
         let raw = RawRequest {
@@ -947,6 +1202,9 @@ mod tests {
                 path: "/this/is/the/path?arg1=x&arg2=y".to_string(),
                 requestid: None,
                 protocol: None,
+                client_cert_subject: None,
+                client_cert_san: None,
+                client_cert_fingerprint: None,
                 extra: HashMap::new(),
             },
             mbody: None,
@@ -996,4 +1254,89 @@ mod tests {
         assert_eq!(expected_args, actual_args);
         assert_eq!(expected_path, actual_path);
     }
+
+    #[test]
+    fn jwt_claim_selector() {
+        // the classic HS256 example token, signed with the key "secret"
+        let token = "eyJhbGciOiJIUzI1NiIsInR5cCI6IkpXVCJ9.\
+             eyJzdWIiOiIxMjM0NTY3ODkwIiwibmFtZSI6IkpvaG4gRG9lIiwiYWRtaW4iOnRydWV9.\
+             TJVA95OrM7E2cBab30RMHrHDcEfxjoYZgeFONFh7HgQ";
+        let raw = RawRequest {
+            ipstr: "1.2.3.4".to_string(),
+            headers: std::iter::once(("authorization".to_string(), format!("Bearer {}", token))).collect(),
+            meta: RequestMeta {
+                authority: Some("main.site".to_string()),
+                method: "GET".to_string(),
+                path: "/".to_string(),
+                requestid: None,
+                protocol: None,
+                client_cert_subject: None,
+                client_cert_san: None,
+                client_cert_fingerprint: None,
+                extra: HashMap::new(),
+            },
+            mbody: None,
+        };
+        let mut logs = Logs::new(crate::logs::LogLevel::Debug);
+        let secpol = SecurityPolicy::empty();
+        let site = Site::default();
+        let ri = map_request(
+            &mut logs,
+            Arc::new(secpol),
+            Arc::new(site),
+            None,
+            &raw,
+            None,
+            HashMap::new(),
+        );
+        assert_eq!(
+            select_string(&ri, &RequestSelector::JwtClaim("sub".to_string()), None),
+            Some("1234567890".to_string())
+        );
+        assert_eq!(select_string(&ri, &RequestSelector::JwtClaim("aud".to_string()), None), None);
+        // no keys are configured, so the token is parsed but not verified
+        assert_eq!(ri.jwt.as_ref().and_then(|j| j.verified), None);
+    }
+
+    #[test]
+    fn client_cert_selectors() {
+        let raw = RawRequest {
+            ipstr: "1.2.3.4".to_string(),
+            headers: HashMap::new(),
+            meta: RequestMeta {
+                authority: Some("main.site".to_string()),
+                method: "GET".to_string(),
+                path: "/".to_string(),
+                requestid: None,
+                protocol: None,
+                client_cert_subject: Some("CN=client,O=acme".to_string()),
+                client_cert_san: Some("spiffe://acme/client".to_string()),
+                client_cert_fingerprint: Some("deadbeef".to_string()),
+                extra: HashMap::new(),
+            },
+            mbody: None,
+        };
+        let mut logs = Logs::new(crate::logs::LogLevel::Debug);
+        let ri = map_request(
+            &mut logs,
+            Arc::new(SecurityPolicy::empty()),
+            Arc::new(Site::default()),
+            None,
+            &raw,
+            None,
+            HashMap::new(),
+        );
+        assert_eq!(
+            select_string(&ri, &RequestSelector::ClientCertSubject, None),
+            Some("CN=client,O=acme".to_string())
+        );
+        assert_eq!(
+            select_string(&ri, &RequestSelector::ClientCertSan, None),
+            Some("spiffe://acme/client".to_string())
+        );
+        assert_eq!(
+            select_string(&ri, &RequestSelector::ClientCertFingerprint, None),
+            Some("deadbeef".to_string())
+        );
+    }
 }
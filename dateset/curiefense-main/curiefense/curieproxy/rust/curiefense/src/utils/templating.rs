@@ -13,6 +13,16 @@ use crate::config::matchers::RequestSelector;
 pub enum TVar {
     Selector(RequestSelector),
     Tag(String), // match for a specific tag
+    BlockPage(BlockPageVar),
+}
+
+/// typed variables available to block page templates as ${blockpage.<field>}
+#[derive(Debug, PartialEq, Eq, Clone)]
+pub enum BlockPageVar {
+    RequestId,
+    Timestamp,
+    ReasonCode,
+    SupportContact,
 }
 
 #[derive(Debug, PartialEq, Eq)]
@@ -84,6 +94,13 @@ fn parse_tvar(input: &str) -> IResult<&str, TVar> {
             }
         }
         ("tags", Some(tagname)) => Ok((input, TVar::Tag(tagname.to_string()))),
+        ("blockpage", Some(field)) => match field {
+            "request_id" => Ok((input, TVar::BlockPage(BlockPageVar::RequestId))),
+            "timestamp" => Ok((input, TVar::BlockPage(BlockPageVar::Timestamp))),
+            "reason_code" => Ok((input, TVar::BlockPage(BlockPageVar::ReasonCode))),
+            "support_contact" => Ok((input, TVar::BlockPage(BlockPageVar::SupportContact))),
+            _ => nom::combinator::fail(input),
+        },
         (_, Some(selp2)) => match RequestSelector::resolve_selector_raw(selp1, selp2) {
             Err(_) => nom::combinator::fail(input),
             Ok(t) => Ok((input, TVar::Selector(t))),
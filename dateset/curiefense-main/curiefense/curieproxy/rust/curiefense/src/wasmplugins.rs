@@ -0,0 +1,196 @@
+/* embedded WASM plugin runtime (feature "wasm-plugins")
+
+   Plugin pipeline steps that carry a module reference are executed by the
+   core itself instead of relying on an embedder callout. Modules are loaded
+   from the wasm directory of the configuration, compiled once and cached by
+   path, then run between tagging and the ACL stages with a fuel budget and
+   an epoch based wall clock deadline, so a misbehaving module cannot stall
+   request processing.
+
+   The guest ABI is deliberately small:
+     - "memory": the exported linear memory
+     - "alloc(len: i32) -> i32": returns a guest pointer where the host
+       writes the request JSON
+     - "inspect(ptr: i32, len: i32) -> i64": inspects the request JSON and
+       returns a response pointer/length packed as (ptr << 32) | len
+
+   The response is a JSON document with an optional "tags" list, inserted
+   with a plugin location, and an optional "hint" that is advisory unless
+   the step carries a failure action.
+*/
+
+use anyhow::Context;
+use lazy_static::lazy_static;
+use serde::Deserialize;
+use std::collections::HashMap;
+use std::path::Path;
+use std::sync::RwLock;
+use std::time::Duration;
+use wasmtime::{Config as WasmConfig, Engine, Instance, Module, Store};
+
+use crate::interface::Tags;
+use crate::pluginpipeline::PluginStep;
+use crate::utils::RequestInfo;
+
+/// default fuel budget for a single inspect call
+pub const DEFAULT_FUEL: u64 = 10_000_000;
+/// default wall clock budget for a single inspect call, in microseconds
+pub const DEFAULT_TIMEOUT_US: u64 = 50_000;
+/// granularity of the epoch ticker, deadlines are rounded up to a tick
+const EPOCH_TICK_US: u64 = 1_000;
+
+lazy_static! {
+    pub static ref WASM_HOST: WasmPluginHost = WasmPluginHost::new();
+}
+
+/// hint returned by a module, advisory unless the step carries a failure action
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum DecisionHint {
+    Pass,
+    Monitor,
+    Block,
+}
+
+/// decoded response of a single inspect call
+#[derive(Debug, Clone, Deserialize)]
+pub struct WasmInspection {
+    #[serde(default)]
+    pub tags: Vec<String>,
+    #[serde(default)]
+    pub hint: Option<DecisionHint>,
+}
+
+pub struct WasmPluginHost {
+    engine: Engine,
+    /// compiled modules, cached by path
+    modules: RwLock<HashMap<String, Module>>,
+}
+
+impl Default for WasmPluginHost {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl WasmPluginHost {
+    pub fn new() -> Self {
+        let mut wconfig = WasmConfig::new();
+        wconfig.consume_fuel(true);
+        wconfig.epoch_interruption(true);
+        let engine = Engine::new(&wconfig).expect("could not build the WASM engine");
+        // the ticker thread drives the wall clock deadlines of all stores
+        let ticker = engine.clone();
+        std::thread::spawn(move || loop {
+            std::thread::sleep(Duration::from_micros(EPOCH_TICK_US));
+            ticker.increment_epoch();
+        });
+        WasmPluginHost {
+            engine,
+            modules: RwLock::new(HashMap::new()),
+        }
+    }
+
+    /// returns the compiled module for a path, compiling and caching it on first use
+    fn module(&self, path: &Path) -> anyhow::Result<Module> {
+        let key = path.to_string_lossy().to_string();
+        if let Some(module) = self.modules.read().ok().and_then(|cache| cache.get(&key).cloned()) {
+            return Ok(module);
+        }
+        let module =
+            Module::from_file(&self.engine, path).with_context(|| format!("loading the WASM module {}", key))?;
+        if let Ok(mut cache) = self.modules.write() {
+            cache.insert(key, module.clone());
+        }
+        Ok(module)
+    }
+
+    /// runs the module of a pipeline step against the request JSON
+    pub fn inspect(&self, step: &PluginStep, request: &str) -> anyhow::Result<WasmInspection> {
+        let path = step
+            .module
+            .as_ref()
+            .ok_or_else(|| anyhow::anyhow!("plugin step {} has no module", step.id))?;
+        let module = self.module(path)?;
+        self.run(
+            &module,
+            request,
+            step.fuel.unwrap_or(DEFAULT_FUEL),
+            step.timeout_us.unwrap_or(DEFAULT_TIMEOUT_US),
+        )
+    }
+
+    fn run(&self, module: &Module, request: &str, fuel: u64, timeout_us: u64) -> anyhow::Result<WasmInspection> {
+        let mut store = Store::new(&self.engine, ());
+        store.set_fuel(fuel)?;
+        store.set_epoch_deadline(1 + timeout_us / EPOCH_TICK_US);
+        let instance = Instance::new(&mut store, module, &[])?;
+        let memory = instance
+            .get_memory(&mut store, "memory")
+            .ok_or_else(|| anyhow::anyhow!("the module does not export its memory"))?;
+        let alloc = instance.get_typed_func::<i32, i32>(&mut store, "alloc")?;
+        let inspect = instance.get_typed_func::<(i32, i32), i64>(&mut store, "inspect")?;
+
+        let ptr = alloc.call(&mut store, request.len() as i32)?;
+        memory.write(&mut store, ptr as usize, request.as_bytes())?;
+        let packed = inspect.call(&mut store, (ptr, request.len() as i32))?;
+
+        let rptr = (packed >> 32) as u32 as usize;
+        let rlen = packed as u32 as usize;
+        let bytes = memory
+            .data(&store)
+            .get(rptr..rptr + rlen)
+            .ok_or_else(|| anyhow::anyhow!("inspect returned an out of bounds slice"))?;
+        serde_json::from_slice(bytes).context("decoding the inspect response")
+    }
+}
+
+/// the request document handed to the inspect export
+pub fn request_json(reqinfo: &RequestInfo, tags: &Tags) -> String {
+    serde_json::json!({
+        "ip": reqinfo.rinfo.geoip.ipstr,
+        "method": reqinfo.rinfo.meta.method,
+        "path": reqinfo.rinfo.qinfo.qpath,
+        "query": reqinfo.rinfo.qinfo.query,
+        "headers": reqinfo.headers.as_map(),
+        "cookies": reqinfo.cookies.as_map(),
+        "args": reqinfo.rinfo.qinfo.args.as_map(),
+        "tags": tags,
+    })
+    .to_string()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // returns a static response, {"tags":["from-wasm"],"hint":"block"} is 37 bytes long
+    const STATIC_WAT: &str = r#"(module
+      (memory (export "memory") 1)
+      (data (i32.const 1024) "{\"tags\":[\"from-wasm\"],\"hint\":\"block\"}")
+      (func (export "alloc") (param i32) (result i32) (i32.const 4096))
+      (func (export "inspect") (param i32 i32) (result i64)
+        (i64.or (i64.shl (i64.const 1024) (i64.const 32)) (i64.const 37))))"#;
+
+    const LOOPING_WAT: &str = r#"(module
+      (memory (export "memory") 1)
+      (func (export "alloc") (param i32) (result i32) (i32.const 4096))
+      (func (export "inspect") (param i32 i32) (result i64)
+        (loop $l (br $l))
+        (i64.const 0)))"#;
+
+    #[test]
+    fn inspect_returns_tags_and_hint() {
+        let module = Module::new(&WASM_HOST.engine, STATIC_WAT).unwrap();
+        let inspection = WASM_HOST.run(&module, "{}", DEFAULT_FUEL, DEFAULT_TIMEOUT_US).unwrap();
+        assert_eq!(inspection.tags, vec!["from-wasm".to_string()]);
+        assert_eq!(inspection.hint, Some(DecisionHint::Block));
+    }
+
+    #[test]
+    fn runaway_module_is_interrupted() {
+        let module = Module::new(&WASM_HOST.engine, LOOPING_WAT).unwrap();
+        // the fuel budget trips before the wall clock deadline
+        assert!(WASM_HOST.run(&module, "{}", 10_000, DEFAULT_TIMEOUT_US).is_err());
+    }
+}